@@ -0,0 +1,59 @@
+//! Host block device: a sector-addressed request queue in guest memory at
+//! `mmio::BLOCK_ADDR`, served by the host from a disk image file
+//!
+//! Each slot holds one request: a fixed header followed by the data
+//! buffer. The guest fills in a slot, bumps `HEAD` and issues
+//! `HyperCall::BlockNotify`; the host carries out every pending request,
+//! writes its `status` (and, for reads, the data) and bumps `DONE` before
+//! the hypercall returns. If `DONE` hasn't moved, there is no block device.
+
+pub const SECTOR_SIZE: usize = 512;
+/// Most sectors one request can move
+pub const MAX_SECTORS: usize = 64;
+
+// --- Queue layout ---
+
+pub const QUEUE_SLOTS: usize = 4;
+pub const HEADER_SIZE: usize = 64;
+pub const REQUEST_HEADER: usize = 64;
+pub const SLOT_SIZE: usize = REQUEST_HEADER + MAX_SECTORS * SECTOR_SIZE;
+pub const QUEUE_SIZE: usize = HEADER_SIZE + QUEUE_SLOTS * SLOT_SIZE;
+/// `u32` offset in the header: requests submitted by the guest
+pub const HEAD: usize = 0;
+/// `u32` offset in the header: requests completed by the host
+pub const DONE: usize = 4;
+
+/// Offset from `BLOCK_ADDR` of the slot for request number `n`
+pub const fn slot_offset(n: u32) -> usize {
+    HEADER_SIZE + (n as usize % QUEUE_SLOTS) * SLOT_SIZE
+}
+
+// --- Request header, at the start of each slot ---
+
+/// `u32`: one of the `OP_*` values
+pub const OP: usize = 0;
+/// `u32`: one of the `STATUS_*` values, written by the host
+pub const STATUS: usize = 4;
+/// `u64`: first sector; for `OP_CAPACITY`, the host's answer
+pub const SECTOR: usize = 8;
+/// `u32`: sectors to move, at most `MAX_SECTORS`
+pub const COUNT: usize = 16;
+/// Data follows the header
+pub const DATA: usize = REQUEST_HEADER;
+
+pub const OP_READ: u32 = 0;
+pub const OP_WRITE: u32 = 1;
+pub const OP_FLUSH: u32 = 2;
+/// Report the disk size in sectors, and whether it's writable
+pub const OP_CAPACITY: u32 = 3;
+
+pub const STATUS_OK: u32 = 0;
+pub const STATUS_IOERR: u32 = 1;
+pub const STATUS_UNSUPPORTED: u32 = 2;
+/// A write to a read-only disk
+pub const STATUS_READ_ONLY: u32 = 3;
+/// A request past the end of the disk, or for more than `MAX_SECTORS`
+pub const STATUS_OUT_OF_RANGE: u32 = 4;
+
+/// `OP_CAPACITY` puts these flags in `COUNT`
+pub const FLAG_READ_ONLY: u32 = 1;
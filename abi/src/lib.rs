@@ -1,13 +1,24 @@
 #![no_std]
 
+pub mod block;
+pub mod share;
+
 #[repr(u64)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HyperCall {
     Print = 0,
     Exit = 1,
+    /// Returns nanoseconds since the UNIX epoch in x0/rax
+    GetTime = 2,
+    /// Answer the requests queued at `mmio::SHARE_ADDR` (see `share`)
+    ShareNotify = 3,
+    /// Carry out the requests queued at `mmio::BLOCK_ADDR` (see `block`)
+    BlockNotify = 4,
+    /// Returns 64 bits from the host's entropy source in x0/rax
+    GetRandom = 5,
     // Future:
-    // DrawFrame = 2,
-    // Sleep = 3,
+    // DrawFrame = 6,
+    // Sleep = 7,
 }
 
 impl HyperCall {
@@ -15,6 +26,10 @@ impl HyperCall {
         match val {
             0 => Some(Self::Print),
             1 => Some(Self::Exit),
+            2 => Some(Self::GetTime),
+            3 => Some(Self::ShareNotify),
+            4 => Some(Self::BlockNotify),
+            5 => Some(Self::GetRandom),
             _ => None,
         }
     }
@@ -26,4 +41,19 @@ pub mod mmio {
     pub const DISK_ADDR: usize = 0x300000;        // 3MB offset
     pub const KEYBOARD_STATUS: usize = 0x80000;
     pub const KEYBOARD_DATA: usize = 0x80004;
+    // Pointer state, rewritten by the host every frame
+    pub const POINTER_X: usize = 0x80010;
+    pub const POINTER_Y: usize = 0x80014;
+    pub const POINTER_BUTTONS: usize = 0x80018; // bit 0 = left, 1 = right, 2 = middle
+    // Device request queues, in a window at the top of RAM that the guest
+    // heap and the RAM disk stop short of
+    pub const QUEUE_WINDOW_SIZE: usize = 0x40000;
+    pub const QUEUE_WINDOW: usize = RAM_SIZE - QUEUE_WINDOW_SIZE;
+    // Host directory share request queue (`share::QUEUE_SIZE` bytes)
+    pub const SHARE_ADDR: usize = QUEUE_WINDOW;
+    // Block device request queue (`block::QUEUE_SIZE` bytes)
+    pub const BLOCK_ADDR: usize = QUEUE_WINDOW + 0x10000;
 }
+
+const _: () = assert!(mmio::SHARE_ADDR + share::QUEUE_SIZE <= mmio::BLOCK_ADDR);
+const _: () = assert!(mmio::BLOCK_ADDR + block::QUEUE_SIZE <= mmio::RAM_SIZE);
@@ -0,0 +1,270 @@
+//! Host directory sharing: a subset of 9P2000.L carried over a queue in
+//! guest memory at `mmio::SHARE_ADDR`
+//!
+//! The guest copies a T-message into the next slot, bumps `HEAD` and issues
+//! `HyperCall::ShareNotify`. The host answers every pending request in
+//! place (the R-message overwrites the T-message in its slot) and bumps
+//! `DONE` before the hypercall returns, so a guest with one request in
+//! flight never has to wait. If `DONE` hasn't moved when the call returns,
+//! there is no share device.
+//!
+//! Messages use 9P framing: `size[4] type[1] tag[2]` followed by the
+//! body, little-endian, with strings as `len[2]` and UTF-8 bytes.
+
+// --- Queue layout ---
+
+/// Largest message in either direction, and the size of a slot
+pub const MSIZE: usize = 8192;
+pub const QUEUE_SLOTS: usize = 4;
+pub const HEADER_SIZE: usize = 64;
+pub const QUEUE_SIZE: usize = HEADER_SIZE + QUEUE_SLOTS * MSIZE;
+/// `u32` offset in the header: requests submitted by the guest
+pub const HEAD: usize = 0;
+/// `u32` offset in the header: requests answered by the host
+pub const DONE: usize = 4;
+
+/// Offset from `SHARE_ADDR` of the slot for request number `n`
+pub const fn slot_offset(n: u32) -> usize {
+    HEADER_SIZE + (n as usize % QUEUE_SLOTS) * MSIZE
+}
+
+pub const VERSION: &str = "9P2000.L";
+/// Framing before the body: size, type and tag
+pub const MESSAGE_HEADER: usize = 7;
+/// Most data a single Tread/Twrite can carry within `MSIZE`
+pub const IO_MAX: usize = MSIZE - MESSAGE_HEADER - 4 - 8 - 4;
+pub const NOFID: u32 = !0;
+pub const NOTAG: u16 = !0;
+
+// --- Message types (9P2000.L numbering; R is always T + 1) ---
+
+pub const RLERROR: u8 = 7;
+pub const TLOPEN: u8 = 12;
+pub const TLCREATE: u8 = 14;
+pub const TREADLINK: u8 = 22;
+pub const TGETATTR: u8 = 24;
+pub const TSETATTR: u8 = 26;
+pub const TREADDIR: u8 = 40;
+pub const TFSYNC: u8 = 50;
+pub const TMKDIR: u8 = 72;
+pub const TRENAMEAT: u8 = 74;
+pub const TUNLINKAT: u8 = 76;
+pub const TVERSION: u8 = 100;
+pub const TATTACH: u8 = 104;
+pub const TWALK: u8 = 110;
+pub const TREAD: u8 = 116;
+pub const TWRITE: u8 = 118;
+pub const TCLUNK: u8 = 120;
+
+/// Most names in one Twalk
+pub const MAX_WALK: usize = 16;
+
+// Qid types
+pub const QT_DIR: u8 = 0x80;
+pub const QT_SYMLINK: u8 = 0x02;
+pub const QT_FILE: u8 = 0x00;
+
+// Tlopen/Tlcreate flags (Linux values)
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_ACCMODE: u32 = 3;
+pub const O_CREAT: u32 = 0o100;
+pub const O_EXCL: u32 = 0o200;
+pub const O_TRUNC: u32 = 0o1000;
+pub const O_APPEND: u32 = 0o2000;
+
+/// Tunlinkat flag: remove a directory
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+// Tgetattr masks
+pub const GETATTR_BASIC: u64 = 0x7FF;
+
+// Tsetattr valid bits
+pub const SETATTR_MODE: u32 = 0x1;
+pub const SETATTR_SIZE: u32 = 0x8;
+
+// Mode file type bits, as in a Unix `st_mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFLNK: u32 = 0o120000;
+
+// Rlerror codes (Linux errno values)
+pub const EPERM: u32 = 1;
+pub const ENOENT: u32 = 2;
+pub const EIO: u32 = 5;
+pub const EBADF: u32 = 9;
+pub const EACCES: u32 = 13;
+pub const EEXIST: u32 = 17;
+pub const EXDEV: u32 = 18;
+pub const ENODEV: u32 = 19;
+pub const ENOTDIR: u32 = 20;
+pub const EISDIR: u32 = 21;
+pub const EINVAL: u32 = 22;
+pub const EFBIG: u32 = 27;
+pub const ENOSPC: u32 = 28;
+pub const EROFS: u32 = 30;
+pub const ENAMETOOLONG: u32 = 36;
+pub const ENOTEMPTY: u32 = 39;
+pub const ELOOP: u32 = 40;
+pub const EPROTO: u32 = 71;
+pub const EOPNOTSUPP: u32 = 95;
+
+/// A server's unique identity for a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Qid {
+    pub kind: u8,
+    pub version: u32,
+    pub path: u64,
+}
+
+impl Qid {
+    pub const SIZE: usize = 13;
+}
+
+/// Builds one message in a caller-provided buffer. Writes past the end
+/// are dropped and make `finish` fail, so callers check only once.
+pub struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    overflow: bool,
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(buf: &'a mut [u8], kind: u8, tag: u16) -> Self {
+        let mut w = Writer { buf, pos: 4, overflow: false };
+        w.u8(kind);
+        w.u16(tag);
+        w
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) -> &mut Self {
+        match self.buf.get_mut(self.pos..self.pos + b.len()) {
+            Some(dst) => {
+                dst.copy_from_slice(b);
+                self.pos += b.len();
+            }
+            None => self.overflow = true,
+        }
+        self
+    }
+
+    pub fn u8(&mut self, v: u8) -> &mut Self {
+        self.bytes(&[v])
+    }
+
+    pub fn u16(&mut self, v: u16) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    pub fn u32(&mut self, v: u32) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    pub fn u64(&mut self, v: u64) -> &mut Self {
+        self.bytes(&v.to_le_bytes())
+    }
+
+    pub fn str(&mut self, s: &str) -> &mut Self {
+        match u16::try_from(s.len()) {
+            Ok(len) => self.u16(len).bytes(s.as_bytes()),
+            Err(_) => {
+                self.overflow = true;
+                self
+            }
+        }
+    }
+
+    pub fn qid(&mut self, q: Qid) -> &mut Self {
+        self.u8(q.kind).u32(q.version).u64(q.path)
+    }
+
+    /// Reserve `len` bytes to fill in later through `buffer`, returning
+    /// their offset
+    pub fn reserve(&mut self, len: usize) -> usize {
+        let at = self.pos;
+        if self.pos + len <= self.buf.len() {
+            self.pos += len;
+        } else {
+            self.overflow = true;
+        }
+        at
+    }
+
+    pub fn buffer(&mut self) -> &mut [u8] {
+        self.buf
+    }
+
+    /// Bytes written so far, including the framing
+    pub fn written(&self) -> usize {
+        self.pos
+    }
+
+    /// Fill in the size field; `None` if the message didn't fit
+    pub fn finish(&mut self) -> Option<usize> {
+        if self.overflow {
+            return None;
+        }
+        self.buf[..4].copy_from_slice(&(self.pos as u32).to_le_bytes());
+        Some(self.pos)
+    }
+}
+
+/// Reads one message. Every accessor fails on a short message instead
+/// of panicking, as either side may be handed garbage.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Check the framing of `msg` and return a reader positioned at the
+    /// body, with the message type and tag
+    pub fn new(msg: &'a [u8]) -> Option<(Self, u8, u16)> {
+        let size = u32::from_le_bytes(msg.get(..4)?.try_into().ok()?) as usize;
+        if !(MESSAGE_HEADER..=msg.len()).contains(&size) {
+            return None;
+        }
+        let mut r = Reader { buf: &msg[..size], pos: 4 };
+        let kind = r.u8()?;
+        let tag = r.u16()?;
+        Some((r, kind, tag))
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let b = self.buf.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(b)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.bytes(2)?.try_into().ok()?))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.bytes(4)?.try_into().ok()?))
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.bytes(8)?.try_into().ok()?))
+    }
+
+    pub fn str(&mut self) -> Option<&'a str> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).ok()
+    }
+
+    pub fn qid(&mut self) -> Option<Qid> {
+        Some(Qid { kind: self.u8()?, version: self.u32()?, path: self.u64()? })
+    }
+
+    /// Bytes not read yet
+    pub fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+}
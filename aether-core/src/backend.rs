@@ -18,4 +18,7 @@ pub trait Backend: Sync + Send {
 
     // Inject a key press into the Guest
     fn inject_key(&self, _c: char) {}
+
+    // Update the Guest's pointer position and button mask
+    fn inject_pointer(&self, _x: u32, _y: u32, _buttons: u32) {}
 }
@@ -6,7 +6,7 @@
 use super::Backend;
 use std::ptr;
 
-use aether_abi::mmio::{RAM_SIZE, FB_ADDR, KEYBOARD_STATUS, KEYBOARD_DATA};
+use aether_abi::mmio::{RAM_SIZE, FB_ADDR, KEYBOARD_STATUS, KEYBOARD_DATA, POINTER_X, POINTER_Y, POINTER_BUTTONS};
 // const RAM_SIZE: usize = 4 * 1024 * 1024; 
 // const FB_ADDR: usize = 0x100000;         
 // const KEYBOARD_STATUS: usize = 0x80000;
@@ -240,4 +240,14 @@ impl Backend for LinuxBackend {
             }
         }
     }
+
+    fn inject_pointer(&self, x: u32, y: u32, buttons: u32) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            let mem = self.inner.get_mem();
+            std::ptr::write_volatile(mem.add(POINTER_X) as *mut u32, x);
+            std::ptr::write_volatile(mem.add(POINTER_Y) as *mut u32, y);
+            std::ptr::write_volatile(mem.add(POINTER_BUTTONS) as *mut u32, buttons);
+        }
+    }
 }
@@ -45,7 +45,7 @@ struct HvVcpuExitException {
 }
 
 // Memory Layout
-use aether_abi::mmio::{RAM_SIZE, FB_ADDR, DISK_ADDR};
+use aether_abi::mmio::{RAM_SIZE, FB_ADDR, POINTER_X, POINTER_Y, POINTER_BUTTONS};
 
 // const RAM_SIZE: usize = 0x800000; // 8MB
 const LOAD_ADDR: u64 = 0x0;  // Guest code at start of RAM (simpler layout)
@@ -97,17 +97,6 @@ impl MacBackend {
             std::ptr::copy_nonoverlapping(guest_bin.as_ptr(), mem, guest_bin.len());
             sys_icache_invalidate(mem as *const c_void, guest_bin.len());
             println!("[Aether::MacBackend] Loaded guest: {} bytes", guest_bin.len());
-
-            // Load local disk.img if present
-            if let Ok(disk_data) = std::fs::read("disk.img") {
-                 let disk_ptr = mem.add(DISK_ADDR);
-                 if disk_data.len() > (RAM_SIZE - DISK_ADDR) {
-                     eprintln!("[Warning] Disk image too large!");
-                 } else {
-                     std::ptr::copy_nonoverlapping(disk_data.as_ptr(), disk_ptr, disk_data.len());
-                     println!("[Aether::MacBackend] Loaded disk image");
-                 }
-            }
         }
         
         MacBackend { 
@@ -181,6 +170,18 @@ impl Backend for MacBackend {
                        hv_vcpu_set_reg(vcpu, HV_REG_X0, 0);
                     } else if x8 == 1 { // Exit
                         return ExitReason::Halt;
+                    } else if x8 == 2 { // GetTime
+                        let now = std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map(|d| d.as_nanos() as u64)
+                            .unwrap_or(0);
+                        hv_vcpu_set_reg(vcpu, HV_REG_X0, now);
+                    } else if x8 == 3 { // ShareNotify
+                        crate::share::notify(self.mem);
+                    } else if x8 == 4 { // BlockNotify
+                        crate::block::notify(self.mem);
+                    } else if x8 == 5 { // GetRandom
+                        hv_vcpu_set_reg(vcpu, HV_REG_X0, getrandom::u64().unwrap_or(0));
                     }
                     
                     // Advance PC
@@ -216,6 +217,14 @@ impl Backend for MacBackend {
             }
         }
     }
+
+    fn inject_pointer(&self, x: u32, y: u32, buttons: u32) {
+        unsafe {
+            std::ptr::write_volatile(self.mem.add(POINTER_X) as *mut u32, x);
+            std::ptr::write_volatile(self.mem.add(POINTER_Y) as *mut u32, y);
+            std::ptr::write_volatile(self.mem.add(POINTER_BUTTONS) as *mut u32, buttons);
+        }
+    }
 }
 
 
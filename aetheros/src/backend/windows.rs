@@ -7,7 +7,7 @@ use windows::{
 };
 use std::ptr;
 
-use aether_abi::mmio::{RAM_SIZE, FB_ADDR, KEYBOARD_STATUS, KEYBOARD_DATA};
+use aether_abi::mmio::{RAM_SIZE, FB_ADDR, KEYBOARD_STATUS, KEYBOARD_DATA, POINTER_X, POINTER_Y, POINTER_BUTTONS};
 // const RAM_SIZE: usize = 4 * 1024 * 1024; 
 // const FB_ADDR: usize = 0x100000;         
 // const KEYBOARD_STATUS: usize = 0x80000;
@@ -228,4 +228,12 @@ impl Backend for WindowsBackend {
             }
         }
     }
+
+    fn inject_pointer(&self, x: u32, y: u32, buttons: u32) {
+        unsafe {
+            std::ptr::write_volatile(self.mem.add(POINTER_X) as *mut u32, x);
+            std::ptr::write_volatile(self.mem.add(POINTER_Y) as *mut u32, y);
+            std::ptr::write_volatile(self.mem.add(POINTER_BUTTONS) as *mut u32, buttons);
+        }
+    }
 }
@@ -0,0 +1,245 @@
+//! Host block device: serves the guest's sector requests (see
+//! `aether_abi::block`) from a disk image on the host, so disks aren't
+//! limited by guest RAM and writes outlive the VM.
+
+// Only the Hypervisor.framework backend handles hypercalls so far
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+
+use aether_abi::block::*;
+use aether_abi::mmio::BLOCK_ADDR;
+
+mod crypt;
+mod overlay;
+mod qcow2;
+pub use crypt::{passphrase, EncryptedDisk, DEFAULT_ITERATIONS};
+pub use overlay::OverlayDisk;
+pub use qcow2::Qcow2Disk;
+
+/// Sector storage behind the device
+pub trait Disk: Send {
+    /// Capacity in sectors
+    fn sectors(&self) -> u64;
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// Whether the image is encrypted at rest, so its contents mustn't be
+    /// copied anywhere that isn't
+    fn encrypted(&self) -> bool {
+        false
+    }
+    /// Read whole sectors starting at `sector`; `buf` is a multiple of `SECTOR_SIZE`
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()>;
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+#[cfg(unix)]
+pub(crate) fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn write_all_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+#[cfg(windows)]
+pub(crate) fn write_all_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    while !data.is_empty() {
+        let n = std::os::windows::fs::FileExt::seek_write(file, data, offset)?;
+        data = &data[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// A raw disk image file. A partial sector at the end is ignored.
+pub struct FileDisk {
+    file: File,
+    sectors: u64,
+    read_only: bool,
+}
+
+/// Open `path` for reading and, unless `read_only`, writing if allowed.
+/// Returns whether it ended up read-only.
+fn open_file(path: &Path, read_only: bool) -> io::Result<(File, bool)> {
+    if read_only {
+        return Ok((File::open(path)?, true));
+    }
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => Ok((file, false)),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok((File::open(path)?, true)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Open the disk image at `path`, raw, qcow2 or encrypted going by its
+/// contents, asking for the passphrase of an encrypted one. Unless
+/// `read_only`, it is opened for writing if it can be.
+pub fn open_image(path: &Path, read_only: bool) -> io::Result<Box<dyn Disk>> {
+    let (file, read_only) = open_file(path, read_only)?;
+    if crypt::is_encrypted(&file)? {
+        let passphrase = crypt::passphrase(&format!("Passphrase for {}", path.display()))?;
+        Ok(Box::new(EncryptedDisk::open(file, read_only, &passphrase)?))
+    } else if qcow2::is_qcow2(&file)? {
+        Ok(Box::new(Qcow2Disk::open(file, path, read_only)?))
+    } else {
+        Ok(Box::new(FileDisk::with_file(file, read_only)?))
+    }
+}
+
+impl FileDisk {
+    /// Open the image at `path`, falling back to read-only if it can't be written
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let (file, read_only) = open_file(path, false)?;
+        Self::with_file(file, read_only)
+    }
+
+    /// Open the image at `path` for reading only, as the base of an overlay
+    pub fn open_read_only(path: &Path) -> io::Result<Self> {
+        Self::with_file(File::open(path)?, true)
+    }
+
+    fn with_file(file: File, read_only: bool) -> io::Result<Self> {
+        let sectors = file.metadata()?.len() / SECTOR_SIZE as u64;
+        Ok(FileDisk { file, sectors, read_only })
+    }
+}
+
+impl Disk for FileDisk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        read_exact_at(&self.file, buf, sector * SECTOR_SIZE as u64)
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        write_all_at(&self.file, data, sector * SECTOR_SIZE as u64)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+pub struct BlockServer {
+    disk: Box<dyn Disk>,
+}
+
+/// The device answering `HyperCall::BlockNotify`, if the host has a disk
+static BLOCK: Mutex<Option<BlockServer>> = Mutex::new(None);
+
+/// Attach `server` as the guest's block device
+pub fn install(server: BlockServer) {
+    *BLOCK.lock().unwrap() = Some(server);
+}
+
+/// Carry out everything pending in the guest's queue. Does nothing
+/// without a disk, which the guest sees as `DONE` not moving.
+///
+/// # Safety
+/// `mem` must point at guest RAM covering `BLOCK_ADDR..BLOCK_ADDR + QUEUE_SIZE`.
+pub unsafe fn notify(mem: *mut u8) {
+    if let Some(server) = BLOCK.lock().unwrap().as_mut() {
+        server.service(mem.add(BLOCK_ADDR));
+    }
+}
+
+fn get_u32(slot: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(slot[at..at + 4].try_into().unwrap())
+}
+
+fn get_u64(slot: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(slot[at..at + 8].try_into().unwrap())
+}
+
+impl BlockServer {
+    pub fn new(disk: Box<dyn Disk>) -> Self {
+        BlockServer { disk }
+    }
+
+    /// Answer every request between `DONE` and `HEAD` in the queue at `queue`
+    ///
+    /// # Safety
+    /// `queue` must point at `QUEUE_SIZE` bytes of guest memory.
+    pub unsafe fn service(&mut self, queue: *mut u8) {
+        let head = (queue.add(HEAD) as *const u32).read_volatile();
+        let mut done = (queue.add(DONE) as *const u32).read_volatile();
+        // More outstanding than there are slots means a confused guest
+        if head.wrapping_sub(done) as usize > QUEUE_SLOTS {
+            done = head.wrapping_sub(QUEUE_SLOTS as u32);
+        }
+        while done != head {
+            let slot = std::slice::from_raw_parts_mut(queue.add(slot_offset(done)), SLOT_SIZE);
+            self.execute(slot);
+            done = done.wrapping_add(1);
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+            (queue.add(DONE) as *mut u32).write_volatile(done);
+        }
+    }
+
+    /// Carry out the request in `slot` and fill in its status
+    pub fn execute(&mut self, slot: &mut [u8]) {
+        let status = self.request(slot);
+        slot[STATUS..STATUS + 4].copy_from_slice(&status.to_le_bytes());
+    }
+
+    fn request(&mut self, slot: &mut [u8]) -> u32 {
+        let op = get_u32(slot, OP);
+        let sector = get_u64(slot, SECTOR);
+        let count = get_u32(slot, COUNT) as usize;
+
+        let io = |r: io::Result<()>| match r {
+            Ok(()) => STATUS_OK,
+            Err(e) => {
+                eprintln!("[Aether::Block] I/O error: {}", e);
+                STATUS_IOERR
+            }
+        };
+        let in_range = count <= MAX_SECTORS
+            && sector.checked_add(count as u64).is_some_and(|end| end <= self.disk.sectors());
+
+        match op {
+            OP_READ | OP_WRITE if !in_range => STATUS_OUT_OF_RANGE,
+            OP_READ => io(self.disk.read(sector, &mut slot[DATA..DATA + count * SECTOR_SIZE])),
+            OP_WRITE if self.disk.read_only() => STATUS_READ_ONLY,
+            OP_WRITE => io(self.disk.write(sector, &slot[DATA..DATA + count * SECTOR_SIZE])),
+            OP_FLUSH => io(self.disk.flush()),
+            OP_CAPACITY => {
+                slot[SECTOR..SECTOR + 8].copy_from_slice(&self.disk.sectors().to_le_bytes());
+                let flags = if self.disk.read_only() { FLAG_READ_ONLY } else { 0 };
+                slot[COUNT..COUNT + 4].copy_from_slice(&flags.to_le_bytes());
+                STATUS_OK
+            }
+            _ => STATUS_UNSUPPORTED,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
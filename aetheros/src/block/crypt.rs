@@ -0,0 +1,240 @@
+//! Encrypted disk images: sectors stored with AES-256-XTS under a key
+//! derived from a passphrase, behind a header that records how.
+//!
+//! Image layout (little-endian):
+//!
+//! ```text
+//! 0     magic "AETHCRYP"
+//! 8     u32 version
+//! 12    u32 header size in sectors
+//! 16    cipher name, NUL-padded to 32 bytes ("aes-xts-plain64")
+//! 48    u32 key size in bits
+//! 52    u32 sector size the cipher works on
+//! 56    KDF name, NUL-padded to 32 bytes ("pbkdf2-sha256")
+//! 88    u32 KDF iterations
+//! 92    32-byte salt
+//! 124   32-byte key check: HMAC-SHA256 of CHECK_MESSAGE under the key
+//! 156   u64 payload size in sectors
+//! 4096  payload: guest sector n, encrypted with tweak n
+//! ```
+//!
+//! The key check lets a wrong passphrase be refused before any sector is
+//! decrypted into garbage.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use aes::cipher::{generic_array::GenericArray, KeyInit};
+use aes::Aes256;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use xts_mode::{get_tweak_default, Xts128};
+
+use aether_abi::block::SECTOR_SIZE;
+
+use super::{read_exact_at, write_all_at, Disk, FileDisk};
+
+const MAGIC: &[u8; 8] = b"AETHCRYP";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 4096;
+const HEADER_SECTORS: u64 = (HEADER_SIZE / SECTOR_SIZE) as u64;
+const CIPHER: &str = "aes-xts-plain64";
+const KEY_BITS: u32 = 512;
+const KDF: &str = "pbkdf2-sha256";
+/// PBKDF2 rounds for new images
+pub const DEFAULT_ITERATIONS: u32 = 600_000;
+const CHECK_MESSAGE: &[u8] = b"AetherOS disk key check";
+/// Sectors encrypted per pass when converting an image
+const CHUNK_SECTORS: u64 = 256;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Whether `file` starts with the encrypted image magic
+pub fn is_encrypted(file: &File) -> io::Result<bool> {
+    let mut magic = [0u8; 8];
+    match read_exact_at(file, &mut magic, 0) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn name_field(name: &str) -> [u8; 32] {
+    let mut field = [0u8; 32];
+    field[..name.len()].copy_from_slice(name.as_bytes());
+    field
+}
+
+struct Key {
+    xts: Xts128<Aes256>,
+    check: [u8; 32],
+}
+
+fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> Key {
+    let mut key = [0u8; KEY_BITS as usize / 8];
+    pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt, iterations, &mut key);
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&key).unwrap();
+    mac.update(CHECK_MESSAGE);
+    let check = mac.finalize().into_bytes().into();
+    let xts = Xts128::new(
+        Aes256::new(GenericArray::from_slice(&key[..32])),
+        Aes256::new(GenericArray::from_slice(&key[32..])),
+    );
+    key.fill(0);
+    Key { xts, check }
+}
+
+/// An encrypted image, presenting its decrypted payload
+pub struct EncryptedDisk {
+    inner: FileDisk,
+    sectors: u64,
+    xts: Xts128<Aes256>,
+}
+
+impl EncryptedDisk {
+    /// Unlock the encrypted image in `file`. Fails with `PermissionDenied`
+    /// if `passphrase` doesn't match.
+    pub fn open(file: File, read_only: bool, passphrase: &str) -> io::Result<Self> {
+        let inner = FileDisk::with_file(file, read_only)?;
+        let mut header = [0u8; HEADER_SIZE];
+        read_exact_at(&inner.file, &mut header, 0)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid("not an encrypted disk image"));
+        }
+        let field = |at: usize| u32::from_le_bytes(header[at..at + 4].try_into().unwrap());
+        let version = field(8);
+        if version != VERSION || field(12) as u64 != HEADER_SECTORS {
+            return Err(invalid(format!("unsupported encrypted image version {}", version)));
+        }
+        if header[16..48] != name_field(CIPHER)
+            || field(48) != KEY_BITS
+            || field(52) as usize != SECTOR_SIZE
+            || header[56..88] != name_field(KDF)
+        {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "unsupported cipher or key derivation"));
+        }
+        let iterations = field(88);
+        if iterations == 0 {
+            return Err(invalid("encrypted image has no KDF iterations"));
+        }
+        let sectors = u64::from_le_bytes(header[156..164].try_into().unwrap());
+        if sectors > inner.sectors.saturating_sub(HEADER_SECTORS) {
+            return Err(invalid("encrypted image is truncated"));
+        }
+
+        let key = derive(passphrase, &header[92..124], iterations);
+        // Compare without an early exit, so timing says nothing about the check
+        if key.check.iter().zip(&header[124..156]).fold(0, |d, (a, b)| d | (a ^ b)) != 0 {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "wrong passphrase"));
+        }
+        Ok(EncryptedDisk { inner, sectors, xts: key.xts })
+    }
+
+    /// Write an encrypted copy of `source` to a new image at `path`
+    pub fn create(path: &Path, source: &mut dyn Disk, passphrase: &str, iterations: u32) -> io::Result<()> {
+        let mut salt = [0u8; 32];
+        getrandom::fill(&mut salt).map_err(|e| io::Error::other(e.to_string()))?;
+        let key = derive(passphrase, &salt, iterations);
+
+        let mut header = vec![0u8; HEADER_SIZE];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(HEADER_SECTORS as u32).to_le_bytes());
+        header[16..48].copy_from_slice(&name_field(CIPHER));
+        header[48..52].copy_from_slice(&KEY_BITS.to_le_bytes());
+        header[52..56].copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        header[56..88].copy_from_slice(&name_field(KDF));
+        header[88..92].copy_from_slice(&iterations.to_le_bytes());
+        header[92..124].copy_from_slice(&salt);
+        header[124..156].copy_from_slice(&key.check);
+        header[156..164].copy_from_slice(&source.sectors().to_le_bytes());
+
+        let file = OpenOptions::new().read(true).write(true).create_new(true).open(path)?;
+        write_all_at(&file, &header, 0)?;
+        let mut buf = vec![0u8; CHUNK_SECTORS as usize * SECTOR_SIZE];
+        let mut sector = 0;
+        while sector < source.sectors() {
+            let count = CHUNK_SECTORS.min(source.sectors() - sector);
+            let buf = &mut buf[..count as usize * SECTOR_SIZE];
+            source.read(sector, buf)?;
+            key.xts.encrypt_area(buf, SECTOR_SIZE, sector as u128, get_tweak_default);
+            write_all_at(&file, buf, (HEADER_SECTORS + sector) * SECTOR_SIZE as u64)?;
+            sector += count;
+        }
+        file.sync_all()
+    }
+}
+
+impl Disk for EncryptedDisk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only
+    }
+
+    fn encrypted(&self) -> bool {
+        true
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.read(HEADER_SECTORS + sector, buf)?;
+        self.xts.decrypt_area(buf, SECTOR_SIZE, sector as u128, get_tweak_default);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        let mut buf = data.to_vec();
+        self.xts.encrypt_area(&mut buf, SECTOR_SIZE, sector as u128, get_tweak_default);
+        self.inner.write(HEADER_SECTORS + sector, &buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A disk passphrase: `AETHER_DISK_PASSPHRASE` if set, else asked for
+/// with `prompt` on the terminal without echo
+pub fn passphrase(prompt: &str) -> io::Result<String> {
+    if let Ok(passphrase) = std::env::var("AETHER_DISK_PASSPHRASE") {
+        return Ok(passphrase);
+    }
+    eprint!("{}: ", prompt);
+    io::stderr().flush()?;
+    let line = read_hidden_line()?;
+    eprintln!();
+    Ok(line)
+}
+
+#[cfg(unix)]
+fn read_hidden_line() -> io::Result<String> {
+    let fd = libc::STDIN_FILENO;
+    unsafe {
+        let mut saved: libc::termios = std::mem::zeroed();
+        let tty = libc::tcgetattr(fd, &mut saved) == 0;
+        if tty {
+            let mut quiet = saved;
+            quiet.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(fd, libc::TCSANOW, &quiet);
+        }
+        let mut line = String::new();
+        let result = io::stdin().read_line(&mut line);
+        if tty {
+            libc::tcsetattr(fd, libc::TCSANOW, &saved);
+        }
+        result?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    }
+}
+
+#[cfg(not(unix))]
+fn read_hidden_line() -> io::Result<String> {
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
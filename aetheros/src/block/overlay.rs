@@ -0,0 +1,244 @@
+//! Copy-on-write overlay: writes land in an overlay file a block at a
+//! time while the base image is only ever read, so several guests can
+//! share one base and each run can start from it unchanged.
+//!
+//! Overlay file layout (little-endian):
+//!
+//! ```text
+//! 0     magic "AETHCOW\0"
+//! 8     u32 version
+//! 12    u32 block size in bytes
+//! 16    u64 base capacity in sectors
+//! 4096  u32 per base block: 1 + index of its copy in the data area, or 0
+//!       while reads still come from the base
+//! ...   data area, aligned to the block size: copied blocks in the order
+//!       they were first written
+//! ```
+//!
+//! A block's data is synced before its table entry is written, so an
+//! entry never points at data that didn't reach the disk. A crash can
+//! leave copied blocks without an entry; opening the overlay collects
+//! every such block, wherever it sits, and new copies fill them first.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use aether_abi::block::SECTOR_SIZE;
+
+use super::{read_exact_at, write_all_at, Disk};
+
+const MAGIC: &[u8; 8] = b"AETHCOW\0";
+const VERSION: u32 = 1;
+/// Copy-on-write granularity
+pub const BLOCK_SIZE: usize = 4096;
+const BLOCK_SECTORS: u64 = (BLOCK_SIZE / SECTOR_SIZE) as u64;
+const TABLE_OFFSET: u64 = 4096;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A writable view of a base disk that keeps its changes in an overlay file
+pub struct OverlayDisk {
+    base: Box<dyn Disk>,
+    file: File,
+    sectors: u64,
+    /// Per base block: 1 + its index in the data area, or 0
+    table: Vec<u32>,
+    /// Blocks in the data area
+    used: u32,
+    /// Entries for data blocks no table entry refers to, highest first
+    free: Vec<u32>,
+}
+
+impl OverlayDisk {
+    /// Put the overlay at `path` over `base`, creating it if it doesn't
+    /// exist or is empty
+    pub fn open(base: Box<dyn Disk>, path: &Path) -> io::Result<Self> {
+        if base.encrypted() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "an overlay would keep an encrypted disk's changes in plaintext",
+            ));
+        }
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let sectors = base.sectors();
+        let blocks = sectors.div_ceil(BLOCK_SECTORS) as usize;
+        let mut disk = OverlayDisk { base, file, sectors, table: vec![0; blocks], used: 0, free: Vec::new() };
+
+        let len = disk.file.metadata()?.len();
+        if len == 0 {
+            disk.reset()?;
+            return Ok(disk);
+        }
+        if len < disk.data_start() {
+            return Err(invalid(format!("overlay {} is truncated", path.display())));
+        }
+        let mut header = [0u8; 24];
+        read_exact_at(&disk.file, &mut header, 0)?;
+        if &header[..8] != MAGIC {
+            return Err(invalid(format!("{} is not an overlay file", path.display())));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let block_size = u32::from_le_bytes(header[12..16].try_into().unwrap());
+        if version != VERSION || block_size as usize != BLOCK_SIZE {
+            return Err(invalid(format!("unsupported overlay format (version {}, block size {})", version, block_size)));
+        }
+        let made_for = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        if made_for != sectors {
+            return Err(invalid(format!(
+                "overlay was made for a base of {} sectors, this one has {}",
+                made_for, sectors
+            )));
+        }
+
+        let mut raw = vec![0u8; blocks * 4];
+        read_exact_at(&disk.file, &mut raw, TABLE_OFFSET)?;
+        // Every stored block belongs to at most one base block. Blocks
+        // copied just before a crash may belong to none: new copies reuse them.
+        let stored = ((len - disk.data_start()) / BLOCK_SIZE as u64) as u32;
+        let mut referenced = vec![false; stored as usize + 1];
+        for (entry, raw) in disk.table.iter_mut().zip(raw.chunks_exact(4)) {
+            *entry = u32::from_le_bytes(raw.try_into().unwrap());
+            if *entry == 0 {
+                continue;
+            }
+            if *entry > stored || std::mem::replace(&mut referenced[*entry as usize], true) {
+                return Err(invalid(format!("overlay {} has a corrupt block table", path.display())));
+            }
+        }
+        disk.used = stored;
+        disk.free = (1..=stored).rev().filter(|&e| !referenced[e as usize]).collect();
+        Ok(disk)
+    }
+
+    /// Blocks that differ from the base
+    pub fn dirty_blocks(&self) -> usize {
+        self.table.iter().filter(|&&e| e != 0).count()
+    }
+
+    /// Write every changed block into the base, then empty the overlay.
+    /// Returns how many blocks were written.
+    pub fn commit(&mut self) -> io::Result<usize> {
+        if self.base.read_only() {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the base image is read-only"));
+        }
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let mut written = 0;
+        for block in 0..self.table.len() {
+            let entry = self.table[block];
+            if entry == 0 {
+                continue;
+            }
+            read_exact_at(&self.file, &mut buf, self.data_offset(entry))?;
+            let (sector, count) = self.block_extent(block);
+            self.base.write(sector, &buf[..count as usize * SECTOR_SIZE])?;
+            written += 1;
+        }
+        self.base.flush()?;
+        self.reset()?;
+        Ok(written)
+    }
+
+    /// Drop every change, so reads see the base again
+    pub fn discard(&mut self) -> io::Result<()> {
+        self.reset()
+    }
+
+    /// Rewrite the overlay as empty
+    fn reset(&mut self) -> io::Result<()> {
+        self.table.fill(0);
+        self.used = 0;
+        self.free.clear();
+        self.file.set_len(0)?;
+        let mut header = vec![0u8; TABLE_OFFSET as usize];
+        header[..8].copy_from_slice(MAGIC);
+        header[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        header[12..16].copy_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        header[16..24].copy_from_slice(&self.sectors.to_le_bytes());
+        write_all_at(&self.file, &header, 0)?;
+        self.file.set_len(self.data_start())?;
+        self.file.sync_all()
+    }
+
+    fn data_start(&self) -> u64 {
+        (TABLE_OFFSET + self.table.len() as u64 * 4).next_multiple_of(BLOCK_SIZE as u64)
+    }
+
+    fn data_offset(&self, entry: u32) -> u64 {
+        self.data_start() + (entry as u64 - 1) * BLOCK_SIZE as u64
+    }
+
+    /// First sector of `block` and how many of its sectors the base has
+    /// (fewer for a partial last block)
+    fn block_extent(&self, block: usize) -> (u64, u64) {
+        let sector = block as u64 * BLOCK_SECTORS;
+        (sector, BLOCK_SECTORS.min(self.sectors - sector))
+    }
+
+    /// Give `block` its own copy in the overlay, seeded from the base
+    fn copy_up(&mut self, block: usize) -> io::Result<u32> {
+        let mut buf = vec![0u8; BLOCK_SIZE];
+        let (sector, count) = self.block_extent(block);
+        self.base.read(sector, &mut buf[..count as usize * SECTOR_SIZE])?;
+        let entry = self.free.pop().unwrap_or(self.used + 1);
+        write_all_at(&self.file, &buf, self.data_offset(entry))?;
+        self.file.sync_data()?;
+        write_all_at(&self.file, &entry.to_le_bytes(), TABLE_OFFSET + block as u64 * 4)?;
+        self.used = self.used.max(entry);
+        self.table[block] = entry;
+        Ok(entry)
+    }
+
+    /// Split `sector..sector + len / SECTOR_SIZE` at block boundaries into
+    /// (block, byte offset within it, byte range of the request)
+    fn pieces(sector: u64, len: usize) -> impl Iterator<Item = (usize, usize, std::ops::Range<usize>)> {
+        let mut done = 0;
+        std::iter::from_fn(move || {
+            if done >= len {
+                return None;
+            }
+            let at = sector * SECTOR_SIZE as u64 + done as u64;
+            let within = (at % BLOCK_SIZE as u64) as usize;
+            let n = (BLOCK_SIZE - within).min(len - done);
+            let piece = ((at / BLOCK_SIZE as u64) as usize, within, done..done + n);
+            done += n;
+            Some(piece)
+        })
+    }
+}
+
+impl Disk for OverlayDisk {
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (block, within, range) in Self::pieces(sector, buf.len()) {
+            match self.table[block] {
+                0 => {
+                    let at = block as u64 * BLOCK_SECTORS + (within / SECTOR_SIZE) as u64;
+                    self.base.read(at, &mut buf[range])?;
+                }
+                entry => read_exact_at(&self.file, &mut buf[range], self.data_offset(entry) + within as u64)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        for (block, within, range) in Self::pieces(sector, data.len()) {
+            let entry = match self.table[block] {
+                0 => self.copy_up(block)?,
+                entry => entry,
+            };
+            write_all_at(&self.file, &data[range], self.data_offset(entry) + within as u64)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
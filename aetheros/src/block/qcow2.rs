@@ -0,0 +1,508 @@
+//! qcow2 (v2 and v3) images: clusters mapped through a two-level
+//! L1/L2 table, allocated on first write and reference counted, with
+//! unallocated clusters read from a backing file or as zeros.
+//!
+//! New clusters are always appended at the end of the file, so clusters
+//! freed by copy-on-write from a snapshot are not reused; `qemu-img
+//! convert` reclaims them. Compressed clusters, encryption and external
+//! data files are not supported.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use aether_abi::block::SECTOR_SIZE;
+
+use super::{read_exact_at, write_all_at, Disk};
+
+const MAGIC: u32 = 0x5146_49fb; // "QFI\xfb"
+/// Longest chain of backing files followed, which also stops loops
+const MAX_BACKING_DEPTH: u32 = 16;
+const V2_HEADER: usize = 72;
+const V3_HEADER: usize = 104;
+
+// Header field offsets
+const BACKING_FILE_OFFSET: usize = 8;
+const BACKING_FILE_SIZE: usize = 16;
+const CLUSTER_BITS: usize = 20;
+const SIZE: usize = 24;
+const CRYPT_METHOD: usize = 32;
+const L1_SIZE: usize = 36;
+const L1_TABLE_OFFSET: usize = 40;
+const REFCOUNT_TABLE_OFFSET: usize = 48;
+const REFCOUNT_TABLE_CLUSTERS: usize = 56;
+const INCOMPATIBLE_FEATURES: usize = 72;
+const AUTOCLEAR_FEATURES: usize = 88;
+const REFCOUNT_ORDER: usize = 96;
+const HEADER_LENGTH: usize = 100;
+
+/// Incompatible feature: refcounts may be stale after a crash
+const INCOMPAT_DIRTY: u64 = 1;
+
+/// Bits 9..56 of a table entry hold a host offset
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster's refcount is exactly one, so it may be written in place
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// v3 L2 entries: the cluster reads as zeros
+const ZERO: u64 = 1;
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+fn be32(b: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+fn be64(b: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(b[at..at + 8].try_into().unwrap())
+}
+
+/// Whether `file` starts with the qcow2 magic
+pub fn is_qcow2(file: &File) -> io::Result<bool> {
+    let mut magic = [0u8; 4];
+    match read_exact_at(file, &mut magic, 0) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == MAGIC),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub struct Qcow2Disk {
+    file: File,
+    read_only: bool,
+    version: u32,
+    cluster_bits: u32,
+    /// Virtual disk size in bytes
+    size: u64,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    refcount_order: u32,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// Where the next cluster is allocated
+    end: u64,
+    backing: Option<Box<dyn Disk>>,
+}
+
+impl Qcow2Disk {
+    /// Open the qcow2 image in `file`; `path` locates a relative backing file
+    pub fn open(file: File, path: &Path, read_only: bool) -> io::Result<Self> {
+        Self::open_backed(file, path, read_only, 0)
+    }
+
+    /// `open`, for an image `depth` files down a backing chain
+    fn open_backed(file: File, path: &Path, read_only: bool, depth: u32) -> io::Result<Self> {
+        let mut header = [0u8; V3_HEADER];
+        read_exact_at(&file, &mut header[..V2_HEADER], 0)?;
+        if be32(&header, 0) != MAGIC {
+            return Err(invalid("not a qcow2 image"));
+        }
+        let version = be32(&header, 4);
+        if version == 3 {
+            read_exact_at(&file, &mut header[V2_HEADER..], V2_HEADER as u64)?;
+        } else if version != 2 {
+            return Err(invalid(format!("unsupported qcow2 version {}", version)));
+        }
+
+        let cluster_bits = be32(&header, CLUSTER_BITS);
+        if !(9..=21).contains(&cluster_bits) {
+            return Err(invalid(format!("bad qcow2 cluster size 2^{}", cluster_bits)));
+        }
+        if be32(&header, CRYPT_METHOD) != 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "encrypted qcow2 images are not supported"));
+        }
+        let (refcount_order, incompatible) = if version == 3 {
+            if (be32(&header, HEADER_LENGTH) as usize) < V3_HEADER {
+                return Err(invalid("qcow2 v3 header too short"));
+            }
+            (be32(&header, REFCOUNT_ORDER), be64(&header, INCOMPATIBLE_FEATURES))
+        } else {
+            (4, 0)
+        };
+        if refcount_order > 6 {
+            return Err(invalid(format!("bad qcow2 refcount order {}", refcount_order)));
+        }
+        if incompatible & INCOMPAT_DIRTY != 0 {
+            return Err(invalid("qcow2 image was not closed cleanly; repair it with `qemu-img check -r all`"));
+        }
+        if incompatible != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("qcow2 image needs unsupported features ({:#x})", incompatible),
+            ));
+        }
+
+        let cluster_size = 1u64 << cluster_bits;
+        let size = be64(&header, SIZE);
+        let l2_entries = cluster_size / 8;
+        let l1_size = be32(&header, L1_SIZE) as usize;
+        let needed = size.div_ceil(cluster_size * l2_entries);
+        if (l1_size as u64) < needed || l1_size > 1 << 25 {
+            return Err(invalid("qcow2 L1 table doesn't cover the disk"));
+        }
+        let l1_offset = be64(&header, L1_TABLE_OFFSET);
+        let l1 = read_table(&file, l1_offset, l1_size)?;
+
+        let refcount_table_offset = be64(&header, REFCOUNT_TABLE_OFFSET);
+        let refcount_clusters = be32(&header, REFCOUNT_TABLE_CLUSTERS) as u64;
+        if refcount_clusters << cluster_bits > 1 << 26 {
+            return Err(invalid("qcow2 refcount table is too large"));
+        }
+        let refcount_table = read_table(&file, refcount_table_offset, (refcount_clusters * l2_entries) as usize)?;
+
+        let backing_offset = be64(&header, BACKING_FILE_OFFSET);
+        let backing = if backing_offset == 0 {
+            None
+        } else {
+            let len = be32(&header, BACKING_FILE_SIZE) as usize;
+            if len > 1023 {
+                return Err(invalid("qcow2 backing file name too long"));
+            }
+            let mut name = vec![0u8; len];
+            read_exact_at(&file, &mut name, backing_offset)?;
+            let name = String::from_utf8(name).map_err(|_| invalid("qcow2 backing file name isn't UTF-8"))?;
+            let backing = backing_path(path, &name);
+            if depth >= MAX_BACKING_DEPTH {
+                return Err(invalid("qcow2 backing chain is too long"));
+            }
+            // The backing file is never written, whatever this image allows
+            let open = |backing: &Path| -> io::Result<Box<dyn Disk>> {
+                let file = File::open(backing)?;
+                if super::crypt::is_encrypted(&file)? {
+                    Err(io::Error::new(io::ErrorKind::Unsupported, "encrypted backing files are not supported"))
+                } else if is_qcow2(&file)? {
+                    Ok(Box::new(Self::open_backed(file, backing, true, depth + 1)?))
+                } else {
+                    Ok(Box::new(super::FileDisk::with_file(file, true)?))
+                }
+            };
+            let disk = open(&backing)
+                .map_err(|e| io::Error::new(e.kind(), format!("backing file {}: {}", backing.display(), e)))?;
+            Some(disk)
+        };
+
+        let end = file.metadata()?.len().next_multiple_of(cluster_size);
+        let disk = Qcow2Disk {
+            file,
+            read_only,
+            version,
+            cluster_bits,
+            size,
+            l1_offset,
+            l1,
+            refcount_order,
+            refcount_table_offset,
+            refcount_table,
+            end,
+            backing,
+        };
+        disk.check_offsets()?;
+        // Features we don't know of must be dropped once we write
+        if version == 3 && !read_only && be64(&header, AUTOCLEAR_FEATURES) != 0 {
+            write_all_at(&disk.file, &0u64.to_be_bytes(), AUTOCLEAR_FEATURES as u64)?;
+        }
+        Ok(disk)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    fn l2_entries(&self) -> u64 {
+        self.cluster_size() / 8
+    }
+
+    /// Refuse tables that point outside the file or at unaligned clusters
+    fn check_offsets(&self) -> io::Result<()> {
+        let bad = |offset: u64| !offset.is_multiple_of(self.cluster_size()) || offset >= self.end;
+        if self.l1.iter().any(|&e| e & OFFSET_MASK != 0 && bad(e & OFFSET_MASK))
+            || self.refcount_table.iter().any(|&e| e != 0 && bad(e))
+        {
+            return Err(invalid("qcow2 table points outside the image"));
+        }
+        Ok(())
+    }
+
+    fn l2_entry_offset(&self, l2: u64, guest: u64) -> u64 {
+        l2 + (guest >> self.cluster_bits) % self.l2_entries() * 8
+    }
+
+    /// The L2 entry for the cluster holding `guest`, or 0 with no L2 table
+    fn l2_entry(&self, guest: u64) -> io::Result<u64> {
+        let l2 = self.l1[((guest >> self.cluster_bits) / self.l2_entries()) as usize] & OFFSET_MASK;
+        if l2 == 0 {
+            return Ok(0);
+        }
+        let mut raw = [0u8; 8];
+        read_exact_at(&self.file, &mut raw, self.l2_entry_offset(l2, guest))?;
+        Ok(u64::from_be_bytes(raw))
+    }
+
+    /// Fill `buf` with what an unallocated cluster holds at `guest`
+    fn read_unallocated(&mut self, guest: u64, buf: &mut [u8]) -> io::Result<()> {
+        buf.fill(0);
+        if let Some(backing) = &mut self.backing {
+            let available = (backing.sectors() * SECTOR_SIZE as u64).saturating_sub(guest);
+            let n = (buf.len() as u64).min(available) as usize;
+            if n > 0 {
+                backing.read(guest / SECTOR_SIZE as u64, &mut buf[..n])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the part of one cluster at `guest` that `buf` covers
+    fn read_cluster(&mut self, guest: u64, buf: &mut [u8]) -> io::Result<()> {
+        let entry = self.l2_entry(guest)?;
+        let host = entry & OFFSET_MASK;
+        if entry & COMPRESSED != 0 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "compressed qcow2 clusters are not supported"));
+        }
+        if self.version >= 3 && entry & ZERO != 0 {
+            buf.fill(0);
+            Ok(())
+        } else if host == 0 {
+            self.read_unallocated(guest, buf)
+        } else {
+            read_exact_at(&self.file, buf, host + guest % self.cluster_size())
+        }
+    }
+
+    // --- Refcounts ---
+
+    fn refcounts_per_block(&self) -> u64 {
+        (self.cluster_size() * 8) >> self.refcount_order
+    }
+
+    /// Byte offset within a refcount block and bit width of the entry for `index`
+    fn refcount_slot(&self, index: u64) -> (u64, u32) {
+        let bits = 1u32 << self.refcount_order;
+        (index % self.refcounts_per_block() * bits as u64 / 8, bits)
+    }
+
+    fn read_refcount(&self, block: u64, index: u64) -> io::Result<u64> {
+        let (at, bits) = self.refcount_slot(index);
+        let mut raw = [0u8; 8];
+        let len = (bits as usize).div_ceil(8);
+        read_exact_at(&self.file, &mut raw[8 - len..], block + at)?;
+        let value = u64::from_be_bytes(raw);
+        Ok(if bits < 8 {
+            // Narrow refcounts pack into bytes from the low bit up
+            (value >> (index * bits as u64 % 8)) & ((1 << bits) - 1)
+        } else {
+            value
+        })
+    }
+
+    fn write_refcount(&self, block: u64, index: u64, refcount: u64) -> io::Result<()> {
+        let (at, bits) = self.refcount_slot(index);
+        if bits < 64 && refcount >> bits != 0 {
+            return Err(invalid("qcow2 refcount overflow"));
+        }
+        if bits < 8 {
+            let mut byte = [0u8; 1];
+            read_exact_at(&self.file, &mut byte, block + at)?;
+            let shift = index * bits as u64 % 8;
+            let mask = ((1u8 << bits) - 1) << shift;
+            byte[0] = (byte[0] & !mask) | ((refcount as u8) << shift);
+            write_all_at(&self.file, &byte, block + at)
+        } else {
+            let raw = refcount.to_be_bytes();
+            write_all_at(&self.file, &raw[8 - bits as usize / 8..], block + at)
+        }
+    }
+
+    /// Add `delta` to the refcount of the cluster at `host`
+    fn adjust_refcount(&mut self, host: u64, delta: i64) -> io::Result<()> {
+        let index = host >> self.cluster_bits;
+        let block = self.refcount_block(index / self.refcounts_per_block())?;
+        let refcount = self.read_refcount(block, index)?;
+        let refcount = refcount.checked_add_signed(delta).ok_or_else(|| invalid("qcow2 refcount underflow"))?;
+        self.write_refcount(block, index, refcount)
+    }
+
+    /// The refcount block numbered `n`, allocated if it doesn't exist yet
+    fn refcount_block(&mut self, n: u64) -> io::Result<u64> {
+        if n >= self.refcount_table.len() as u64 {
+            self.grow_refcount_table(n + 1)?;
+        }
+        let block = self.refcount_table[n as usize];
+        if block != 0 {
+            return Ok(block);
+        }
+        let block = self.append_clusters(1)?;
+        self.refcount_table[n as usize] = block;
+        write_all_at(&self.file, &block.to_be_bytes(), self.refcount_table_offset + n * 8)?;
+        // The new block is itself a cluster that needs counting
+        self.adjust_refcount(block, 1)?;
+        Ok(block)
+    }
+
+    /// Move the refcount table somewhere with room for `entries`
+    fn grow_refcount_table(&mut self, entries: u64) -> io::Result<()> {
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = (self.refcount_table.len() as u64 * 8).div_ceil(self.cluster_size());
+        let entries = entries.max(self.refcount_table.len() as u64 * 2);
+        let clusters = (entries * 8).div_ceil(self.cluster_size());
+        let offset = self.append_clusters(clusters)?;
+        self.refcount_table.resize((clusters * self.l2_entries()) as usize, 0);
+        write_table(&self.file, offset, &self.refcount_table)?;
+        self.file.sync_data()?;
+
+        let mut fields = [0u8; 12];
+        fields[..8].copy_from_slice(&offset.to_be_bytes());
+        fields[8..].copy_from_slice(&(clusters as u32).to_be_bytes());
+        write_all_at(&self.file, &fields, REFCOUNT_TABLE_OFFSET as u64)?;
+        self.refcount_table_offset = offset;
+
+        for i in 0..clusters {
+            self.adjust_refcount(offset + (i << self.cluster_bits), 1)?;
+        }
+        for i in 0..old_clusters {
+            self.adjust_refcount(old_offset + (i << self.cluster_bits), -1)?;
+        }
+        Ok(())
+    }
+
+    /// Zeroed clusters at the end of the file, not yet counted
+    fn append_clusters(&mut self, count: u64) -> io::Result<u64> {
+        let offset = self.end;
+        self.end += count << self.cluster_bits;
+        self.file.set_len(self.end)?;
+        Ok(offset)
+    }
+
+    /// A new cluster, counted, optionally holding `data`
+    fn allocate(&mut self, data: Option<&[u8]>) -> io::Result<u64> {
+        let offset = self.append_clusters(1)?;
+        self.adjust_refcount(offset, 1)?;
+        if let Some(data) = data {
+            write_all_at(&self.file, data, offset)?;
+        }
+        Ok(offset)
+    }
+
+    // --- Writes ---
+
+    /// The L2 table for `guest`, made writable in place
+    fn writable_l2(&mut self, guest: u64) -> io::Result<u64> {
+        let index = (guest >> self.cluster_bits) / self.l2_entries();
+        let entry = self.l1[index as usize];
+        let old = entry & OFFSET_MASK;
+        if old != 0 && entry & COPIED != 0 {
+            return Ok(old);
+        }
+        // A new table, or a private copy of one a snapshot shares
+        let mut table = vec![0u8; self.cluster_size() as usize];
+        if old != 0 {
+            read_exact_at(&self.file, &mut table, old)?;
+        }
+        let l2 = self.allocate(Some(&table))?;
+        self.l1[index as usize] = l2 | COPIED;
+        write_all_at(&self.file, &(l2 | COPIED).to_be_bytes(), self.l1_offset + index * 8)?;
+        if old != 0 {
+            self.adjust_refcount(old, -1)?;
+        }
+        Ok(l2)
+    }
+
+    /// Write `data` into the one cluster at `guest`, allocating it first
+    /// if it isn't this image's alone
+    fn write_cluster(&mut self, guest: u64, data: &[u8]) -> io::Result<()> {
+        let within = guest % self.cluster_size();
+        let entry = self.l2_entry(guest)?;
+        let host = entry & OFFSET_MASK;
+        let zero = self.version >= 3 && entry & ZERO != 0;
+        if entry & COMPRESSED == 0 && host != 0 && entry & COPIED != 0 && !zero {
+            return write_all_at(&self.file, data, host + within);
+        }
+
+        // Copy on write: what the cluster reads as now, with `data` over it
+        let start = guest - within;
+        let mut cluster = vec![0u8; self.cluster_size() as usize];
+        let len = (self.size - start).min(self.cluster_size()) as usize;
+        self.read_cluster(start, &mut cluster[..len])?;
+        cluster[within as usize..within as usize + data.len()].copy_from_slice(data);
+
+        let l2 = self.writable_l2(guest)?;
+        let new = if host != 0 && entry & COPIED != 0 && entry & COMPRESSED == 0 {
+            // A preallocated zero cluster: fill it in place
+            write_all_at(&self.file, &cluster, host)?;
+            host
+        } else {
+            self.allocate(Some(&cluster))?
+        };
+        write_all_at(&self.file, &(new | COPIED).to_be_bytes(), self.l2_entry_offset(l2, guest))?;
+        if host != 0 && new != host {
+            self.adjust_refcount(host, -1)?;
+        }
+        Ok(())
+    }
+
+    /// Split `len` bytes from sector `sector` at cluster boundaries into
+    /// (guest offset, byte range of the request)
+    fn pieces(&self, sector: u64, len: usize) -> Vec<(u64, std::ops::Range<usize>)> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < len {
+            let guest = sector * SECTOR_SIZE as u64 + done as u64;
+            let n = ((self.cluster_size() - guest % self.cluster_size()) as usize).min(len - done);
+            pieces.push((guest, done..done + n));
+            done += n;
+        }
+        pieces
+    }
+}
+
+/// Where a backing file named `name` in the image at `image` lives
+fn backing_path(image: &Path, name: &str) -> PathBuf {
+    let name = Path::new(name);
+    match image.parent() {
+        Some(dir) if name.is_relative() => dir.join(name),
+        _ => name.to_path_buf(),
+    }
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut raw = vec![0u8; entries * 8];
+    read_exact_at(file, &mut raw, offset)?;
+    Ok(raw.chunks_exact(8).map(|e| u64::from_be_bytes(e.try_into().unwrap())).collect())
+}
+
+fn write_table(file: &File, offset: u64, table: &[u64]) -> io::Result<()> {
+    let raw: Vec<u8> = table.iter().flat_map(|e| e.to_be_bytes()).collect();
+    write_all_at(file, &raw, offset)
+}
+
+impl Disk for Qcow2Disk {
+    fn sectors(&self) -> u64 {
+        self.size / SECTOR_SIZE as u64
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn read(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (guest, range) in self.pieces(sector, buf.len()) {
+            self.read_cluster(guest, &mut buf[range])?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "the image is read-only"));
+        }
+        for (guest, range) in self.pieces(sector, data.len()) {
+            self.write_cluster(guest, &data[range])?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
@@ -0,0 +1,593 @@
+//! Server and overlay tests against scratch image files, driving a queue in a Vec
+use super::*;
+use std::path::{Path, PathBuf};
+
+/// A disk image under the system temp dir, removed on drop
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str, sectors: usize) -> Self {
+        let path = std::env::temp_dir().join(format!("aether-block-{}-{}.img", std::process::id(), name));
+        let image: Vec<u8> = (0..sectors * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+        std::fs::write(&path, image).unwrap();
+        Scratch(path)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn server(image: &Scratch) -> BlockServer {
+    BlockServer::new(Box::new(FileDisk::open(&image.0).unwrap()))
+}
+
+/// Run one request through `execute`, returning its status and slot
+fn request(server: &mut BlockServer, op: u32, sector: u64, count: u32, data: &[u8]) -> (u32, Vec<u8>) {
+    let mut slot = vec![0u8; SLOT_SIZE];
+    slot[OP..OP + 4].copy_from_slice(&op.to_le_bytes());
+    slot[SECTOR..SECTOR + 8].copy_from_slice(&sector.to_le_bytes());
+    slot[COUNT..COUNT + 4].copy_from_slice(&count.to_le_bytes());
+    slot[DATA..DATA + data.len()].copy_from_slice(data);
+    server.execute(&mut slot);
+    (get_u32(&slot, STATUS), slot)
+}
+
+#[test]
+fn capacity_and_read() {
+    let image = Scratch::new("read", 16);
+    let mut server = server(&image);
+    let (status, slot) = request(&mut server, OP_CAPACITY, 0, 0, &[]);
+    assert_eq!(status, STATUS_OK);
+    assert_eq!(get_u64(&slot, SECTOR), 16);
+    assert_eq!(get_u32(&slot, COUNT), 0);
+
+    let (status, slot) = request(&mut server, OP_READ, 3, 2, &[]);
+    assert_eq!(status, STATUS_OK);
+    assert!(slot[DATA..DATA + SECTOR_SIZE].iter().all(|&b| b == 3));
+    assert!(slot[DATA + SECTOR_SIZE..DATA + 2 * SECTOR_SIZE].iter().all(|&b| b == 4));
+}
+
+#[test]
+fn write_persists() {
+    let image = Scratch::new("write", 8);
+    let mut server = server(&image);
+    let data = vec![0xAB; 2 * SECTOR_SIZE];
+    assert_eq!(request(&mut server, OP_WRITE, 6, 2, &data).0, STATUS_OK);
+    assert_eq!(request(&mut server, OP_FLUSH, 0, 0, &[]).0, STATUS_OK);
+    drop(server);
+
+    let bytes = std::fs::read(&image.0).unwrap();
+    assert_eq!(bytes.len(), 8 * SECTOR_SIZE);
+    assert!(bytes[6 * SECTOR_SIZE..].iter().all(|&b| b == 0xAB));
+    assert!(bytes[5 * SECTOR_SIZE..6 * SECTOR_SIZE].iter().all(|&b| b == 5));
+}
+
+#[test]
+fn rejects_out_of_range() {
+    let image = Scratch::new("range", 8);
+    let mut server = server(&image);
+    assert_eq!(request(&mut server, OP_READ, 7, 2, &[]).0, STATUS_OUT_OF_RANGE);
+    assert_eq!(request(&mut server, OP_WRITE, u64::MAX, 1, &[]).0, STATUS_OUT_OF_RANGE);
+    assert_eq!(request(&mut server, OP_READ, 0, MAX_SECTORS as u32 + 1, &[]).0, STATUS_OUT_OF_RANGE);
+    assert_eq!(request(&mut server, 99, 0, 0, &[]).0, STATUS_UNSUPPORTED);
+    // The file is untouched
+    assert_eq!(std::fs::read(&image.0).unwrap().len(), 8 * SECTOR_SIZE);
+}
+
+#[test]
+fn services_queue() {
+    let image = Scratch::new("queue", 8);
+    let mut server = server(&image);
+    let mut queue = vec![0u8; QUEUE_SIZE];
+    // Two reads, the second wrapping past a full lap of the slots
+    let start = u32::MAX - 1;
+    queue[DONE..DONE + 4].copy_from_slice(&start.to_le_bytes());
+    for (i, n) in [start, start.wrapping_add(1)].into_iter().enumerate() {
+        let slot = &mut queue[slot_offset(n)..slot_offset(n) + SLOT_SIZE];
+        slot[OP..OP + 4].copy_from_slice(&OP_READ.to_le_bytes());
+        slot[SECTOR..SECTOR + 8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+        slot[COUNT..COUNT + 4].copy_from_slice(&1u32.to_le_bytes());
+    }
+    queue[HEAD..HEAD + 4].copy_from_slice(&start.wrapping_add(2).to_le_bytes());
+    unsafe { server.service(queue.as_mut_ptr()) };
+
+    assert_eq!(get_u32(&queue, DONE), start.wrapping_add(2));
+    for (i, n) in [start, start.wrapping_add(1)].into_iter().enumerate() {
+        let slot = &queue[slot_offset(n)..slot_offset(n) + SLOT_SIZE];
+        assert_eq!(get_u32(slot, STATUS), STATUS_OK);
+        assert_eq!(slot[DATA], i as u8 + 1);
+    }
+}
+
+/// An overlay file path under the system temp dir, removed on drop
+struct ScratchOverlay(PathBuf);
+
+impl ScratchOverlay {
+    fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("aether-overlay-{}-{}.cow", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        ScratchOverlay(path)
+    }
+
+    fn open(&self, base: &Scratch) -> io::Result<OverlayDisk> {
+        OverlayDisk::open(Box::new(FileDisk::open_read_only(&base.0)?), &self.0)
+    }
+}
+
+impl Drop for ScratchOverlay {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+fn read(disk: &mut dyn Disk, sector: u64, count: usize) -> Vec<u8> {
+    let mut buf = vec![0u8; count * SECTOR_SIZE];
+    disk.read(sector, &mut buf).unwrap();
+    buf
+}
+
+#[test]
+fn overlay_keeps_base_pristine() {
+    // 21 sectors: the last overlay block covers only 5 of them
+    let image = Scratch::new("cow-base", 21);
+    let pristine = std::fs::read(&image.0).unwrap();
+    let cow = ScratchOverlay::new("pristine");
+    let mut disk = cow.open(&image).unwrap();
+    assert_eq!(disk.sectors(), 21);
+
+    // Straddles the first block boundary, and touches the partial last block
+    disk.write(7, &[0xEE; 2 * SECTOR_SIZE]).unwrap();
+    disk.write(20, &[0xDD; SECTOR_SIZE]).unwrap();
+    assert_eq!(disk.dirty_blocks(), 3);
+    let all = read(&mut disk, 0, 21);
+    assert!(all[6 * SECTOR_SIZE..7 * SECTOR_SIZE].iter().all(|&b| b == 6));
+    assert!(all[7 * SECTOR_SIZE..9 * SECTOR_SIZE].iter().all(|&b| b == 0xEE));
+    assert!(all[9 * SECTOR_SIZE..10 * SECTOR_SIZE].iter().all(|&b| b == 9));
+    assert!(all[20 * SECTOR_SIZE..].iter().all(|&b| b == 0xDD));
+    drop(disk);
+    assert_eq!(std::fs::read(&image.0).unwrap(), pristine);
+
+    // The changes survive reopening the overlay
+    let mut disk = cow.open(&image).unwrap();
+    assert_eq!(disk.dirty_blocks(), 3);
+    assert_eq!(read(&mut disk, 0, 21), all);
+
+    disk.discard().unwrap();
+    assert_eq!(disk.dirty_blocks(), 0);
+    assert_eq!(read(&mut disk, 0, 21), pristine);
+}
+
+#[test]
+fn overlay_commit() {
+    let image = Scratch::new("cow-commit", 16);
+    let cow = ScratchOverlay::new("commit");
+    let mut disk = cow.open(&image).unwrap();
+    disk.write(3, &[0x77; SECTOR_SIZE]).unwrap();
+    let expected = read(&mut disk, 0, 16);
+    drop(disk);
+
+    // Committing needs a writable base
+    assert!(cow.open(&image).unwrap().commit().is_err());
+    let base = FileDisk::open(&image.0).unwrap();
+    let mut disk = OverlayDisk::open(Box::new(base), &cow.0).unwrap();
+    assert_eq!(disk.commit().unwrap(), 1);
+    assert_eq!(disk.dirty_blocks(), 0);
+    drop(disk);
+    assert_eq!(std::fs::read(&image.0).unwrap(), expected);
+}
+
+#[test]
+fn overlay_reuses_orphaned_blocks() {
+    let image = Scratch::new("cow-orphan", 32);
+    let cow = ScratchOverlay::new("orphan");
+    let mut disk = cow.open(&image).unwrap();
+    disk.write(0, &[0x11; SECTOR_SIZE]).unwrap();
+    disk.write(8, &[0x22; SECTOR_SIZE]).unwrap();
+    disk.write(16, &[0x33; SECTOR_SIZE]).unwrap();
+    drop(disk);
+
+    // A crash after the last two copies' data but before their entries
+    let table = 4096;
+    let file = std::fs::OpenOptions::new().write(true).open(&cow.0).unwrap();
+    write_all_at(&file, &[0; 8], table + 4).unwrap();
+    let len = file.metadata().unwrap().len();
+    drop(file);
+
+    let mut disk = cow.open(&image).unwrap();
+    assert_eq!(disk.dirty_blocks(), 1);
+    assert!(read(&mut disk, 8, 1).iter().all(|&b| b == 8));
+    disk.write(24, &[0x44; SECTOR_SIZE]).unwrap();
+    drop(disk);
+    // The new copy took the first orphan's place instead of growing the file
+    assert_eq!(std::fs::metadata(&cow.0).unwrap().len(), len);
+    let mut disk = cow.open(&image).unwrap();
+    assert!(read(&mut disk, 0, 1).iter().all(|&b| b == 0x11));
+    assert!(read(&mut disk, 24, 1).iter().all(|&b| b == 0x44));
+}
+
+#[test]
+fn overlay_reuses_orphans_below_the_last_entry() {
+    let image = Scratch::new("cow-gap", 32);
+    let cow = ScratchOverlay::new("gap");
+    let mut disk = cow.open(&image).unwrap();
+    for (sector, byte) in [(0, 0x11), (8, 0x22), (16, 0x33)] {
+        disk.write(sector, &[byte; SECTOR_SIZE]).unwrap();
+    }
+    drop(disk);
+
+    // The middle entry lost, the ones around it kept
+    let file = std::fs::OpenOptions::new().write(true).open(&cow.0).unwrap();
+    write_all_at(&file, &[0; 4], 4096 + 4).unwrap();
+    let len = file.metadata().unwrap().len();
+    drop(file);
+
+    let mut disk = cow.open(&image).unwrap();
+    assert_eq!(disk.dirty_blocks(), 2);
+    disk.write(24, &[0x44; SECTOR_SIZE]).unwrap();
+    disk.write(8, &[0x55; SECTOR_SIZE]).unwrap();
+    drop(disk);
+    // The first copy filled the gap; only the second grew the file
+    assert_eq!(std::fs::metadata(&cow.0).unwrap().len(), len + 4096);
+    let mut disk = cow.open(&image).unwrap();
+    for (sector, byte) in [(0, 0x11), (8, 0x55), (16, 0x33), (24, 0x44)] {
+        assert!(read(&mut disk, sector, 1).iter().all(|&b| b == byte));
+    }
+}
+
+#[test]
+fn overlay_rejects_shared_table_entries() {
+    let image = Scratch::new("cow-dup", 16);
+    let cow = ScratchOverlay::new("dup");
+    let mut disk = cow.open(&image).unwrap();
+    disk.write(0, &[0x11; SECTOR_SIZE]).unwrap();
+    disk.write(8, &[0x22; SECTOR_SIZE]).unwrap();
+    drop(disk);
+
+    // Point the second base block at the first one's copy
+    let file = std::fs::OpenOptions::new().write(true).open(&cow.0).unwrap();
+    write_all_at(&file, &1u32.to_le_bytes(), 4096 + 4).unwrap();
+    drop(file);
+    assert_eq!(cow.open(&image).err().map(|e| e.kind()), Some(io::ErrorKind::InvalidData));
+}
+
+#[test]
+fn overlay_rejects_other_base() {
+    let image = Scratch::new("cow-a", 16);
+    let other = Scratch::new("cow-b", 32);
+    let cow = ScratchOverlay::new("other");
+    cow.open(&image).unwrap().write(0, &[1; SECTOR_SIZE]).unwrap();
+    assert_eq!(cow.open(&other).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    // Nor is some other file taken for an overlay
+    assert!(OverlayDisk::open(Box::new(FileDisk::open_read_only(&image.0).unwrap()), &other.0).is_err());
+}
+
+// --- qcow2 ---
+
+fn put_be32(b: &mut [u8], at: usize, v: u32) {
+    b[at..at + 4].copy_from_slice(&v.to_be_bytes());
+}
+
+fn put_be64(b: &mut [u8], at: usize, v: u64) {
+    b[at..at + 8].copy_from_slice(&v.to_be_bytes());
+}
+
+fn be64_at(file: &[u8], at: u64) -> u64 {
+    u64::from_be_bytes(file[at as usize..at as usize + 8].try_into().unwrap())
+}
+
+/// A fresh qcow2 image like `qemu-img create` makes: header, refcount
+/// table and one refcount block, a cluster each, then the L1 table
+fn make_qcow2(path: &Path, version: u32, cluster_bits: u32, refcount_order: u32, size: u64, backing: Option<&str>) {
+    let cluster = 1usize << cluster_bits;
+    let l1_size = size.div_ceil((cluster as u64) * (cluster as u64 / 8));
+    let l1_clusters = (l1_size as usize * 8).div_ceil(cluster);
+    let mut image = vec![0u8; (3 + l1_clusters) * cluster];
+    put_be32(&mut image, 0, 0x5146_49fb);
+    put_be32(&mut image, 4, version);
+    if let Some(name) = backing {
+        put_be64(&mut image, 8, 256);
+        put_be32(&mut image, 16, name.len() as u32);
+        image[256..256 + name.len()].copy_from_slice(name.as_bytes());
+    }
+    put_be32(&mut image, 20, cluster_bits);
+    put_be64(&mut image, 24, size);
+    put_be32(&mut image, 36, l1_size as u32);
+    put_be64(&mut image, 40, 3 * cluster as u64);
+    put_be64(&mut image, 48, cluster as u64);
+    put_be32(&mut image, 56, 1);
+    if version == 3 {
+        put_be32(&mut image, 96, refcount_order);
+        put_be32(&mut image, 100, 104);
+    }
+    put_be64(&mut image, cluster, 2 * cluster as u64);
+    for index in 0..3 + l1_clusters {
+        set_refcount(&mut image[2 * cluster..3 * cluster], refcount_order, index, 1);
+    }
+    std::fs::write(path, image).unwrap();
+}
+
+fn set_refcount(block: &mut [u8], order: u32, index: usize, value: u64) {
+    let bits = 1usize << order;
+    if bits < 8 {
+        let shift = index * bits % 8;
+        let byte = &mut block[index * bits / 8];
+        *byte = (*byte & !(((1u8 << bits) - 1) << shift)) | ((value as u8) << shift);
+    } else {
+        let raw = value.to_be_bytes();
+        block[index * bits / 8..(index + 1) * bits / 8].copy_from_slice(&raw[8 - bits / 8..]);
+    }
+}
+
+fn get_refcount(block: &[u8], order: u32, index: usize) -> u64 {
+    let bits = 1usize << order;
+    if bits < 8 {
+        (block[index * bits / 8] as u64 >> (index * bits % 8)) & ((1 << bits) - 1)
+    } else {
+        let mut raw = [0u8; 8];
+        raw[8 - bits / 8..].copy_from_slice(&block[index * bits / 8..(index + 1) * bits / 8]);
+        u64::from_be_bytes(raw)
+    }
+}
+
+/// Count every reference in the image and compare with its refcounts,
+/// as `qemu-img check` would; `extra` holds references from outside the
+/// active tables (a snapshot's, say)
+fn check_qcow2(path: &Path, extra: &[(u64, u64)]) {
+    let file = std::fs::read(path).unwrap();
+    let header = &file[..104];
+    let cluster_bits = u32::from_be_bytes(header[20..24].try_into().unwrap());
+    let cluster = 1u64 << cluster_bits;
+    let version = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let order = if version == 3 { u32::from_be_bytes(header[96..100].try_into().unwrap()) } else { 4 };
+    let l1_size = u32::from_be_bytes(header[36..40].try_into().unwrap()) as u64;
+    let l1_offset = be64_at(&file, 40);
+    let rt_offset = be64_at(&file, 48);
+    let rt_clusters = u32::from_be_bytes(header[56..60].try_into().unwrap()) as u64;
+    assert_eq!(file.len() as u64 % cluster, 0);
+
+    let clusters = file.len() as u64 / cluster;
+    let mut refs = vec![0u64; clusters as usize];
+    let mut add = |offset: u64, len: u64| {
+        assert_eq!(offset % cluster, 0, "unaligned cluster {:#x}", offset);
+        for c in offset / cluster..(offset + len).div_ceil(cluster) {
+            refs[c as usize] += 1;
+        }
+    };
+    add(0, cluster);
+    add(l1_offset, l1_size * 8);
+    add(rt_offset, rt_clusters * cluster);
+    let per_block = (cluster * 8) >> order;
+    let rt: Vec<u64> = (0..rt_clusters * cluster / 8).map(|i| be64_at(&file, rt_offset + i * 8)).collect();
+    for &block in rt.iter().filter(|&&b| b != 0) {
+        add(block, cluster);
+    }
+    let mut shared = Vec::new();
+    for i in 0..l1_size {
+        let l1e = be64_at(&file, l1_offset + i * 8);
+        let l2 = l1e & 0x00ff_ffff_ffff_fe00;
+        if l2 == 0 {
+            continue;
+        }
+        add(l2, cluster);
+        shared.push((l2, l1e >> 63));
+        for j in 0..cluster / 8 {
+            let l2e = be64_at(&file, l2 + j * 8);
+            let data = l2e & 0x00ff_ffff_ffff_fe00;
+            if data != 0 {
+                add(data, cluster);
+                shared.push((data, l2e >> 63));
+            }
+        }
+    }
+    for &(offset, n) in extra {
+        refs[(offset / cluster) as usize] += n;
+    }
+
+    let stored = |c: u64| -> u64 {
+        match rt.get((c / per_block) as usize) {
+            Some(&block) if block != 0 => {
+                get_refcount(&file[block as usize..(block + cluster) as usize], order, (c % per_block) as usize)
+            }
+            _ => 0,
+        }
+    };
+    for c in 0..clusters {
+        assert_eq!(stored(c), refs[c as usize], "refcount of cluster {} ({:#x})", c, c * cluster);
+    }
+    // COPIED is set exactly when the cluster has one reference
+    for (offset, copied) in shared {
+        assert_eq!(copied == 1, stored(offset / cluster) == 1, "COPIED flag of {:#x}", offset);
+    }
+}
+
+fn open_qcow2(path: &Path) -> Box<dyn Disk> {
+    open_image(path, false).unwrap()
+}
+
+#[test]
+fn qcow2_read_write() {
+    // Small clusters so the refcount table has to grow; narrow and wide refcounts
+    for (version, cluster_bits, order) in [(2, 9, 4), (3, 9, 6), (3, 10, 1), (3, 16, 4)] {
+        let image = Scratch::new(&format!("qcow2-rw-{}-{}-{}", version, cluster_bits, order), 0);
+        let size = 3 << 20;
+        make_qcow2(&image.0, version, cluster_bits, order, size, None);
+
+        let mut disk = open_qcow2(&image.0);
+        assert_eq!(disk.sectors(), size / SECTOR_SIZE as u64);
+        assert!(read(&mut *disk, 0, 64).iter().all(|&b| b == 0));
+
+        let sectors = (size / SECTOR_SIZE as u64) as usize;
+        let mut model = vec![0u8; size as usize];
+        let mut seed = 0x2545_f491_4f6c_dd1du64;
+        for round in 0..300 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let count = (seed % 40) as usize + 1;
+            // Fill the whole disk sequentially first, then scatter writes
+            let sector = if round < 100 { round * sectors / 100 } else { (seed >> 8) as usize % (sectors - count) };
+            let count = count.min(sectors - sector);
+            let data: Vec<u8> = (0..count * SECTOR_SIZE).map(|i| (i as u64 ^ seed) as u8).collect();
+            disk.write(sector as u64, &data).unwrap();
+            model[sector * SECTOR_SIZE..(sector + count) * SECTOR_SIZE].copy_from_slice(&data);
+        }
+        disk.flush().unwrap();
+        drop(disk);
+
+        check_qcow2(&image.0, &[]);
+        let mut disk = open_qcow2(&image.0);
+        assert_eq!(read(&mut *disk, 0, sectors), model);
+    }
+}
+
+#[test]
+fn qcow2_backing_and_zero_clusters() {
+    let base = Scratch::new("qcow2-base", 64);
+    let pristine = std::fs::read(&base.0).unwrap();
+    let image = Scratch::new("qcow2-overlay", 0);
+    let name = base.0.file_name().unwrap().to_str().unwrap();
+    // Larger than its backing file: the rest reads as zeros
+    make_qcow2(&image.0, 3, 12, 4, 80 * SECTOR_SIZE as u64, Some(name));
+
+    let mut disk = open_qcow2(&image.0);
+    let mut expected = pristine.clone();
+    expected.resize(80 * SECTOR_SIZE, 0);
+    assert_eq!(read(&mut *disk, 0, 80), expected);
+
+    // Allocate cluster 0, then mark cluster 1 as zeros by hand
+    disk.write(2, &[0xAA; SECTOR_SIZE]).unwrap();
+    drop(disk);
+    let mut raw = std::fs::read(&image.0).unwrap();
+    let l2 = be64_at(&raw, 3 * 4096) & 0x00ff_ffff_ffff_fe00;
+    put_be64(&mut raw, l2 as usize + 8, 1);
+    std::fs::write(&image.0, raw).unwrap();
+
+    let mut disk = open_qcow2(&image.0);
+    expected[2 * SECTOR_SIZE..3 * SECTOR_SIZE].fill(0xAA);
+    expected[4096..8192].fill(0);
+    assert_eq!(read(&mut *disk, 0, 80), expected);
+
+    // Partial writes keep the rest of a zero cluster and of the backing data
+    disk.write(9, &[0xBB; SECTOR_SIZE]).unwrap();
+    disk.write(17, &[0xCC; SECTOR_SIZE]).unwrap();
+    expected[9 * SECTOR_SIZE..10 * SECTOR_SIZE].fill(0xBB);
+    expected[17 * SECTOR_SIZE..18 * SECTOR_SIZE].fill(0xCC);
+    assert_eq!(read(&mut *disk, 0, 80), expected);
+    drop(disk);
+
+    check_qcow2(&image.0, &[]);
+    assert_eq!(std::fs::read(&base.0).unwrap(), pristine);
+}
+
+#[test]
+fn qcow2_copies_shared_clusters() {
+    let image = Scratch::new("qcow2-shared", 0);
+    make_qcow2(&image.0, 3, 12, 4, 1 << 20, None);
+    let mut disk = open_qcow2(&image.0);
+    disk.write(0, &[0x11; 4096]).unwrap();
+    drop(disk);
+
+    // Pretend a snapshot holds the L2 table and data cluster too
+    let mut raw = std::fs::read(&image.0).unwrap();
+    let l1e = be64_at(&raw, 3 * 4096);
+    let l2 = l1e & !(1 << 63);
+    let data = be64_at(&raw, l2) & !(1 << 63);
+    put_be64(&mut raw, 3 * 4096, l2);
+    put_be64(&mut raw, l2 as usize, data);
+    let block = be64_at(&raw, 4096) as usize;
+    set_refcount(&mut raw[block..block + 4096], 4, (l2 / 4096) as usize, 2);
+    set_refcount(&mut raw[block..block + 4096], 4, (data / 4096) as usize, 2);
+    std::fs::write(&image.0, &raw).unwrap();
+    check_qcow2(&image.0, &[(l2, 1), (data, 1)]);
+
+    let mut disk = open_qcow2(&image.0);
+    disk.write(1, &[0x22; SECTOR_SIZE]).unwrap();
+    let mut expected = vec![0x11; 4096];
+    expected[SECTOR_SIZE..2 * SECTOR_SIZE].fill(0x22);
+    assert_eq!(read(&mut *disk, 0, 8), expected);
+    drop(disk);
+
+    // The snapshot's copies are untouched
+    let after = std::fs::read(&image.0).unwrap();
+    assert!(after[data as usize..data as usize + 4096].iter().all(|&b| b == 0x11));
+    check_qcow2(&image.0, &[(l2, 1), (data, 1)]);
+}
+
+#[test]
+fn qcow2_rejects_unsupported() {
+    let image = Scratch::new("qcow2-bad", 0);
+    make_qcow2(&image.0, 3, 16, 4, 1 << 20, None);
+    let mut raw = std::fs::read(&image.0).unwrap();
+    // Dirty: refcounts can't be trusted
+    put_be64(&mut raw, 72, 1);
+    std::fs::write(&image.0, &raw).unwrap();
+    assert_eq!(open_image(&image.0, false).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    // An unknown incompatible feature
+    put_be64(&mut raw, 72, 1 << 10);
+    std::fs::write(&image.0, &raw).unwrap();
+    assert_eq!(open_image(&image.0, false).err().unwrap().kind(), io::ErrorKind::Unsupported);
+
+    // An image that is its own backing file
+    let name = image.0.file_name().unwrap().to_str().unwrap();
+    make_qcow2(&image.0, 3, 16, 4, 1 << 20, Some(name));
+    assert!(open_image(&image.0, false).is_err());
+}
+
+// --- Encryption ---
+
+/// Few KDF rounds, to keep the tests quick
+const TEST_ITERATIONS: u32 = 1000;
+
+fn unlock(path: &Path, passphrase: &str) -> io::Result<EncryptedDisk> {
+    EncryptedDisk::open(std::fs::File::options().read(true).write(true).open(path)?, false, passphrase)
+}
+
+#[test]
+fn encrypted_round_trip() {
+    let plain = Scratch::new("crypt-plain", 40);
+    let pristine = std::fs::read(&plain.0).unwrap();
+    let image = Scratch::new("crypt-image", 0);
+    std::fs::remove_file(&image.0).unwrap();
+    let mut source = FileDisk::open_read_only(&plain.0).unwrap();
+    EncryptedDisk::create(&image.0, &mut source, "hunter2", TEST_ITERATIONS).unwrap();
+
+    // Nothing of the plaintext is left on disk
+    let raw = std::fs::read(&image.0).unwrap();
+    assert_eq!(raw.len(), 4096 + pristine.len());
+    assert!(raw[4096..].chunks(SECTOR_SIZE).zip(pristine.chunks(SECTOR_SIZE)).all(|(a, b)| a != b));
+
+    let mut disk = unlock(&image.0, "hunter2").unwrap();
+    assert_eq!(disk.sectors(), 40);
+    assert_eq!(read(&mut disk, 0, 40), pristine);
+    disk.write(5, &[0x5A; 2 * SECTOR_SIZE]).unwrap();
+    drop(disk);
+
+    let mut expected = pristine.clone();
+    expected[5 * SECTOR_SIZE..7 * SECTOR_SIZE].fill(0x5A);
+    let mut disk = unlock(&image.0, "hunter2").unwrap();
+    assert_eq!(read(&mut disk, 0, 40), expected);
+    // Equal plaintext sectors encrypt differently
+    let raw = std::fs::read(&image.0).unwrap();
+    assert_ne!(raw[4096 + 5 * SECTOR_SIZE..4096 + 6 * SECTOR_SIZE], raw[4096 + 6 * SECTOR_SIZE..4096 + 7 * SECTOR_SIZE]);
+}
+
+#[test]
+fn encrypted_rejects_wrong_passphrase() {
+    let plain = Scratch::new("crypt-wrong-plain", 8);
+    let image = Scratch::new("crypt-wrong", 0);
+    std::fs::remove_file(&image.0).unwrap();
+    let mut source = FileDisk::open_read_only(&plain.0).unwrap();
+    EncryptedDisk::create(&image.0, &mut source, "right", TEST_ITERATIONS).unwrap();
+    // Never over an existing file
+    assert!(EncryptedDisk::create(&image.0, &mut source, "right", TEST_ITERATIONS).is_err());
+
+    assert_eq!(unlock(&image.0, "wrong").err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+    // An overlay would hold the changes unencrypted
+    let disk = unlock(&image.0, "right").unwrap();
+    let cow = ScratchOverlay::new("crypt");
+    assert!(OverlayDisk::open(Box::new(disk), &cow.0).is_err());
+    assert!(!cow.0.exists());
+}
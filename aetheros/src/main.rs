@@ -1,11 +1,13 @@
 mod backend;
+mod block;
+mod share;
 // mod scheduler; // DELETED
 
 use aether_core::scheduler::Scheduler;
 
 use backend::Backend;
 #[cfg(any(target_os = "macos", target_os = "linux", target_os = "windows"))]
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -37,6 +39,87 @@ fn main() {
     // 3. Thread spawn: instance.run().
     // 4. Main Loop: Read RAM via ptr, Update Window.
 
+    // Optional host directory share: --share DIR or --share-ro DIR
+    // Block device image: --disk PATH, or disk.img if there is one.
+    // --overlay FILE keeps the guest's writes out of the image; --commit
+    // or --discard then folds them into it or throws them away.
+    // --encrypt RAW writes an encrypted copy of RAW to the --disk path.
+    let mut disk = None;
+    let mut overlay = None;
+    let mut encrypt = None;
+    let (mut commit, mut discard) = (false, false);
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let read_only = match arg.as_str() {
+            "--share" => false,
+            "--share-ro" => true,
+            "--disk" => {
+                disk = args.next();
+                if disk.is_none() {
+                    eprintln!("[Warning] --disk needs an image file");
+                }
+                continue;
+            }
+            "--overlay" => {
+                overlay = args.next();
+                if overlay.is_none() {
+                    eprintln!("[Warning] --overlay needs a file");
+                }
+                continue;
+            }
+            "--commit" => {
+                commit = true;
+                continue;
+            }
+            "--encrypt" => {
+                encrypt = args.next();
+                if encrypt.is_none() {
+                    eprintln!("[Warning] --encrypt needs an image file");
+                }
+                continue;
+            }
+            "--discard" => {
+                discard = true;
+                continue;
+            }
+            _ => {
+                eprintln!("[Warning] Unknown argument: {}", arg);
+                continue;
+            }
+        };
+        let Some(dir) = args.next() else {
+            eprintln!("[Warning] {} needs a directory", arg);
+            break;
+        };
+        match share::ShareServer::new(std::path::Path::new(&dir), read_only) {
+            Ok(server) => {
+                println!(
+                    "[Aether::Share] Sharing {} ({})",
+                    server.root().display(),
+                    if read_only { "read-only" } else { "read-write" }
+                );
+                share::install(server);
+            }
+            Err(e) => eprintln!("[Warning] Can't share {}: {}", dir, e),
+        }
+    }
+    if let Some(source) = encrypt {
+        let Some(path) = disk else {
+            eprintln!("[Error] --encrypt needs --disk for the new image");
+            std::process::exit(1);
+        };
+        encrypt_disk(&source, &path);
+    }
+    let disk = disk.or_else(|| std::path::Path::new("disk.img").exists().then(|| "disk.img".into()));
+    match disk {
+        Some(path) => attach_disk(&path, overlay.as_deref(), commit, discard),
+        None if commit || discard => {
+            eprintln!("[Error] --commit and --discard need a disk image");
+            std::process::exit(1);
+        }
+        None => {}
+    }
+
     // 1. Initialize Backend (Shared Ownership via Arc)
     let backend = Arc::new(backend::CurrentBackend::new());
     
@@ -159,6 +242,15 @@ fn main() {
                }
             }
             
+            // Pointer Handling (position is clamped to the window)
+            if let Some((x, y)) = window.get_mouse_pos(MouseMode::Clamp) {
+                let mut buttons = 0;
+                if window.get_mouse_down(MouseButton::Left) { buttons |= 1; }
+                if window.get_mouse_down(MouseButton::Right) { buttons |= 2; }
+                if window.get_mouse_down(MouseButton::Middle) { buttons |= 4; }
+                backend.inject_pointer(x as u32, y as u32, buttons);
+            }
+            
             window.update_with_buffer(fb_buffer, WIDTH, HEIGHT).unwrap();
         }
     }
@@ -171,3 +263,96 @@ fn main() {
         }
     }
 }
+
+/// Attach the disk image at `path` as the guest's block device, under
+/// `overlay` if given. With `commit` or `discard`, apply or drop the
+/// overlay's changes and exit instead of booting.
+fn attach_disk(path: &str, overlay: Option<&str>, commit: bool, discard: bool) {
+    use block::{Disk, OverlayDisk};
+    use std::path::Path;
+
+    let Some(overlay) = overlay else {
+        if commit || discard {
+            eprintln!("[Error] --commit and --discard need --overlay");
+            std::process::exit(1);
+        }
+        match block::open_image(Path::new(path), false) {
+            Ok(image) => {
+                println!(
+                    "[Aether::Block] Attached {} ({} sectors, {})",
+                    path,
+                    image.sectors(),
+                    if image.read_only() { "read-only" } else { "read-write" }
+                );
+                block::install(block::BlockServer::new(image));
+            }
+            // Booting without the disk asked for would only fail later
+            Err(e) => {
+                eprintln!("[Error] Can't open disk {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    };
+
+    // The base is only ever written by a commit
+    let base = block::open_image(Path::new(path), !commit);
+    let mut disk = match base.and_then(|base| OverlayDisk::open(base, Path::new(overlay))) {
+        Ok(disk) => disk,
+        Err(e) => {
+            eprintln!("[Error] Can't open overlay {} on {}: {}", overlay, path, e);
+            std::process::exit(1);
+        }
+    };
+    if commit || discard {
+        let result = if commit {
+            disk.commit().map(|n| format!("Committed {} blocks from {} into {}", n, overlay, path))
+        } else {
+            let n = disk.dirty_blocks();
+            disk.discard().map(|()| format!("Discarded {} changed blocks in {}", n, overlay))
+        };
+        match result {
+            Ok(done) => {
+                println!("[Aether::Block] {}", done);
+                std::process::exit(0);
+            }
+            Err(e) => {
+                eprintln!("[Error] {}: {}", overlay, e);
+                std::process::exit(1);
+            }
+        }
+    }
+    println!(
+        "[Aether::Block] Attached {} over {} ({} sectors, {} changed blocks)",
+        overlay,
+        path,
+        disk.sectors(),
+        disk.dirty_blocks()
+    );
+    block::install(block::BlockServer::new(Box::new(disk)));
+}
+
+/// Write an encrypted copy of the raw image `source` to `path`, then exit
+fn encrypt_disk(source: &str, path: &str) -> ! {
+    use block::{Disk, EncryptedDisk, FileDisk};
+    use std::path::Path;
+
+    let result = FileDisk::open_read_only(Path::new(source)).and_then(|mut image| {
+        let passphrase = block::passphrase(&format!("New passphrase for {}", path))?;
+        if std::env::var_os("AETHER_DISK_PASSPHRASE").is_none() && block::passphrase("Repeat it")? != passphrase {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "passphrases don't match"));
+        }
+        EncryptedDisk::create(Path::new(path), &mut image, &passphrase, block::DEFAULT_ITERATIONS)?;
+        Ok(image.sectors())
+    });
+    match result {
+        Ok(sectors) => {
+            println!("[Aether::Block] Encrypted {} into {} ({} sectors)", source, path, sectors);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("[Error] Can't encrypt {}: {}", source, e);
+            std::process::exit(1);
+        }
+    }
+}
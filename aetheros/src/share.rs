@@ -0,0 +1,545 @@
+//! Host directory sharing: serves a directory on the host to the guest
+//! over the 9P2000.L request queue described in `aether_abi::share`.
+//!
+//! Everything the guest names is a single path component checked here,
+//! and the server never walks through a symlink or opens one, so the
+//! guest can't reach anything outside the shared directory.
+
+// Only the Hypervisor.framework backend handles hypercalls so far
+#![cfg_attr(not(target_os = "macos"), allow(dead_code))]
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+use aether_abi::mmio::SHARE_ADDR;
+use aether_abi::share::*;
+
+// Treaddir entry types (`d_type`)
+const DT_DIR: u8 = 4;
+const DT_REG: u8 = 8;
+const DT_LNK: u8 = 10;
+
+struct Fid {
+    /// Relative to the share root; empty for the root itself
+    path: PathBuf,
+    file: Option<File>,
+    /// Snapshot taken when a directory is opened, so offsets stay stable
+    listing: Option<Vec<(String, Qid, u8)>>,
+}
+
+pub struct ShareServer {
+    root: PathBuf,
+    read_only: bool,
+    fids: HashMap<u32, Fid>,
+}
+
+/// The server answering `HyperCall::ShareNotify`, if the host exports a directory
+static SHARE: Mutex<Option<ShareServer>> = Mutex::new(None);
+
+/// Export `server` to the guest
+pub fn install(server: ShareServer) {
+    *SHARE.lock().unwrap() = Some(server);
+}
+
+/// Answer everything pending in the guest's queue. Does nothing without
+/// a share, which the guest sees as `DONE` not moving.
+///
+/// # Safety
+/// `mem` must point at guest RAM covering `SHARE_ADDR..SHARE_ADDR + QUEUE_SIZE`.
+pub unsafe fn notify(mem: *mut u8) {
+    if let Some(server) = SHARE.lock().unwrap().as_mut() {
+        server.service(mem.add(SHARE_ADDR));
+    }
+}
+
+/// The errno a guest sees for a host I/O error
+fn errno(e: &io::Error) -> u32 {
+    match e.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::NotADirectory => ENOTDIR,
+        ErrorKind::IsADirectory => EISDIR,
+        ErrorKind::DirectoryNotEmpty => ENOTEMPTY,
+        ErrorKind::ReadOnlyFilesystem => EROFS,
+        ErrorKind::StorageFull => ENOSPC,
+        ErrorKind::FileTooLarge => EFBIG,
+        ErrorKind::CrossesDevices => EXDEV,
+        ErrorKind::InvalidInput => EINVAL,
+        ErrorKind::InvalidFilename => ENAMETOOLONG,
+        _ => EIO,
+    }
+}
+
+/// A name the guest may use: one component, and not `.` or `..`
+fn check_name(name: &str) -> Result<&str, u32> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\', '\0']) {
+        return Err(EINVAL);
+    }
+    if name.len() > 255 {
+        return Err(ENAMETOOLONG);
+    }
+    Ok(name)
+}
+
+fn qid(meta: &fs::Metadata) -> Qid {
+    let kind = if meta.is_dir() {
+        QT_DIR
+    } else if meta.is_symlink() {
+        QT_SYMLINK
+    } else {
+        QT_FILE
+    };
+    let version = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs() as u32);
+    Qid { kind, version, path: file_id(meta) }
+}
+
+#[cfg(unix)]
+fn file_id(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.ino()
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &fs::Metadata) -> u64 {
+    0
+}
+
+fn mode(meta: &fs::Metadata) -> u32 {
+    let kind = if meta.is_dir() {
+        S_IFDIR
+    } else if meta.is_symlink() {
+        S_IFLNK
+    } else {
+        S_IFREG
+    };
+    #[cfg(unix)]
+    let perm = {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o7777
+    };
+    #[cfg(not(unix))]
+    let perm = match (meta.is_dir(), meta.permissions().readonly()) {
+        (true, _) => 0o755,
+        (false, true) => 0o444,
+        (false, false) => 0o644,
+    };
+    kind | perm
+}
+
+fn seconds(t: io::Result<std::time::SystemTime>) -> u64 {
+    t.ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+#[cfg(unix)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, data, offset)
+}
+
+#[cfg(windows)]
+fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, data, offset)
+}
+
+/// Open without following a final symlink
+fn open(path: &Path, options: &mut OpenOptions) -> io::Result<File> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW);
+    }
+    options.open(path)
+}
+
+impl ShareServer {
+    /// Serve the directory at `root`; with `read_only`, every change fails with EROFS
+    pub fn new(root: &Path, read_only: bool) -> io::Result<Self> {
+        let root = root.canonicalize()?;
+        if !root.is_dir() {
+            return Err(io::Error::new(ErrorKind::NotADirectory, "share root is not a directory"));
+        }
+        Ok(ShareServer { root, read_only, fids: HashMap::new() })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Answer every request between `DONE` and `HEAD` in the queue at `queue`
+    ///
+    /// # Safety
+    /// `queue` must point at `QUEUE_SIZE` bytes of guest memory.
+    pub unsafe fn service(&mut self, queue: *mut u8) {
+        let head = (queue.add(HEAD) as *const u32).read_volatile();
+        let mut done = (queue.add(DONE) as *const u32).read_volatile();
+        // More outstanding than there are slots means a confused guest
+        if head.wrapping_sub(done) as usize > QUEUE_SLOTS {
+            done = head.wrapping_sub(QUEUE_SLOTS as u32);
+        }
+        let mut request = vec![0u8; MSIZE];
+        let mut reply = vec![0u8; MSIZE];
+        while done != head {
+            let slot = queue.add(slot_offset(done));
+            // Copy first: the guest may still be scribbling on the slot
+            std::ptr::copy_nonoverlapping(slot, request.as_mut_ptr(), MSIZE);
+            let len = self.handle(&request, &mut reply);
+            std::ptr::copy_nonoverlapping(reply.as_ptr(), slot, len);
+            done = done.wrapping_add(1);
+            std::sync::atomic::fence(std::sync::atomic::Ordering::Release);
+            (queue.add(DONE) as *mut u32).write_volatile(done);
+        }
+    }
+
+    /// Answer one T-message, writing the R-message into `reply` and
+    /// returning its length
+    pub fn handle(&mut self, request: &[u8], reply: &mut [u8]) -> usize {
+        let Some((mut r, kind, tag)) = Reader::new(request) else {
+            return Self::error(reply, NOTAG, EPROTO);
+        };
+        let mut w = Writer::new(reply, kind.wrapping_add(1), tag);
+        let result = self.dispatch(kind, &mut r, &mut w).and_then(|()| w.finish().ok_or(EPROTO));
+        match result {
+            Ok(len) => len,
+            Err(code) => Self::error(reply, tag, code),
+        }
+    }
+
+    fn error(reply: &mut [u8], tag: u16, code: u32) -> usize {
+        Writer::new(reply, RLERROR, tag).u32(code).finish().unwrap_or(0)
+    }
+
+    fn fid(&self, fid: u32) -> Result<&Fid, u32> {
+        self.fids.get(&fid).ok_or(EBADF)
+    }
+
+    fn fid_mut(&mut self, fid: u32) -> Result<&mut Fid, u32> {
+        self.fids.get_mut(&fid).ok_or(EBADF)
+    }
+
+    fn host_path(&self, rel: &Path) -> PathBuf {
+        self.root.join(rel)
+    }
+
+    fn writable(&self) -> Result<(), u32> {
+        if self.read_only {
+            return Err(EROFS);
+        }
+        Ok(())
+    }
+
+    /// Host path of `name` inside the directory named by `dfid`, which has
+    /// to be a real directory: a walk may end on a symlink
+    fn child(&self, dfid: u32, name: &str) -> Result<PathBuf, u32> {
+        let dir = self.host_path(&self.fid(dfid)?.path);
+        let name = check_name(name)?;
+        let meta = fs::symlink_metadata(&dir).map_err(|e| errno(&e))?;
+        if !meta.is_dir() {
+            return Err(ENOTDIR);
+        }
+        Ok(dir.join(name))
+    }
+
+    fn dispatch(&mut self, kind: u8, r: &mut Reader, w: &mut Writer) -> Result<(), u32> {
+        match kind {
+            TVERSION => {
+                let msize = r.u32().ok_or(EPROTO)?;
+                let version = r.str().ok_or(EPROTO)?;
+                // A new session: forget every fid
+                self.fids.clear();
+                w.u32(msize.min(MSIZE as u32));
+                w.str(if version == VERSION { VERSION } else { "unknown" });
+            }
+            TATTACH => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                if self.fids.contains_key(&fid) {
+                    return Err(EBADF);
+                }
+                let meta = fs::metadata(&self.root).map_err(|e| errno(&e))?;
+                self.fids.insert(fid, Fid { path: PathBuf::new(), file: None, listing: None });
+                w.qid(qid(&meta));
+            }
+            TWALK => self.walk(r, w)?,
+            TLOPEN => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let flags = r.u32().ok_or(EPROTO)?;
+                self.lopen(fid, flags, w)?;
+            }
+            TLCREATE => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let name = r.str().ok_or(EPROTO)?;
+                let _flags = r.u32().ok_or(EPROTO)?;
+                let perm = r.u32().ok_or(EPROTO)?;
+                self.writable()?;
+                let path = self.child(fid, name)?;
+                let mut options = OpenOptions::new();
+                options.read(true).write(true).create_new(true);
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(perm & 0o777);
+                }
+                #[cfg(not(unix))]
+                let _ = perm;
+                let file = open(&path, &mut options).map_err(|e| errno(&e))?;
+                let meta = file.metadata().map_err(|e| errno(&e))?;
+                let f = self.fid_mut(fid)?;
+                f.path.push(name);
+                f.file = Some(file);
+                f.listing = None;
+                w.qid(qid(&meta)).u32(IO_MAX as u32);
+            }
+            TREADLINK => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let path = self.host_path(&self.fid(fid)?.path);
+                let target = fs::read_link(path).map_err(|e| errno(&e))?;
+                w.str(target.to_str().ok_or(EINVAL)?);
+            }
+            TGETATTR => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let path = self.host_path(&self.fid(fid)?.path);
+                let meta = fs::symlink_metadata(path).map_err(|e| errno(&e))?;
+                w.u64(GETATTR_BASIC).qid(qid(&meta)).u32(mode(&meta));
+                w.u32(0).u32(0); // uid, gid
+                w.u64(meta_links(&meta)).u64(0); // nlink, rdev
+                w.u64(meta.len()).u64(4096).u64(meta.len().div_ceil(512));
+                w.u64(seconds(meta.accessed())).u64(0);
+                w.u64(seconds(meta.modified())).u64(0);
+                w.u64(seconds(meta.modified())).u64(0); // ctime
+                w.u64(0).u64(0).u64(0).u64(0); // btime, gen, data_version
+            }
+            TSETATTR => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let valid = r.u32().ok_or(EPROTO)?;
+                let perm = r.u32().ok_or(EPROTO)?;
+                let _uid = r.u32().ok_or(EPROTO)?;
+                let _gid = r.u32().ok_or(EPROTO)?;
+                let size = r.u64().ok_or(EPROTO)?;
+                self.writable()?;
+                let path = self.host_path(&self.fid(fid)?.path);
+                if valid & SETATTR_SIZE != 0 {
+                    let file = open(&path, OpenOptions::new().write(true)).map_err(|e| errno(&e))?;
+                    file.set_len(size).map_err(|e| errno(&e))?;
+                }
+                if valid & SETATTR_MODE != 0 {
+                    let meta = fs::symlink_metadata(&path).map_err(|e| errno(&e))?;
+                    if meta.is_symlink() {
+                        return Err(ELOOP);
+                    }
+                    let mut permissions = meta.permissions();
+                    #[cfg(unix)]
+                    {
+                        use std::os::unix::fs::PermissionsExt;
+                        permissions.set_mode(perm & 0o7777);
+                    }
+                    #[cfg(not(unix))]
+                    permissions.set_readonly(perm & 0o222 == 0);
+                    fs::set_permissions(&path, permissions).map_err(|e| errno(&e))?;
+                }
+            }
+            TREADDIR => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let offset = r.u64().ok_or(EPROTO)?;
+                let count = r.u32().ok_or(EPROTO)? as usize;
+                let listing = self.fid(fid)?.listing.as_ref().ok_or(EBADF)?;
+                let at = w.reserve(4);
+                let start = w.written();
+                let limit = count.min(IO_MAX);
+                for (i, (name, q, kind)) in listing.iter().enumerate().skip(offset as usize) {
+                    let size = Qid::SIZE + 8 + 1 + 2 + name.len();
+                    if w.written() - start + size > limit {
+                        break;
+                    }
+                    w.qid(*q).u64(i as u64 + 1).u8(*kind).str(name);
+                }
+                let len = (w.written() - start) as u32;
+                w.buffer()[at..at + 4].copy_from_slice(&len.to_le_bytes());
+            }
+            TREAD => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let offset = r.u64().ok_or(EPROTO)?;
+                let count = (r.u32().ok_or(EPROTO)? as usize).min(IO_MAX);
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let mut buf = vec![0u8; count];
+                let n = read_at(file, &mut buf, offset).map_err(|e| errno(&e))?;
+                w.u32(n as u32).bytes(&buf[..n]);
+            }
+            TWRITE => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                let offset = r.u64().ok_or(EPROTO)?;
+                let count = r.u32().ok_or(EPROTO)? as usize;
+                let data = r.bytes(count).ok_or(EPROTO)?;
+                self.writable()?;
+                let file = self.fid(fid)?.file.as_ref().ok_or(EBADF)?;
+                let n = write_at(file, data, offset).map_err(|e| errno(&e))?;
+                w.u32(n as u32);
+            }
+            TCLUNK => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                self.fids.remove(&fid).ok_or(EBADF)?;
+            }
+            TFSYNC => {
+                let fid = r.u32().ok_or(EPROTO)?;
+                if let Some(file) = &self.fid(fid)?.file {
+                    file.sync_all().map_err(|e| errno(&e))?;
+                }
+            }
+            TMKDIR => {
+                let dfid = r.u32().ok_or(EPROTO)?;
+                let name = r.str().ok_or(EPROTO)?;
+                let _perm = r.u32().ok_or(EPROTO)?;
+                self.writable()?;
+                let path = self.child(dfid, name)?;
+                fs::create_dir(&path).map_err(|e| errno(&e))?;
+                let meta = fs::symlink_metadata(&path).map_err(|e| errno(&e))?;
+                w.qid(qid(&meta));
+            }
+            TRENAMEAT => {
+                let old_dir = r.u32().ok_or(EPROTO)?;
+                let old_name = r.str().ok_or(EPROTO)?;
+                let new_dir = r.u32().ok_or(EPROTO)?;
+                let new_name = r.str().ok_or(EPROTO)?;
+                self.writable()?;
+                let from = self.child(old_dir, old_name)?;
+                let to = self.child(new_dir, new_name)?;
+                fs::rename(from, to).map_err(|e| errno(&e))?;
+            }
+            TUNLINKAT => {
+                let dfid = r.u32().ok_or(EPROTO)?;
+                let name = r.str().ok_or(EPROTO)?;
+                let flags = r.u32().ok_or(EPROTO)?;
+                self.writable()?;
+                let path = self.child(dfid, name)?;
+                let meta = fs::symlink_metadata(&path).map_err(|e| errno(&e))?;
+                match (flags & AT_REMOVEDIR != 0, meta.is_dir()) {
+                    (true, true) => fs::remove_dir(&path),
+                    (false, false) => fs::remove_file(&path),
+                    (true, false) => return Err(ENOTDIR),
+                    (false, true) => return Err(EISDIR),
+                }
+                .map_err(|e| errno(&e))?;
+            }
+            _ => return Err(EOPNOTSUPP),
+        }
+        Ok(())
+    }
+
+    /// Twalk: clone `fid` as `newfid`, then step through each name
+    fn walk(&mut self, r: &mut Reader, w: &mut Writer) -> Result<(), u32> {
+        let fid = r.u32().ok_or(EPROTO)?;
+        let newfid = r.u32().ok_or(EPROTO)?;
+        let count = r.u16().ok_or(EPROTO)? as usize;
+        if count > MAX_WALK {
+            return Err(EINVAL);
+        }
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(EBADF);
+        }
+
+        let mut path = self.fid(fid)?.path.clone();
+        let mut qids = Vec::new();
+        for i in 0..count {
+            let name = r.str().ok_or(EPROTO)?;
+            let step = check_name(name).and_then(|name| {
+                // Only ever step into real directories, never through a symlink
+                let here = fs::symlink_metadata(self.host_path(&path)).map_err(|e| errno(&e))?;
+                if !here.is_dir() {
+                    return Err(ENOTDIR);
+                }
+                let next = path.join(name);
+                let meta = fs::symlink_metadata(self.host_path(&next)).map_err(|e| errno(&e))?;
+                Ok((next, meta))
+            });
+            match step {
+                Ok((next, meta)) => {
+                    path = next;
+                    qids.push(qid(&meta));
+                }
+                // Only a failure on the first name is an error; otherwise
+                // the reply says how far the walk got and `newfid` stays unused
+                Err(e) if i == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        if qids.len() == count {
+            self.fids.insert(newfid, Fid { path, file: None, listing: None });
+        }
+        w.u16(qids.len() as u16);
+        for q in qids {
+            w.qid(q);
+        }
+        Ok(())
+    }
+
+    fn lopen(&mut self, fid: u32, flags: u32, w: &mut Writer) -> Result<(), u32> {
+        let path = self.host_path(&self.fid(fid)?.path);
+        let meta = fs::symlink_metadata(&path).map_err(|e| errno(&e))?;
+        if meta.is_symlink() {
+            return Err(ELOOP);
+        }
+        let access = flags & O_ACCMODE;
+        if access != O_RDONLY || flags & O_TRUNC != 0 {
+            self.writable()?;
+        }
+
+        if meta.is_dir() {
+            if access != O_RDONLY {
+                return Err(EISDIR);
+            }
+            let mut listing = Vec::new();
+            for entry in fs::read_dir(&path).map_err(|e| errno(&e))? {
+                let entry = entry.map_err(|e| errno(&e))?;
+                let (Ok(name), Ok(meta)) = (entry.file_name().into_string(), entry.metadata()) else { continue };
+                let kind = if meta.is_dir() {
+                    DT_DIR
+                } else if meta.is_symlink() {
+                    DT_LNK
+                } else {
+                    DT_REG
+                };
+                listing.push((name, qid(&meta), kind));
+            }
+            listing.sort_by(|a, b| a.0.cmp(&b.0));
+            self.fid_mut(fid)?.listing = Some(listing);
+        } else {
+            let mut options = OpenOptions::new();
+            options
+                .read(access != O_WRONLY)
+                .write(access != O_RDONLY)
+                .truncate(flags & O_TRUNC != 0)
+                .append(flags & O_APPEND != 0);
+            let file = open(&path, &mut options).map_err(|e| errno(&e))?;
+            self.fid_mut(fid)?.file = Some(file);
+        }
+        w.qid(qid(&meta)).u32(IO_MAX as u32);
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn meta_links(meta: &fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    meta.nlink()
+}
+
+#[cfg(not(unix))]
+fn meta_links(_meta: &fs::Metadata) -> u64 {
+    1
+}
+
+#[cfg(test)]
+mod tests;
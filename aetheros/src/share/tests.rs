@@ -0,0 +1,289 @@
+//! Server tests against a scratch directory, speaking raw 9P messages
+use super::*;
+
+/// A fresh directory under the system temp dir, removed on drop
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("aether-share-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("docs")).unwrap();
+        fs::write(dir.join("hello.txt"), b"Hello from the host").unwrap();
+        fs::write(dir.join("docs/notes"), b"notes").unwrap();
+        Scratch(dir)
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Send one request built by `body` and return the reply type and bytes
+fn call(server: &mut ShareServer, kind: u8, body: impl FnOnce(&mut Writer)) -> (u8, Vec<u8>) {
+    let mut request = vec![0u8; MSIZE];
+    let mut w = Writer::new(&mut request, kind, 1);
+    body(&mut w);
+    let len = w.finish().unwrap();
+    let mut reply = vec![0u8; MSIZE];
+    let n = server.handle(&request[..len], &mut reply);
+    reply.truncate(n);
+    let (_, kind, tag) = Reader::new(&reply).unwrap();
+    assert!(tag == 1 || kind == RLERROR);
+    (kind, reply)
+}
+
+/// Body of a successful reply to `kind`
+fn ok(server: &mut ShareServer, kind: u8, body: impl FnOnce(&mut Writer)) -> Vec<u8> {
+    let (rkind, reply) = call(server, kind, body);
+    assert_eq!(rkind, kind + 1, "error {:?}", error_code(&reply));
+    reply[MESSAGE_HEADER..].to_vec()
+}
+
+fn error_code(reply: &[u8]) -> Option<u32> {
+    let (mut r, kind, _) = Reader::new(reply)?;
+    (kind == RLERROR).then(|| r.u32()).flatten()
+}
+
+/// The errno a request fails with
+fn err(server: &mut ShareServer, kind: u8, body: impl FnOnce(&mut Writer)) -> u32 {
+    let (rkind, reply) = call(server, kind, body);
+    assert_eq!(rkind, RLERROR);
+    error_code(&reply).unwrap()
+}
+
+fn attach(root: &Path, read_only: bool) -> ShareServer {
+    let mut server = ShareServer::new(root, read_only).unwrap();
+    ok(&mut server, TVERSION, |w| {
+        w.u32(MSIZE as u32).str(VERSION);
+    });
+    ok(&mut server, TATTACH, |w| {
+        w.u32(0).u32(NOFID).str("guest").str("").u32(0);
+    });
+    server
+}
+
+fn walk(server: &mut ShareServer, newfid: u32, names: &[&str]) -> Result<usize, u32> {
+    let (kind, reply) = call(server, TWALK, |w| {
+        w.u32(0).u32(newfid).u16(names.len() as u16);
+        for name in names {
+            w.str(name);
+        }
+    });
+    if kind == RLERROR {
+        return Err(error_code(&reply).unwrap());
+    }
+    let (mut r, ..) = Reader::new(&reply).unwrap();
+    Ok(r.u16().unwrap() as usize)
+}
+
+fn read_all(server: &mut ShareServer, names: &[&str]) -> Vec<u8> {
+    assert_eq!(walk(server, 9, names), Ok(names.len()));
+    ok(server, TLOPEN, |w| {
+        w.u32(9).u32(O_RDONLY);
+    });
+    let body = ok(server, TREAD, |w| {
+        w.u32(9).u64(0).u32(4096);
+    });
+    ok(server, TCLUNK, |w| {
+        w.u32(9);
+    });
+    let count = u32::from_le_bytes(body[..4].try_into().unwrap()) as usize;
+    body[4..4 + count].to_vec()
+}
+
+fn list(server: &mut ShareServer, names: &[&str]) -> Vec<String> {
+    walk(server, 8, names).unwrap();
+    ok(server, TLOPEN, |w| {
+        w.u32(8).u32(O_RDONLY);
+    });
+    let body = ok(server, TREADDIR, |w| {
+        w.u32(8).u64(0).u32(4096);
+    });
+    ok(server, TCLUNK, |w| {
+        w.u32(8);
+    });
+    let mut msg = vec![0u8; MESSAGE_HEADER];
+    msg[..4].copy_from_slice(&((MESSAGE_HEADER + body.len()) as u32).to_le_bytes());
+    msg.extend_from_slice(&body);
+    let (mut r, ..) = Reader::new(&msg).unwrap();
+    r.u32().unwrap();
+    let mut names = Vec::new();
+    while r.remaining() > 0 {
+        r.qid().unwrap();
+        r.u64().unwrap();
+        r.u8().unwrap();
+        names.push(r.str().unwrap().to_string());
+    }
+    names
+}
+
+#[test]
+fn read_files_and_directories() {
+    let scratch = Scratch::new("read");
+    let mut server = attach(&scratch.0, true);
+    assert_eq!(read_all(&mut server, &["hello.txt"]), b"Hello from the host");
+    assert_eq!(read_all(&mut server, &["docs", "notes"]), b"notes");
+    assert_eq!(list(&mut server, &[]), ["docs", "hello.txt"]);
+
+    // A walk that fails part way reports how far it got and creates no fid
+    assert_eq!(walk(&mut server, 5, &["docs", "missing"]), Ok(1));
+    assert_eq!(err(&mut server, TCLUNK, |w| { w.u32(5); }), EBADF);
+    assert_eq!(walk(&mut server, 5, &["missing"]), Err(ENOENT));
+
+    walk(&mut server, 5, &["hello.txt"]).unwrap();
+    let body = ok(&mut server, TGETATTR, |w| {
+        w.u32(5).u64(GETATTR_BASIC);
+    });
+    let mode = u32::from_le_bytes(body[8 + Qid::SIZE..8 + Qid::SIZE + 4].try_into().unwrap());
+    assert_eq!(mode & S_IFMT, S_IFREG);
+}
+
+#[test]
+fn read_only_share_refuses_changes() {
+    let scratch = Scratch::new("ro");
+    let mut server = attach(&scratch.0, true);
+    assert_eq!(err(&mut server, TMKDIR, |w| { w.u32(0).str("new").u32(0o755).u32(0); }), EROFS);
+    assert_eq!(err(&mut server, TUNLINKAT, |w| { w.u32(0).str("hello.txt").u32(0); }), EROFS);
+    walk(&mut server, 3, &["hello.txt"]).unwrap();
+    assert_eq!(err(&mut server, TLOPEN, |w| { w.u32(3).u32(O_RDWR); }), EROFS);
+    assert!(scratch.0.join("hello.txt").exists());
+}
+
+#[test]
+fn read_write_share() {
+    let scratch = Scratch::new("rw");
+    let mut server = attach(&scratch.0, false);
+
+    walk(&mut server, 1, &["docs"]).unwrap();
+    ok(&mut server, TLCREATE, |w| {
+        w.u32(1).str("new.txt").u32(O_RDWR).u32(0o644).u32(0);
+    });
+    ok(&mut server, TWRITE, |w| {
+        w.u32(1).u64(0).u32(5).bytes(b"fresh");
+    });
+    ok(&mut server, TCLUNK, |w| {
+        w.u32(1);
+    });
+    assert_eq!(fs::read(scratch.0.join("docs/new.txt")).unwrap(), b"fresh");
+
+    ok(&mut server, TMKDIR, |w| {
+        w.u32(0).str("made").u32(0o755).u32(0);
+    });
+    walk(&mut server, 2, &["docs"]).unwrap();
+    walk(&mut server, 3, &["made"]).unwrap();
+    ok(&mut server, TRENAMEAT, |w| {
+        w.u32(2).str("new.txt").u32(3).str("moved.txt");
+    });
+    assert_eq!(read_all(&mut server, &["made", "moved.txt"]), b"fresh");
+
+    walk(&mut server, 4, &["made", "moved.txt"]).unwrap();
+    ok(&mut server, TSETATTR, |w| {
+        w.u32(4).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(2);
+        w.u64(0).u64(0).u64(0).u64(0);
+    });
+    assert_eq!(fs::read(scratch.0.join("made/moved.txt")).unwrap(), b"fr");
+
+    assert_eq!(err(&mut server, TUNLINKAT, |w| { w.u32(0).str("made").u32(0); }), EISDIR);
+    assert_eq!(err(&mut server, TUNLINKAT, |w| { w.u32(0).str("made").u32(AT_REMOVEDIR); }), ENOTEMPTY);
+    ok(&mut server, TUNLINKAT, |w| {
+        w.u32(3).str("moved.txt").u32(0);
+    });
+    ok(&mut server, TUNLINKAT, |w| {
+        w.u32(0).str("made").u32(AT_REMOVEDIR);
+    });
+    assert_eq!(list(&mut server, &[]), ["docs", "hello.txt"]);
+}
+
+#[test]
+fn cannot_escape_the_share() {
+    let scratch = Scratch::new("escape");
+    let outside = Scratch::new("outside");
+    let mut server = attach(&scratch.0, false);
+
+    assert_eq!(walk(&mut server, 1, &[".."]), Err(EINVAL));
+    assert_eq!(walk(&mut server, 1, &["docs/notes"]), Err(EINVAL));
+    assert_eq!(err(&mut server, TMKDIR, |w| { w.u32(0).str("../x").u32(0o755).u32(0); }), EINVAL);
+
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(&outside.0, scratch.0.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.0.join("hello.txt"), scratch.0.join("file-link")).unwrap();
+        // The link itself can be named, but not walked through or opened
+        assert_eq!(walk(&mut server, 1, &["link"]), Ok(1));
+        assert_eq!(walk(&mut server, 2, &["link", "hello.txt"]), Ok(1));
+        assert_eq!(err(&mut server, TLOPEN, |w| { w.u32(1).u32(O_RDONLY); }), ELOOP);
+        walk(&mut server, 3, &["file-link"]).unwrap();
+        assert_eq!(err(&mut server, TLOPEN, |w| { w.u32(3).u32(O_RDWR); }), ELOOP);
+        let target = ok(&mut server, TREADLINK, |w| {
+            w.u32(3);
+        });
+        assert!(target.len() > 2);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn changes_refuse_a_symlinked_directory() {
+    let scratch = Scratch::new("linked-dir");
+    let outside = Scratch::new("linked-dir-outside");
+    std::os::unix::fs::symlink(&outside.0, scratch.0.join("link")).unwrap();
+    let mut server = attach(&scratch.0, false);
+    walk(&mut server, 1, &["link"]).unwrap();
+
+    assert_eq!(err(&mut server, TMKDIR, |w| { w.u32(1).str("made").u32(0o755).u32(0); }), ENOTDIR);
+    assert_eq!(err(&mut server, TLCREATE, |w| { w.u32(1).str("new.txt").u32(O_RDWR).u32(0o644).u32(0); }), ENOTDIR);
+    assert_eq!(err(&mut server, TUNLINKAT, |w| { w.u32(1).str("hello.txt").u32(0); }), ENOTDIR);
+    assert_eq!(err(&mut server, TUNLINKAT, |w| { w.u32(1).str("docs").u32(AT_REMOVEDIR); }), ENOTDIR);
+    // Neither end of a rename may go through the link
+    assert_eq!(err(&mut server, TRENAMEAT, |w| { w.u32(1).str("hello.txt").u32(0).str("stolen"); }), ENOTDIR);
+    assert_eq!(err(&mut server, TRENAMEAT, |w| { w.u32(0).str("hello.txt").u32(1).str("planted"); }), ENOTDIR);
+
+    assert!(!outside.0.join("made").exists());
+    assert!(!outside.0.join("new.txt").exists());
+    assert!(!outside.0.join("planted").exists());
+    assert!(outside.0.join("hello.txt").exists());
+    assert!(outside.0.join("docs").exists());
+    assert!(scratch.0.join("hello.txt").exists());
+    assert!(!scratch.0.join("stolen").exists());
+}
+
+#[test]
+fn bad_requests() {
+    let scratch = Scratch::new("bad");
+    let mut server = attach(&scratch.0, false);
+    let mut reply = vec![0u8; MSIZE];
+    let n = server.handle(&[3, 0, 0, 0, 1, 2, 3], &mut reply);
+    assert_eq!(error_code(&reply[..n]), Some(EPROTO));
+    assert_eq!(err(&mut server, TREAD, |w| { w.u32(77).u64(0).u32(10); }), EBADF);
+    assert_eq!(err(&mut server, TWALK, |w| { w.u32(0).u32(1); }), EPROTO);
+    assert_eq!(err(&mut server, 200, |_| {}), EOPNOTSUPP);
+}
+
+#[test]
+fn queue_round_trip() {
+    let scratch = Scratch::new("queue");
+    let mut server = ShareServer::new(&scratch.0, true).unwrap();
+    let mut queue = vec![0u8; QUEUE_SIZE];
+
+    // Two requests queued before one notify
+    for (n, kind) in [(0u32, TVERSION), (1, TATTACH)] {
+        let slot = &mut queue[slot_offset(n)..slot_offset(n) + MSIZE];
+        let mut w = Writer::new(slot, kind, n as u16);
+        match kind {
+            TVERSION => w.u32(MSIZE as u32).str(VERSION),
+            _ => w.u32(0).u32(NOFID).str("").str("").u32(0),
+        };
+        w.finish().unwrap();
+    }
+    queue[HEAD..HEAD + 4].copy_from_slice(&2u32.to_le_bytes());
+    unsafe { server.service(queue.as_mut_ptr()) };
+
+    assert_eq!(&queue[DONE..DONE + 4], &2u32.to_le_bytes());
+    let (_, kind, tag) = Reader::new(&queue[slot_offset(0)..]).unwrap();
+    assert_eq!((kind, tag), (TVERSION + 1, 0));
+    let (_, kind, tag) = Reader::new(&queue[slot_offset(1)..]).unwrap();
+    assert_eq!((kind, tag), (TATTACH + 1, 1));
+}
@@ -1,7 +1,13 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
 use aether_user::{print, console_init, console_println, set_colors, entry_point, console_getc, console_putc};
+use aether_user::{SCREEN_WIDTH, SCREEN_HEIGHT, image, wasm};
+use aether_user::gfx::{self, BlitMode, Canvas, Framebuffer, Painter, Surface};
+use alloc::boxed::Box;
+use aether_user::fs::{self, DevFs, FileType, HostDisk, Metadata, RamDisk, ShareFs, TmpFs, Vfs};
 
 // Shell input buffer
 const MAX_INPUT: usize = 256;
@@ -21,6 +27,8 @@ fn main() -> ! {
     console_println("");
     
     print("Guest: Shell initialized.\n");
+
+    let mut vfs = mount_filesystems();
     
     // Shell main loop
     let mut input_buffer: [u8; MAX_INPUT] = [0; MAX_INPUT];
@@ -48,7 +56,7 @@ fn main() -> ! {
                 '\n' => {
                     console_putc('\n');
                     if input_len > 0 {
-                        execute_command(&input_buffer[..input_len]);
+                        execute_command(&mut vfs, &input_buffer[..input_len]);
                         input_len = 0;
                     }
                     // Reprint prompt
@@ -75,73 +83,213 @@ fn main() -> ! {
     }
 }
 
-fn execute_command(cmd: &[u8]) {
+/// The disk at `/`, devices at `/dev` and scratch space at `/tmp`
+fn mount_filesystems() -> Vfs {
+    let mut vfs = Vfs::new();
+    // The host's block device when it has one, else the image in guest RAM.
+    // Either may hold ext2 or FAT; the driver is picked from its magic.
+    let root = match HostDisk::guest() {
+        Ok(disk) => fs::mount_any(disk),
+        Err(_) => fs::mount_any(RamDisk::guest()),
+    };
+    match root {
+        Ok(root) => {
+            let _ = vfs.mount("/", root);
+        }
+        Err(e) => {
+            print("Disk: ");
+            console_println(e.as_str());
+        }
+    }
+    let _ = vfs.mount("/dev", Box::new(DevFs));
+    let _ = vfs.mount("/tmp", Box::new(TmpFs::new()));
+    // Only there when the host was started with --share or --share-ro
+    if let Ok(share) = ShareFs::guest() {
+        let _ = vfs.mount("/share", Box::new(share));
+    }
+    vfs
+}
+
+fn execute_command(vfs: &mut Vfs, cmd: &[u8]) {
     if starts_with(cmd, b"help") {
         cmd_help();
     } else if starts_with(cmd, b"ls") {
-        cmd_ls();
+        cmd_ls(vfs, trim(&cmd[2..]));
     } else if starts_with(cmd, b"cat ") {
-        cmd_cat(&cmd[4..]);
+        cmd_cat(vfs, &cmd[4..]);
+    } else if starts_with(cmd, b"cd") {
+        cmd_cd(vfs, trim(&cmd[2..]));
+    } else if starts_with(cmd, b"pwd") {
+        console_println(vfs.cwd());
+    } else if starts_with(cmd, b"echo ") {
+        cmd_echo(vfs, &cmd[5..]);
+    } else if starts_with(cmd, b"mkdir ") {
+        report(vfs.mkdir(arg(&cmd[6..])));
+    } else if starts_with(cmd, b"rmdir ") {
+        report(vfs.rmdir(arg(&cmd[6..])));
+    } else if starts_with(cmd, b"rm ") {
+        report(vfs.unlink(arg(&cmd[3..])));
+    } else if starts_with(cmd, b"mv ") {
+        let args = arg(&cmd[3..]);
+        match args.split_once(' ') {
+            Some((from, to)) => report(vfs.rename(from, to.trim())),
+            None => console_println("Usage: mv <from> <to>"),
+        }
     } else if starts_with(cmd, b"clear") {
         cmd_clear();
     } else if starts_with(cmd, b"info") {
         cmd_info();
     } else if starts_with(cmd, b"wasm ") {
-        cmd_wasm(&cmd[5..]);
+        cmd_wasm(vfs, &cmd[5..]);
+    } else if starts_with(cmd, b"view ") {
+        cmd_view(vfs, &cmd[5..]);
     } else {
         console_println("Unknown command.");
     }
 }
 
-fn cmd_ls() {
-    if let Some(fs) = aether_user::fs::Ext2Driver::new() {
-        fs.list_root();
-    } else {
-        console_println("Error: Failed to mount Ext2 filesystem.");
+fn cmd_ls(vfs: &mut Vfs, args: &[u8]) {
+    let (long, path) = if starts_with(args, b"-l") { (true, trim(&args[2..])) } else { (false, args) };
+    let path = core::str::from_utf8(path).unwrap_or("?");
+    let path = if path.is_empty() { "." } else { path };
+    let entries = match vfs.read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            console_println(e.as_str());
+            return;
+        }
+    };
+
+    for entry in entries {
+        let full = alloc::format!("{}/{}", path.trim_end_matches('/'), entry.name);
+
+        if long {
+            match vfs.lstat(&full) {
+                Ok(meta) => print_long(&meta),
+                Err(e) => console_println(e.as_str()),
+            }
+        }
+        aether_user::console::print(&entry.name);
+        match entry.file_type {
+            FileType::Directory => console_putc('/'),
+            FileType::Symlink if long => {
+                aether_user::console::print(" -> ");
+                aether_user::console::print(&vfs.readlink(&full).unwrap_or_default());
+            }
+            _ => {}
+        }
+        console_putc('\n');
     }
 }
 
-fn cmd_cat(filename: &[u8]) {
+/// `echo text`, or `echo text > file` to write it to a file
+fn cmd_echo(vfs: &mut Vfs, args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or("?");
+    match args.split_once('>') {
+        Some((text, path)) => {
+            let text = alloc::format!("{}\n", text.trim());
+            report(vfs.write_file(path.trim(), text.as_bytes()));
+        }
+        None => console_println(args),
+    }
+}
+
+fn cmd_cd(vfs: &mut Vfs, path: &[u8]) {
+    let path = core::str::from_utf8(path).unwrap_or("?");
+    let path = if path.is_empty() { "/" } else { path };
+    if let Err(e) = vfs.chdir(path) {
+        console_println(e.as_str());
+    }
+}
+
+/// `ls -l` columns before the name: type and permissions, links, size
+fn print_long(meta: &Metadata) {
+    let kind = match meta.file_type {
+        FileType::Directory => 'd',
+        FileType::Symlink => 'l',
+        FileType::CharDevice => 'c',
+        FileType::BlockDevice => 'b',
+        FileType::Fifo => 'p',
+        FileType::Socket => 's',
+        _ => '-',
+    };
+    console_putc(kind);
+    for (bit, c) in [(0o400, 'r'), (0o200, 'w'), (0o100, 'x'), (0o40, 'r'), (0o20, 'w'), (0o10, 'x'), (0o4, 'r'), (0o2, 'w'), (0o1, 'x')] {
+        console_putc(if meta.mode & bit != 0 { c } else { '-' });
+    }
+    aether_user::console::print(&alloc::format!(" {:>3} {:>9} ", meta.links, meta.size));
+}
+
+fn cmd_cat(vfs: &mut Vfs, filename: &[u8]) {
     // Convert bytes to string for lookup
     let name = core::str::from_utf8(filename).unwrap_or("?");
-    if let Some(fs) = aether_user::fs::Ext2Driver::new() {
-        if let Some(data) = fs.read_file(name) {
+    match vfs.read_file(name) {
+        Ok(data) => {
             // Print file contents as string
             if let Ok(s) = core::str::from_utf8(&data) {
                 console_println(s);
             } else {
                 console_println("[Binary data]");
             }
-        } else {
-            console_println("File not found.");
         }
-    } else {
-        console_println("Error: FS not mounted.");
+        Err(e) => console_println(e.as_str()),
     }
 }
 
-fn cmd_wasm(filename: &[u8]) {
-    let name = core::str::from_utf8(filename).unwrap_or("?");
+/// `wasm <file> [args...]`: the arguments reach WASI programs after the
+/// file name
+fn cmd_wasm(vfs: &mut Vfs, args: &[u8]) {
+    let args = core::str::from_utf8(args).unwrap_or("?");
+    let name = args.split_whitespace().next().unwrap_or("");
     console_println("Loading WASM...");
-    if let Some(fs) = aether_user::fs::Ext2Driver::new() {
-        if let Some(wasm_bytes) = fs.read_file(name) {
-            run_wasm(&wasm_bytes);
-        } else {
-            console_println("WASM file not found.");
-        }
-    } else {
-        console_println("Error: FS not mounted.");
+    match vfs.read_file(name) {
+        Ok(wasm_bytes) => run_wasm(vfs, &wasm_bytes, args),
+        Err(e) => console_println(e.as_str()),
     }
 }
 
-fn run_wasm(bytes: &[u8]) {
-    // WASM execution stub - full wasmi integration requires std environment
-    // For now, just validate the header and report
-    if bytes.len() > 8 && bytes[0..4] == [0x00, 0x61, 0x73, 0x6D] {
-        console_println("Valid WASM module detected.");
-        console_println("(WASM execution not yet implemented in bare-metal mode)");
-    } else {
-        console_println("Invalid WASM module header.");
+fn cmd_view(vfs: &mut Vfs, filename: &[u8]) {
+    let name = core::str::from_utf8(filename).unwrap_or("?");
+    match vfs.read_file(name) {
+        Ok(data) => match image::decode(&data) {
+            Ok(img) => show_image(&img),
+            Err(e) => console_println(e.as_str()),
+        },
+        Err(e) => console_println(e.as_str()),
+    }
+}
+
+/// Show an image centered on a black screen until a key is pressed
+fn show_image(img: &Surface) {
+    let mut fb = Framebuffer::new();
+    let mut painter = Painter::new(&mut fb);
+    painter.clear(gfx::BLACK);
+
+    let x = (SCREEN_WIDTH as i32 - img.width() as i32) / 2;
+    let y = (SCREEN_HEIGHT as i32 - img.height() as i32) / 2;
+    painter.blit(img, x, y, BlitMode::Alpha);
+
+    while console_getc().is_none() {}
+
+    // Redraw the console over the image
+    console_init();
+}
+
+/// Run a module with the AetherOS host imports and the filesystem, and
+/// report how it ended
+fn run_wasm(vfs: &mut Vfs, bytes: &[u8], args: &str) {
+    let mut host = wasm::Host::new().args(args.split_whitespace()).vfs(core::mem::take(vfs));
+    let result = wasm::run_with(bytes, &mut host);
+    if let Some(fs) = host.take_vfs() {
+        *vfs = fs;
+    }
+    // Redraw the console over whatever the program drew
+    if host.used_display() {
+        console_init();
+    }
+    match result {
+        Ok(code) => console_println(&alloc::format!("[exited with code {}]", code)),
+        Err(e) => console_println(&alloc::format!("[{}]", e)),
     }
 }
 
@@ -163,7 +311,7 @@ fn format_i32(val: i32, buf: &mut [u8; 16]) -> &str {
 }
 
 fn cmd_help() {
-    console_println("Commands: help, ls, cat <file>, wasm <file>, clear, info");
+    console_println("Commands: help, ls [-l] [dir], cd [dir], pwd, cat <file>, echo <text> [> file], mkdir, rmdir, rm, mv, view <file>, wasm <file> [args], clear, info");
 }
 
 fn cmd_clear() {
@@ -174,6 +322,24 @@ fn cmd_info() {
     console_println("AetherOS v0.3 / 8MB RAM / Ext2 FS");
 }
 
+/// A command's argument as a trimmed string
+fn arg(s: &[u8]) -> &str {
+    core::str::from_utf8(trim(s)).unwrap_or("?")
+}
+
+/// Print the error, if any
+fn report(result: Result<(), fs::FsError>) {
+    if let Err(e) = result {
+        console_println(e.as_str());
+    }
+}
+
+fn trim(s: &[u8]) -> &[u8] {
+    let start = s.iter().position(|&c| c != b' ').unwrap_or(s.len());
+    let end = s.iter().rposition(|&c| c != b' ').map_or(start, |i| i + 1);
+    &s[start..end]
+}
+
 fn starts_with(haystack: &[u8], needle: &[u8]) -> bool {
     if haystack.len() < needle.len() { return false; }
     for i in 0..needle.len() {
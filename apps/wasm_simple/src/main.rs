@@ -4,7 +4,6 @@
 extern crate alloc;
 
 use aether_user::{entry_point, console_println, console_init};
-use wasmi::{Engine, Linker, Module, Store, Caller};
 
 entry_point!(main);
 
@@ -12,25 +11,7 @@ fn main() -> ! {
     console_init();
     console_println("\n[AetherOS] Starting WASM Runtime...");
     
-    // 1. Initialize WASM Engine
-    let engine = Engine::default();
-    let mut linker = <Linker<()>>::new(&engine);
-    
-    // 2. Define Host Function: env.print(ptr, len)
-    linker.func_wrap("env", "print", |mut caller: Caller<'_, ()>, ptr: i32, len: i32| {
-        let mem = caller.get_export("memory").and_then(|e| e.into_memory()).unwrap();
-        let mut buffer = alloc::vec![0u8; len as usize];
-        
-        // Read string from WASM memory
-        if let Ok(_) = mem.read(&caller, ptr as usize, &mut buffer) {
-            if let Ok(s) = core::str::from_utf8(&buffer) {
-                // Print to AetherOS Console
-                aether_user::console_println(s);
-            }
-        }
-    }).unwrap();
-
-    // 3. Load WASM Module (Hardcoded "Hello from WASM!" module)
+    // Load WASM Module (Hardcoded "Hello from WASM!" module)
     // WAT Source:
     // (module
     //   (type $t0 (func (param i32 i32)))
@@ -56,16 +37,10 @@ fn main() -> ! {
         0x0b, 0x16, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x10, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x20, 0x66, 0x72, 0x6f, 0x6d, 0x20, 0x57, 0x41, 0x53, 0x4d, 0x21 // Data
     ];
     
-    let module = Module::new(&engine, wasm_bytes).expect("Failed to parse WASM");
-    let mut store = Store::new(&engine, ());
-    
-    // 4. Instantiate
-    let instance = linker.instantiate(&mut store, &module).unwrap()
-        .start(&mut store).unwrap();
-        
-    // 5. Call "run"
-    let run = instance.get_typed_func::<(), ()>(&store, "run").unwrap();
-    run.call(&mut store, ()).unwrap();
+    // Instantiate with the AetherOS host imports (env.print) and call "run"
+    if let Err(e) = aether_user::wasm::run(wasm_bytes) {
+        console_println(&alloc::format!("[AetherOS] WASM failed: {}", e));
+    }
     
     console_println("[AetherOS] WASM Execution Finished.");
     
@@ -0,0 +1,112 @@
+use crate::*;
+
+/// A directory record that isn't 4-byte aligned, runs past its block or
+/// can't hold its name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadDirEntry;
+
+/// A directory entry header, validated against the block it sits in
+pub struct DirEntry {
+    /// 0 for an unused record
+    pub inode: u32,
+    pub rec_len: usize,
+    pub name_len: usize,
+    pub file_type: u8,
+}
+
+impl DirEntry {
+    /// Parse the entry at `offset`. The record must be 4-byte aligned, fit
+    /// in the block and, when in use, hold its name.
+    pub fn parse(block: &[u8], offset: usize) -> Result<Self, BadDirEntry> {
+        if offset + DIR_ENTRY_HEADER > block.len() {
+            return Err(BadDirEntry);
+        }
+        let mut rec_len = read_u16(block, offset + 4) as usize;
+        // 64KiB blocks can't store their own size in 16 bits
+        if block.len() == 65536 && (rec_len == 0 || rec_len == 65535) {
+            rec_len = 65536;
+        }
+        let entry = DirEntry {
+            inode: read_u32(block, offset),
+            rec_len,
+            name_len: block[offset + 6] as usize,
+            file_type: block[offset + 7],
+        };
+        let valid = rec_len >= DIR_ENTRY_HEADER
+            && rec_len.is_multiple_of(4)
+            && offset + rec_len <= block.len()
+            && (entry.inode == 0 || DIR_ENTRY_HEADER + entry.name_len <= rec_len);
+        if valid { Ok(entry) } else { Err(BadDirEntry) }
+    }
+
+    pub fn name<'b>(&self, block: &'b [u8], offset: usize) -> &'b [u8] {
+        &block[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + self.name_len]
+    }
+}
+
+/// Record length of an entry holding a `name_len`-byte name
+pub fn dir_entry_len(name_len: usize) -> usize {
+    (DIR_ENTRY_HEADER + name_len).next_multiple_of(4)
+}
+
+pub fn set_rec_len(block: &mut [u8], offset: usize, rec_len: usize) {
+    put_u16(block, offset + 4, rec_len.min(65535) as u16);
+}
+
+/// Store an entry header and name at `offset`
+pub fn write_dir_entry(block: &mut [u8], offset: usize, inode: u32, rec_len: usize, name: &[u8], file_type: u8) {
+    put_u32(block, offset, inode);
+    set_rec_len(block, offset, rec_len);
+    block[offset + 6] = name.len() as u8;
+    block[offset + 7] = file_type;
+    block[offset + DIR_ENTRY_HEADER..offset + DIR_ENTRY_HEADER + name.len()].copy_from_slice(name);
+}
+
+/// Fill the first block of a new directory with `.` and `..`
+pub fn init_dir_block(block: &mut [u8], inode: u32, parent: u32, file_type: u8) {
+    write_dir_entry(block, 0, inode, 12, b".", file_type);
+    write_dir_entry(block, 12, parent, block.len() - 12, b"..", file_type);
+}
+
+/// Link `name` to `inode` in a directory block, in an unused record or the
+/// slack at the end of one in use. Returns false if the block is full.
+pub fn insert_entry(block: &mut [u8], name: &[u8], inode: u32, file_type: u8) -> Result<bool, BadDirEntry> {
+    let need = dir_entry_len(name.len());
+    let mut offset = 0;
+    while offset < block.len() {
+        let entry = DirEntry::parse(block, offset)?;
+        let used = if entry.inode == 0 { 0 } else { dir_entry_len(entry.name_len) };
+        if entry.rec_len >= used + need {
+            if used != 0 {
+                // Split: the existing entry keeps just what it needs
+                set_rec_len(block, offset, used);
+            }
+            write_dir_entry(block, offset + used, inode, entry.rec_len - used, name, file_type);
+            return Ok(true);
+        }
+        offset += entry.rec_len;
+    }
+    Ok(false)
+}
+
+/// Unlink `name` from a directory block, returning the inode it named, or
+/// `None` if it isn't in this block
+pub fn remove_entry(block: &mut [u8], name: &[u8]) -> Result<Option<u32>, BadDirEntry> {
+    let mut prev: Option<(usize, usize)> = None;
+    let mut offset = 0;
+    while offset < block.len() {
+        let entry = DirEntry::parse(block, offset)?;
+        if entry.inode != 0 && entry.name(block, offset) == name {
+            match prev {
+                // Fold the entry into the one before it
+                Some((at, len)) => set_rec_len(block, at, len + entry.rec_len),
+                // First in its block: just mark the slot unused
+                None => put_u32(block, offset, 0),
+            }
+            return Ok(Some(entry.inode));
+        }
+        prev = Some((offset, entry.rec_len));
+        offset += entry.rec_len;
+    }
+    Ok(None)
+}
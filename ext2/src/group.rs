@@ -0,0 +1,66 @@
+use crate::*;
+
+#[derive(Debug, Clone, Copy)]
+pub struct GroupDescriptor {
+    pub block_bitmap: u64,
+    pub inode_bitmap: u64,
+    pub inode_table: u64,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub used_dirs_count: u32,
+    /// `BG_*` flags, meaningful only with `GDT_CSUM` or `METADATA_CSUM`
+    pub flags: u16,
+}
+
+impl GroupDescriptor {
+    /// Offset of the three 16-bit counters, which the guest driver writes back
+    pub const COUNTS: usize = 0x0C;
+
+    /// `b` is one descriptor; descriptors of 64 bytes or more carry high halves
+    pub fn parse(b: &[u8]) -> Self {
+        let wide = b.len() >= 64;
+        let hi32 = |off| if wide { (read_u32(b, off) as u64) << 32 } else { 0 };
+        let hi16 = |off| if wide { (read_u16(b, off) as u32) << 16 } else { 0 };
+        GroupDescriptor {
+            block_bitmap: hi32(0x20) | read_u32(b, 0x00) as u64,
+            inode_bitmap: hi32(0x24) | read_u32(b, 0x04) as u64,
+            inode_table: hi32(0x28) | read_u32(b, 0x08) as u64,
+            free_blocks_count: hi16(0x2C) | read_u16(b, 0x0C) as u32,
+            free_inodes_count: hi16(0x2E) | read_u16(b, 0x0E) as u32,
+            used_dirs_count: hi16(0x30) | read_u16(b, 0x10) as u32,
+            flags: read_u16(b, 0x12),
+        }
+    }
+
+    /// Store the fields in one descriptor, high halves too if it's 64
+    /// bytes or more; checksums and flags are left alone
+    pub fn encode_into(&self, b: &mut [u8]) {
+        put_u32(b, 0x00, self.block_bitmap as u32);
+        put_u32(b, 0x04, self.inode_bitmap as u32);
+        put_u32(b, 0x08, self.inode_table as u32);
+        b[Self::COUNTS..Self::COUNTS + 6].copy_from_slice(&self.encode_counts());
+        if b.len() >= 64 {
+            put_u32(b, 0x20, (self.block_bitmap >> 32) as u32);
+            put_u32(b, 0x24, (self.inode_bitmap >> 32) as u32);
+            put_u32(b, 0x28, (self.inode_table >> 32) as u32);
+            put_u16(b, 0x2C, (self.free_blocks_count >> 16) as u16);
+            put_u16(b, 0x2E, (self.free_inodes_count >> 16) as u16);
+            put_u16(b, 0x30, (self.used_dirs_count >> 16) as u16);
+        }
+    }
+
+    pub fn encode(&self) -> [u8; GROUP_DESC_SIZE] {
+        let mut b = [0u8; GROUP_DESC_SIZE];
+        self.encode_into(&mut b);
+        b
+    }
+
+    /// The counters as stored at `COUNTS` in a 32-byte descriptor
+    pub fn encode_counts(&self) -> [u8; 6] {
+        let mut b = [0u8; 6];
+        put_u16(&mut b, 0, self.free_blocks_count as u16);
+        put_u16(&mut b, 2, self.free_inodes_count as u16);
+        put_u16(&mut b, 4, self.used_dirs_count as u16);
+        b
+    }
+}
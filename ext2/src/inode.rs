@@ -0,0 +1,202 @@
+use crate::*;
+
+/// The first `GOOD_OLD_INODE_SIZE` bytes of an inode, every byte kept:
+/// `encode` gives back exactly what `parse` read
+#[derive(Clone, Copy, Default)]
+pub struct Inode {
+    pub mode: u16,
+    /// With the high 16 bits Linux keeps in osd2
+    pub uid: u32,
+    /// Size in bytes; for regular files the high 32 bits come from
+    /// `i_size_high`, which other inodes use as `dir_acl`
+    pub size: u64,
+    pub atime: u32,
+    pub ctime: u32,
+    pub mtime: u32,
+    pub dtime: u32,
+    /// With the high 16 bits Linux keeps in osd2
+    pub gid: u32,
+    pub links_count: u16,
+    /// 512-byte sectors in use, counting indirect and xattr blocks
+    pub blocks: u32,
+    pub flags: u32,
+    pub osd1: u32,
+    /// Block pointers, an extent tree root or a fast symlink's target
+    pub block: [u32; 15],
+    pub generation: u32,
+    /// Extended attribute block
+    pub file_acl: u32,
+    /// `i_size_high` of anything but a regular file
+    pub dir_acl: u32,
+    pub faddr: u32,
+    /// osd2 bar the uid and gid high halves
+    pub osd2: [u8; 12],
+}
+
+impl Inode {
+    // osd2 offsets of l_i_uid_high and l_i_gid_high
+    const UID_HIGH: usize = 0x78;
+    const GID_HIGH: usize = 0x7A;
+
+    /// Parse the first `GOOD_OLD_INODE_SIZE` bytes of `b`
+    pub fn parse(b: &[u8]) -> Self {
+        let mode = read_u16(b, 0x00);
+        let mut block = [0u32; 15];
+        for (i, ptr) in block.iter_mut().enumerate() {
+            *ptr = read_u32(b, 0x28 + i * 4);
+        }
+        let mut osd2 = [0u8; 12];
+        osd2.copy_from_slice(&b[0x74..0x80]);
+        let (size_high, dir_acl) = if mode & S_IFMT == S_IFREG { (read_u32(b, 0x6C), 0) } else { (0, read_u32(b, 0x6C)) };
+        Inode {
+            mode,
+            uid: (read_u16(b, Self::UID_HIGH) as u32) << 16 | read_u16(b, 0x02) as u32,
+            size: (size_high as u64) << 32 | read_u32(b, 0x04) as u64,
+            atime: read_u32(b, 0x08),
+            ctime: read_u32(b, 0x0C),
+            mtime: read_u32(b, 0x10),
+            dtime: read_u32(b, 0x14),
+            gid: (read_u16(b, Self::GID_HIGH) as u32) << 16 | read_u16(b, 0x18) as u32,
+            links_count: read_u16(b, 0x1A),
+            blocks: read_u32(b, 0x1C),
+            flags: read_u32(b, 0x20),
+            osd1: read_u32(b, 0x24),
+            block,
+            generation: read_u32(b, 0x64),
+            file_acl: read_u32(b, 0x68),
+            dir_acl,
+            faddr: read_u32(b, 0x70),
+            osd2,
+        }
+    }
+
+    /// Store the inode in the first `GOOD_OLD_INODE_SIZE` bytes of `b`;
+    /// any extra space in large inodes is left alone
+    pub fn encode_into(&self, b: &mut [u8]) {
+        put_u16(b, 0x00, self.mode);
+        put_u16(b, 0x02, self.uid as u16);
+        put_u32(b, 0x04, self.size as u32);
+        put_u32(b, 0x08, self.atime);
+        put_u32(b, 0x0C, self.ctime);
+        put_u32(b, 0x10, self.mtime);
+        put_u32(b, 0x14, self.dtime);
+        put_u16(b, 0x18, self.gid as u16);
+        put_u16(b, 0x1A, self.links_count);
+        put_u32(b, 0x1C, self.blocks);
+        put_u32(b, 0x20, self.flags);
+        put_u32(b, 0x24, self.osd1);
+        b[0x28..0x64].copy_from_slice(&self.block_bytes());
+        put_u32(b, 0x64, self.generation);
+        put_u32(b, 0x68, self.file_acl);
+        put_u32(b, 0x6C, if self.is_reg() { (self.size >> 32) as u32 } else { self.dir_acl });
+        put_u32(b, 0x70, self.faddr);
+        b[0x74..0x80].copy_from_slice(&self.osd2);
+        put_u16(b, Self::UID_HIGH, (self.uid >> 16) as u16);
+        put_u16(b, Self::GID_HIGH, (self.gid >> 16) as u16);
+    }
+
+    pub fn encode(&self) -> [u8; GOOD_OLD_INODE_SIZE] {
+        let mut b = [0u8; GOOD_OLD_INODE_SIZE];
+        self.encode_into(&mut b);
+        b
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.mode & S_IFMT == S_IFDIR
+    }
+
+    pub fn is_reg(&self) -> bool {
+        self.mode & S_IFMT == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+
+    /// A symlink with no data blocks (other than an xattr block), whose
+    /// target is in `i_block`
+    pub fn is_fast_symlink(&self, block_size: usize) -> bool {
+        let xattr_sectors = if self.file_acl != 0 { (block_size / 512) as u32 } else { 0 };
+        self.is_symlink() && self.blocks == xattr_sectors
+    }
+
+    /// Whether `i_block` maps data blocks: not for devices, FIFOs, sockets
+    /// or fast symlinks. Extent-mapped inodes (`EXTENTS_FL`) say yes too.
+    pub fn has_blocks(&self, block_size: usize) -> bool {
+        match self.mode & S_IFMT {
+            S_IFREG | S_IFDIR => true,
+            S_IFLNK => !self.is_fast_symlink(block_size),
+            _ => false,
+        }
+    }
+
+    /// `i_block` as stored on disk: block pointers, an extent tree root or a
+    /// fast symlink target
+    pub fn block_bytes(&self) -> [u8; 60] {
+        let mut b = [0u8; 60];
+        for (i, ptr) in self.block.iter().enumerate() {
+            put_u32(&mut b, i * 4, *ptr);
+        }
+        b
+    }
+
+    /// Keep a short symlink target in `i_block`, where its bytes are stored
+    /// as they are
+    pub fn set_fast_symlink(&mut self, target: &[u8]) {
+        for (ptr, chunk) in self.block.iter_mut().zip(target.chunks(4)) {
+            let mut bytes = [0u8; 4];
+            bytes[..chunk.len()].copy_from_slice(chunk);
+            *ptr = u32::from_le_bytes(bytes);
+        }
+        self.size = target.len() as u64;
+    }
+}
+
+/// The directory entry file type for an inode mode
+pub fn file_type(mode: u16) -> u8 {
+    match mode & S_IFMT {
+        S_IFREG => FT_REG_FILE,
+        S_IFDIR => FT_DIR,
+        S_IFCHR => FT_CHRDEV,
+        S_IFBLK => FT_BLKDEV,
+        S_IFIFO => FT_FIFO,
+        S_IFSOCK => FT_SOCK,
+        S_IFLNK => FT_SYMLINK,
+        _ => FT_UNKNOWN,
+    }
+}
+
+/// Where a logical block lives in an inode's block tree
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlockPath {
+    /// Slot in `i_block`
+    pub slot: usize,
+    /// Entry index at each level of indirection below it
+    pub index: [usize; 3],
+    /// Levels of indirection in use
+    pub depth: usize,
+}
+
+/// The path to logical block `n`, or `None` past what a triple indirect
+/// block reaches
+pub fn block_path(n: u64, block_size: usize) -> Option<BlockPath> {
+    let p = (block_size / 4) as u64; // Block pointers per indirect block
+    let path = |slot, index: [u64; 3], depth| BlockPath { slot, index: index.map(|i| i as usize), depth };
+
+    if n < DIRECT_BLOCKS as u64 {
+        return Some(path(n as usize, [0; 3], 0));
+    }
+    let n = n - DIRECT_BLOCKS as u64;
+    if n < p {
+        return Some(path(IND_BLOCK, [n, 0, 0], 1));
+    }
+    let n = n - p;
+    if n < p * p {
+        return Some(path(DIND_BLOCK, [n / p, n % p, 0], 2));
+    }
+    let n = n - p * p;
+    if n < p * p * p {
+        return Some(path(TIND_BLOCK, [n / (p * p), (n / p) % p, n % p], 3));
+    }
+    None
+}
@@ -0,0 +1,164 @@
+//! Ext2 on-disk format, shared by the guest driver (`aether-user`) and the
+//! host image tool (`mkext2`)
+//!
+//! Structures are decoded from and encoded to little-endian bytes field by
+//! field; nothing here points into a buffer or trusts what it reads. Beside
+//! the structures are the pieces of reading and writing that don't need a
+//! device: where a logical block sits in the block tree, bitmap bits, and
+//! adding and removing entries within one directory block. Changes that span
+//! blocks (allocation, block trees, linking names) are provided methods of
+//! the `Volume` trait, over whatever I/O the caller implements.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+mod dir;
+mod group;
+mod inode;
+mod superblock;
+mod volume;
+
+#[cfg(test)]
+mod tests;
+
+pub use dir::{dir_entry_len, init_dir_block, insert_entry, remove_entry, set_rec_len, write_dir_entry, BadDirEntry, DirEntry};
+pub use group::GroupDescriptor;
+pub use inode::{block_path, file_type, BlockPath, Inode};
+pub use superblock::Superblock;
+pub use volume::{Volume, WriteError};
+
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+pub const SUPERBLOCK_SIZE: usize = 1024;
+/// Revision 0 inode size, and the part of any inode modelled by `Inode`
+pub const GOOD_OLD_INODE_SIZE: usize = 128;
+/// Descriptor size without the 64bit feature
+pub const GROUP_DESC_SIZE: usize = 32;
+pub const DIR_ENTRY_HEADER: usize = 8;
+pub const MAX_NAME_LEN: usize = 255;
+pub const MAX_LOG_BLOCK_SIZE: u32 = 6; // 64KiB
+
+pub const EXT2_MAGIC: u16 = 0xEF53;
+pub const GOOD_OLD_REV: u32 = 0;
+pub const DYNAMIC_REV: u32 = 1;
+/// First non-reserved inode on revision 0 images
+pub const GOOD_OLD_FIRST_INO: u32 = 11;
+pub const ROOT_INODE: u32 = 2;
+/// Owns the blocks reserved for growing the descriptor tables
+pub const RESIZE_INODE: u32 = 7;
+
+pub const DIRECT_BLOCKS: usize = 12;
+pub const IND_BLOCK: usize = 12;
+pub const DIND_BLOCK: usize = 13;
+pub const TIND_BLOCK: usize = 14;
+/// Symlink targets shorter than this live in `i_block` itself
+pub const FAST_SYMLINK_MAX: usize = 60;
+
+// Inode mode: file type bits
+pub const S_IFMT: u16 = 0xF000;
+pub const S_IFSOCK: u16 = 0xC000;
+pub const S_IFLNK: u16 = 0xA000;
+pub const S_IFREG: u16 = 0x8000;
+pub const S_IFBLK: u16 = 0x6000;
+pub const S_IFDIR: u16 = 0x4000;
+pub const S_IFCHR: u16 = 0x2000;
+pub const S_IFIFO: u16 = 0x1000;
+
+// Directory entry file types
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
+
+// Inode flags
+/// Directory uses a hashed index (dir_index); plain ext2 code clears it
+/// when it changes the directory
+pub const INDEX_FL: u32 = 0x1000;
+/// `i_block` holds an extent tree root (ext4)
+pub const EXTENTS_FL: u32 = 0x0008_0000;
+
+// Extent trees: each node is a header followed by 12-byte entries
+pub const EXTENT_MAGIC: u16 = 0xF30A;
+pub const EXTENT_HEADER_SIZE: usize = 12;
+pub const EXTENT_ENTRY_SIZE: usize = 12;
+/// The kernel never builds deeper trees
+pub const EXTENT_MAX_DEPTH: u16 = 5;
+/// Extents longer than this are uninitialized (preallocated, read as zeros)
+pub const EXTENT_INIT_MAX_LEN: u16 = 32768;
+
+// Compatible features
+pub const FEATURE_COMPAT_RESIZE_INODE: u32 = 0x0010;
+
+// Incompatible features: a reader that doesn't know one must not mount
+/// Directory entries carry a file type byte
+pub const FEATURE_INCOMPAT_FILETYPE: u32 = 0x0002;
+pub const FEATURE_INCOMPAT_RECOVER: u32 = 0x0004;
+pub const FEATURE_INCOMPAT_EXTENTS: u32 = 0x0040;
+pub const FEATURE_INCOMPAT_64BIT: u32 = 0x0080;
+pub const FEATURE_INCOMPAT_FLEX_BG: u32 = 0x0200;
+pub const FEATURE_INCOMPAT_CSUM_SEED: u32 = 0x2000;
+pub const FEATURE_INCOMPAT_LARGEDIR: u32 = 0x4000;
+
+// Read-only compatible features: anything unknown forces a read-only mount
+pub const FEATURE_RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+pub const FEATURE_RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub const FEATURE_RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+/// Group descriptors carry checksums and the `BG_*_UNINIT` flags
+pub const FEATURE_RO_COMPAT_GDT_CSUM: u32 = 0x0010;
+pub const FEATURE_RO_COMPAT_DIR_NLINK: u32 = 0x0020;
+pub const FEATURE_RO_COMPAT_EXTRA_ISIZE: u32 = 0x0040;
+/// Checksummed metadata, which also implies the `BG_*_UNINIT` flags
+pub const FEATURE_RO_COMPAT_METADATA_CSUM: u32 = 0x0400;
+
+// Group descriptor flags
+/// The inode bitmap and table were never written; every inode is free
+pub const BG_INODE_UNINIT: u16 = 0x0001;
+/// The block bitmap was never written; only the group's own metadata is in use
+pub const BG_BLOCK_UNINIT: u16 = 0x0002;
+
+pub const STATE_CLEAN: u16 = 1;
+pub const ERRORS_CONTINUE: u16 = 1;
+
+/// Extended attribute blocks start with this and a reference count
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+
+pub fn read_u16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+pub fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+pub fn put_u16(b: &mut [u8], off: usize, v: u16) {
+    b[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_u32(b: &mut [u8], off: usize, v: u32) {
+    b[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+// --- Bitmaps ---
+
+pub fn test_bit(map: &[u8], i: usize) -> bool {
+    map[i / 8] & (1 << (i % 8)) != 0
+}
+
+pub fn set_bit(map: &mut [u8], i: usize) {
+    map[i / 8] |= 1 << (i % 8);
+}
+
+pub fn clear_bit(map: &mut [u8], i: usize) {
+    map[i / 8] &= !(1 << (i % 8));
+}
+
+/// The first clear bit in `start..limit`
+pub fn find_clear(map: &[u8], start: usize, limit: usize) -> Option<usize> {
+    (start..limit).find(|&i| !test_bit(map, i))
+}
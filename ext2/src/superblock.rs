@@ -0,0 +1,172 @@
+use crate::*;
+
+/// The superblock fields the guest driver and mkext2 use
+///
+/// `encode_into` only stores these, so rewriting a superblock read from an
+/// existing image keeps whatever else it holds.
+#[derive(Default)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub r_blocks_count: u32,
+    pub free_blocks_count: u32,
+    pub free_inodes_count: u32,
+    pub first_data_block: u32,
+    pub log_block_size: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub wtime: u32,
+    pub max_mnt_count: u16,
+    pub magic: u16,
+    pub state: u16,
+    pub errors: u16,
+    pub lastcheck: u32,
+    pub rev_level: u32,
+    // Revision 1 (dynamic) fields
+    pub first_ino: u32,
+    pub inode_size: u16,
+    /// Group this copy lives in (0 for the primary)
+    pub block_group_nr: u16,
+    pub feature_compat: u32,
+    pub feature_incompat: u32,
+    pub feature_ro_compat: u32,
+    pub uuid: [u8; 16],
+    /// GDT blocks set aside after each copy for growing the filesystem
+    pub reserved_gdt_blocks: u16,
+    // ext4 (64bit) fields
+    pub desc_size: u16,
+    pub mkfs_time: u32,
+    pub blocks_count_hi: u32,
+}
+
+impl Superblock {
+    // Offsets of the fields the guest driver writes back on their own
+    pub const FREE_BLOCKS_COUNT: usize = 0x0C;
+    pub const FREE_INODES_COUNT: usize = 0x10;
+    pub const WTIME: usize = 0x30;
+
+    pub fn parse(b: &[u8; SUPERBLOCK_SIZE]) -> Self {
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&b[0x68..0x78]);
+        Superblock {
+            inodes_count: read_u32(b, 0x00),
+            blocks_count: read_u32(b, 0x04),
+            r_blocks_count: read_u32(b, 0x08),
+            free_blocks_count: read_u32(b, Self::FREE_BLOCKS_COUNT),
+            free_inodes_count: read_u32(b, Self::FREE_INODES_COUNT),
+            first_data_block: read_u32(b, 0x14),
+            log_block_size: read_u32(b, 0x18),
+            blocks_per_group: read_u32(b, 0x20),
+            inodes_per_group: read_u32(b, 0x28),
+            wtime: read_u32(b, Self::WTIME),
+            max_mnt_count: read_u16(b, 0x36),
+            magic: read_u16(b, 0x38),
+            state: read_u16(b, 0x3A),
+            errors: read_u16(b, 0x3C),
+            lastcheck: read_u32(b, 0x40),
+            rev_level: read_u32(b, 0x4C),
+            first_ino: read_u32(b, 0x54),
+            inode_size: read_u16(b, 0x58),
+            block_group_nr: read_u16(b, 0x5A),
+            feature_compat: read_u32(b, 0x5C),
+            feature_incompat: read_u32(b, 0x60),
+            feature_ro_compat: read_u32(b, 0x64),
+            uuid,
+            reserved_gdt_blocks: read_u16(b, 0xCE),
+            desc_size: read_u16(b, 0xFE),
+            mkfs_time: read_u32(b, 0x108),
+            blocks_count_hi: read_u32(b, 0x150),
+        }
+    }
+
+    pub fn encode_into(&self, b: &mut [u8; SUPERBLOCK_SIZE]) {
+        put_u32(b, 0x00, self.inodes_count);
+        put_u32(b, 0x04, self.blocks_count);
+        put_u32(b, 0x08, self.r_blocks_count);
+        put_u32(b, Self::FREE_BLOCKS_COUNT, self.free_blocks_count);
+        put_u32(b, Self::FREE_INODES_COUNT, self.free_inodes_count);
+        put_u32(b, 0x14, self.first_data_block);
+        put_u32(b, 0x18, self.log_block_size);
+        put_u32(b, 0x1C, self.log_block_size); // fragments are blocks
+        put_u32(b, 0x20, self.blocks_per_group);
+        put_u32(b, 0x24, self.blocks_per_group);
+        put_u32(b, 0x28, self.inodes_per_group);
+        put_u32(b, Self::WTIME, self.wtime);
+        put_u16(b, 0x36, self.max_mnt_count);
+        put_u16(b, 0x38, self.magic);
+        put_u16(b, 0x3A, self.state);
+        put_u16(b, 0x3C, self.errors);
+        put_u32(b, 0x40, self.lastcheck);
+        put_u32(b, 0x4C, self.rev_level);
+        if self.rev_level >= DYNAMIC_REV {
+            put_u32(b, 0x54, self.first_ino);
+            put_u16(b, 0x58, self.inode_size);
+            put_u16(b, 0x5A, self.block_group_nr);
+            put_u32(b, 0x5C, self.feature_compat);
+            put_u32(b, 0x60, self.feature_incompat);
+            put_u32(b, 0x64, self.feature_ro_compat);
+            b[0x68..0x78].copy_from_slice(&self.uuid);
+            put_u16(b, 0xCE, self.reserved_gdt_blocks);
+            put_u16(b, 0xFE, self.desc_size);
+            put_u32(b, 0x108, self.mkfs_time);
+            put_u32(b, 0x150, self.blocks_count_hi);
+        }
+    }
+
+    pub fn encode(&self) -> [u8; SUPERBLOCK_SIZE] {
+        let mut b = [0u8; SUPERBLOCK_SIZE];
+        self.encode_into(&mut b);
+        b
+    }
+
+    /// Only meaningful once `log_block_size` is checked against
+    /// `MAX_LOG_BLOCK_SIZE`
+    pub fn block_size(&self) -> usize {
+        1024 << self.log_block_size
+    }
+
+    /// Revision 0 predates feature flags, so the fields are meaningless there
+    pub fn features(&self) -> (u32, u32, u32) {
+        if self.rev_level == GOOD_OLD_REV {
+            (0, 0, 0)
+        } else {
+            (self.feature_compat, self.feature_incompat, self.feature_ro_compat)
+        }
+    }
+
+    /// First inode not reserved for the filesystem itself
+    pub fn first_ino(&self) -> u32 {
+        if self.rev_level == GOOD_OLD_REV { GOOD_OLD_FIRST_INO } else { self.first_ino }
+    }
+
+    /// Bytes per inode slot; revision 0 images always use 128
+    pub fn inode_size(&self) -> usize {
+        if self.rev_level == GOOD_OLD_REV { GOOD_OLD_INODE_SIZE } else { self.inode_size as usize }
+    }
+
+    /// Block groups, with `blocks_per_group` already checked non-zero
+    pub fn groups(&self) -> u32 {
+        self.blocks_count.saturating_sub(self.first_data_block).div_ceil(self.blocks_per_group)
+    }
+
+    /// Blocks in a group; the last group may be short
+    pub fn blocks_in_group(&self, group: u32) -> u32 {
+        let start = self.first_data_block + group * self.blocks_per_group;
+        (self.blocks_count - start).min(self.blocks_per_group)
+    }
+
+    /// Whether `group` holds a superblock copy
+    pub fn has_super(&self, group: u32) -> bool {
+        fn power_of(mut n: u32, base: u32) -> bool {
+            while n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        }
+        self.features().2 & FEATURE_RO_COMPAT_SPARSE_SUPER == 0
+            || group <= 1
+            || power_of(group, 3)
+            || power_of(group, 5)
+            || power_of(group, 7)
+    }
+}
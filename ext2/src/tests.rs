@@ -0,0 +1,143 @@
+//! Round trips through the on-disk encodings, run on the host
+use crate::*;
+
+/// Bytes where every offset holds a different value
+fn pattern(len: usize, seed: u8) -> impl Iterator<Item = u8> {
+    (0..len).map(move |i| (i as u8).wrapping_mul(31).wrapping_add(seed))
+}
+
+#[test]
+fn superblock_round_trip() {
+    let mut raw = [0u8; SUPERBLOCK_SIZE];
+    raw.iter_mut().zip(pattern(SUPERBLOCK_SIZE, 7)).for_each(|(b, v)| *b = v);
+    put_u32(&mut raw, 0x4C, DYNAMIC_REV);
+    let sb = Superblock::parse(&raw);
+
+    // Unmodelled bytes survive, and so does everything modelled but the
+    // fragment fields, which always mirror the block fields
+    let mut out = raw;
+    sb.encode_into(&mut out);
+    put_u32(&mut raw, 0x1C, sb.log_block_size);
+    put_u32(&mut raw, 0x24, sb.blocks_per_group);
+    assert!(out == raw);
+
+    // From scratch, only the modelled fields are set
+    let fresh = Superblock::parse(&sb.encode());
+    assert_eq!((fresh.inodes_count, fresh.desc_size, fresh.mkfs_time), (sb.inodes_count, sb.desc_size, sb.mkfs_time));
+    assert_eq!(fresh.uuid, sb.uuid);
+    assert_eq!(read_u32(&sb.encode(), 0x44), 0); // s_checkinterval isn't modelled
+}
+
+#[test]
+fn superblock_geometry() {
+    let sb = Superblock {
+        blocks_count: 20000,
+        first_data_block: 1,
+        blocks_per_group: 8192,
+        rev_level: DYNAMIC_REV,
+        feature_ro_compat: FEATURE_RO_COMPAT_SPARSE_SUPER,
+        ..Default::default()
+    };
+    assert_eq!(sb.groups(), 3);
+    assert_eq!(sb.blocks_in_group(2), 20000 - 1 - 2 * 8192);
+    assert!((0..10).map(|g| sb.has_super(g)).eq([true, true, false, true, false, true, false, true, false, true]));
+    // Revision 0 ignores feature flags, so every group has a copy
+    let old = Superblock { rev_level: GOOD_OLD_REV, ..sb };
+    assert!(old.has_super(2));
+    assert_eq!((old.inode_size(), old.first_ino()), (GOOD_OLD_INODE_SIZE, GOOD_OLD_FIRST_INO));
+}
+
+#[test]
+fn group_descriptor_round_trip() {
+    let raw: [u8; 64] = core::array::from_fn(|i| (i as u8).wrapping_mul(13) | 1);
+    let wide = GroupDescriptor::parse(&raw);
+    assert_eq!(wide.block_bitmap >> 32, read_u32(&raw, 0x20) as u64);
+    let mut out = [0u8; 64];
+    out.copy_from_slice(&raw);
+    wide.encode_into(&mut out);
+    assert!(out == raw);
+
+    let narrow = GroupDescriptor::parse(&raw[..GROUP_DESC_SIZE]);
+    assert_eq!(narrow.free_inodes_count, read_u16(&raw, 0x0E) as u32);
+    assert_eq!(narrow.encode()[..0x12], raw[..0x12]);
+}
+
+#[test]
+fn inode_round_trip() {
+    for mode in [S_IFREG | 0o644, S_IFDIR | 0o755] {
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        raw.iter_mut().zip(pattern(GOOD_OLD_INODE_SIZE, 3)).for_each(|(b, v)| *b = v);
+        put_u16(&mut raw, 0, mode);
+        let inode = Inode::parse(&raw);
+        assert!(inode.encode() == raw);
+        assert_eq!(inode.uid >> 16, read_u16(&raw, 0x78) as u32);
+        // Only regular files take the high half of their size from i_size_high
+        assert_eq!(inode.size >> 32 != 0, inode.is_reg());
+    }
+
+    let mut link = Inode { mode: S_IFLNK | 0o777, ..Default::default() };
+    link.set_fast_symlink(b"../target");
+    assert_eq!(&link.block_bytes()[..9], b"../target");
+    assert!(link.is_fast_symlink(1024) && !link.has_blocks(1024));
+    link.file_acl = 99;
+    link.blocks = 2;
+    assert!(link.is_fast_symlink(1024));
+    assert!(!link.is_fast_symlink(4096));
+}
+
+#[test]
+fn block_paths() {
+    let path = |n| block_path(n, 1024).unwrap();
+    assert_eq!(path(11), BlockPath { slot: 11, index: [0; 3], depth: 0 });
+    assert_eq!(path(12), BlockPath { slot: IND_BLOCK, index: [0; 3], depth: 1 });
+    assert_eq!(path(12 + 256 + 257), BlockPath { slot: DIND_BLOCK, index: [1, 1, 0], depth: 2 });
+    let last = 12 + 256 + 256 * 256 + 256 * 256 * 256 - 1;
+    assert_eq!(path(last), BlockPath { slot: TIND_BLOCK, index: [255; 3], depth: 3 });
+    assert_eq!(block_path(last + 1, 1024), None);
+}
+
+#[test]
+fn directory_blocks() {
+    let mut block = [0u8; 1024];
+    init_dir_block(&mut block, 12, ROOT_INODE, FT_DIR);
+
+    // Names go into the slack of the last entry until the block fills
+    let mut added = 0u32;
+    let name = |i: u32| [b'f', b'0' + (i / 10) as u8, b'0' + (i % 10) as u8];
+    while insert_entry(&mut block, &name(added), 100 + added, FT_REG_FILE).unwrap() {
+        added += 1;
+    }
+    // 12 + 12 for . and .., then 12 bytes each
+    assert_eq!(added, (1024 - 24) / 12);
+
+    // Removing one frees room for exactly one more
+    assert_eq!(remove_entry(&mut block, b"f07").unwrap(), Some(107));
+    assert_eq!(remove_entry(&mut block, b"f07").unwrap(), None);
+    assert!(insert_entry(&mut block, b"new", 500, FT_REG_FILE).unwrap());
+    assert!(!insert_entry(&mut block, b"more", 501, FT_REG_FILE).unwrap());
+
+    // Every record parses and they tile the block
+    let (mut offset, mut names) = (0, 0);
+    while offset < block.len() {
+        let entry = DirEntry::parse(&block, offset).unwrap();
+        names += (entry.inode != 0) as u32;
+        offset += entry.rec_len;
+    }
+    assert_eq!((offset, names), (1024, 2 + added));
+
+    // A record running past the block is refused
+    set_rec_len(&mut block, 12, 2000);
+    assert_eq!(DirEntry::parse(&block, 12).err(), Some(BadDirEntry));
+    assert_eq!(insert_entry(&mut block, b"x", 1, 0), Err(BadDirEntry));
+}
+
+#[test]
+fn bitmaps() {
+    let mut map = [0u8; 4];
+    (0..9).for_each(|i| set_bit(&mut map, i));
+    assert_eq!(find_clear(&map, 0, 32), Some(9));
+    clear_bit(&mut map, 3);
+    assert_eq!(find_clear(&map, 0, 32), Some(3));
+    assert!(test_bit(&map, 8) && !test_bit(&map, 3));
+    assert_eq!(find_clear(&[0xFF], 0, 8), None);
+}
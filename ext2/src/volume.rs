@@ -0,0 +1,435 @@
+//! Changing a filesystem through a `Volume`: block and inode allocation,
+//! block trees, and linking and unlinking directory entries. The guest
+//! driver and mkext2 supply the I/O, so both place and free things the
+//! same way.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::*;
+
+/// Why a change failed, apart from the volume's own I/O errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteError {
+    /// No free blocks left
+    NoSpace,
+    /// No free inodes left
+    NoInodes,
+    /// A block pointer outside the filesystem
+    BlockOutOfRange,
+    /// A directory block that doesn't parse
+    BadDirEntry,
+    /// Past what the block tree can map
+    TooLarge,
+    /// No directory entry by that name
+    NotFound,
+}
+
+impl WriteError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            WriteError::NoSpace => "the filesystem is full",
+            WriteError::NoInodes => "out of inodes",
+            WriteError::BlockOutOfRange => "block pointer out of range",
+            WriteError::BadDirEntry => "bad directory entry",
+            WriteError::TooLarge => "file is too large for its block tree",
+            WriteError::NotFound => "no such directory entry",
+        }
+    }
+}
+
+impl From<BadDirEntry> for WriteError {
+    fn from(_: BadDirEntry) -> Self {
+        WriteError::BadDirEntry
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<WriteError> for std::io::Error {
+    fn from(e: WriteError) -> Self {
+        use std::io::ErrorKind;
+        let kind = match e {
+            WriteError::NoSpace | WriteError::NoInodes => ErrorKind::StorageFull,
+            WriteError::BlockOutOfRange | WriteError::BadDirEntry => ErrorKind::InvalidData,
+            WriteError::TooLarge => ErrorKind::FileTooLarge,
+            WriteError::NotFound => ErrorKind::NotFound,
+        };
+        std::io::Error::new(kind, e.as_str())
+    }
+}
+
+/// A mounted filesystem's metadata and blocks. Implementations range-check
+/// block and inode numbers; the provided methods keep the bitmaps, the free
+/// counts and `i_blocks` in step with every change they make.
+pub trait Volume {
+    type Error: From<WriteError>;
+
+    fn sb(&self) -> &Superblock;
+    fn sb_mut(&mut self) -> &mut Superblock;
+    /// Store the superblock's free counts, now or at a later flush
+    fn write_counts(&mut self) -> Result<(), Self::Error>;
+
+    fn group(&self, group: u32) -> Result<GroupDescriptor, Self::Error>;
+    /// Store a group's free and directory counts
+    fn write_group(&mut self, group: u32, desc: &GroupDescriptor) -> Result<(), Self::Error>;
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>, Self::Error>;
+    fn write_block(&mut self, block: u64, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// A bitmap block, which the volume may cache
+    fn read_bitmap(&mut self, block: u64) -> Result<Vec<u8>, Self::Error> {
+        self.read_block(block)
+    }
+
+    fn write_bitmap(&mut self, block: u64, data: &[u8]) -> Result<(), Self::Error> {
+        self.write_block(block, data)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode, Self::Error>;
+    /// Store the first `GOOD_OLD_INODE_SIZE` bytes of an inode
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> Result<(), Self::Error>;
+    /// Zero an inode's whole on-disk slot
+    fn clear_inode(&mut self, ino: u32) -> Result<(), Self::Error>;
+
+    /// Seconds since the UNIX epoch, for timestamps
+    fn now(&self) -> u32;
+
+    fn block_size(&self) -> usize {
+        self.sb().block_size()
+    }
+
+    /// Group holding an inode, the goal for its blocks
+    fn inode_group(&self, ino: u32) -> u32 {
+        (ino - 1) / self.sb().inodes_per_group
+    }
+
+    /// The entry type byte, which rev 0 and pre-filetype images leave 0
+    fn dir_file_type(&self, file_type: u8) -> u8 {
+        if self.sb().feature_incompat & FEATURE_INCOMPAT_FILETYPE != 0 { file_type } else { 0 }
+    }
+
+    // --- Allocation ---
+
+    /// Set the first clear bit in `start..limit` of a bitmap
+    fn take_bit(&mut self, bitmap: u64, start: u32, limit: u32) -> Result<Option<u32>, Self::Error> {
+        let mut map = self.read_bitmap(bitmap)?;
+        let Some(i) = find_clear(&map, start as usize, limit as usize) else {
+            return Ok(None);
+        };
+        set_bit(&mut map, i);
+        self.write_bitmap(bitmap, &map)?;
+        Ok(Some(i as u32))
+    }
+
+    /// Clear a bitmap bit, returning whether it was set
+    fn give_bit(&mut self, bitmap: u64, i: u32) -> Result<bool, Self::Error> {
+        let mut map = self.read_bitmap(bitmap)?;
+        if !test_bit(&map, i as usize) {
+            return Ok(false);
+        }
+        clear_bit(&mut map, i as usize);
+        self.write_bitmap(bitmap, &map)?;
+        Ok(true)
+    }
+
+    /// Allocate a zeroed block: the first free one in group `goal`, or
+    /// failing that in the groups after it
+    fn alloc_block(&mut self, goal: u32) -> Result<u32, Self::Error> {
+        let groups = self.sb().groups();
+        for k in 0..groups {
+            let group = (goal + k) % groups;
+            let desc = self.group(group)?;
+            if desc.free_blocks_count == 0 {
+                continue;
+            }
+            let limit = self.sb().blocks_in_group(group);
+            if let Some(bit) = self.take_bit(desc.block_bitmap, 0, limit)? {
+                let desc = GroupDescriptor { free_blocks_count: desc.free_blocks_count - 1, ..desc };
+                self.write_group(group, &desc)?;
+                let sb = self.sb_mut();
+                sb.free_blocks_count = sb.free_blocks_count.saturating_sub(1);
+                let block = sb.first_data_block + group * sb.blocks_per_group + bit;
+                self.write_counts()?;
+                self.write_block(block as u64, &vec![0u8; self.block_size()])?;
+                return Ok(block);
+            }
+        }
+        Err(WriteError::NoSpace.into())
+    }
+
+    fn free_block(&mut self, block: u32) -> Result<(), Self::Error> {
+        let sb = self.sb();
+        if block < sb.first_data_block || block >= sb.blocks_count {
+            return Err(WriteError::BlockOutOfRange.into());
+        }
+        let rel = block - sb.first_data_block;
+        let (group, bit) = (rel / sb.blocks_per_group, rel % sb.blocks_per_group);
+        let desc = self.group(group)?;
+        if self.give_bit(desc.block_bitmap, bit)? {
+            let desc = GroupDescriptor { free_blocks_count: desc.free_blocks_count + 1, ..desc };
+            self.write_group(group, &desc)?;
+            self.sb_mut().free_blocks_count += 1;
+            self.write_counts()?;
+        }
+        Ok(())
+    }
+
+    /// Allocate an inode, preferring group `goal`. Its slot is zeroed.
+    fn alloc_inode(&mut self, goal: u32, dir: bool) -> Result<u32, Self::Error> {
+        // Inodes below first_ino are reserved (root, journal, resize, ...)
+        let (first_ino, per_group, count) = (self.sb().first_ino(), self.sb().inodes_per_group, self.sb().inodes_count);
+        let groups = self.sb().groups();
+        for k in 0..groups {
+            let group = (goal + k) % groups;
+            let desc = self.group(group)?;
+            if desc.free_inodes_count == 0 {
+                continue;
+            }
+            let base = group * per_group;
+            // The last group may hold fewer inodes than the bitmap covers
+            let limit = per_group.min(count.saturating_sub(base));
+            let start = first_ino.saturating_sub(base + 1).min(limit);
+            if let Some(bit) = self.take_bit(desc.inode_bitmap, start, limit)? {
+                let desc = GroupDescriptor {
+                    free_inodes_count: desc.free_inodes_count - 1,
+                    used_dirs_count: desc.used_dirs_count + dir as u32,
+                    ..desc
+                };
+                self.write_group(group, &desc)?;
+                let sb = self.sb_mut();
+                sb.free_inodes_count = sb.free_inodes_count.saturating_sub(1);
+                self.write_counts()?;
+                let ino = base + bit + 1;
+                self.clear_inode(ino)?;
+                return Ok(ino);
+            }
+        }
+        Err(WriteError::NoInodes.into())
+    }
+
+    fn free_inode(&mut self, ino: u32, dir: bool) -> Result<(), Self::Error> {
+        let group = self.inode_group(ino);
+        let desc = self.group(group)?;
+        if self.give_bit(desc.inode_bitmap, (ino - 1) % self.sb().inodes_per_group)? {
+            let desc = GroupDescriptor {
+                free_inodes_count: desc.free_inodes_count + 1,
+                used_dirs_count: desc.used_dirs_count.saturating_sub(dir as u32),
+                ..desc
+            };
+            self.write_group(group, &desc)?;
+            self.sb_mut().free_inodes_count += 1;
+            self.write_counts()?;
+        }
+        Ok(())
+    }
+
+    // --- Block trees ---
+
+    /// Entry `i` of an indirect block (0 if the indirect block itself is a hole)
+    fn indirect(&self, block: u32, i: usize) -> Result<u32, Self::Error> {
+        if block == 0 {
+            return Ok(0);
+        }
+        Ok(read_u32(&self.read_block(block as u64)?, i * 4))
+    }
+
+    fn set_indirect(&mut self, block: u32, i: usize, value: u32) -> Result<(), Self::Error> {
+        let mut pointers = self.read_block(block as u64)?;
+        put_u32(&mut pointers, i * 4, value);
+        self.write_block(block as u64, &pointers)
+    }
+
+    /// The disk block holding logical block `n` of a block-mapped inode, 0
+    /// for a hole
+    fn map_block(&self, inode: &Inode, n: u64) -> Result<u32, Self::Error> {
+        let path = block_path(n, self.block_size()).ok_or(WriteError::TooLarge)?;
+        let mut block = inode.block[path.slot];
+        for &i in &path.index[..path.depth] {
+            block = self.indirect(block, i)?;
+        }
+        Ok(block)
+    }
+
+    /// Like `map_block`, but allocates the data block and any missing
+    /// indirect blocks on the way down, in group `goal` if there's room
+    fn map_block_alloc(&mut self, inode: &mut Inode, goal: u32, n: u64) -> Result<u32, Self::Error> {
+        let path = block_path(n, self.block_size()).ok_or(WriteError::TooLarge)?;
+        let sectors = (self.block_size() / 512) as u32;
+        if inode.block[path.slot] == 0 {
+            inode.block[path.slot] = self.alloc_block(goal)?;
+            inode.blocks += sectors;
+        }
+        let mut block = inode.block[path.slot];
+        for &i in &path.index[..path.depth] {
+            let mut next = self.indirect(block, i)?;
+            if next == 0 {
+                next = self.alloc_block(goal)?;
+                inode.blocks += sectors;
+                self.set_indirect(block, i, next)?;
+            }
+            block = next;
+        }
+        Ok(block)
+    }
+
+    /// Free every data block from logical block `keep` on, along with
+    /// indirect blocks that end up empty
+    fn free_blocks_from(&mut self, inode: &mut Inode, keep: u64) -> Result<(), Self::Error> {
+        let sectors = (self.block_size() / 512) as u32;
+        for i in keep.min(DIRECT_BLOCKS as u64) as usize..DIRECT_BLOCKS {
+            if inode.block[i] != 0 {
+                self.free_block(inode.block[i])?;
+                inode.block[i] = 0;
+                inode.blocks = inode.blocks.saturating_sub(sectors);
+            }
+        }
+
+        let p = (self.block_size() / 4) as u64;
+        let mut first = DIRECT_BLOCKS as u64;
+        let mut span = p;
+        for (slot, level) in [(IND_BLOCK, 1), (DIND_BLOCK, 2), (TIND_BLOCK, 3)] {
+            let k = keep.saturating_sub(first);
+            if inode.block[slot] != 0 && k < span {
+                let freed = self.trim_tree(inode.block[slot], level, k)?;
+                inode.blocks = inode.blocks.saturating_sub(freed * sectors);
+                if k == 0 {
+                    inode.block[slot] = 0;
+                }
+            }
+            first += span;
+            span *= p;
+        }
+        Ok(())
+    }
+
+    /// Free the part of a `level`-deep indirect tree past its first `keep`
+    /// data blocks. The tree's own block goes too when `keep` is 0.
+    /// Returns the number of blocks freed.
+    fn trim_tree(&mut self, block: u32, level: u32, keep: u64) -> Result<u32, Self::Error> {
+        let mut freed = 0;
+        let p = (self.block_size() / 4) as u64;
+        let per = p.pow(level - 1); // Data blocks under each entry
+
+        for i in keep / per..p {
+            let child = self.indirect(block, i as usize)?;
+            let k = keep.saturating_sub(i * per);
+            if child == 0 || k >= per {
+                continue;
+            }
+            if level > 1 {
+                freed += self.trim_tree(child, level - 1, k)?;
+            } else {
+                self.free_block(child)?;
+                freed += 1;
+            }
+            if k == 0 {
+                self.set_indirect(block, i as usize, 0)?;
+            }
+        }
+
+        if keep == 0 {
+            self.free_block(block)?;
+            freed += 1;
+        }
+        Ok(freed)
+    }
+
+    /// Drop a reference to an extended attribute block, freeing it with
+    /// the last one
+    fn release_xattr(&mut self, block: u32) -> Result<(), Self::Error> {
+        let mut data = self.read_block(block as u64)?;
+        let refs = read_u32(&data, 4);
+        if read_u32(&data, 0) == XATTR_MAGIC && refs > 1 {
+            put_u32(&mut data, 4, refs - 1);
+            self.write_block(block as u64, &data)
+        } else {
+            self.free_block(block)
+        }
+    }
+
+    /// Free an inode whose last link is gone, with its blocks
+    fn release_inode(&mut self, ino: u32, inode: &mut Inode) -> Result<(), Self::Error> {
+        if inode.has_blocks(self.block_size()) {
+            self.free_blocks_from(inode, 0)?;
+        }
+        if inode.file_acl != 0 {
+            self.release_xattr(inode.file_acl)?;
+            inode.file_acl = 0;
+            inode.blocks = 0;
+        }
+        inode.size = 0;
+        inode.links_count = 0;
+        // e2fsck flags a freed inode with no dtime, and reads one below the
+        // inode count as an orphan list link; the guest's clock reads 0 on
+        // hosts without GetTime
+        inode.dtime = self.now().max(self.sb().inodes_count);
+        self.write_inode(ino, inode)?;
+        self.free_inode(ino, inode.is_dir())
+    }
+
+    // --- Directory entries ---
+
+    /// Link `name` to `ino` in a directory, reusing slack in its entries
+    /// before growing it by a block
+    fn add_entry(&mut self, dir_ino: u32, name: &[u8], ino: u32, file_type: u8) -> Result<(), Self::Error> {
+        let mut dir = self.read_inode(dir_ino)?;
+        let file_type = self.dir_file_type(file_type);
+        let bs = self.block_size();
+        let count = dir.size.div_ceil(bs as u64);
+        let now = self.now();
+        dir.mtime = now;
+        dir.ctime = now;
+        // The hashed index would go stale
+        dir.flags &= !INDEX_FL;
+
+        for n in 0..count {
+            let block = self.map_block(&dir, n)?;
+            if block == 0 {
+                continue;
+            }
+            let mut data = self.read_block(block as u64)?;
+            if insert_entry(&mut data, name, ino, file_type).map_err(WriteError::from)? {
+                self.write_block(block as u64, &data)?;
+                return self.write_inode(dir_ino, &dir);
+            }
+        }
+
+        let goal = self.inode_group(dir_ino);
+        let block = match self.map_block_alloc(&mut dir, goal, count) {
+            Ok(block) => block,
+            Err(e) => {
+                // Keep any indirect blocks it did allocate accounted for
+                self.write_inode(dir_ino, &dir)?;
+                return Err(e);
+            }
+        };
+        let mut data = vec![0u8; bs];
+        write_dir_entry(&mut data, 0, ino, bs, name, file_type);
+        self.write_block(block as u64, &data)?;
+        dir.size = (count + 1) * bs as u64;
+        self.write_inode(dir_ino, &dir)
+    }
+
+    /// Unlink `name` from a directory, returning the inode it named
+    fn remove_entry(&mut self, dir_ino: u32, name: &[u8]) -> Result<u32, Self::Error> {
+        let mut dir = self.read_inode(dir_ino)?;
+        let count = dir.size.div_ceil(self.block_size() as u64);
+        for n in 0..count {
+            let block = self.map_block(&dir, n)?;
+            if block == 0 {
+                continue;
+            }
+            let mut data = self.read_block(block as u64)?;
+            if let Some(ino) = crate::remove_entry(&mut data, name).map_err(WriteError::from)? {
+                self.write_block(block as u64, &data)?;
+                let now = self.now();
+                dir.mtime = now;
+                dir.ctime = now;
+                dir.flags &= !INDEX_FL;
+                self.write_inode(dir_ino, &dir)?;
+                return Ok(ino);
+            }
+        }
+        Err(WriteError::NotFound.into())
+    }
+}
@@ -25,7 +25,12 @@ cd ../..
 wasm_path="target/wasm32-unknown-unknown/release/wasm_simple.wasm"
 
 echo "=== Creating Disk Image (with WASM) ==="
-cargo run --release -p mkext2 -- "$wasm_path"
+disk_root="target/disk-root"
+rm -rf "$disk_root"
+mkdir -p "$disk_root"
+printf 'Hello, World!' > "$disk_root/hello.txt"
+cp "$wasm_path" "$disk_root/test.wasm"
+cargo run --release -p mkext2 -- -o disk.img "$disk_root"
 
 echo "=== Building Kernel ==="
 # We default to aarch64 guest on aarch64 host for now in macos.rs
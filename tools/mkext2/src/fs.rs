@@ -0,0 +1,648 @@
+//! Reading and changing an existing ext2 image: path lookup, file data,
+//! directories, and adding, replacing or deleting files. Allocation, block
+//! trees and directory entries are the `Volume` methods shared with the
+//! guest driver.
+//!
+//! Bitmaps are cached and written back, along with the group descriptor
+//! counts and the superblock, by `flush`. Only the primary superblock and
+//! descriptor table are updated; the backups keep their layout, which is
+//! all that matters for recovery.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use aether_ext2::*;
+use crate::image::{Kind, Node};
+
+/// Incompatible features mkext2 can read, the same set as the guest driver
+const INCOMPAT_READ: u32 = FEATURE_INCOMPAT_FILETYPE
+    | FEATURE_INCOMPAT_EXTENTS
+    | FEATURE_INCOMPAT_64BIT
+    | FEATURE_INCOMPAT_FLEX_BG
+    | FEATURE_INCOMPAT_CSUM_SEED
+    | FEATURE_INCOMPAT_LARGEDIR;
+/// Incompatible features that don't change how mkext2 writes
+const INCOMPAT_WRITE: u32 = FEATURE_INCOMPAT_FILETYPE;
+/// Read-only compatible features mkext2 keeps intact while writing
+const RO_COMPAT_SUPPORTED: u32 =
+    FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE | FEATURE_RO_COMPAT_BTREE_DIR;
+
+pub fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn not_found(path: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{}: no such file or directory", path))
+}
+
+fn now() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// Split an image path into its parent directory and final component
+pub fn split_path(path: &str) -> io::Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() || name == "." || name == ".." || name.len() > 255 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bad path {:?}", path)));
+    }
+    Ok((parent, name))
+}
+
+/// One entry of a directory
+pub struct Entry {
+    pub inode: u32,
+    pub name: Vec<u8>,
+}
+
+/// The blocks an inode owns
+#[derive(Default)]
+pub struct BlockList {
+    /// (logical block, disk block) for each data block; holes are skipped
+    pub data: Vec<(u64, u32)>,
+    /// Indirect blocks, or the interior nodes of an extent tree
+    pub indirect: Vec<u32>,
+    /// Blocks of uninitialized extents, which read as zeros
+    pub unwritten: Vec<u32>,
+}
+
+pub struct Image {
+    file: File,
+    pub sb: Superblock,
+    raw_sb: [u8; SUPERBLOCK_SIZE],
+    pub block_size: usize,
+    pub inode_size: usize,
+    pub groups: Vec<GroupDescriptor>,
+    /// Bytes per group descriptor: 64 or more with the 64bit feature
+    pub desc_size: usize,
+    /// First block of the primary group descriptor table
+    gdt_block: u32,
+    writable: bool,
+    /// Bitmap blocks read so far, and whether each changed
+    bitmaps: HashMap<u64, (Vec<u8>, bool)>,
+    time: u32,
+}
+
+impl Image {
+    /// Open the image at `path`, for changes too if `writable`
+    pub fn open(path: &Path, writable: bool) -> io::Result<Self> {
+        let mut file = OpenOptions::new().read(true).write(writable).open(path)?;
+        let mut raw_sb = [0u8; SUPERBLOCK_SIZE];
+        file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        file.read_exact(&mut raw_sb)?;
+        let sb = Superblock::parse(&raw_sb);
+        if sb.magic != EXT2_MAGIC {
+            return Err(invalid(format!("{} is not an ext2 image", path.display())));
+        }
+        if sb.feature_incompat & FEATURE_INCOMPAT_RECOVER != 0 {
+            return Err(invalid("the journal needs recovery; run e2fsck first".into()));
+        }
+        let unsupported = sb.feature_incompat & !INCOMPAT_READ;
+        if unsupported != 0 {
+            return Err(invalid(format!("unsupported incompatible features {:#x}", unsupported)));
+        }
+        let unsupported = sb.feature_incompat & !INCOMPAT_WRITE;
+        if writable && unsupported != 0 {
+            return Err(invalid(format!("can't write with incompatible features {:#x}", unsupported)));
+        }
+        let unsupported = sb.feature_ro_compat & !RO_COMPAT_SUPPORTED;
+        if writable && unsupported != 0 {
+            return Err(invalid(format!("can't write with read-only features {:#x}", unsupported)));
+        }
+        let inode_size = sb.inode_size();
+        let wide = sb.feature_incompat & FEATURE_INCOMPAT_64BIT != 0;
+        if wide && sb.blocks_count_hi != 0 {
+            return Err(invalid("more than 2^32 blocks".into()));
+        }
+        let desc_size = if wide { sb.desc_size as usize } else { GROUP_DESC_SIZE };
+        let valid = sb.log_block_size <= 6
+            && sb.blocks_per_group > 0
+            && sb.blocks_per_group as usize <= sb.block_size() * 8
+            && sb.inodes_per_group > 0
+            && sb.inodes_per_group as usize <= sb.block_size() * 8
+            && sb.first_data_block < sb.blocks_count
+            && inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size.is_power_of_two()
+            && inode_size <= sb.block_size()
+            && desc_size >= GROUP_DESC_SIZE
+            && desc_size.is_power_of_two()
+            && desc_size <= sb.block_size()
+            && sb.first_ino() > ROOT_INODE;
+        if !valid {
+            return Err(invalid("bad superblock geometry".into()));
+        }
+        let block_size = sb.block_size();
+        if sb.groups() as u64 * sb.inodes_per_group as u64 != sb.inodes_count as u64 {
+            return Err(invalid("superblock inode count doesn't match its groups".into()));
+        }
+
+        let gdt_block = sb.first_data_block + 1;
+        let mut table = vec![0u8; sb.groups() as usize * desc_size];
+        file.seek(SeekFrom::Start(gdt_block as u64 * block_size as u64))?;
+        file.read_exact(&mut table)?;
+        let groups = table.chunks_exact(desc_size).map(GroupDescriptor::parse).collect();
+
+        Ok(Image {
+            file,
+            sb,
+            raw_sb,
+            block_size,
+            inode_size,
+            groups,
+            desc_size,
+            gdt_block,
+            writable,
+            bitmaps: HashMap::new(),
+            time: now(),
+        })
+    }
+
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn check_block(&self, block: u64) -> io::Result<()> {
+        if block < self.sb.first_data_block as u64 || block >= self.sb.blocks_count as u64 {
+            return Err(invalid(format!("block {} is out of range", block)));
+        }
+        Ok(())
+    }
+
+    // --- Inodes ---
+
+    fn inode_offset(&self, ino: u32) -> io::Result<u64> {
+        if ino == 0 || ino > self.sb.inodes_count {
+            return Err(invalid(format!("inode {} is out of range", ino)));
+        }
+        let (group, index) = ((ino - 1) / self.sb.inodes_per_group, (ino - 1) % self.sb.inodes_per_group);
+        let table = self.groups[group as usize].inode_table;
+        Ok(table * self.block_size as u64 + index as u64 * self.inode_size as u64)
+    }
+
+    /// Every inode of a group, in order
+    pub fn group_inodes(&self, group: u32) -> io::Result<Vec<Inode>> {
+        let mut table = vec![0u8; self.sb.inodes_per_group as usize * self.inode_size];
+        let at = self.groups[group as usize].inode_table * self.block_size as u64;
+        self.read_at(at, &mut table)?;
+        Ok(table.chunks_exact(self.inode_size).map(Inode::parse).collect())
+    }
+
+    pub fn inode(&self, ino: u32) -> io::Result<Inode> {
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        self.read_at(self.inode_offset(ino)?, &mut raw)?;
+        Ok(Inode::parse(&raw))
+    }
+
+    // --- Block trees ---
+
+    /// Every block behind `inode`'s pointers
+    pub fn blocks(&self, inode: &Inode) -> io::Result<BlockList> {
+        let mut list = BlockList::default();
+        if !inode.has_blocks(self.block_size) {
+            return Ok(list);
+        }
+        if inode.flags & EXTENTS_FL != 0 {
+            self.walk_extents(&inode.block_bytes(), None, &mut list)?;
+            return Ok(list);
+        }
+        for (i, &block) in inode.block[..DIRECT_BLOCKS].iter().enumerate() {
+            if block != 0 {
+                self.check_block(block as u64)?;
+                list.data.push((i as u64, block));
+            }
+        }
+        let p = (self.block_size / 4) as u64;
+        let mut first = DIRECT_BLOCKS as u64;
+        for (slot, level) in [(IND_BLOCK, 1), (DIND_BLOCK, 2), (TIND_BLOCK, 3)] {
+            if inode.block[slot] != 0 {
+                self.walk(inode.block[slot], level, first, &mut list)?;
+            }
+            first += p.pow(level);
+        }
+        Ok(list)
+    }
+
+    fn walk(&self, block: u32, level: u32, first: u64, list: &mut BlockList) -> io::Result<()> {
+        let pointers = self.read_block(block as u64)?;
+        list.indirect.push(block);
+        let per = (self.block_size as u64 / 4).pow(level - 1);
+        for (i, raw) in pointers.chunks_exact(4).enumerate() {
+            let child = u32::from_le_bytes(raw.try_into().unwrap());
+            if child == 0 {
+                continue;
+            }
+            let logical = first + i as u64 * per;
+            if level == 1 {
+                self.check_block(child as u64)?;
+                list.data.push((logical, child));
+            } else {
+                self.walk(child, level - 1, logical, list)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// One node of an extent tree: extents at the leaves (`level` 0), index
+    /// entries above them
+    fn walk_extents(&self, node: &[u8], depth: Option<u16>, list: &mut BlockList) -> io::Result<()> {
+        let bad = || invalid("bad extent tree".into());
+        let entries = read_u16(node, 2) as usize;
+        let level = read_u16(node, 6);
+        if read_u16(node, 0) != EXTENT_MAGIC
+            || EXTENT_HEADER_SIZE + entries * EXTENT_ENTRY_SIZE > node.len()
+            || level > EXTENT_MAX_DEPTH
+            || depth.is_some_and(|d| d != level)
+        {
+            return Err(bad());
+        }
+        for entry in node[EXTENT_HEADER_SIZE..].chunks_exact(EXTENT_ENTRY_SIZE).take(entries) {
+            if level > 0 {
+                let child = (read_u16(entry, 8) as u64) << 32 | read_u32(entry, 4) as u64;
+                let child = u32::try_from(child).map_err(|_| bad())?;
+                let data = self.read_block(child as u64)?;
+                list.indirect.push(child);
+                self.walk_extents(&data, Some(level - 1), list)?;
+                continue;
+            }
+            let first = read_u32(entry, 0) as u64;
+            let (len, unwritten) = match read_u16(entry, 4) {
+                len if len > EXTENT_INIT_MAX_LEN => (len - EXTENT_INIT_MAX_LEN, true),
+                len => (len, false),
+            };
+            let start = (read_u16(entry, 6) as u64) << 32 | read_u32(entry, 8) as u64;
+            for i in 0..len as u64 {
+                let block = u32::try_from(start + i).map_err(|_| bad())?;
+                self.check_block(block as u64)?;
+                if unwritten {
+                    list.unwritten.push(block);
+                } else {
+                    list.data.push((first + i, block));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Store `len` bytes from `src` as the data of inode `ino`, which has none
+    fn write_data(&mut self, ino: u32, inode: &mut Inode, src: &mut dyn Read, len: u64) -> io::Result<()> {
+        let goal = self.inode_group(ino);
+        let mut buf = vec![0u8; self.block_size];
+        let mut n = 0;
+        while n * (self.block_size as u64) < len {
+            let chunk = (len - n * self.block_size as u64).min(self.block_size as u64) as usize;
+            buf.fill(0);
+            src.read_exact(&mut buf[..chunk])?;
+            let block = self.map_block_alloc(inode, goal, n)?;
+            self.write_block(block as u64, &buf)?;
+            n += 1;
+        }
+        inode.size = len;
+        Ok(())
+    }
+
+    /// A file's or symlink's contents
+    pub fn read_data(&self, inode: &Inode) -> io::Result<Vec<u8>> {
+        if inode.is_symlink() && !inode.has_blocks(self.block_size) {
+            return Ok(inode.block_bytes()[..(inode.size as usize).min(FAST_SYMLINK_MAX)].to_vec());
+        }
+        let size = usize::try_from(inode.size).map_err(|_| invalid("file is too large".into()))?;
+        let mut data = vec![0u8; size];
+        for (logical, block) in self.blocks(inode)?.data {
+            let at = logical as usize * self.block_size;
+            if at < size {
+                let end = (at + self.block_size).min(size);
+                let content = self.read_block(block as u64)?;
+                data[at..end].copy_from_slice(&content[..end - at]);
+            }
+        }
+        Ok(data)
+    }
+
+    /// Copy a file's contents to `out` a block at a time
+    pub fn copy_data(&self, inode: &Inode, out: &mut dyn Write) -> io::Result<()> {
+        let blocks: HashMap<u64, u32> = self.blocks(inode)?.data.into_iter().collect();
+        let zeros = vec![0u8; self.block_size];
+        let mut left = inode.size;
+        let mut n = 0;
+        while left > 0 {
+            let len = left.min(self.block_size as u64) as usize;
+            match blocks.get(&n) {
+                Some(&block) => out.write_all(&self.read_block(block as u64)?[..len])?,
+                None => out.write_all(&zeros[..len])?,
+            }
+            left -= len as u64;
+            n += 1;
+        }
+        Ok(())
+    }
+
+    // --- Directories ---
+
+    /// A directory's blocks in order, with their disk block numbers
+    fn dir_blocks(&self, dir: &Inode) -> io::Result<Vec<(u32, Vec<u8>)>> {
+        let count = dir.size.div_ceil(self.block_size as u64);
+        let mut blocks = Vec::new();
+        for (logical, block) in self.blocks(dir)?.data {
+            if logical < count {
+                blocks.push((block, self.read_block(block as u64)?));
+            }
+        }
+        Ok(blocks)
+    }
+
+    /// Entries of a directory, `.` and `..` included
+    pub fn read_dir(&self, ino: u32) -> io::Result<Vec<Entry>> {
+        let dir = self.inode(ino)?;
+        if !dir.is_dir() {
+            return Err(invalid(format!("inode {} is not a directory", ino)));
+        }
+        let mut entries = Vec::new();
+        for (block, data) in self.dir_blocks(&dir)? {
+            let mut offset = 0;
+            while offset < data.len() {
+                let entry = DirEntry::parse(&data, offset)
+                    .map_err(|_| invalid(format!("bad directory entry in block {}", block)))?;
+                if entry.inode != 0 {
+                    let name = entry.name(&data, offset).to_vec();
+                    entries.push(Entry { inode: entry.inode, name });
+                }
+                offset += entry.rec_len;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// The inode at `path`, without following symlinks
+    pub fn lookup(&self, path: &str) -> io::Result<u32> {
+        let mut ino = ROOT_INODE;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            if !self.inode(ino)?.is_dir() {
+                return Err(not_found(path));
+            }
+            ino = self
+                .read_dir(ino)?
+                .into_iter()
+                .find(|e| e.name == part.as_bytes())
+                .ok_or_else(|| not_found(path))?
+                .inode;
+        }
+        Ok(ino)
+    }
+
+    // --- Bitmaps ---
+
+    fn bitmap(&mut self, block: u64) -> io::Result<&mut (Vec<u8>, bool)> {
+        if !self.bitmaps.contains_key(&block) {
+            let data = self.read_block(block)?;
+            self.bitmaps.insert(block, (data, false));
+        }
+        Ok(self.bitmaps.get_mut(&block).unwrap())
+    }
+
+    /// Whether bit `i` of the bitmap in `block` is set
+    pub fn bit(&mut self, block: u64, i: u32) -> io::Result<bool> {
+        let (map, _) = self.bitmap(block)?;
+        Ok(test_bit(map, i as usize))
+    }
+
+    /// Write the cached bitmaps, descriptor counts and superblock
+    pub fn flush(&mut self) -> io::Result<()> {
+        if !self.writable {
+            return Ok(());
+        }
+        let dirty: Vec<(u64, Vec<u8>)> = self
+            .bitmaps
+            .iter_mut()
+            .filter(|(_, (_, dirty))| *dirty)
+            .map(|(&block, (data, dirty))| {
+                *dirty = false;
+                (block, data.clone())
+            })
+            .collect();
+        for (block, data) in dirty {
+            self.write_block(block, &data)?;
+        }
+        let mut table = vec![0u8; self.groups.len() * GROUP_DESC_SIZE];
+        let table_at = self.gdt_block as u64 * self.block_size as u64;
+        self.read_at(table_at, &mut table)?;
+        for (desc, raw) in self.groups.iter().zip(table.chunks_exact_mut(GROUP_DESC_SIZE)) {
+            desc.encode_into(raw);
+        }
+        self.write_at(table_at, &table)?;
+        self.sb.wtime = self.time;
+        self.sb.encode_into(&mut self.raw_sb);
+        let raw_sb = self.raw_sb;
+        self.write_at(SUPERBLOCK_OFFSET, &raw_sb)?;
+        self.file.sync_all()
+    }
+
+    // --- Changes ---
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.writable { Ok(()) } else { Err(io::Error::new(io::ErrorKind::PermissionDenied, "image is read-only")) }
+    }
+
+    /// Add the host tree `node` as `name` in the directory `parent`.
+    /// `linked` maps host files with several links to their image inode.
+    pub fn add(&mut self, parent: u32, name: &[u8], node: &Node, linked: &mut HashMap<(u64, u64), u32>) -> io::Result<u32> {
+        self.check_writable()?;
+        if let Some(&ino) = node.link_key.as_ref().and_then(|key| linked.get(key)) {
+            let mut inode = self.inode(ino)?;
+            inode.links_count += 1;
+            inode.ctime = self.time;
+            self.write_inode(ino, &inode)?;
+            self.add_entry(parent, name, ino, FT_REG_FILE)?;
+            return Ok(ino);
+        }
+
+        let dir = matches!(node.kind, Kind::Dir(_));
+        let ino = self.alloc_inode(self.inode_group(parent), dir)?;
+        let type_bits = match node.kind {
+            Kind::Dir(_) => S_IFDIR,
+            Kind::File { .. } => S_IFREG,
+            Kind::Symlink(_) => S_IFLNK,
+        };
+        let mut inode = Inode {
+            mode: type_bits | node.perm,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: self.time,
+            links_count: 1,
+            ..Default::default()
+        };
+        match &node.kind {
+            Kind::Dir(_) => {
+                let mut data = vec![0u8; self.block_size];
+                init_dir_block(&mut data, ino, parent, self.dir_file_type(FT_DIR));
+                inode.links_count = 2;
+                self.write_data(ino, &mut inode, &mut data.as_slice(), self.block_size as u64)?;
+            }
+            Kind::File { size } => {
+                let mut src = File::open(&node.host)?;
+                self.write_data(ino, &mut inode, &mut src, *size)?;
+            }
+            Kind::Symlink(target) if target.len() < FAST_SYMLINK_MAX => {
+                inode.set_fast_symlink(target);
+            }
+            Kind::Symlink(target) => {
+                if target.len() >= self.block_size {
+                    return Err(invalid(format!("{}: symlink target is longer than a block", node.host.display())));
+                }
+                self.write_data(ino, &mut inode, &mut target.as_slice(), target.len() as u64)?;
+            }
+        }
+        self.write_inode(ino, &inode)?;
+        self.add_entry(parent, name, ino, file_type(inode.mode))?;
+        if let Some(key) = node.link_key {
+            linked.insert(key, ino);
+        }
+
+        if let Kind::Dir(children) = &node.kind {
+            let mut parent_inode = self.inode(parent)?;
+            parent_inode.links_count += 1;
+            self.write_inode(parent, &parent_inode)?;
+            for child in children {
+                self.add(ino, &child.name, child, linked)?;
+            }
+        }
+        Ok(ino)
+    }
+
+    /// Give the regular file at `ino` the contents, permissions and times
+    /// of the host file `node`
+    pub fn replace(&mut self, ino: u32, node: &Node) -> io::Result<()> {
+        self.check_writable()?;
+        let Kind::File { size } = node.kind else {
+            return Err(invalid(format!("{} is not a regular file", node.host.display())));
+        };
+        let mut inode = self.inode(ino)?;
+        if !inode.is_reg() {
+            return Err(invalid(format!("inode {} is not a regular file", ino)));
+        }
+        self.free_blocks_from(&mut inode, 0)?;
+        let mut src = File::open(&node.host)?;
+        self.write_data(ino, &mut inode, &mut src, size)?;
+        inode.mode = S_IFREG | node.perm;
+        inode.atime = node.atime;
+        inode.mtime = node.mtime;
+        inode.ctime = self.time;
+        self.write_inode(ino, &inode)
+    }
+
+    /// Remove `name` from the directory `parent`; a directory has to be
+    /// empty unless `recursive`
+    pub fn delete(&mut self, parent: u32, name: &[u8], recursive: bool) -> io::Result<()> {
+        self.check_writable()?;
+        let ino = self
+            .read_dir(parent)?
+            .into_iter()
+            .find(|e| e.name == name)
+            .ok_or_else(|| not_found(&String::from_utf8_lossy(name)))?
+            .inode;
+        let mut inode = self.inode(ino)?;
+        if inode.is_dir() {
+            let children: Vec<Entry> =
+                self.read_dir(ino)?.into_iter().filter(|e| e.name != b"." && e.name != b"..").collect();
+            if !children.is_empty() && !recursive {
+                return Err(io::Error::new(
+                    io::ErrorKind::DirectoryNotEmpty,
+                    format!("{} is not empty", String::from_utf8_lossy(name)),
+                ));
+            }
+            for child in children {
+                self.delete(ino, &child.name, true)?;
+            }
+            self.remove_entry(parent, name)?;
+            let mut inode = self.inode(ino)?;
+            self.release_inode(ino, &mut inode)?;
+            let mut parent_inode = self.inode(parent)?;
+            parent_inode.links_count = parent_inode.links_count.saturating_sub(1);
+            return self.write_inode(parent, &parent_inode);
+        }
+
+        self.remove_entry(parent, name)?;
+        inode.links_count = inode.links_count.saturating_sub(1);
+        inode.ctime = self.time;
+        if inode.links_count == 0 {
+            self.release_inode(ino, &mut inode)
+        } else {
+            self.write_inode(ino, &inode)
+        }
+    }
+}
+
+impl Volume for Image {
+    type Error = io::Error;
+
+    fn sb(&self) -> &Superblock {
+        &self.sb
+    }
+
+    fn sb_mut(&mut self) -> &mut Superblock {
+        &mut self.sb
+    }
+
+    /// The counts go out with the superblock in `flush`
+    fn write_counts(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn group(&self, group: u32) -> io::Result<GroupDescriptor> {
+        self.groups.get(group as usize).copied().ok_or_else(|| invalid(format!("group {} is out of range", group)))
+    }
+
+    fn write_group(&mut self, group: u32, desc: &GroupDescriptor) -> io::Result<()> {
+        self.groups[group as usize] = *desc;
+        Ok(())
+    }
+
+    fn read_block(&self, block: u64) -> io::Result<Vec<u8>> {
+        self.check_block(block)?;
+        let mut buf = vec![0u8; self.block_size];
+        self.read_at(block * self.block_size as u64, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, block: u64, data: &[u8]) -> io::Result<()> {
+        self.check_block(block)?;
+        self.write_at(block * self.block_size as u64, data)
+    }
+
+    fn read_bitmap(&mut self, block: u64) -> io::Result<Vec<u8>> {
+        Ok(self.bitmap(block)?.0.clone())
+    }
+
+    fn write_bitmap(&mut self, block: u64, data: &[u8]) -> io::Result<()> {
+        *self.bitmap(block)? = (data.to_vec(), true);
+        Ok(())
+    }
+
+    fn read_inode(&self, ino: u32) -> io::Result<Inode> {
+        self.inode(ino)
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> io::Result<()> {
+        let at = self.inode_offset(ino)?;
+        self.write_at(at, &inode.encode())
+    }
+
+    fn clear_inode(&mut self, ino: u32) -> io::Result<()> {
+        let at = self.inode_offset(ino)?;
+        self.write_at(at, &vec![0u8; self.inode_size])
+    }
+
+    fn now(&self) -> u32 {
+        self.time
+    }
+}
@@ -0,0 +1,352 @@
+//! A read-only consistency check along the lines of `e2fsck -n`: block
+//! ownership against the block bitmaps, inode use against the inode
+//! bitmaps, directory entries, link counts and the free counts.
+
+use std::collections::HashMap;
+use std::io;
+
+use aether_ext2::*;
+use crate::fs::Image;
+
+/// Who a block belongs to
+#[derive(Clone, Copy, PartialEq)]
+enum Owner {
+    Free,
+    Metadata,
+    Inode(u32),
+    /// An extended attribute block, which inodes may share
+    Xattr,
+}
+
+/// Collapse sorted block numbers into "a-b, c, d-e"
+fn ranges(blocks: &[u32]) -> String {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < blocks.len() {
+        let start = blocks[i];
+        while i + 1 < blocks.len() && blocks[i + 1] == blocks[i] + 1 {
+            i += 1;
+        }
+        out.push(if blocks[i] == start { start.to_string() } else { format!("{}-{}", start, blocks[i]) });
+        i += 1;
+    }
+    out.join(", ")
+}
+
+struct Checker<'a> {
+    img: &'a mut Image,
+    problems: Vec<String>,
+    /// Indexed by block number
+    owners: Vec<Owner>,
+    /// Inodes in use, by number
+    inodes: HashMap<u32, Inode>,
+    /// Inodes sharing each extended attribute block
+    xattr_refs: HashMap<u32, u32>,
+}
+
+impl Checker<'_> {
+    fn problem(&mut self, msg: String) {
+        self.problems.push(msg);
+    }
+
+    /// Whether group `g` has `flag`, so its bitmap was never written
+    fn uninit(&self, g: u32, flag: u16) -> bool {
+        let csum = FEATURE_RO_COMPAT_GDT_CSUM | FEATURE_RO_COMPAT_METADATA_CSUM;
+        self.img.sb.feature_ro_compat & csum != 0 && self.img.groups[g as usize].flags & flag != 0
+    }
+
+    fn claim(&mut self, block: u32, owner: Owner) {
+        let Some(slot) = self.owners.get_mut(block as usize) else {
+            self.problems.push(format!("block {} is past the end of the filesystem", block));
+            return;
+        };
+        let name = |owner| match owner {
+            Owner::Inode(ino) => format!("inode {}", ino),
+            Owner::Xattr => "extended attributes".into(),
+            _ => "metadata".into(),
+        };
+        match *slot {
+            Owner::Free => *slot = owner,
+            other => self.problems.push(format!("block {} is used by {} and {}", block, name(other), name(owner))),
+        }
+    }
+
+    /// Superblock copies, descriptor tables, bitmaps and inode tables
+    fn claim_metadata(&mut self) {
+        let sb = &self.img.sb;
+        let gdt_blocks = (sb.groups() as usize * self.img.desc_size).div_ceil(self.img.block_size) as u32;
+        // The resize inode owns the reserved GDT blocks
+        let reserved =
+            if sb.feature_compat & FEATURE_COMPAT_RESIZE_INODE != 0 { 0 } else { sb.reserved_gdt_blocks as u32 };
+        let table_blocks = (sb.inodes_per_group as usize * self.img.inode_size).div_ceil(self.img.block_size) as u32;
+        let mut metadata = Vec::new();
+        for g in 0..sb.groups() {
+            let start = sb.first_data_block + g * sb.blocks_per_group;
+            if sb.has_super(g) {
+                metadata.extend(start..start + 1 + gdt_blocks + reserved);
+            }
+            let desc = &self.img.groups[g as usize];
+            // `Image::open` refuses more than 2^32 blocks
+            let table = desc.inode_table as u32;
+            metadata.extend([desc.block_bitmap as u32, desc.inode_bitmap as u32]);
+            metadata.extend(table..table + table_blocks);
+        }
+        for block in metadata {
+            self.claim(block, Owner::Metadata);
+        }
+    }
+
+    /// Read every inode, keeping those in use and claiming their blocks
+    fn scan_inodes(&mut self) -> io::Result<()> {
+        let (per_group, first_ino) = (self.img.sb.inodes_per_group, self.img.sb.first_ino());
+        let sectors = (self.img.block_size / 512) as u32;
+        for g in 0..self.img.sb.groups() {
+            if self.uninit(g, BG_INODE_UNINIT) {
+                continue;
+            }
+            let bitmap = self.img.groups[g as usize].inode_bitmap;
+            for (i, inode) in self.img.group_inodes(g)?.into_iter().enumerate() {
+                let ino = g * per_group + i as u32 + 1;
+                let reserved = ino < first_ino && ino != ROOT_INODE;
+                let in_use = inode.links_count > 0 || (reserved && inode.blocks > 0);
+                let marked = self.img.bit(bitmap, i as u32)?;
+                if reserved {
+                    if !marked {
+                        self.problem(format!("reserved inode {} is marked free", ino));
+                    }
+                } else if in_use && !marked {
+                    self.problem(format!("inode {} is in use but marked free", ino));
+                } else if !in_use && marked {
+                    self.problem(format!("inode {} is marked in use but free", ino));
+                }
+                if !in_use {
+                    continue;
+                }
+
+                let list = match self.img.blocks(&inode) {
+                    Ok(list) => list,
+                    Err(e) => {
+                        self.problem(format!("inode {}: {}", ino, e));
+                        if !reserved {
+                            self.inodes.insert(ino, inode);
+                        }
+                        continue;
+                    }
+                };
+                let mut count = (list.data.len() + list.indirect.len() + list.unwritten.len()) as u32;
+                for block in list.data.iter().map(|&(_, b)| b).chain(list.indirect).chain(list.unwritten) {
+                    self.claim(block, Owner::Inode(ino));
+                }
+                if inode.file_acl != 0 {
+                    count += 1;
+                    *self.xattr_refs.entry(inode.file_acl).or_default() += 1;
+                }
+                if count * sectors != inode.blocks {
+                    self.problem(format!(
+                        "inode {} counts {} sectors but has {}",
+                        ino,
+                        inode.blocks,
+                        count * sectors
+                    ));
+                }
+                if inode.is_dir() && inode.size % self.img.block_size as u64 != 0 {
+                    self.problem(format!("directory inode {} has size {}", ino, inode.size));
+                }
+                if !reserved {
+                    self.inodes.insert(ino, inode);
+                }
+            }
+        }
+
+        let xattrs: Vec<(u32, u32)> = self.xattr_refs.iter().map(|(&b, &n)| (b, n)).collect();
+        for (block, users) in xattrs {
+            self.claim(block, Owner::Xattr);
+            let refs = match self.img.read_block(block as u64) {
+                Ok(data) if read_u32(&data, 0) == XATTR_MAGIC => read_u32(&data, 4),
+                _ => {
+                    self.problem(format!("extended attribute block {} is invalid", block));
+                    continue;
+                }
+            };
+            if refs != users {
+                self.problem(format!("extended attribute block {} has {} references, not {}", block, refs, users));
+            }
+        }
+        Ok(())
+    }
+
+    /// Walk every directory, returning how many entries name each inode
+    fn scan_dirs(&mut self) -> io::Result<HashMap<u32, u32>> {
+        let mut refs: HashMap<u32, u32> = HashMap::new();
+        // Directory -> the directory holding it, from both ends
+        let mut parent_entry: HashMap<u32, u32> = HashMap::new();
+        let mut dotdot: HashMap<u32, u32> = HashMap::new();
+        let filetype = self.img.sb.feature_incompat & FEATURE_INCOMPAT_FILETYPE != 0;
+
+        let mut dirs: Vec<u32> = self.inodes.iter().filter(|(_, i)| i.is_dir()).map(|(&n, _)| n).collect();
+        dirs.sort();
+        for dir in dirs {
+            let inode = self.inodes[&dir];
+            // A bad block tree was reported with the inode
+            let Ok(list) = self.img.blocks(&inode) else { continue };
+            let count = inode.size / self.img.block_size as u64;
+            let mut position = 0;
+            for (logical, block) in list.data {
+                if logical >= count {
+                    continue;
+                }
+                let data = self.img.read_block(block as u64)?;
+                let mut offset = 0;
+                while offset < data.len() {
+                    let Ok(entry) = DirEntry::parse(&data, offset) else {
+                        self.problem(format!("directory inode {}: bad entry at block {} offset {}", dir, block, offset));
+                        break;
+                    };
+                    let name = entry.name(&data, offset).to_vec();
+                    offset += entry.rec_len;
+                    if entry.inode == 0 {
+                        continue;
+                    }
+                    position += 1;
+                    let shown = String::from_utf8_lossy(&name).into_owned();
+                    let Some(&target) = self.inodes.get(&entry.inode) else {
+                        self.problem(format!("directory inode {}: {:?} names unused inode {}", dir, shown, entry.inode));
+                        continue;
+                    };
+                    *refs.entry(entry.inode).or_default() += 1;
+                    if name.is_empty() || name.contains(&b'/') || name.contains(&0) {
+                        self.problem(format!("directory inode {}: bad name {:?}", dir, shown));
+                    }
+                    if filetype && entry.file_type != file_type(target.mode) {
+                        self.problem(format!("directory inode {}: {:?} has the wrong file type", dir, shown));
+                    }
+                    match (position, name.as_slice()) {
+                        (1, b".") if entry.inode == dir => {}
+                        (2, b"..") => {
+                            dotdot.insert(dir, entry.inode);
+                        }
+                        (1, _) | (2, _) => self.problem(format!("directory inode {}: no . and .. entries", dir)),
+                        (_, b"." | b"..") => self.problem(format!("directory inode {}: extra {:?} entry", dir, shown)),
+                        _ if target.is_dir() && parent_entry.insert(entry.inode, dir).is_some() => {
+                            self.problem(format!("directory inode {} is linked more than once", entry.inode));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        for (&dir, &parent) in &dotdot {
+            let expected = if dir == ROOT_INODE { ROOT_INODE } else { parent_entry.get(&dir).copied().unwrap_or(0) };
+            if expected != 0 && parent != expected {
+                self.problems.push(format!("directory inode {}: .. is {}, not {}", dir, parent, expected));
+            }
+        }
+        Ok(refs)
+    }
+
+    fn check_links(&mut self, refs: &HashMap<u32, u32>) {
+        let mut inodes: Vec<(u32, u16)> = self.inodes.iter().map(|(&n, i)| (n, i.links_count)).collect();
+        inodes.sort();
+        for (ino, links) in inodes {
+            match refs.get(&ino).copied().unwrap_or(0) {
+                0 => self.problem(format!("inode {} is in use but in no directory", ino)),
+                n if n != links as u32 => self.problem(format!("inode {} has {} links but {} entries", ino, links, n)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Compare the block bitmaps and every free count with what was found
+    fn check_counts(&mut self) -> io::Result<()> {
+        let sb = &self.img.sb;
+        let (first, per_group, groups) = (sb.first_data_block, sb.blocks_per_group, sb.groups());
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        let (mut unowned, mut unmarked) = (Vec::new(), Vec::new());
+        let mut dirs = vec![0u32; groups as usize];
+        for (&ino, inode) in &self.inodes {
+            if inode.is_dir() {
+                dirs[((ino - 1) / sb.inodes_per_group) as usize] += 1;
+            }
+        }
+        for g in 0..groups {
+            let desc = &self.img.groups[g as usize];
+            let (block_bitmap, inode_bitmap) = (desc.block_bitmap, desc.inode_bitmap);
+            let (desc_blocks, desc_inodes, desc_dirs) = (desc.free_blocks_count, desc.free_inodes_count, desc.used_dirs_count);
+
+            let (blocks_uninit, inodes_uninit) = (self.uninit(g, BG_BLOCK_UNINIT), self.uninit(g, BG_INODE_UNINIT));
+            let mut group_free = 0;
+            for i in 0..self.img.sb.blocks_in_group(g) {
+                let block = first + g * per_group + i;
+                let owner = self.owners[block as usize];
+                // An unwritten bitmap stands for the group's own metadata,
+                // reserved descriptor blocks included
+                let marked = if blocks_uninit {
+                    matches!(owner, Owner::Metadata | Owner::Inode(RESIZE_INODE))
+                } else {
+                    self.img.bit(block_bitmap, i)?
+                };
+                let owned = owner != Owner::Free;
+                if marked && !owned {
+                    unowned.push(block);
+                } else if owned && !marked {
+                    unmarked.push(block);
+                }
+                group_free += !marked as u32;
+            }
+            let mut group_free_inodes = 0;
+            for i in 0..self.img.sb.inodes_per_group {
+                group_free_inodes += (inodes_uninit || !self.img.bit(inode_bitmap, i)?) as u32;
+            }
+            let dirs = dirs[g as usize];
+            if desc_blocks != group_free {
+                self.problem(format!("group {} counts {} free blocks, its bitmap {}", g, desc_blocks, group_free));
+            }
+            if desc_inodes != group_free_inodes {
+                self.problem(format!("group {} counts {} free inodes, its bitmap {}", g, desc_inodes, group_free_inodes));
+            }
+            if desc_dirs != dirs {
+                self.problem(format!("group {} counts {} directories, found {}", g, desc_dirs, dirs));
+            }
+            free_blocks += group_free;
+            free_inodes += group_free_inodes;
+        }
+        if !unowned.is_empty() {
+            self.problem(format!("blocks marked in use but not used: {}", ranges(&unowned)));
+        }
+        if !unmarked.is_empty() {
+            self.problem(format!("blocks in use but marked free: {}", ranges(&unmarked)));
+        }
+        let sb = &self.img.sb;
+        let (sb_blocks, sb_inodes) = (sb.free_blocks_count, sb.free_inodes_count);
+        if sb_blocks != free_blocks {
+            self.problem(format!("superblock counts {} free blocks, the bitmaps {}", sb_blocks, free_blocks));
+        }
+        if sb_inodes != free_inodes {
+            self.problem(format!("superblock counts {} free inodes, the bitmaps {}", sb_inodes, free_inodes));
+        }
+        Ok(())
+    }
+}
+
+/// Check `img`, returning a description of each problem found
+pub fn check(img: &mut Image) -> io::Result<Vec<String>> {
+    let blocks = img.sb.blocks_count as usize;
+    let mut checker = Checker {
+        img,
+        problems: Vec::new(),
+        owners: vec![Owner::Free; blocks],
+        inodes: HashMap::new(),
+        xattr_refs: HashMap::new(),
+    };
+    checker.claim_metadata();
+    checker.scan_inodes()?;
+    if !checker.inodes.get(&ROOT_INODE).is_some_and(|i| i.is_dir()) {
+        checker.problem("the root inode is not a directory".into());
+        return Ok(checker.problems);
+    }
+    let refs = checker.scan_dirs()?;
+    checker.check_links(&refs);
+    checker.check_counts()?;
+    Ok(checker.problems)
+}
@@ -0,0 +1,809 @@
+//! Building an ext2 image from a host directory tree.
+//!
+//! The tree is scanned first so the image can be sized to fit it, then
+//! written in one pass: inodes are numbered and data blocks allocated in
+//! sorted directory order, so the same tree and options always give the
+//! same image.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use aether_ext2::*;
+
+/// Inode size mkext2 writes
+const INODE_SIZE: usize = GOOD_OLD_INODE_SIZE;
+
+/// Image-wide settings; `None` picks a size or count that fits the tree
+pub struct Options {
+    pub size: Option<u64>,
+    pub block_size: u32,
+    pub inodes: Option<u32>,
+    /// Stamp every inode and the superblock with this time instead of the
+    /// host's, for reproducible images
+    pub timestamp: Option<u32>,
+}
+
+/// What went into an image
+pub struct Summary {
+    pub blocks: u32,
+    pub free_blocks: u32,
+    pub inodes: u32,
+    pub free_inodes: u32,
+    pub files: u32,
+    pub dirs: u32,
+    pub symlinks: u32,
+}
+
+/// Default inode density when no count is given
+const BYTES_PER_INODE: u64 = 8192;
+/// Smallest image picked when sizing to fit
+const MIN_AUTO_SIZE: u64 = 1 << 20;
+/// Blocks read from a host file per write
+const RUN_BLOCKS: usize = 64;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+// --- Geometry ---
+
+/// Where everything goes: block groups of `blocks_per_group` blocks, each
+/// led by its bitmaps and inode table, and (in groups 0, 1 and powers of
+/// 3, 5 and 7) a copy of the superblock and group descriptors
+pub struct Geometry {
+    pub block_size: u32,
+    pub blocks_count: u32,
+    pub first_data_block: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub groups: u32,
+    pub gdt_blocks: u32,
+    pub inode_table_blocks: u32,
+}
+
+impl Geometry {
+    /// Lay out `blocks` blocks with at least `inodes` inodes. A last group
+    /// too short to hold its own metadata is left off.
+    pub fn new(blocks: u64, block_size: u32, inodes: u64) -> io::Result<Self> {
+        let blocks_count = u32::try_from(blocks).map_err(|_| invalid(format!("{} blocks is too many for ext2", blocks)))?;
+        let first_data_block = (block_size == 1024) as u32;
+        let blocks_per_group = block_size * 8;
+        let inodes_per_block = block_size / INODE_SIZE as u32;
+        let mut geo = Geometry {
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group: 0,
+            groups: 0,
+            gdt_blocks: 0,
+            inode_table_blocks: 0,
+        };
+        loop {
+            if geo.blocks_count <= first_data_block {
+                return Err(invalid(format!("a {}-block image is too small", blocks)));
+            }
+            geo.groups = (geo.blocks_count - first_data_block).div_ceil(blocks_per_group);
+            geo.gdt_blocks = (geo.groups * GROUP_DESC_SIZE as u32).div_ceil(block_size);
+            let per_group = inodes.div_ceil(geo.groups as u64).next_multiple_of(inodes_per_block as u64);
+            geo.inodes_per_group = per_group.clamp(inodes_per_block as u64, blocks_per_group as u64) as u32;
+            geo.inode_table_blocks = geo.inodes_per_group / inodes_per_block;
+
+            let last = geo.groups - 1;
+            if geo.group_len(last) > geo.overhead(last) {
+                break;
+            }
+            if last == 0 {
+                return Err(invalid(format!("a {}-block image is too small", blocks)));
+            }
+            geo.blocks_count = geo.group_start(last);
+        }
+        if (geo.inodes_per_group as u64 * geo.groups as u64) < inodes {
+            return Err(invalid(format!("{} inodes don't fit in {} block groups", inodes, geo.groups)));
+        }
+        if geo.inodes_count() > u32::MAX as u64 {
+            return Err(invalid(format!("{} inodes is too many for ext2", inodes)));
+        }
+        Ok(geo)
+    }
+
+    pub fn inodes_count(&self) -> u64 {
+        self.inodes_per_group as u64 * self.groups as u64
+    }
+
+    pub fn group_start(&self, group: u32) -> u32 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    pub fn group_len(&self, group: u32) -> u32 {
+        self.blocks_per_group.min(self.blocks_count - self.group_start(group))
+    }
+
+    /// Whether `group` holds a superblock copy (the sparse_super rule)
+    pub fn has_super(&self, group: u32) -> bool {
+        fn power_of(mut n: u32, base: u32) -> bool {
+            while n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        }
+        group <= 1 || power_of(group, 3) || power_of(group, 5) || power_of(group, 7)
+    }
+
+    /// Metadata blocks at the start of `group`
+    pub fn overhead(&self, group: u32) -> u32 {
+        let super_blocks = if self.has_super(group) { 1 + self.gdt_blocks } else { 0 };
+        super_blocks + 2 + self.inode_table_blocks
+    }
+
+    pub fn block_bitmap(&self, group: u32) -> u32 {
+        self.group_start(group) + self.overhead(group) - 2 - self.inode_table_blocks
+    }
+
+    pub fn inode_bitmap(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 1
+    }
+
+    pub fn inode_table(&self, group: u32) -> u32 {
+        self.block_bitmap(group) + 2
+    }
+
+    /// Blocks left for files and directories
+    pub fn data_blocks(&self) -> u64 {
+        (0..self.groups).map(|g| (self.group_len(g) - self.overhead(g)) as u64).sum()
+    }
+
+    /// Pointers per indirect block
+    fn pointers(&self) -> u64 {
+        self.block_size as u64 / 4
+    }
+
+    /// Blocks, data and indirect, a file of `data` data blocks takes, or
+    /// `None` if it's past what triple indirection reaches
+    pub fn blocks_for(&self, data: u64) -> Option<u64> {
+        let p = self.pointers();
+        let mut left = data.saturating_sub(DIRECT_BLOCKS as u64);
+        let mut total = data;
+        for level in 1..=3 {
+            if left == 0 {
+                return Some(total);
+            }
+            let take = left.min(p.pow(level));
+            // An indirect block k levels above the data covers p^k blocks
+            total += (1..=level).map(|k| take.div_ceil(p.pow(k))).sum::<u64>();
+            left -= take;
+        }
+        (left == 0).then_some(total)
+    }
+}
+
+// --- Scanning the host tree ---
+
+pub enum Kind {
+    Dir(Vec<Node>),
+    File { size: u64 },
+    Symlink(Vec<u8>),
+}
+
+/// A scanned host file, directory or symlink
+pub struct Node {
+    pub name: Vec<u8>,
+    pub kind: Kind,
+    pub host: std::path::PathBuf,
+    /// Permission bits
+    pub perm: u16,
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
+    /// Device and inode of a file with several host links, so its links
+    /// share one inode in the image too
+    pub link_key: Option<(u64, u64)>,
+}
+
+fn clamp_time(secs: i64) -> u32 {
+    secs.clamp(0, u32::MAX as i64) as u32
+}
+
+#[cfg(unix)]
+fn name_bytes(name: &std::ffi::OsStr) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(name.as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn name_bytes(name: &std::ffi::OsStr) -> io::Result<Vec<u8>> {
+    name.to_str()
+        .map(|s| s.as_bytes().to_vec())
+        .ok_or_else(|| invalid(format!("{:?} is not valid UTF-8", name)))
+}
+
+#[cfg(unix)]
+fn host_attrs(meta: &fs::Metadata) -> (u16, [u32; 3], Option<(u64, u64)>) {
+    use std::os::unix::fs::MetadataExt;
+    let times = [clamp_time(meta.atime()), clamp_time(meta.mtime()), clamp_time(meta.ctime())];
+    let key = (meta.is_file() && meta.nlink() > 1).then(|| (meta.dev(), meta.ino()));
+    ((meta.mode() & 0o7777) as u16, times, key)
+}
+
+#[cfg(not(unix))]
+fn host_attrs(meta: &fs::Metadata) -> (u16, [u32; 3], Option<(u64, u64)>) {
+    let secs = |t: io::Result<std::time::SystemTime>| {
+        t.ok()
+            .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+            .map_or(0, |d| clamp_time(d.as_secs() as i64))
+    };
+    let mtime = secs(meta.modified());
+    let perm = if meta.is_dir() || meta.is_symlink() {
+        0o755
+    } else if meta.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    };
+    (perm, [secs(meta.accessed()), mtime, mtime], None)
+}
+
+/// Scan the host tree at `path`, without following symlinks. Anything
+/// but files, directories and symlinks is skipped with a warning.
+pub fn scan(path: &Path, name: Vec<u8>) -> io::Result<Option<Node>> {
+    let meta = fs::symlink_metadata(path)?;
+    let (perm, [atime, mtime, ctime], link_key) = host_attrs(&meta);
+    let kind = if meta.is_dir() {
+        let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        let mut children = Vec::new();
+        for entry in entries {
+            let name = name_bytes(&entry.file_name())?;
+            if name.len() > 255 {
+                return Err(invalid(format!("{}: name is longer than 255 bytes", entry.path().display())));
+            }
+            children.extend(scan(&entry.path(), name)?);
+        }
+        Kind::Dir(children)
+    } else if meta.is_file() {
+        Kind::File { size: meta.len() }
+    } else if meta.is_symlink() {
+        Kind::Symlink(name_bytes(fs::read_link(path)?.as_os_str())?)
+    } else {
+        eprintln!("mkext2: skipping {}: not a file, directory or symlink", path.display());
+        return Ok(None);
+    };
+    Ok(Some(Node { name, kind, host: path.to_path_buf(), perm, atime, mtime, ctime, link_key }))
+}
+
+/// An empty directory made up for the image (the root without a source,
+/// or lost+found)
+fn empty_dir(name: &[u8], perm: u16, time: u32) -> Node {
+    Node {
+        name: name.to_vec(),
+        kind: Kind::Dir(Vec::new()),
+        host: Default::default(),
+        perm,
+        atime: time,
+        mtime: time,
+        ctime: time,
+        link_key: None,
+    }
+}
+
+/// Pack directory entries into blocks, never splitting one across blocks.
+/// The last entry of each block stretches to its end.
+fn pack_dir(entries: &[(&[u8], u32, u8)], block_size: usize) -> Vec<u8> {
+    let mut data = vec![0u8; block_size];
+    let mut block_start = 0;
+    let mut last: Option<usize> = None;
+    let mut at = 0;
+    for &(name, inode, file_type) in entries {
+        let len = dir_entry_len(name.len());
+        if at + len > block_start + block_size {
+            let last = last.take().unwrap();
+            let end = block_start + block_size;
+            set_rec_len(&mut data, last, end - last);
+            block_start = end;
+            at = end;
+            data.resize(end + block_size, 0);
+        }
+        write_dir_entry(&mut data, at, inode, len, name, file_type);
+        last = Some(at);
+        at += len;
+    }
+    if let Some(last) = last {
+        set_rec_len(&mut data, last, block_start + block_size - last);
+    }
+    data
+}
+
+/// Blocks and inodes a scanned tree needs
+#[derive(Default)]
+struct Needs {
+    blocks: u64,
+    inodes: u64,
+}
+
+impl Needs {
+    /// Add up `node` and everything under it. `seen` collects the host
+    /// files with several links, which only count once.
+    fn count(&mut self, node: &Node, geo: &Geometry, seen: &mut HashSet<(u64, u64)>) -> io::Result<()> {
+        if node.link_key.is_some_and(|key| !seen.insert(key)) {
+            return Ok(());
+        }
+        self.inodes += 1;
+        let bs = geo.block_size as u64;
+        let data = match &node.kind {
+            Kind::Dir(children) => {
+                let names: Vec<(&[u8], u32, u8)> = [b".".as_slice(), b".."]
+                    .into_iter()
+                    .chain(children.iter().map(|c| c.name.as_slice()))
+                    .map(|n| (n, 0, 0))
+                    .collect();
+                for child in children {
+                    self.count(child, geo, seen)?;
+                }
+                pack_dir(&names, bs as usize).len() as u64 / bs
+            }
+            Kind::File { size } => size.div_ceil(bs),
+            Kind::Symlink(target) if target.len() < FAST_SYMLINK_MAX => 0,
+            Kind::Symlink(target) if target.len() < bs as usize => 1,
+            Kind::Symlink(_) => {
+                return Err(invalid(format!("{}: symlink target is longer than a block", node.host.display())));
+            }
+        };
+        self.blocks += geo
+            .blocks_for(data)
+            .ok_or_else(|| invalid(format!("{}: too large for {}-byte blocks", node.host.display(), bs)))?;
+        Ok(())
+    }
+}
+
+// --- Writing ---
+
+struct Writer<'a> {
+    file: File,
+    geo: &'a Geometry,
+    timestamp: Option<u32>,
+    /// One bit per block from `first_data_block`, a group's worth per block
+    /// bitmap block
+    block_bitmap: Vec<u8>,
+    /// One bitmap block per group
+    inode_bitmap: Vec<u8>,
+    used_dirs: Vec<u32>,
+    next_block: usize,
+    next_inode: u32,
+    /// Image inodes of host files with several links
+    linked: HashMap<(u64, u64), u32>,
+    summary: Summary,
+}
+
+impl<'a> Writer<'a> {
+    fn new(file: File, geo: &'a Geometry, timestamp: Option<u32>) -> Self {
+        let bs = geo.block_size as usize;
+        let mut w = Writer {
+            file,
+            geo,
+            timestamp,
+            block_bitmap: vec![0; geo.groups as usize * bs],
+            inode_bitmap: vec![0; geo.groups as usize * bs],
+            used_dirs: vec![0; geo.groups as usize],
+            next_block: 0,
+            next_inode: GOOD_OLD_FIRST_INO,
+            linked: HashMap::new(),
+            summary: Summary {
+                blocks: geo.blocks_count,
+                free_blocks: 0,
+                inodes: geo.inodes_count() as u32,
+                free_inodes: 0,
+                files: 0,
+                dirs: 0,
+                symlinks: 0,
+            },
+        };
+        // Blocks past the end of the last group, and inodes past the end of
+        // each group, don't exist: their bits stay set
+        let valid = (geo.blocks_count - geo.first_data_block) as usize;
+        for i in valid..w.block_bitmap.len() * 8 {
+            set_bit(&mut w.block_bitmap, i);
+        }
+        for g in 0..geo.groups as usize {
+            let metadata = geo.overhead(g as u32) as usize;
+            let start = g * geo.blocks_per_group as usize;
+            (start..start + metadata).for_each(|i| set_bit(&mut w.block_bitmap, i));
+            let map = &mut w.inode_bitmap[g * bs..(g + 1) * bs];
+            (geo.inodes_per_group as usize..bs * 8).for_each(|i| set_bit(map, i));
+        }
+        // The reserved inodes, root included
+        (0..GOOD_OLD_FIRST_INO as usize - 1).for_each(|i| set_bit(&mut w.inode_bitmap, i));
+        w
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(data)
+    }
+
+    fn write_block(&mut self, block: u32, data: &[u8]) -> io::Result<()> {
+        self.write_at(block as u64 * self.geo.block_size as u64, data)
+    }
+
+    fn alloc_block(&mut self) -> io::Result<u32> {
+        let total = (self.geo.blocks_count - self.geo.first_data_block) as usize;
+        while self.next_block < total && test_bit(&self.block_bitmap, self.next_block) {
+            self.next_block += 1;
+        }
+        if self.next_block == total {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "the image is full"));
+        }
+        set_bit(&mut self.block_bitmap, self.next_block);
+        Ok(self.geo.first_data_block + self.next_block as u32)
+    }
+
+    fn alloc_inode(&mut self, dir: bool) -> io::Result<u32> {
+        let ino = self.next_inode;
+        if ino as u64 > self.geo.inodes_count() {
+            return Err(io::Error::new(io::ErrorKind::StorageFull, "out of inodes"));
+        }
+        self.next_inode += 1;
+        self.mark_inode(ino, dir);
+        Ok(ino)
+    }
+
+    fn mark_inode(&mut self, ino: u32, dir: bool) {
+        let ipg = self.geo.inodes_per_group;
+        let group = (ino - 1) / ipg;
+        let bit_index = group as usize * self.geo.block_size as usize * 8 + ((ino - 1) % ipg) as usize;
+        set_bit(&mut self.inode_bitmap, bit_index);
+        if dir {
+            self.used_dirs[group as usize] += 1;
+        }
+    }
+
+    fn inode_offset(&self, ino: u32) -> u64 {
+        let ipg = self.geo.inodes_per_group;
+        let table = self.geo.inode_table((ino - 1) / ipg) as u64 * self.geo.block_size as u64;
+        table + ((ino - 1) % ipg) as u64 * INODE_SIZE as u64
+    }
+
+    fn write_inode(&mut self, ino: u32, inode: &Inode) -> io::Result<()> {
+        self.write_at(self.inode_offset(ino), &inode.encode())
+    }
+
+    /// Add a link to an inode already written
+    fn bump_links(&mut self, ino: u32) -> io::Result<()> {
+        let at = self.inode_offset(ino) + 0x1A;
+        let mut links = [0u8; 2];
+        self.file.seek(SeekFrom::Start(at))?;
+        self.file.read_exact(&mut links)?;
+        let links = u16::from_le_bytes(links).saturating_add(1);
+        self.write_at(at, &links.to_le_bytes())
+    }
+
+    /// Allocate `count` data blocks behind `inode`'s pointers, writing any
+    /// indirect blocks. Returns the data blocks in file order.
+    fn map_blocks(&mut self, inode: &mut Inode, count: u64) -> io::Result<Vec<u32>> {
+        let mut data = Vec::with_capacity(count as usize);
+        let mut meta = 0;
+        for i in 0..count.min(DIRECT_BLOCKS as u64) as usize {
+            let block = self.alloc_block()?;
+            inode.block[i] = block;
+            data.push(block);
+        }
+        let mut left = count.saturating_sub(DIRECT_BLOCKS as u64);
+        for (level, slot) in [(1, IND_BLOCK), (2, DIND_BLOCK), (3, TIND_BLOCK)] {
+            if left == 0 {
+                break;
+            }
+            let take = left.min(self.geo.pointers().pow(level));
+            let block = self.indirect(level, take, &mut data, &mut meta)?;
+            inode.block[slot] = block;
+            left -= take;
+        }
+        inode.blocks = ((data.len() as u64 + meta) * (self.geo.block_size as u64 / 512)) as u32;
+        Ok(data)
+    }
+
+    /// An indirect block `level` deep over `count` data blocks
+    fn indirect(&mut self, level: u32, count: u64, data: &mut Vec<u32>, meta: &mut u64) -> io::Result<u32> {
+        let block = self.alloc_block()?;
+        *meta += 1;
+        let mut pointers = vec![0u8; self.geo.block_size as usize];
+        let per_child = self.geo.pointers().pow(level - 1);
+        let mut left = count;
+        for slot in pointers.chunks_exact_mut(4) {
+            if left == 0 {
+                break;
+            }
+            let take = left.min(per_child);
+            let child = if level == 1 {
+                let b = self.alloc_block()?;
+                data.push(b);
+                b
+            } else {
+                self.indirect(level - 1, take, data, meta)?
+            };
+            slot.copy_from_slice(&child.to_le_bytes());
+            left -= take;
+        }
+        self.write_block(block, &pointers)?;
+        Ok(block)
+    }
+
+    /// Copy `len` bytes from `src` into `blocks`, a run of adjacent blocks
+    /// per write
+    fn write_data(&mut self, blocks: &[u32], src: &mut dyn Read, mut len: u64) -> io::Result<()> {
+        let bs = self.geo.block_size as usize;
+        let mut buf = vec![0u8; RUN_BLOCKS * bs];
+        let mut i = 0;
+        while i < blocks.len() {
+            let mut run = 1;
+            while run < RUN_BLOCKS && i + run < blocks.len() && blocks[i + run] == blocks[i] + run as u32 {
+                run += 1;
+            }
+            let bytes = (run * bs).min(len as usize);
+            buf[..run * bs].fill(0);
+            src.read_exact(&mut buf[..bytes])?;
+            self.write_block(blocks[i], &buf[..run * bs])?;
+            len -= bytes as u64;
+            i += run;
+        }
+        Ok(())
+    }
+
+    fn base_inode(&self, node: &Node, type_bits: u16) -> Inode {
+        let [atime, mtime, ctime] = match self.timestamp {
+            Some(t) => [t; 3],
+            None => [node.atime, node.mtime, node.ctime],
+        };
+        Inode { mode: type_bits | node.perm, atime, mtime, ctime, links_count: 1, ..Default::default() }
+    }
+
+    /// Write `node` and everything under it, in the directory `parent`
+    /// (`None` for the root); returns its inode
+    fn write_node(&mut self, node: &Node, parent: Option<u32>) -> io::Result<u32> {
+        if let Some(key) = node.link_key {
+            if let Some(&ino) = self.linked.get(&key) {
+                self.bump_links(ino)?;
+                return Ok(ino);
+            }
+        }
+        let bs = self.geo.block_size as u64;
+        let ino = match &node.kind {
+            Kind::Dir(children) => {
+                let ino = match parent {
+                    Some(_) => self.alloc_inode(true)?,
+                    None => {
+                        self.mark_inode(ROOT_INODE, true);
+                        ROOT_INODE
+                    }
+                };
+                let mut entries = vec![(b".".as_slice(), ino, FT_DIR), (b"..".as_slice(), parent.unwrap_or(ino), FT_DIR)];
+                let mut subdirs = 0;
+                for child in children {
+                    let file_type = match child.kind {
+                        Kind::Dir(_) => {
+                            subdirs += 1;
+                            FT_DIR
+                        }
+                        Kind::File { .. } => FT_REG_FILE,
+                        Kind::Symlink(_) => FT_SYMLINK,
+                    };
+                    let child_ino = self.write_node(child, Some(ino))?;
+                    entries.push((child.name.as_slice(), child_ino, file_type));
+                }
+                let data = pack_dir(&entries, bs as usize);
+                let mut inode = self.base_inode(node, S_IFDIR);
+                inode.links_count = 2 + subdirs;
+                inode.size = data.len() as u64;
+                let blocks = self.map_blocks(&mut inode, data.len() as u64 / bs)?;
+                self.write_data(&blocks, &mut data.as_slice(), inode.size)?;
+                self.write_inode(ino, &inode)?;
+                self.summary.dirs += 1;
+                ino
+            }
+            Kind::File { size } => {
+                let ino = self.alloc_inode(false)?;
+                let mut inode = self.base_inode(node, S_IFREG);
+                inode.size = *size;
+                let blocks = self.map_blocks(&mut inode, size.div_ceil(bs))?;
+                let mut src = File::open(&node.host)?;
+                self.write_data(&blocks, &mut src, *size).map_err(|e| {
+                    io::Error::new(e.kind(), format!("{}: {}", node.host.display(), e))
+                })?;
+                self.write_inode(ino, &inode)?;
+                self.summary.files += 1;
+                ino
+            }
+            Kind::Symlink(target) => {
+                let ino = self.alloc_inode(false)?;
+                let mut inode = self.base_inode(node, S_IFLNK);
+                inode.size = target.len() as u64;
+                if target.len() < FAST_SYMLINK_MAX {
+                    inode.set_fast_symlink(target);
+                } else {
+                    let blocks = self.map_blocks(&mut inode, 1)?;
+                    self.write_data(&blocks, &mut target.as_slice(), inode.size)?;
+                }
+                self.write_inode(ino, &inode)?;
+                self.summary.symlinks += 1;
+                ino
+            }
+        };
+        if let Some(key) = node.link_key {
+            self.linked.insert(key, ino);
+        }
+        Ok(ino)
+    }
+
+    /// Write the bitmaps, group descriptors and superblock copies
+    fn finish(mut self, uuid: [u8; 16], time: u32) -> io::Result<Summary> {
+        let geo = self.geo;
+        let bs = geo.block_size as usize;
+        let mut descriptors = vec![0u8; geo.gdt_blocks as usize * bs];
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for g in 0..geo.groups {
+            let range = g as usize * bs..(g as usize + 1) * bs;
+            let group_free_blocks = (0..geo.group_len(g) as usize)
+                .filter(|&i| !test_bit(&self.block_bitmap[range.clone()], i))
+                .count() as u32;
+            let group_free_inodes = (0..geo.inodes_per_group as usize)
+                .filter(|&i| !test_bit(&self.inode_bitmap[range.clone()], i))
+                .count() as u32;
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+            let desc = GroupDescriptor {
+                block_bitmap: geo.block_bitmap(g) as u64,
+                inode_bitmap: geo.inode_bitmap(g) as u64,
+                inode_table: geo.inode_table(g) as u64,
+                free_blocks_count: group_free_blocks,
+                free_inodes_count: group_free_inodes,
+                used_dirs_count: self.used_dirs[g as usize],
+                flags: 0,
+            };
+            let at = g as usize * GROUP_DESC_SIZE;
+            descriptors[at..at + GROUP_DESC_SIZE].copy_from_slice(&desc.encode());
+            let bitmaps = [self.block_bitmap[range.clone()].to_vec(), self.inode_bitmap[range].to_vec()];
+            self.write_block(geo.block_bitmap(g), &bitmaps[0])?;
+            self.write_block(geo.inode_bitmap(g), &bitmaps[1])?;
+        }
+
+        let mut sb = Superblock {
+            inodes_count: geo.inodes_count() as u32,
+            blocks_count: geo.blocks_count,
+            free_blocks_count: free_blocks,
+            free_inodes_count: free_inodes,
+            first_data_block: geo.first_data_block,
+            log_block_size: geo.block_size.trailing_zeros() - 10,
+            blocks_per_group: geo.blocks_per_group,
+            inodes_per_group: geo.inodes_per_group,
+            wtime: time,
+            max_mnt_count: u16::MAX, // no mount-count forced checks
+            magic: EXT2_MAGIC,
+            state: STATE_CLEAN,
+            errors: ERRORS_CONTINUE,
+            lastcheck: time,
+            rev_level: DYNAMIC_REV,
+            first_ino: GOOD_OLD_FIRST_INO,
+            inode_size: INODE_SIZE as u16,
+            feature_incompat: FEATURE_INCOMPAT_FILETYPE,
+            feature_ro_compat: FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_LARGE_FILE,
+            uuid,
+            mkfs_time: time,
+            ..Default::default()
+        };
+        for g in (0..geo.groups).filter(|&g| geo.has_super(g)) {
+            sb.block_group_nr = g as u16;
+            let start = geo.group_start(g);
+            let at = if g == 0 { SUPERBLOCK_OFFSET } else { start as u64 * bs as u64 };
+            self.write_at(at, &sb.encode())?;
+            self.write_block(start + 1, &descriptors)?;
+        }
+        self.file.set_len(geo.blocks_count as u64 * bs as u64)?;
+        self.file.sync_all()?;
+
+        self.summary.free_blocks = free_blocks;
+        self.summary.free_inodes = free_inodes;
+        Ok(self.summary)
+    }
+}
+
+/// A volume UUID: random, or derived from the fixed timestamp so
+/// reproducible builds get the same one
+fn uuid(timestamp: Option<u32>) -> [u8; 16] {
+    use std::hash::{BuildHasher, Hash, Hasher};
+    let half = |salt: u8| {
+        let mut h = match timestamp {
+            Some(_) => std::hash::BuildHasherDefault::<std::collections::hash_map::DefaultHasher>::default().build_hasher(),
+            None => std::collections::hash_map::RandomState::new().build_hasher(),
+        };
+        ("mkext2", salt, timestamp).hash(&mut h);
+        h.finish().to_le_bytes()
+    };
+    let mut uuid = [0u8; 16];
+    uuid[..8].copy_from_slice(&half(0));
+    uuid[8..].copy_from_slice(&half(1));
+    uuid[6] = (uuid[6] & 0x0F) | 0x40; // version 4
+    uuid[8] = (uuid[8] & 0x3F) | 0x80; // RFC 4122 variant
+    uuid
+}
+
+/// Build an image at `output` holding the tree under `source`, or just an
+/// empty root without one
+pub fn build(source: Option<&Path>, output: &Path, opts: &Options) -> io::Result<Summary> {
+    let bs = opts.block_size;
+    if ![1024, 2048, 4096].contains(&bs) {
+        return Err(invalid(format!("block size must be 1024, 2048 or 4096, not {}", bs)));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| clamp_time(d.as_secs() as i64));
+    let time = opts.timestamp.unwrap_or(now);
+
+    let mut root = match source {
+        Some(path) => {
+            let root = scan(path, Vec::new())?;
+            match root {
+                Some(root @ Node { kind: Kind::Dir(_), .. }) => root,
+                _ => return Err(invalid(format!("{} is not a directory", path.display()))),
+            }
+        }
+        None => empty_dir(b"", 0o755, time),
+    };
+    if let Kind::Dir(children) = &mut root.kind {
+        if !children.iter().any(|c| c.name == b"lost+found") {
+            let at = children.partition_point(|c| c.name.as_slice() < b"lost+found".as_slice());
+            children.insert(at, empty_dir(b"lost+found", 0o700, time));
+        }
+    }
+
+    // Any geometry of this block size agrees on how many blocks a file takes
+    let probe = Geometry::new(64, bs, 1)?;
+    let mut needs = Needs::default();
+    needs.count(&root, &probe, &mut HashSet::new())?;
+    // Root is a reserved inode
+    let needed_inodes = needs.inodes - 1;
+
+    let inodes_for = |blocks: u64| -> u64 {
+        opts.inodes.map(u64::from).unwrap_or_else(|| {
+            let spare = needed_inodes + needed_inodes / 4 + 16;
+            (blocks * bs as u64 / BYTES_PER_INODE).max(GOOD_OLD_FIRST_INO as u64 - 1 + spare)
+        })
+    };
+    let fits = |geo: &Geometry| {
+        geo.data_blocks() >= needs.blocks && geo.inodes_count() >= GOOD_OLD_FIRST_INO as u64 - 1 + needed_inodes
+    };
+    let geo = match opts.size {
+        Some(size) => {
+            let geo = Geometry::new(size / bs as u64, bs, inodes_for(size / bs as u64))?;
+            if geo.data_blocks() < needs.blocks {
+                return Err(invalid(format!(
+                    "the tree needs {} blocks, but the image only has room for {}",
+                    needs.blocks,
+                    geo.data_blocks()
+                )));
+            }
+            if !fits(&geo) {
+                return Err(invalid(format!(
+                    "the tree needs {} inodes, but the image only has {}",
+                    needed_inodes,
+                    geo.inodes_count() - (GOOD_OLD_FIRST_INO as u64 - 1)
+                )));
+            }
+            geo
+        }
+        None => {
+            let mut blocks = (needs.blocks + needs.blocks / 4 + 64).max(MIN_AUTO_SIZE / bs as u64);
+            loop {
+                let geo = Geometry::new(blocks, bs, inodes_for(blocks))?;
+                if fits(&geo) {
+                    break geo;
+                }
+                blocks += blocks / 8;
+            }
+        }
+    };
+
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(output)?;
+    let mut writer = Writer::new(file, &geo, opts.timestamp);
+    writer.write_node(&root, None)?;
+    writer.finish(uuid(opts.timestamp), time)
+}
@@ -0,0 +1,173 @@
+//! Looking inside an image: listing a tree and extracting it to the host
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+use aether_ext2::*;
+use crate::fs::Image;
+
+/// `ls -l` style type and permission letters
+fn mode_string(mode: u16) -> String {
+    let kind = match mode & S_IFMT {
+        S_IFDIR => 'd',
+        S_IFLNK => 'l',
+        S_IFCHR => 'c',
+        S_IFBLK => 'b',
+        S_IFIFO => 'p',
+        S_IFSOCK => 's',
+        S_IFREG => '-',
+        _ => '?',
+    };
+    let mut s = String::from(kind);
+    for (shift, special, lower, upper) in [(6, 0o4000, 's', 'S'), (3, 0o2000, 's', 'S'), (0, 0o1000, 't', 'T')] {
+        let bits = (mode >> shift) & 7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => lower,
+            (false, true) => upper,
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+/// `YYYY-MM-DD HH:MM` in UTC
+fn time_string(secs: u32) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, rem / 3600, rem % 3600 / 60)
+}
+
+/// Print the tree at `path` in the image, one line per inode:
+/// number, mode, links, owner, size, mtime and path
+pub fn list(img: &mut Image, path: &str) -> io::Result<()> {
+    let ino = img.lookup(path)?;
+    let shown = format!("/{}", path.trim_matches('/'));
+    let mut out = BufWriter::new(io::stdout().lock());
+    list_inode(img, ino, &shown, &mut out)?;
+    out.flush()
+}
+
+fn list_inode(img: &mut Image, ino: u32, path: &str, out: &mut dyn Write) -> io::Result<()> {
+    let inode = img.inode(ino)?;
+    write!(
+        out,
+        "{:>7} {} {:>3} {:>5}:{:<5} {:>10} {} {}",
+        ino,
+        mode_string(inode.mode),
+        inode.links_count,
+        inode.uid,
+        inode.gid,
+        inode.size,
+        time_string(inode.mtime),
+        path
+    )?;
+    if inode.is_symlink() {
+        write!(out, " -> {}", String::from_utf8_lossy(&img.read_data(&inode)?))?;
+    }
+    writeln!(out)?;
+
+    if inode.is_dir() {
+        let mut entries = img.read_dir(ino)?;
+        entries.retain(|e| e.name != b"." && e.name != b"..");
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        for entry in entries {
+            let name = String::from_utf8_lossy(&entry.name);
+            let child = if path == "/" { format!("/{}", name) } else { format!("{}/{}", path, name) };
+            list_inode(img, entry.inode, &child, out)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the tree at `path` in the image to `dest` on the host, keeping
+/// permissions and modification times. Devices, FIFOs and sockets are
+/// skipped with a warning.
+pub fn extract(img: &mut Image, path: &str, dest: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(dest).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", dest.display())));
+    }
+    let ino = img.lookup(path)?;
+    extract_inode(img, ino, dest)
+}
+
+fn extract_inode(img: &mut Image, ino: u32, dest: &Path) -> io::Result<()> {
+    let inode = img.inode(ino)?;
+    let mtime = UNIX_EPOCH + Duration::from_secs(inode.mtime as u64);
+    match inode.mode & S_IFMT {
+        S_IFREG => {
+            let mut file = File::create(dest)?;
+            img.copy_data(&inode, &mut BufWriter::new(&mut file))?;
+            file.set_modified(mtime)?;
+        }
+        S_IFDIR => {
+            fs::create_dir(dest)?;
+            for entry in img.read_dir(ino)? {
+                if entry.name != b"." && entry.name != b".." {
+                    let name = String::from_utf8_lossy(&entry.name).into_owned();
+                    extract_inode(img, entry.inode, &dest.join(name))?;
+                }
+            }
+            // After the children, whose creation would bump it
+            set_dir_mtime(dest, mtime)?;
+        }
+        S_IFLNK => {
+            let target = img.read_data(&inode)?;
+            return symlink(&target, dest);
+        }
+        _ => {
+            eprintln!("mkext2: skipping {}: not a file, directory or symlink", dest.display());
+            return Ok(());
+        }
+    }
+    set_permissions(dest, inode.mode)
+}
+
+#[cfg(unix)]
+fn set_dir_mtime(dest: &Path, mtime: std::time::SystemTime) -> io::Result<()> {
+    File::open(dest)?.set_modified(mtime)
+}
+
+#[cfg(not(unix))]
+fn set_dir_mtime(_dest: &Path, _mtime: std::time::SystemTime) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_permissions(dest: &Path, mode: u16) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(dest, fs::Permissions::from_mode((mode & 0o7777) as u32))
+}
+
+#[cfg(not(unix))]
+fn set_permissions(dest: &Path, mode: u16) -> io::Result<()> {
+    let mut perms = fs::metadata(dest)?.permissions();
+    perms.set_readonly(mode & 0o222 == 0);
+    fs::set_permissions(dest, perms)
+}
+
+#[cfg(unix)]
+fn symlink(target: &[u8], dest: &Path) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(std::ffi::OsStr::from_bytes(target), dest)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &[u8], dest: &Path) -> io::Result<()> {
+    eprintln!("mkext2: skipping symlink {}: not supported on this host", dest.display());
+    Ok(())
+}
@@ -1,307 +1,209 @@
-use std::fs::File;
-use std::io::{Write, Seek, SeekFrom};
-
-const BLOCK_SIZE: usize = 1024;
-const BLOCK_COUNT: u32 = 1024; // 1MB disk
-const INODES_COUNT: u32 = 32;
-
-// Utility to convert struct to bytes
-unsafe fn as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        std::mem::size_of::<T>(),
-    )
+//! mkext2: build an ext2 disk image for the guest from a host directory
+//!
+//! ```text
+//! mkext2 [OPTIONS] [SOURCE]
+//!   -o, --output FILE      image to write (default disk.img)
+//!   -s, --size SIZE        image size in bytes, or with a K, M or G suffix
+//!                          (default: the tree plus a quarter, at least 1M)
+//!   -b, --block-size N     1024, 2048 or 4096 (default 1024)
+//!   -N, --inodes N         inode count (default: one per 8K, or what fits the tree)
+//!   -t, --timestamp SECS   stamp every inode with this time, for reproducible
+//!                          images (default: $SOURCE_DATE_EPOCH if set)
+//! ```
+//!
+//! Without a SOURCE the image holds an empty root. Files keep their host
+//! permissions and times but are owned by root.
+//!
+//! Subcommands look inside or change an existing image, from mkext2 or
+//! from mke2fs; PATHs are inside the image, from its root:
+//!
+//! ```text
+//! mkext2 list IMAGE [PATH]              the tree under PATH with its metadata
+//! mkext2 extract IMAGE PATH [DEST]      copy PATH out to DEST (default: its name)
+//! mkext2 add IMAGE HOST_PATH PATH       copy a host file or tree in as PATH
+//! mkext2 replace IMAGE HOST_FILE PATH   overwrite the file at PATH
+//! mkext2 delete [-r] IMAGE PATH         remove PATH, with -r a whole tree
+//! mkext2 fsck IMAGE                     check bitmaps, counts and directories
+//! ```
+//!
+//! Images from mkfs.ext4 (extents, 64bit, flex_bg) can be listed, extracted
+//! and checked, but not changed.
+
+mod fs;
+mod fsck;
+mod image;
+mod inspect;
+
+#[cfg(test)]
+mod tests;
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use fs::Image;
+
+const SUBCOMMANDS: [&str; 6] = ["list", "extract", "add", "replace", "delete", "fsck"];
+
+const USAGE: &str = "usage: mkext2 [-o FILE] [-s SIZE] [-b BLOCK_SIZE] [-N INODES] [-t SECS] [SOURCE]
+       mkext2 list IMAGE [PATH]
+       mkext2 extract IMAGE PATH [DEST]
+       mkext2 add IMAGE HOST_PATH PATH
+       mkext2 replace IMAGE HOST_FILE PATH
+       mkext2 delete [-r] IMAGE PATH
+       mkext2 fsck IMAGE";
+
+fn fail(msg: impl std::fmt::Display) -> ! {
+    eprintln!("mkext2: {}", msg);
+    exit(1);
 }
 
-#[repr(C)]
-#[derive(Debug)]
-struct Superblock {
-    inodes_count: u32,
-    blocks_count: u32,
-    r_blocks_count: u32,
-    free_blocks_count: u32,
-    free_inodes_count: u32,
-    first_data_block: u32,
-    log_block_size: u32,
-    log_frag_size: u32,
-    blocks_per_group: u32,
-    frags_per_group: u32,
-    inodes_per_group: u32,
-    mtime: u32,
-    wtime: u32,
-    mnt_count: u16,
-    max_mnt_count: u16,
-    magic: u16,
-    state: u16,
-    errors: u16,
-    minor_rev_level: u16,
-    lastcheck: u32,
-    checkinterval: u32,
-    creator_os: u32,
-    rev_level: u32,
-    def_resuid: u16,
-    def_resgid: u16,
-    // ... padding
-    padding: [u8; 940],
+fn parse_size(s: &str) -> Option<u64> {
+    let (digits, unit) = match s.char_indices().last()? {
+        (i, 'K' | 'k') => (&s[..i], 1 << 10),
+        (i, 'M' | 'm') => (&s[..i], 1 << 20),
+        (i, 'G' | 'g') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(unit)
 }
 
-impl Default for Superblock {
-    fn default() -> Self {
-        unsafe { std::mem::zeroed() }
+/// Run a subcommand on an existing image
+fn run(command: &str, mut args: Vec<String>) -> io::Result<()> {
+    let recursive = command == "delete" && args.first().is_some_and(|a| a == "-r");
+    if recursive {
+        args.remove(0);
     }
-}
-
-#[repr(C)]
-#[derive(Debug)]
-struct GroupDescriptor {
-    block_bitmap: u32,
-    inode_bitmap: u32,
-    inode_table: u32,
-    free_blocks_count: u16,
-    free_inodes_count: u16,
-    used_dirs_count: u16,
-    pad: u16,
-    reserved: [u32; 3],
-}
-
-impl Default for GroupDescriptor {
-    fn default() -> Self {
-        unsafe { std::mem::zeroed() }
+    let arity = match command {
+        "list" => 1..=2,
+        "extract" => 2..=3,
+        "add" | "replace" => 3..=3,
+        "delete" => 2..=2,
+        _ => 1..=1,
+    };
+    if !arity.contains(&args.len()) || args.iter().any(|a| a.starts_with('-')) {
+        fail(USAGE);
     }
-}
-
-#[repr(C)]
-#[derive(Debug)]
-struct Inode {
-    mode: u16,
-    uid: u16,
-    size: u32,
-    atime: u32,
-    ctime: u32,
-    mtime: u32,
-    dtime: u32,
-    gid: u16,
-    links_count: u16,
-    blocks: u32,
-    flags: u32,
-    osd1: u32,
-    block: [u32; 15],
-    generation: u32,
-    file_acl: u32,
-    dir_acl: u32,
-    faddr: u32,
-    osd2: [u8; 12],
-}
-
-impl Default for Inode {
-    fn default() -> Self {
-        unsafe { std::mem::zeroed() }
+    let writable = matches!(command, "add" | "replace" | "delete");
+    let mut img = Image::open(Path::new(&args[0]), writable)?;
+
+    match command {
+        "list" => inspect::list(&mut img, args.get(1).map_or("/", |p| p.as_str())),
+        "extract" => {
+            let path = &args[1];
+            let dest = match args.get(2) {
+                Some(dest) => PathBuf::from(dest),
+                None if path.trim_matches('/').is_empty() => fail("extracting the root needs a DEST"),
+                None => PathBuf::from(fs::split_path(path)?.1),
+            };
+            inspect::extract(&mut img, path, &dest)
+        }
+        "add" => {
+            let (parent, name) = fs::split_path(&args[2])?;
+            let parent = img.lookup(parent)?;
+            if img.read_dir(parent)?.iter().any(|e| e.name == name.as_bytes()) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", args[2])));
+            }
+            let node = image::scan(Path::new(&args[1]), name.as_bytes().to_vec())?
+                .ok_or_else(|| fs::invalid(format!("{} is not a file, directory or symlink", args[1])))?;
+            img.add(parent, name.as_bytes(), &node, &mut HashMap::new())?;
+            img.flush()
+        }
+        "replace" => {
+            let ino = img.lookup(&args[2])?;
+            let node = image::scan(Path::new(&args[1]), Vec::new())?
+                .ok_or_else(|| fs::invalid(format!("{} is not a regular file", args[1])))?;
+            img.replace(ino, &node)?;
+            img.flush()
+        }
+        "delete" => {
+            let (parent, name) = fs::split_path(&args[1])?;
+            let parent = img.lookup(parent)?;
+            img.delete(parent, name.as_bytes(), recursive)?;
+            img.flush()
+        }
+        _ => {
+            let problems = fsck::check(&mut img)?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                fail(format!("{}: {} problems found", args[0], problems.len()));
+            }
+            let sb = &img.sb;
+            println!(
+                "{}: clean, {}/{} inodes, {}/{} blocks",
+                args[0],
+                sb.inodes_count - sb.free_inodes_count,
+                sb.inodes_count,
+                sb.blocks_count - sb.free_blocks_count,
+                sb.blocks_count
+            );
+            Ok(())
+        }
     }
 }
 
-#[repr(C, packed)]
-#[derive(Debug)]
-struct DirEntry {
-    inode: u32,
-    rec_len: u16,
-    name_len: u8,
-    file_type: u8,
-    // Name is variable length, but we don't map it here directly
-}
-
-fn main() -> std::io::Result<()> {
-    let mut file = File::create("disk.img")?;
-    file.set_len((BLOCK_SIZE as u64) * (BLOCK_COUNT as u64))?;
-
-    // 1. Write Superblock (Block 1)
-    let mut s_block = Superblock::default();
-    s_block.inodes_count = INODES_COUNT;
-    s_block.blocks_count = BLOCK_COUNT;
-    s_block.r_blocks_count = 0;
-    s_block.free_blocks_count = BLOCK_COUNT - 10;
-    s_block.free_inodes_count = INODES_COUNT - 2;
-    s_block.first_data_block = 1;
-    s_block.log_block_size = 0;
-    s_block.log_frag_size = 0;
-    s_block.blocks_per_group = 8192;
-    s_block.frags_per_group = 8192;
-    s_block.inodes_per_group = INODES_COUNT;
-    s_block.magic = 0xEF53;
-    
-    file.seek(SeekFrom::Start(1024))?;
-    unsafe { file.write_all(as_u8_slice(&s_block))?; }
-
-    // 2. Write Group Descriptor (Block 2)
-    let mut gd = GroupDescriptor::default();
-    gd.block_bitmap = 3;
-    gd.inode_bitmap = 4;
-    gd.inode_table = 5;
-    gd.free_blocks_count = (BLOCK_COUNT - 9) as u16;
-    gd.free_inodes_count = (INODES_COUNT - 2) as u16;
-    gd.used_dirs_count = 1;
-
-    file.seek(SeekFrom::Start(2048))?;
-    unsafe { file.write_all(as_u8_slice(&gd))?; }
-
-    // 3. Write Inode Table (Block 5)
-    // Inode 2: Root Directory
-    let mut root_inode = Inode::default();
-    root_inode.mode = 0x41ED; // Directory + rwxr-xr-x
-    root_inode.size = 1024;
-    root_inode.links_count = 2; // . and ..
-    root_inode.blocks = 2;
-    root_inode.block[0] = 7; // Root Directory Data at Block 7
-    
-    // Inode 11: hello.txt
-    let mut hello_inode = Inode::default();
-    hello_inode.mode = 0x81A4; // File + rw-r--r--
-    hello_inode.size = 13;     // "Hello, World!"
-    hello_inode.links_count = 1;
-    hello_inode.blocks = 2;
-    hello_inode.block[0] = 8; // File Data at Block 8
-
-    let inode_size = std::mem::size_of::<Inode>();
-    // Offset for Inode 2 (Index 1)
-    file.seek(SeekFrom::Start(5 * 1024 + 1 * inode_size as u64))?;
-    unsafe { file.write_all(as_u8_slice(&root_inode))?; }
-
-    // Offset for Inode 11 (Index 10)
-    file.seek(SeekFrom::Start(5 * 1024 + 10 * inode_size as u64))?;
-    unsafe { file.write_all(as_u8_slice(&hello_inode))?; }
-
-    // 4. Write Root Directory Data (Block 7)
-    let mut dir_data = vec![0u8; 1024];
-    let mut w = std::io::Cursor::new(&mut dir_data);
-    
-    // .
-    w.write_all(&2u32.to_le_bytes())?; // inode
-    w.write_all(&12u16.to_le_bytes())?; // rec_len
-    w.write_all(&1u8.to_le_bytes())?; // name_len
-    w.write_all(&2u8.to_le_bytes())?; // type
-    w.write_all(b".\0\0\0")?; // name + pad
-
-    // ..
-    w.write_all(&2u32.to_le_bytes())?; // inode
-    w.write_all(&12u16.to_le_bytes())?; // rec_len
-    w.write_all(&2u8.to_le_bytes())?; // name_len
-    w.write_all(&2u8.to_le_bytes())?; // type
-    w.write_all(b"..\0\0")?; // name + pad
-
-    // hello.txt
-    let name = b"hello.txt";
-    w.write_all(&11u32.to_le_bytes())?; // inode
-    w.write_all(&(1000u16).to_le_bytes())?; // rec_len (rest of block)
-    w.write_all(&(name.len() as u8).to_le_bytes())?; // name_len
-    w.write_all(&1u8.to_le_bytes())?; // type
-    w.write_all(name)?; // name
-    
-    file.seek(SeekFrom::Start(7 * 1024))?;
-    file.write_all(&dir_data)?;
-
-    // ... (Previous write of hello.txt at Inode 11 calls) ...
-
-    // 5. Write File Data (Block 8)
-    file.seek(SeekFrom::Start(8 * 1024))?;
-    file.write_all(b"Hello, World!")?;
-
-    // --- Dynamic File Injection (test.wasm) ---
-    // Usage: mkext2 <path_to_wasm>
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() > 1 {
-        let wasm_path = &args[1];
-        let wasm_data = std::fs::read(wasm_path).expect("Failed to read input file");
-        let file_size = wasm_data.len() as u32;
-        
-        let num_blocks = (file_size + BLOCK_SIZE as u32 - 1) / BLOCK_SIZE as u32;
-        let start_block = 9; // Next free block after hello.txt (Block 8)
-        
-        if start_block + num_blocks > BLOCK_COUNT {
-             panic!("File too large for disk!");
+fn main() {
+    let mut args = std::env::args().skip(1).peekable();
+    if let Some(command) = args.next_if(|a| SUBCOMMANDS.contains(&a.as_str())) {
+        if let Err(e) = run(&command, args.collect()) {
+            fail(e);
         }
+        return;
+    }
+
+    let mut output = PathBuf::from("disk.img");
+    let mut source = None;
+    let mut opts = image::Options { size: None, block_size: 1024, inodes: None, timestamp: None };
+    if let Ok(epoch) = std::env::var("SOURCE_DATE_EPOCH") {
+        opts.timestamp = Some(epoch.parse().unwrap_or_else(|_| fail("SOURCE_DATE_EPOCH is not a time in seconds")));
+    }
 
-        // Write Inode 12
-        let mut wasm_inode = Inode::default();
-        wasm_inode::mode = 0x81A4;
-        wasm_inode::size = file_size;
-        wasm_inode::links_count = 1;
-        wasm_inode::blocks = num_blocks * 2; // Sectors? usually blocks * (block_size/512). Ext2 `blocks` field is in 512-byte sectors!
-        // Wait, standard linux ext2 `i_blocks` is 512-byte sectors.
-        // My previous code: `root_inode.blocks = 2`. root dir is 1024 bytes (1 block). 1 block = 2 sectors. Correct.
-        // So `blocks` = `num_blocks * 2`.
-        
-        for i in 0..num_blocks {
-             if i < 12 { // Only direct blocks support in this simple tool
-                 wasm_inode::block[i as usize] = start_block + i;
-             }
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| args.next().unwrap_or_else(|| fail(format!("{} needs {}", arg, what)));
+        match arg.as_str() {
+            "-o" | "--output" => output = value("a file").into(),
+            "-s" | "--size" => {
+                let v = value("a size");
+                opts.size = Some(parse_size(&v).unwrap_or_else(|| fail(format!("bad size {}", v))));
+            }
+            "-b" | "--block-size" => {
+                let v = value("a block size");
+                opts.block_size = v.parse().unwrap_or_else(|_| fail(format!("bad block size {}", v)));
+            }
+            "-N" | "--inodes" => {
+                let v = value("a count");
+                opts.inodes = Some(v.parse().unwrap_or_else(|_| fail(format!("bad inode count {}", v))));
+            }
+            "-t" | "--timestamp" => {
+                let v = value("a time");
+                opts.timestamp = Some(v.parse().unwrap_or_else(|_| fail(format!("bad timestamp {}", v))));
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => fail(format!("unknown option {}\n{}", arg, USAGE)),
+            _ if source.is_none() => source = Some(PathBuf::from(arg)),
+            _ => fail(USAGE),
         }
-        
-        // Write Inode 12 (Index 11)
-        file.seek(SeekFrom::Start(5 * 1024 + 11 * inode_size as u64))?;
-        unsafe { file.write_all(as_u8_slice(&wasm_inode))?; }
-        
-        // Add Directory Entry
-        // We append to Root Dir Data (Block 7).
-        // Previous contents: ., .., hello.txt
-        
-        // Re-read Block 7 (or just simulate append since we know offset)
-        // . (12 bytes), .. (12 bytes), hello.txt (rec_len=1000).
-        // Effectively `hello.txt` claimed the rest of the block.
-        // We need to split the last entry or just rewrite the dir block.
-        
-        // Rewrite Block 7
-        let mut dir_data = vec![0u8; 1024];
-        let mut w = std::io::Cursor::new(&mut dir_data);
-        
-        // .
-        w.write_all(&2u32.to_le_bytes())?;
-        w.write_all(&12u16.to_le_bytes())?;
-        w.write_all(&1u8.to_le_bytes())?;
-        w.write_all(&2u8.to_le_bytes())?;
-        w.write_all(b".\0\0\0")?;
-        
-        // ..
-        w.write_all(&2u32.to_le_bytes())?;
-        w.write_all(&12u16.to_le_bytes())?;
-        w.write_all(&2u8.to_le_bytes())?;
-        w.write_all(&2u8.to_le_bytes())?;
-        w.write_all(b"..\0\0")?;
-        
-        // hello.txt
-        let name_hello = b"hello.txt";
-        let hello_len = 8 + name_hello.len() + 3; // 20 bytes -> align 4 -> 20.
-        // header(8) + name(9) + pad(3) = 20.
-        // previous header was 8? 
-        // inode(4) + rec_len(2) + name_len(1) + type(1) = 8.
-        let hello_rec_len = 20u16; 
-        
-        w.write_all(&11u32.to_le_bytes())?;
-        w.write_all(&hello_rec_len.to_le_bytes())?;
-        w.write_all(&(name_hello.len() as u8).to_le_bytes())?;
-        w.write_all(&1u8.to_le_bytes())?;
-        w.write_all(name_hello)?; 
-        w.write_all(b"\0\0\0")?; // Pad to 4 bytes boundary
-        
-        // test.wasm
-        let name_wasm = b"test.wasm";
-        let wasm_rec_len = (1024 - 12 - 12 - 20) as u16; // Rest of block
-        
-        w.write_all(&12u32.to_le_bytes())?; // Inode 12
-        w.write_all(&wasm_rec_len.to_le_bytes())?;
-        w.write_all(&(name_wasm.len() as u8).to_le_bytes())?;
-        w.write_all(&1u8.to_le_bytes())?;
-        w.write_all(name_wasm)?;
-        
-        // Write dir block
-        file.seek(SeekFrom::Start(7 * 1024))?;
-        file.write_all(&dir_data)?;
-        
-        // Write Data Blocks
-        file.seek(SeekFrom::Start((start_block as u64) * 1024))?;
-        file.write_all(&wasm_data)?;
-        
-        println!("Injected {} as test.wasm ({} bytes)", wasm_path, file_size);
     }
 
-    println!("Created disk.img (1MB EXT2)");
-    Ok(())
+    match image::build(source.as_deref(), &output, &opts) {
+        Ok(s) => println!(
+            "Created {}: {} blocks of {} bytes ({} free), {} inodes ({} free); {} files, {} directories, {} symlinks",
+            output.display(),
+            s.blocks,
+            opts.block_size,
+            s.free_blocks,
+            s.inodes,
+            s.free_inodes,
+            s.files,
+            s.dirs,
+            s.symlinks
+        ),
+        Err(e) => fail(e),
+    }
 }
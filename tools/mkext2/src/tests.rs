@@ -0,0 +1,195 @@
+//! Image builder and editor tests against scratch trees under the system
+//! temp dir
+use aether_ext2::*;
+use crate::fs::{split_path, Image};
+use crate::fsck::check;
+use crate::image::{build, scan, Geometry, Options};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// A fresh directory holding a small tree, plus the image path next to
+/// it, both removed on drop
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("mkext2-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("tree/docs/deep")).unwrap();
+        fs::write(dir.join("tree/hello.txt"), b"Hello, world!").unwrap();
+        // Past the direct blocks of a 1K-block image
+        let big: Vec<u8> = (0..300 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("tree/docs/big.bin"), big).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("../hello.txt", dir.join("tree/docs/link")).unwrap();
+        Scratch(dir)
+    }
+
+    fn image(&self, opts: &Options) -> Vec<u8> {
+        let path = self.0.join("disk.img");
+        build(Some(&self.0.join("tree")), &path, opts).unwrap();
+        fs::read(path).unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn opts(block_size: u32) -> Options {
+    Options { size: None, block_size, inodes: None, timestamp: Some(1_700_000_000) }
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes(b[at..at + 2].try_into().unwrap())
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(b[at..at + 4].try_into().unwrap())
+}
+
+#[test]
+fn geometry() {
+    let geo = Geometry::new(20000, 1024, 2000).unwrap();
+    assert_eq!((geo.groups, geo.first_data_block, geo.gdt_blocks), (3, 1, 1));
+    assert!(geo.inodes_count() >= 2000);
+    assert_eq!(geo.block_bitmap(0), 3);
+    assert_eq!(geo.block_bitmap(2), 1 + 2 * 8192); // group 2 has no superblock
+    assert!((0..8).map(|g| geo.has_super(g)).eq([true, true, false, true, false, true, false, true]));
+
+    // A last group too short for its own bitmaps and inode table is dropped
+    let geo = Geometry::new(8193 + 10, 1024, 4096).unwrap();
+    assert_eq!((geo.groups, geo.blocks_count), (1, 8193));
+
+    let geo = Geometry::new(64, 1024, 16).unwrap();
+    assert_eq!(geo.blocks_for(12), Some(12));
+    assert_eq!(geo.blocks_for(13), Some(14));
+    assert_eq!(geo.blocks_for(12 + 256 + 1), Some(12 + 256 + 1 + 1 + 2));
+    assert_eq!(geo.blocks_for(12 + 256 + 65536 + 16_777_216 + 1), None);
+}
+
+#[test]
+fn counts_match_bitmaps() {
+    let scratch = Scratch::new("counts");
+    for block_size in [1024, 4096] {
+        let image = scratch.image(&opts(block_size));
+        let sb = &image[1024..2048];
+        assert_eq!(u16_at(sb, 0x38), EXT2_MAGIC);
+        let bs = 1024usize << u32_at(sb, 0x18);
+        assert_eq!(bs as u32, block_size);
+        assert_eq!(image.len(), u32_at(sb, 0x04) as usize * bs);
+
+        let gdt = (u32_at(sb, 0x14) as usize + 1) * bs;
+        let free_bits = |block: u32, bits: usize| {
+            let map = &image[block as usize * bs..];
+            (0..bits).filter(|&i| map[i / 8] & (1 << (i % 8)) == 0).count() as u32
+        };
+        let (desc_blocks, desc_inodes) = (u16_at(&image, gdt + 0x0C), u16_at(&image, gdt + 0x0E));
+        let blocks_in_group = u32_at(sb, 0x04) - u32_at(sb, 0x14);
+        assert_eq!(free_bits(u32_at(&image, gdt), blocks_in_group as usize), desc_blocks as u32);
+        assert_eq!(free_bits(u32_at(&image, gdt + 4), u32_at(sb, 0x28) as usize), desc_inodes as u32);
+        assert_eq!(u32_at(sb, 0x0C), desc_blocks as u32);
+        assert_eq!(u32_at(sb, 0x10), desc_inodes as u32);
+        // root, lost+found, docs and deep
+        assert_eq!(u16_at(&image, gdt + 0x10), 4);
+    }
+}
+
+#[test]
+fn reproducible() {
+    let scratch = Scratch::new("repro");
+    let first = scratch.image(&opts(1024));
+    fs::write(scratch.0.join("tree/hello.txt"), b"Hello, world!").unwrap();
+    assert!(first == scratch.image(&opts(1024)));
+
+    // Every time in the image is the fixed one: root's atime, ctime, mtime
+    let sb = &first[1024..2048];
+    let table = u32_at(&first, 2048 + 8) as usize * 1024;
+    let root = &first[table + GOOD_OLD_INODE_SIZE..table + 2 * GOOD_OLD_INODE_SIZE];
+    for at in [0x08, 0x0C, 0x10] {
+        assert_eq!(u32_at(root, at), 1_700_000_000);
+    }
+    assert_eq!(u32_at(sb, 0x30), 1_700_000_000);
+}
+
+#[test]
+fn edit_and_check() {
+    let scratch = Scratch::new("edit");
+    scratch.image(&opts(1024));
+    let path = scratch.0.join("disk.img");
+    let mut img = Image::open(&path, true).unwrap();
+    assert!(check(&mut img).unwrap().is_empty());
+
+    let ino = img.lookup("/docs/big.bin").unwrap();
+    let inode = img.inode(ino).unwrap();
+    assert_eq!(img.read_data(&inode).unwrap(), fs::read(scratch.0.join("tree/docs/big.bin")).unwrap());
+
+    // A second copy of the tree, then the first one's files edited away
+    let node = scan(&scratch.0.join("tree"), b"copy".to_vec()).unwrap().unwrap();
+    img.add(ROOT_INODE, b"copy", &node, &mut HashMap::new()).unwrap();
+    let node = scan(&scratch.0.join("tree/hello.txt"), Vec::new()).unwrap().unwrap();
+    img.replace(ino, &node).unwrap();
+    let (parent, name) = split_path("/docs").unwrap();
+    let parent = img.lookup(parent).unwrap();
+    assert!(img.delete(parent, name.as_bytes(), false).is_err());
+    img.delete(ROOT_INODE, b"docs", true).unwrap();
+    img.flush().unwrap();
+
+    let mut img = Image::open(&path, false).unwrap();
+    assert_eq!(check(&mut img).unwrap(), Vec::<String>::new());
+    assert!(img.lookup("/docs").is_err());
+    let ino = img.lookup("/copy/docs/big.bin").unwrap();
+    assert_eq!(img.inode(ino).unwrap().size, 300 * 1024);
+}
+
+#[test]
+fn fsck_finds_damage() {
+    let scratch = Scratch::new("damage");
+    let mut image = scratch.image(&opts(1024));
+    // Free a used block in group 0's bitmap and miscount root's links
+    let gdt = 2048;
+    let bitmap = u32_at(&image, gdt) as usize * 1024;
+    image[bitmap + 8] &= !1;
+    let root = u32_at(&image, gdt + 8) as usize * 1024 + GOOD_OLD_INODE_SIZE;
+    image[root + 0x1A] += 1;
+    let path = scratch.0.join("disk.img");
+    fs::write(&path, image).unwrap();
+
+    let problems = check(&mut Image::open(&path, false).unwrap()).unwrap();
+    assert!(problems.iter().any(|p| p.contains("bitmap")), "{:?}", problems);
+    assert!(problems.iter().any(|p| p.contains("inode 2")), "{:?}", problems);
+}
+
+#[test]
+fn ext4_images_are_read_only() {
+    let scratch = Scratch::new("ext4");
+    scratch.image(&opts(1024));
+    let path = scratch.0.join("disk.img");
+    let img = Image::open(&path, false).unwrap();
+    let ino = img.lookup("/hello.txt").unwrap();
+    let block = img.inode(ino).unwrap().block[0];
+    let at = img.groups[0].inode_table as usize * 1024 + (ino as usize - 1) * img.inode_size;
+
+    // Map hello.txt through a one-extent tree instead of a block pointer
+    let mut image = fs::read(&path).unwrap();
+    let features = u32_at(&image, 1024 + 0x60) | FEATURE_INCOMPAT_EXTENTS;
+    image[1024 + 0x60..][..4].copy_from_slice(&features.to_le_bytes());
+    let flags = u32_at(&image, at + 0x20) | EXTENTS_FL;
+    image[at + 0x20..][..4].copy_from_slice(&flags.to_le_bytes());
+    let mut root = [0u8; 60];
+    root[..8].copy_from_slice(&[0x0A, 0xF3, 1, 0, 4, 0, 0, 0]);
+    root[16..18].copy_from_slice(&1u16.to_le_bytes());
+    root[20..24].copy_from_slice(&block.to_le_bytes());
+    image[at + 0x28..][..60].copy_from_slice(&root);
+    fs::write(&path, image).unwrap();
+
+    let mut img = Image::open(&path, false).unwrap();
+    assert_eq!(check(&mut img).unwrap(), Vec::<String>::new());
+    let inode = img.inode(ino).unwrap();
+    assert_eq!(img.read_data(&inode).unwrap(), b"Hello, world!");
+    let err = Image::open(&path, true).err().unwrap();
+    assert!(err.to_string().contains("incompatible features"), "{}", err);
+}
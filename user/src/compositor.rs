@@ -0,0 +1,511 @@
+/// Window Compositor - per-program surfaces, z-order, decorations and input focus
+///
+/// Each window owns an off-screen `Surface` for its client area. Programs draw
+/// into it, report what changed with `damage`, and `compose` repaints only the
+/// damaged screen regions, stacking windows bottom to top.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::font::FONT_HEIGHT;
+use crate::gfx::{rgb, BlitMode, Canvas, Color, Framebuffer, Painter, Rect, Surface};
+use crate::input::{Event, BUTTON_LEFT};
+
+pub const TITLE_HEIGHT: u32 = 20;
+pub const BORDER: u32 = 1;
+
+const BORDER_COLOR: Color = rgb(0x20, 0x20, 0x20);
+const TITLE_FOCUSED: Color = rgb(0x30, 0x50, 0xA0);
+const TITLE_UNFOCUSED: Color = rgb(0x60, 0x60, 0x60);
+const TITLE_TEXT: Color = rgb(0xFF, 0xFF, 0xFF);
+
+/// Past this many separate damage rectangles, collapse them into one
+const MAX_DAMAGE_RECTS: usize = 16;
+
+pub type WindowId = u32;
+
+pub struct Window {
+    id: WindowId,
+    title: String,
+    /// Top-left of the frame (decorations included), in screen coordinates
+    x: i32,
+    y: i32,
+    decorated: bool,
+    surface: Surface,
+}
+
+impl Window {
+    pub fn id(&self) -> WindowId { self.id }
+    pub fn title(&self) -> &str { &self.title }
+    pub fn surface(&self) -> &Surface { &self.surface }
+
+    fn inset(&self) -> (u32, u32) {
+        if self.decorated { (BORDER, BORDER + TITLE_HEIGHT) } else { (0, 0) }
+    }
+
+    /// Outer rectangle including decorations
+    pub fn frame(&self) -> Rect {
+        let (side, top) = self.inset();
+        Rect::new(
+            self.x,
+            self.y,
+            self.surface.width() as u32 + 2 * side,
+            self.surface.height() as u32 + top + side,
+        )
+    }
+
+    /// Client area (the surface) in screen coordinates
+    pub fn client_rect(&self) -> Rect {
+        let (side, top) = self.inset();
+        Rect::new(
+            self.x + side as i32,
+            self.y + top as i32,
+            self.surface.width() as u32,
+            self.surface.height() as u32,
+        )
+    }
+
+    fn title_rect(&self) -> Rect {
+        if !self.decorated {
+            return Rect::default();
+        }
+        Rect::new(self.x + BORDER as i32, self.y + BORDER as i32, self.surface.width() as u32, TITLE_HEIGHT)
+    }
+}
+
+pub struct Compositor {
+    /// Stacking order, bottom to top
+    windows: Vec<Window>,
+    focus: Option<WindowId>,
+    /// Window receiving pointer events while a button is held
+    grab: Option<WindowId>,
+    /// Window being dragged by its title bar, with the pointer offset into the frame
+    drag: Option<(WindowId, i32, i32)>,
+    damage: Vec<Rect>,
+    screen: Rect,
+    background: Color,
+    next_id: WindowId,
+}
+
+impl Compositor {
+    pub fn new(width: usize, height: usize, background: Color) -> Self {
+        let screen = Rect::new(0, 0, width as u32, height as u32);
+        Compositor {
+            windows: Vec::new(),
+            focus: None,
+            grab: None,
+            drag: None,
+            damage: alloc::vec![screen],
+            screen,
+            background,
+            next_id: 1,
+        }
+    }
+
+    /// Compositor covering the whole framebuffer
+    pub fn for_screen(background: Color) -> Self {
+        Self::new(crate::SCREEN_WIDTH, crate::SCREEN_HEIGHT, background)
+    }
+
+    // --- Windows ---
+
+    /// Create a window with a `width` x `height` client area; it becomes topmost and focused
+    pub fn create_window(&mut self, title: &str, x: i32, y: i32, width: usize, height: usize, decorated: bool) -> WindowId {
+        let id = self.next_id;
+        self.next_id += 1;
+        let window = Window {
+            id,
+            title: String::from(title),
+            x,
+            y,
+            decorated,
+            surface: Surface::filled(width, height, rgb(0, 0, 0)),
+        };
+        self.add_damage(window.frame());
+        self.windows.push(window);
+        self.set_focus(id);
+        id
+    }
+
+    pub fn close_window(&mut self, id: WindowId) {
+        if let Some(idx) = self.index_of(id) {
+            let window = self.windows.remove(idx);
+            self.add_damage(window.frame());
+            if self.focus == Some(id) {
+                self.focus = self.windows.last().map(|w| w.id);
+                if let Some(top) = self.focus {
+                    self.damage_title(top);
+                }
+            }
+            if self.grab == Some(id) { self.grab = None; }
+            if matches!(self.drag, Some((d, _, _)) if d == id) { self.drag = None; }
+        }
+    }
+
+    pub fn window(&self, id: WindowId) -> Option<&Window> {
+        self.windows.iter().find(|w| w.id == id)
+    }
+
+    /// Windows in stacking order, bottom to top
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    /// Client surface to draw into. Call `damage` afterwards.
+    pub fn surface_mut(&mut self, id: WindowId) -> Option<&mut Surface> {
+        self.windows.iter_mut().find(|w| w.id == id).map(|w| &mut w.surface)
+    }
+
+    /// Draw into a window's client surface and mark all of it as changed
+    pub fn paint<F: FnOnce(&mut Painter<Surface>)>(&mut self, id: WindowId, f: F) {
+        if let Some(surface) = self.surface_mut(id) {
+            f(&mut Painter::new(surface));
+            let full = surface.bounds();
+            self.damage(id, full);
+        }
+    }
+
+    /// Mark `rect` (in client coordinates) of a window as needing recomposition
+    pub fn damage(&mut self, id: WindowId, rect: Rect) {
+        if let Some(w) = self.window(id) {
+            let client = w.client_rect();
+            let r = rect.offset(client.x, client.y).intersect(&client);
+            self.add_damage(r);
+        }
+    }
+
+    pub fn move_window(&mut self, id: WindowId, x: i32, y: i32) {
+        if let Some(idx) = self.index_of(id) {
+            let old = self.windows[idx].frame();
+            self.windows[idx].x = x;
+            self.windows[idx].y = y;
+            let new = self.windows[idx].frame();
+            self.add_damage(old);
+            self.add_damage(new);
+        }
+    }
+
+    /// Bring a window to the top of the stack
+    pub fn raise(&mut self, id: WindowId) {
+        if let Some(idx) = self.index_of(id) {
+            if idx + 1 != self.windows.len() {
+                let window = self.windows.remove(idx);
+                self.add_damage(window.frame());
+                self.windows.push(window);
+            }
+        }
+    }
+
+    /// Give keyboard focus to a window and raise it
+    pub fn set_focus(&mut self, id: WindowId) {
+        if self.index_of(id).is_none() { return; }
+        if let Some(old) = self.focus.replace(id) {
+            self.damage_title(old);
+        }
+        self.damage_title(id);
+        self.raise(id);
+    }
+
+    pub fn focused(&self) -> Option<WindowId> {
+        self.focus
+    }
+
+    /// Topmost window whose frame contains the screen point
+    pub fn window_at(&self, x: i32, y: i32) -> Option<WindowId> {
+        self.windows.iter().rev().find(|w| w.frame().contains(x, y)).map(|w| w.id)
+    }
+
+    fn index_of(&self, id: WindowId) -> Option<usize> {
+        self.windows.iter().position(|w| w.id == id)
+    }
+
+    fn damage_title(&mut self, id: WindowId) {
+        if let Some(w) = self.window(id) {
+            let r = w.title_rect();
+            self.add_damage(r);
+        }
+    }
+
+    // --- Input ---
+
+    /// Route an input event to the window it belongs to
+    ///
+    /// Keys go to the focused window. Pointer events go to the window under
+    /// the pointer (or the one holding the grab) in client coordinates.
+    /// Clicking a window focuses and raises it; dragging a title bar moves it.
+    /// Returns `None` when the compositor consumed the event itself.
+    pub fn dispatch(&mut self, event: Event) -> Option<(WindowId, Event)> {
+        match event {
+            Event::Key(_) => self.focus.map(|id| (id, event)),
+
+            Event::PointerDown { x, y, button } => {
+                let id = self.window_at(x, y)?;
+                self.set_focus(id);
+                let w = self.window(id)?;
+                if button == BUTTON_LEFT && w.title_rect().contains(x, y) {
+                    self.drag = Some((id, x - w.x, y - w.y));
+                    return None;
+                }
+                if !w.client_rect().contains(x, y) {
+                    return None; // border
+                }
+                self.grab = Some(id);
+                self.to_client(id, event)
+            }
+
+            Event::PointerMove { x, y } => {
+                if let Some((id, dx, dy)) = self.drag {
+                    self.move_window(id, x - dx, y - dy);
+                    return None;
+                }
+                let id = match self.grab {
+                    Some(id) => id,
+                    None => self.client_at(x, y)?,
+                };
+                self.to_client(id, event)
+            }
+
+            Event::PointerUp { x, y, .. } => {
+                if self.drag.take().is_some() {
+                    return None;
+                }
+                let id = match self.grab.take() {
+                    Some(id) => id,
+                    None => self.client_at(x, y)?,
+                };
+                self.to_client(id, event)
+            }
+        }
+    }
+
+    /// Topmost window under the screen point, if the point is in its client
+    /// area rather than on its decorations
+    fn client_at(&self, x: i32, y: i32) -> Option<WindowId> {
+        let id = self.window_at(x, y)?;
+        self.window(id)?.client_rect().contains(x, y).then_some(id)
+    }
+
+    fn to_client(&self, id: WindowId, event: Event) -> Option<(WindowId, Event)> {
+        let client = self.window(id)?.client_rect();
+        Some((id, event.translate(-client.x, -client.y)))
+    }
+
+    // --- Composition ---
+
+    fn add_damage(&mut self, rect: Rect) {
+        let mut r = rect.intersect(&self.screen);
+        if r.is_empty() { return; }
+
+        // Absorb any overlapping rectangles so the list stays disjoint-ish
+        let mut i = 0;
+        while i < self.damage.len() {
+            if !self.damage[i].intersect(&r).is_empty() {
+                r = r.union(&self.damage.swap_remove(i));
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        self.damage.push(r);
+
+        if self.damage.len() > MAX_DAMAGE_RECTS {
+            let all = self.damage.iter().fold(Rect::default(), |acc, d| acc.union(d));
+            self.damage.clear();
+            self.damage.push(all);
+        }
+    }
+
+    /// Mark the whole screen for redraw
+    pub fn damage_all(&mut self) {
+        let screen = self.screen;
+        self.add_damage(screen);
+    }
+
+    pub fn has_damage(&self) -> bool {
+        !self.damage.is_empty()
+    }
+
+    /// Repaint every damaged region of `target`. Returns false if nothing changed.
+    pub fn compose<C: Canvas + ?Sized>(&mut self, target: &mut C) -> bool {
+        if self.damage.is_empty() {
+            return false;
+        }
+        let damage = core::mem::take(&mut self.damage);
+        let mut painter = Painter::new(target);
+
+        for region in damage {
+            painter.set_clip(region);
+            painter.clear(self.background);
+
+            for w in self.windows.iter() {
+                if w.frame().intersect(&region).is_empty() {
+                    continue;
+                }
+                if w.decorated {
+                    draw_decorations(&mut painter, w, self.focus == Some(w.id));
+                }
+                let client = w.client_rect();
+                painter.blit(&w.surface, client.x, client.y, BlitMode::Copy);
+            }
+        }
+        true
+    }
+
+    /// Compose damaged regions straight into the framebuffer at `FB_ADDR`
+    pub fn compose_to_framebuffer(&mut self) -> bool {
+        let mut fb = Framebuffer::new();
+        self.compose(&mut fb)
+    }
+}
+
+fn draw_decorations<C: Canvas + ?Sized>(painter: &mut Painter<C>, w: &Window, focused: bool) {
+    painter.rect(w.frame(), BORDER_COLOR);
+    let title = w.title_rect();
+    painter.fill_rect(title, if focused { TITLE_FOCUSED } else { TITLE_UNFOCUSED });
+
+    // Keep the title inside the bar
+    let clip = painter.clip();
+    painter.set_clip(clip.intersect(&title));
+    let text_y = title.y + (TITLE_HEIGHT as i32 - FONT_HEIGHT as i32) / 2;
+    painter.text(title.x + 4, text_y, &w.title, TITLE_TEXT);
+    painter.set_clip(clip);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::WHITE;
+
+    const BG: Color = rgb(0, 0, 0x40);
+    const RED: Color = rgb(255, 0, 0);
+    const GREEN: Color = rgb(0, 255, 0);
+
+    /// Two overlapping undecorated 4x4 windows on a 16x16 screen: `a` at
+    /// (2, 2) filled red and `b` at (4, 4) filled green, on top
+    fn two_windows() -> (Compositor, WindowId, WindowId) {
+        let mut c = Compositor::new(16, 16, BG);
+        let a = c.create_window("a", 2, 2, 4, 4, false);
+        let b = c.create_window("b", 4, 4, 4, 4, false);
+        c.paint(a, |p| p.clear(RED));
+        c.paint(b, |p| p.clear(GREEN));
+        (c, a, b)
+    }
+
+    fn composed(c: &mut Compositor) -> Surface {
+        let mut screen = Surface::new(16, 16);
+        c.compose(&mut screen);
+        screen
+    }
+
+    #[test]
+    fn stacking_order() {
+        let (mut c, a, b) = two_windows();
+        assert_eq!(c.focused(), Some(b));
+        let mut screen = composed(&mut c);
+        assert_eq!(screen.get(3, 3), Some(RED));
+        assert_eq!(screen.get(5, 5), Some(GREEN));
+        assert_eq!(screen.get(0, 0), Some(BG));
+        assert_eq!(c.window_at(5, 5), Some(b));
+
+        c.set_focus(a);
+        assert_eq!(c.windows().iter().map(|w| w.id()).collect::<Vec<_>>(), [b, a]);
+        assert_eq!(c.window_at(5, 5), Some(a));
+        c.compose(&mut screen);
+        assert_eq!(screen.get(5, 5), Some(RED));
+        assert_eq!(screen.get(7, 7), Some(GREEN));
+
+        // Closing the focused window focuses the one below and uncovers it
+        c.close_window(a);
+        assert_eq!(c.focused(), Some(b));
+        c.compose(&mut screen);
+        assert_eq!(screen.get(5, 5), Some(GREEN));
+        assert_eq!(screen.get(3, 3), Some(BG));
+    }
+
+    #[test]
+    fn only_damage_is_repainted() {
+        let (mut c, a, _) = two_windows();
+        let mut screen = composed(&mut c);
+        assert!(!c.has_damage());
+        assert!(!c.compose(&mut screen));
+
+        // Changes nobody reported stay off screen until damaged
+        c.surface_mut(a).unwrap().pixels_mut().fill(WHITE);
+        assert!(!c.compose(&mut screen));
+        assert_eq!(screen.get(2, 2), Some(RED));
+        c.damage(a, Rect::new(0, 0, 1, 1));
+        // Damage is clipped to the client area
+        c.damage(a, Rect::new(-10, -10, 5, 5));
+        assert!(c.compose(&mut screen));
+        assert_eq!(screen.get(2, 2), Some(WHITE));
+        assert_eq!(screen.get(3, 2), Some(RED));
+
+        // Moving repaints both the old and new places
+        c.move_window(a, 10, 10);
+        c.compose(&mut screen);
+        assert_eq!(screen.get(2, 2), Some(BG));
+        assert_eq!(screen.get(10, 10), Some(WHITE));
+    }
+
+    #[test]
+    fn damage_is_merged_and_bounded() {
+        let mut c = Compositor::new(64, 64, BG);
+        c.compose(&mut Surface::new(64, 64));
+        let id = c.create_window("w", 0, 0, 64, 64, false);
+        c.compose(&mut Surface::new(64, 64));
+
+        c.damage(id, Rect::new(0, 0, 4, 4));
+        c.damage(id, Rect::new(2, 2, 4, 4));
+        assert_eq!(c.damage, [Rect::new(0, 0, 6, 6)]);
+        for i in 0..MAX_DAMAGE_RECTS as i32 + 4 {
+            c.damage(id, Rect::new(i * 3, 40, 1, 1));
+        }
+        assert!(c.damage.len() <= MAX_DAMAGE_RECTS);
+        // Damaging everything absorbs the rest
+        c.damage_all();
+        assert_eq!(c.damage, [Rect::new(0, 0, 64, 64)]);
+    }
+
+    #[test]
+    fn pointer_routing_and_drag() {
+        let mut c = Compositor::new(100, 100, BG);
+        let a = c.create_window("a", 10, 10, 20, 20, true);
+        let b = c.create_window("b", 50, 50, 20, 20, false);
+
+        // Clicks land in client coordinates and focus the window
+        let (x, y) = (10 + BORDER as i32 + 3, 10 + (BORDER + TITLE_HEIGHT) as i32 + 4);
+        let down = c.dispatch(Event::PointerDown { x, y, button: BUTTON_LEFT });
+        assert_eq!(down, Some((a, Event::PointerDown { x: 3, y: 4, button: BUTTON_LEFT })));
+        assert_eq!(c.focused(), Some(a));
+        // The grab keeps events with `a` even over `b`
+        assert_eq!(c.dispatch(Event::PointerMove { x: 55, y: 55 }).map(|e| e.0), Some(a));
+        assert_eq!(c.dispatch(Event::PointerUp { x: 55, y: 55, button: BUTTON_LEFT }).map(|e| e.0), Some(a));
+        assert_eq!(c.dispatch(Event::Key('k')), Some((a, Event::Key('k'))));
+
+        // Dragging the title bar moves the window and is consumed
+        assert_eq!(c.dispatch(Event::PointerDown { x: 15, y: 12, button: BUTTON_LEFT }), None);
+        assert_eq!(c.dispatch(Event::PointerMove { x: 25, y: 32 }), None);
+        assert_eq!(c.dispatch(Event::PointerUp { x: 25, y: 32, button: BUTTON_LEFT }), None);
+        assert_eq!(c.window(a).unwrap().frame().x, 20);
+        assert_eq!(c.window(a).unwrap().frame().y, 30);
+
+        // Nothing under the pointer
+        assert_eq!(c.dispatch(Event::PointerDown { x: 99, y: 0, button: BUTTON_LEFT }), None);
+        assert_eq!(c.window_at(60, 60), Some(b));
+    }
+
+    #[test]
+    fn ungrabbed_release_on_decorations() {
+        let mut c = Compositor::new(100, 100, BG);
+        let a = c.create_window("a", 10, 10, 20, 20, true);
+
+        // A release with no press before it, as after a press elsewhere:
+        // the title bar and border are the compositor's, not the client's
+        assert_eq!(c.dispatch(Event::PointerUp { x: 15, y: 12, button: BUTTON_LEFT }), None);
+        assert_eq!(c.dispatch(Event::PointerUp { x: 10, y: 20, button: BUTTON_LEFT }), None);
+        assert_eq!(c.dispatch(Event::PointerUp { x: 99, y: 99, button: BUTTON_LEFT }), None);
+
+        let (x, y) = (10 + BORDER as i32 + 3, 10 + (BORDER + TITLE_HEIGHT) as i32 + 4);
+        let up = c.dispatch(Event::PointerUp { x, y, button: BUTTON_LEFT });
+        assert_eq!(up, Some((a, Event::PointerUp { x: 3, y: 4, button: BUTTON_LEFT })));
+    }
+}
@@ -231,6 +231,14 @@ pub fn print(s: &str) {
     }
 }
 
+/// Print raw bytes to the console, such as a program's output
+pub fn write(bytes: &[u8]) {
+    let console = unsafe { &mut *core::ptr::addr_of_mut!(CONSOLE) };
+    for &c in bytes {
+        console.putc(c);
+    }
+}
+
 /// Print a string with newline
 pub fn println(s: &str) {
     unsafe {
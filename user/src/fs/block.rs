@@ -0,0 +1,237 @@
+/// Block devices - byte-addressed storage that filesystems are mounted on
+use alloc::vec::Vec;
+
+use aether_abi::block::*;
+use aether_abi::mmio::{BLOCK_ADDR, DISK_ADDR, QUEUE_WINDOW};
+
+use super::FsError;
+
+/// Storage a filesystem reads and writes. Offsets are in bytes; accesses
+/// past `size()` fail with `FsError::Io` rather than touching other memory.
+pub trait BlockDevice {
+    /// Capacity in bytes
+    fn size(&self) -> u64;
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError>;
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), FsError>;
+    /// Make earlier writes durable
+    fn flush(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Byte range of `len` bytes at `offset`, if it lies within `size`
+fn range(offset: u64, len: usize, size: u64) -> Result<core::ops::Range<usize>, FsError> {
+    let end = offset.checked_add(len as u64).ok_or(FsError::Io)?;
+    if end > size {
+        return Err(FsError::Io);
+    }
+    Ok(offset as usize..end as usize)
+}
+
+/// The disk image the host copies into guest RAM at `DISK_ADDR`
+pub struct RamDisk {
+    base: *mut u8,
+    len: usize,
+}
+
+impl RamDisk {
+    /// The window from `DISK_ADDR` up to the device queues
+    pub fn guest() -> Self {
+        let base = unsafe { crate::BASE_ADDRESS } + DISK_ADDR;
+        unsafe { Self::new(base as *mut u8, QUEUE_WINDOW - DISK_ADDR) }
+    }
+
+    /// # Safety
+    /// `base..base + len` must be valid, writable memory for as long as the
+    /// disk is used, and not accessed through other references meanwhile.
+    pub unsafe fn new(base: *mut u8, len: usize) -> Self {
+        RamDisk { base, len }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn size(&self) -> u64 {
+        self.len as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let r = range(offset, buf.len(), self.size())?;
+        unsafe { core::ptr::copy_nonoverlapping(self.base.add(r.start), buf.as_mut_ptr(), buf.len()) }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let r = range(offset, data.len(), self.size())?;
+        unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), self.base.add(r.start), data.len()) }
+        Ok(())
+    }
+}
+
+/// The host's emulated disk: sector requests through the queue at
+/// `BLOCK_ADDR`, served from an image file on the host
+pub struct HostDisk {
+    base: *mut u8,
+    sectors: u64,
+    read_only: bool,
+}
+
+impl HostDisk {
+    /// Attach to the host's disk, if it has one
+    pub fn guest() -> Result<Self, FsError> {
+        let base = unsafe { crate::BASE_ADDRESS } + BLOCK_ADDR;
+        unsafe { Self::new(base as *mut u8) }
+    }
+
+    /// # Safety
+    /// `base` must point at `QUEUE_SIZE` bytes shared with the host and
+    /// used by nothing else.
+    pub unsafe fn new(base: *mut u8) -> Result<Self, FsError> {
+        let mut disk = HostDisk { base, sectors: 0, read_only: true };
+        let (sectors, flags) = disk.submit(OP_CAPACITY, 0, 0, |_| {}, |slot| {
+            let sectors = u64::from_le_bytes(slot[SECTOR..SECTOR + 8].try_into().unwrap());
+            let flags = u32::from_le_bytes(slot[COUNT..COUNT + 4].try_into().unwrap());
+            (sectors, flags)
+        })?;
+        disk.sectors = sectors;
+        disk.read_only = flags & FLAG_READ_ONLY != 0;
+        Ok(disk)
+    }
+
+    /// Whether the host refuses writes
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn header(&self, offset: usize) -> *mut u32 {
+        unsafe { self.base.add(offset) as *mut u32 }
+    }
+
+    /// Queue one request, with `fill` writing its data into the slot first,
+    /// and return what `reply` takes from the completed slot
+    fn submit<T>(
+        &self,
+        op: u32,
+        sector: u64,
+        count: usize,
+        fill: impl FnOnce(&mut [u8]),
+        reply: impl FnOnce(&[u8]) -> T,
+    ) -> Result<T, FsError> {
+        unsafe {
+            let head = self.header(HEAD).read_volatile();
+            // The queue belongs to this driver alone and the slot reference
+            // never leaves this call, so nothing else aliases it
+            let slot = core::slice::from_raw_parts_mut(self.base.add(slot_offset(head)), SLOT_SIZE);
+            slot[OP..OP + 4].copy_from_slice(&op.to_le_bytes());
+            slot[SECTOR..SECTOR + 8].copy_from_slice(&sector.to_le_bytes());
+            slot[COUNT..COUNT + 4].copy_from_slice(&(count as u32).to_le_bytes());
+            fill(&mut slot[DATA..DATA + count * SECTOR_SIZE]);
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            self.header(HEAD).write_volatile(head.wrapping_add(1));
+
+            crate::block_notify();
+
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+            if self.header(DONE).read_volatile() != head.wrapping_add(1) {
+                // Nobody answered: take the request back so the queue stays in step
+                self.header(HEAD).write_volatile(head);
+                return Err(FsError::Io);
+            }
+            match u32::from_le_bytes(slot[STATUS..STATUS + 4].try_into().unwrap()) {
+                STATUS_OK => Ok(reply(slot)),
+                STATUS_READ_ONLY => Err(FsError::ReadOnly),
+                STATUS_UNSUPPORTED => Err(FsError::Unsupported("block: operation not supported by the host")),
+                _ => Err(FsError::Io),
+            }
+        }
+    }
+}
+
+/// Sectors to move for `len` bytes starting `skip` bytes into the first one
+fn sectors_for(skip: usize, len: usize) -> usize {
+    (skip + len).div_ceil(SECTOR_SIZE).min(MAX_SECTORS)
+}
+
+impl BlockDevice for HostDisk {
+    fn size(&self) -> u64 {
+        self.sectors * SECTOR_SIZE as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        range(offset, buf.len(), self.size())?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let skip = (pos % SECTOR_SIZE as u64) as usize;
+            let count = sectors_for(skip, buf.len() - done);
+            let n = (count * SECTOR_SIZE - skip).min(buf.len() - done);
+            let dest = &mut buf[done..done + n];
+            self.submit(OP_READ, pos / SECTOR_SIZE as u64, count, |_| {}, |slot| {
+                dest.copy_from_slice(&slot[DATA + skip..DATA + skip + n]);
+            })?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        range(offset, data.len(), self.size())?;
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let sector = pos / SECTOR_SIZE as u64;
+            let skip = (pos % SECTOR_SIZE as u64) as usize;
+            let count = sectors_for(skip, data.len() - done);
+            let n = (count * SECTOR_SIZE - skip).min(data.len() - done);
+            let last = sector + count as u64 - 1;
+
+            // Sectors only partly covered keep the rest of their contents
+            let mut first_old = [0u8; SECTOR_SIZE];
+            let mut last_old = [0u8; SECTOR_SIZE];
+            let head_partial = skip != 0 || n < SECTOR_SIZE;
+            let tail_partial = count > 1 && !(skip + n).is_multiple_of(SECTOR_SIZE);
+            if head_partial {
+                self.read_at(sector * SECTOR_SIZE as u64, &mut first_old)?;
+            }
+            if tail_partial {
+                self.read_at(last * SECTOR_SIZE as u64, &mut last_old)?;
+            }
+            self.submit(OP_WRITE, sector, count, |buf| {
+                if head_partial {
+                    buf[..SECTOR_SIZE].copy_from_slice(&first_old);
+                }
+                if tail_partial {
+                    buf[(count - 1) * SECTOR_SIZE..].copy_from_slice(&last_old);
+                }
+                buf[skip..skip + n].copy_from_slice(&data[done..done + n]);
+            }, |_| {})?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), FsError> {
+        self.submit(OP_FLUSH, 0, 0, |_| {}, |_| {})
+    }
+}
+
+/// An image held on the heap (host tests, images loaded from another filesystem)
+impl BlockDevice for Vec<u8> {
+    fn size(&self) -> u64 {
+        self.len() as u64
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let r = range(offset, buf.len(), self.size())?;
+        buf.copy_from_slice(&self[r]);
+        Ok(())
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), FsError> {
+        let r = range(offset, data.len(), self.size())?;
+        self[r].copy_from_slice(data);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+/// Device filesystem - the console and input devices as files, usually mounted at `/dev`
+///
+/// `console` reads pending keystrokes and writes text to the screen.
+/// `input` reads input events as the records WASM apps get from
+/// `poll_event` (`aether_wasm_sdk::abi::encode_event`).
+/// Both read without blocking and share the keyboard: a key consumed
+/// through one is not seen by the other. Offsets are ignored.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::vfs::FileSystem;
+use super::{DirEntry, FileType, FsError, Metadata};
+use crate::input;
+use aether_wasm_sdk::abi::EVENT_SIZE;
+
+const ROOT_INODE: u32 = 1;
+
+#[derive(Clone, Copy)]
+enum Device {
+    Console,
+    Input,
+}
+
+/// Name, inode number and permission bits of each device
+const DEVICES: [(&str, u32, u16, Device); 2] = [
+    ("console", 2, 0o620, Device::Console),
+    ("input", 3, 0o440, Device::Input),
+];
+
+pub struct DevFs;
+
+impl DevFs {
+    fn device(path: &str) -> Result<(u32, u16, Device), FsError> {
+        let name = path.trim_matches('/');
+        DEVICES
+            .iter()
+            .find(|(n, ..)| *n == name)
+            .map(|&(_, inode, mode, dev)| (inode, mode, dev))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn is_root(path: &str) -> bool {
+        path.trim_matches('/').is_empty()
+    }
+}
+
+fn metadata(inode: u32, file_type: FileType, mode: u16) -> Metadata {
+    Metadata {
+        inode,
+        file_type,
+        mode,
+        size: 0,
+        uid: 0,
+        gid: 0,
+        links: 1,
+        atime: 0,
+        mtime: 0,
+        ctime: 0,
+    }
+}
+
+impl FileSystem for DevFs {
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        if Self::is_root(path) {
+            return Ok(metadata(ROOT_INODE, FileType::Directory, 0o755));
+        }
+        let (inode, mode, _) = Self::device(path)?;
+        Ok(metadata(inode, FileType::CharDevice, mode))
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        if !Self::is_root(path) {
+            Self::device(path)?;
+            return Err(FsError::NotADirectory);
+        }
+        let mut entries: Vec<DirEntry> = [(".", ROOT_INODE), ("..", ROOT_INODE)]
+            .iter()
+            .map(|&(name, inode)| DirEntry { name: name.to_string(), inode, file_type: FileType::Directory })
+            .collect();
+        entries.extend(DEVICES.iter().map(|&(name, inode, ..)| DirEntry {
+            name: name.to_string(),
+            inode,
+            file_type: FileType::CharDevice,
+        }));
+        Ok(entries)
+    }
+
+    fn read_at(&mut self, path: &str, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if Self::is_root(path) {
+            return Err(FsError::IsADirectory);
+        }
+        let mut n = 0;
+        match Self::device(path)?.2 {
+            Device::Console => {
+                let mut utf8 = [0u8; 4];
+                while n < buf.len() {
+                    let Some(c) = crate::console_getc() else { break };
+                    let bytes = c.encode_utf8(&mut utf8).as_bytes();
+                    if n + bytes.len() > buf.len() {
+                        break;
+                    }
+                    buf[n..n + bytes.len()].copy_from_slice(bytes);
+                    n += bytes.len();
+                }
+            }
+            Device::Input => {
+                if buf.len() < EVENT_SIZE {
+                    return Err(FsError::InvalidArgument);
+                }
+                // Whole records only, so nothing is lost between reads
+                while n + EVENT_SIZE <= buf.len() {
+                    let Some(event) = input::poll_event() else { break };
+                    buf[n..n + EVENT_SIZE].copy_from_slice(&event.encode());
+                    n += EVENT_SIZE;
+                }
+            }
+        }
+        Ok(n)
+    }
+
+    fn write_at(&mut self, path: &str, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        if Self::is_root(path) {
+            return Err(FsError::IsADirectory);
+        }
+        match Self::device(path)?.2 {
+            Device::Console => {
+                crate::console::print(&String::from_utf8_lossy(data));
+                Ok(data.len())
+            }
+            Device::Input => Err(FsError::BadFileDescriptor),
+        }
+    }
+
+    /// Opening a device for writing truncates it; that's a no-op here
+    fn truncate(&mut self, path: &str, _size: u64) -> Result<(), FsError> {
+        self.stat(path).map(|_| ())
+    }
+}
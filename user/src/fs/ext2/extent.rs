@@ -0,0 +1,64 @@
+/// ext4 extent trees - block mapping for inodes with `EXTENTS_FL`
+///
+/// The tree root sits in the inode's 60-byte `i_block`; deeper levels are
+/// whole blocks. Each node is a 12-byte header followed by 12-byte entries:
+/// index entries at interior levels, extents (runs of blocks) at the leaves.
+use super::*;
+
+impl<D: BlockDevice> Ext2Driver<D> {
+    /// Disk block holding logical block `n` of an extent-mapped inode, 0 for a hole
+    pub(super) fn extent_map(&self, inode: &Inode, n: usize) -> Result<u32, FsError> {
+        let n = u32::try_from(n).map_err(|_| FsError::TooLarge)?;
+        let bad = || FsError::Corrupt(Corruption::BadExtentTree);
+        let mut node = inode.block_bytes().to_vec();
+        let mut expected_depth = None;
+        loop {
+            if read_u16(&node, 0) != EXTENT_MAGIC {
+                return Err(bad());
+            }
+            let entries = read_u16(&node, 2) as usize;
+            let depth = read_u16(&node, 6);
+            if EXTENT_HEADER_SIZE + entries * EXTENT_ENTRY_SIZE > node.len()
+                || depth > EXTENT_MAX_DEPTH
+                || expected_depth.is_some_and(|d| d != depth)
+            {
+                return Err(bad());
+            }
+            let entry = |i: usize| &node[EXTENT_HEADER_SIZE + i * EXTENT_ENTRY_SIZE..][..EXTENT_ENTRY_SIZE];
+
+            if depth == 0 {
+                for i in 0..entries {
+                    let e = entry(i);
+                    let first = read_u32(e, 0);
+                    let mut len = read_u16(e, 4);
+                    let uninit = len > EXTENT_INIT_MAX_LEN;
+                    if uninit { len -= EXTENT_INIT_MAX_LEN; }
+                    if n < first || n - first >= len as u32 {
+                        continue;
+                    }
+                    if uninit {
+                        return Ok(0);
+                    }
+                    let start = (read_u16(e, 6) as u64) << 32 | read_u32(e, 8) as u64;
+                    return u32::try_from(start + (n - first) as u64)
+                        .map_err(|_| FsError::Corrupt(Corruption::BlockOutOfRange));
+                }
+                return Ok(0);
+            }
+
+            // Entries are sorted: follow the last one starting at or before n
+            let mut child = None;
+            for i in 0..entries {
+                let e = entry(i);
+                if read_u32(e, 0) > n { break; }
+                child = Some((read_u16(e, 8) as u64) << 32 | read_u32(e, 4) as u64);
+            }
+            let child = match child {
+                Some(c) => c,
+                None => return Ok(0),
+            };
+            node = self.read_block(child)?;
+            expected_depth = Some(depth - 1);
+        }
+    }
+}
@@ -0,0 +1,591 @@
+/// Ext2 filesystem driver (with read-only ext4 support)
+///
+/// The driver works on any `BlockDevice` and never trusts the image: every
+/// block number, inode number and directory record is range-checked, and
+/// bad structures come back as `FsError::Corrupt` instead of a panic.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::{BlockDevice, Corruption, DirEntry, FileSystem, FileType, FsError, Metadata, RamDisk};
+use aether_ext2::DirEntry as RawDirEntry;
+use aether_ext2::*;
+
+mod extent;
+mod write;
+
+#[cfg(test)]
+mod tests;
+
+/// Symlinks followed during one lookup before giving up (same as Linux)
+const MAX_SYMLINKS: u32 = 40;
+
+/// Incompatible features we can read
+const INCOMPAT_READ: u32 = FEATURE_INCOMPAT_FILETYPE
+    | FEATURE_INCOMPAT_EXTENTS
+    | FEATURE_INCOMPAT_64BIT
+    | FEATURE_INCOMPAT_FLEX_BG
+    | FEATURE_INCOMPAT_CSUM_SEED
+    | FEATURE_INCOMPAT_LARGEDIR;
+/// Incompatible features we can also write
+const INCOMPAT_WRITE: u32 = FEATURE_INCOMPAT_FILETYPE;
+
+/// Read-only compatible features we can keep intact while writing
+const RO_COMPAT_WRITE: u32 = FEATURE_RO_COMPAT_SPARSE_SUPER
+    | FEATURE_RO_COMPAT_LARGE_FILE
+    | FEATURE_RO_COMPAT_DIR_NLINK
+    | FEATURE_RO_COMPAT_EXTRA_ISIZE;
+
+const fn corrupt(what: Corruption) -> FsError {
+    FsError::Corrupt(what)
+}
+
+impl From<BadDirEntry> for FsError {
+    fn from(_: BadDirEntry) -> Self {
+        corrupt(Corruption::BadDirEntry)
+    }
+}
+
+impl From<WriteError> for FsError {
+    fn from(e: WriteError) -> Self {
+        match e {
+            WriteError::NoSpace | WriteError::NoInodes => FsError::NoSpace,
+            WriteError::BlockOutOfRange => corrupt(Corruption::BlockOutOfRange),
+            WriteError::BadDirEntry => corrupt(Corruption::BadDirEntry),
+            WriteError::TooLarge => FsError::TooLarge,
+            WriteError::NotFound => FsError::NotFound,
+        }
+    }
+}
+
+pub struct Ext2Driver<D: BlockDevice = RamDisk> {
+    dev: D,
+    sb: Superblock,
+    block_size: usize,
+    inode_size: usize,
+    groups_count: u32,
+    /// First block of the group descriptor table
+    gdt_block: u32,
+    /// Bytes per group descriptor (64 or more with the 64bit feature)
+    desc_size: usize,
+    /// Set when the image uses features we can read but not safely modify
+    read_only: bool,
+}
+
+impl Ext2Driver<RamDisk> {
+    /// Mount the disk image in guest RAM, reporting the outcome on the console
+    pub fn new() -> Option<Self> {
+        match Self::mount(RamDisk::guest()) {
+            Ok(fs) => {
+                if fs.read_only {
+                    crate::console_println("Ext2: Mounted read-only (ext4 features present).");
+                } else {
+                    crate::console_println("Ext2: Mounted Successfully.");
+                }
+                Some(fs)
+            }
+            Err(e) => {
+                crate::console::print("Ext2: ");
+                crate::console_println(e.as_str());
+                None
+            }
+        }
+    }
+}
+
+/// Whether `dev` carries an ext2/3/4 superblock signature
+pub fn is_ext2<D: BlockDevice>(dev: &D) -> bool {
+    let mut raw = [0u8; SUPERBLOCK_SIZE];
+    dev.read_at(SUPERBLOCK_OFFSET, &mut raw).is_ok() && Superblock::parse(&raw).magic == EXT2_MAGIC
+}
+
+impl<D: BlockDevice> Ext2Driver<D> {
+    /// Mount the filesystem on `dev`, validating the superblock geometry
+    pub fn mount(dev: D) -> Result<Self, FsError> {
+        let mut raw = [0u8; SUPERBLOCK_SIZE];
+        dev.read_at(SUPERBLOCK_OFFSET, &mut raw)?;
+        let sb = Superblock::parse(&raw);
+
+        if sb.magic != EXT2_MAGIC {
+            return Err(corrupt(Corruption::BadMagic));
+        }
+        if sb.log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+        let block_size = sb.block_size();
+        let (_, incompat, ro_compat) = sb.features();
+        if incompat & FEATURE_INCOMPAT_RECOVER != 0 {
+            return Err(FsError::Unsupported("Journal needs recovery"));
+        }
+        if incompat & !INCOMPAT_READ != 0 {
+            return Err(FsError::Unsupported("Unsupported incompatible features"));
+        }
+
+        let inode_size = sb.inode_size();
+        let desc_size = if incompat & FEATURE_INCOMPAT_64BIT != 0 {
+            if sb.blocks_count_hi != 0 {
+                return Err(FsError::Unsupported("Filesystem too large"));
+            }
+            sb.desc_size as usize
+        } else {
+            GROUP_DESC_SIZE
+        };
+
+        // Bitmaps are one block per group, so groups can't outgrow them
+        let bits_per_block = block_size as u32 * 8;
+        let valid = inode_size >= GOOD_OLD_INODE_SIZE
+            && inode_size.is_power_of_two()
+            && inode_size <= block_size
+            && desc_size >= GROUP_DESC_SIZE
+            && desc_size.is_power_of_two()
+            && desc_size <= block_size
+            && sb.blocks_per_group != 0
+            && sb.blocks_per_group <= bits_per_block
+            && sb.inodes_per_group != 0
+            && sb.inodes_per_group <= bits_per_block
+            && sb.first_data_block <= 1
+            && sb.first_data_block < sb.blocks_count;
+        if !valid {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+
+        let groups_count = sb.groups();
+        if sb.inodes_count as u64 > groups_count as u64 * sb.inodes_per_group as u64 {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+        if sb.blocks_count as u64 * block_size as u64 > dev.size() {
+            return Err(corrupt(Corruption::Truncated));
+        }
+
+        // The descriptor table starts in the block after the superblock
+        let gdt_block = sb.first_data_block + 1;
+        let gdt_blocks = (groups_count as usize * desc_size).div_ceil(block_size);
+        if gdt_block as u64 + gdt_blocks as u64 > sb.blocks_count as u64 {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+
+        let read_only = incompat & !INCOMPAT_WRITE != 0 || ro_compat & !RO_COMPAT_WRITE != 0;
+
+        Ok(Ext2Driver {
+            dev,
+            sb,
+            block_size,
+            inode_size,
+            groups_count,
+            gdt_block,
+            desc_size,
+            read_only,
+        })
+    }
+
+    /// Give back the underlying device
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Whether writes are refused because of ext4 features
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // --- Bounded device access ---
+
+    /// Byte offset of a block, which must lie inside the filesystem
+    fn block_addr(&self, block_idx: u64) -> Result<u64, FsError> {
+        if block_idx >= self.sb.blocks_count as u64 {
+            return Err(corrupt(Corruption::BlockOutOfRange));
+        }
+        Ok(block_idx * self.block_size as u64)
+    }
+
+    fn group_desc_addr(&self, group: u32) -> Result<u64, FsError> {
+        if group >= self.groups_count {
+            return Err(corrupt(Corruption::InodeOutOfRange));
+        }
+        Ok(self.gdt_block as u64 * self.block_size as u64 + group as u64 * self.desc_size as u64)
+    }
+
+    fn group_desc(&self, group: u32) -> Result<GroupDescriptor, FsError> {
+        let mut raw = [0u8; 64];
+        let len = self.desc_size.min(raw.len());
+        self.dev.read_at(self.group_desc_addr(group)?, &mut raw[..len])?;
+        Ok(GroupDescriptor::parse(&raw[..len]))
+    }
+
+    /// Byte offset of an inode's on-disk slot
+    fn inode_addr(&self, index: u32) -> Result<u64, FsError> {
+        // Inode index starts at 1
+        if index < 1 || index > self.sb.inodes_count {
+            return Err(corrupt(Corruption::InodeOutOfRange));
+        }
+
+        let group = (index - 1) / self.sb.inodes_per_group;
+        let slot = (index - 1) % self.sb.inodes_per_group;
+        let gd = self.group_desc(group)?;
+
+        // The whole slot has to sit inside the filesystem
+        let offset = slot as u64 * self.inode_size as u64;
+        let block_idx = gd.inode_table + offset / self.block_size as u64;
+        let addr = self.block_addr(block_idx)?;
+        Ok(addr + offset % self.block_size as u64)
+    }
+
+    fn get_inode(&self, index: u32) -> Result<Inode, FsError> {
+        let mut raw = [0u8; GOOD_OLD_INODE_SIZE];
+        self.dev.read_at(self.inode_addr(index)?, &mut raw)?;
+        Ok(Inode::parse(&raw))
+    }
+
+    // --- Block mapping ---
+
+    /// Disk block holding logical block `n` of an inode's data.
+    /// Returns 0 for a hole (sparse region that reads as zeros).
+    fn block_map(&self, inode: &Inode, n: usize) -> Result<u32, FsError> {
+        if inode.flags & EXTENTS_FL != 0 {
+            return self.extent_map(inode, n);
+        }
+        self.map_block(inode, n as u64)
+    }
+
+    // --- Directories ---
+
+    /// Walk every entry in every data block of a directory.
+    /// `f` gets the entry header and its name; returning false stops the walk.
+    fn for_each_entry<F>(&self, dir: &Inode, mut f: F) -> Result<(), FsError>
+    where
+        F: FnMut(&RawDirEntry, &[u8]) -> bool,
+    {
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let num_blocks = dir.size.div_ceil(self.block_size as u64) as usize;
+
+        for i in 0..num_blocks {
+            let block_idx = self.block_map(dir, i)?;
+            if block_idx == 0 { continue; }
+            let data = self.read_block(block_idx as u64)?;
+
+            let mut offset = 0;
+            while offset < self.block_size {
+                let ent = RawDirEntry::parse(&data, offset)?;
+
+                // inode 0 marks an unused slot (e.g. a deleted entry), not the end
+                if ent.inode != 0 && !f(&ent, ent.name(&data, offset)) {
+                    return Ok(());
+                }
+                offset += ent.rec_len;
+            }
+        }
+        Ok(())
+    }
+
+    /// Find `name` in a directory, returning its inode number
+    fn find_entry(&self, dir: &Inode, name: &str) -> Result<u32, FsError> {
+        let mut found = 0;
+        self.for_each_entry(dir, |ent, ent_name| {
+            if ent_name == name.as_bytes() {
+                found = ent.inode;
+                return false;
+            }
+            true
+        })?;
+        if found == 0 { Err(FsError::NotFound) } else { Ok(found) }
+    }
+
+    // --- Path resolution ---
+
+    /// Resolve a `/`-separated path to an inode number, following symlinks.
+    /// Paths are relative to the root; a leading `/` is optional.
+    pub fn lookup(&self, path: &str) -> Result<u32, FsError> {
+        let mut budget = MAX_SYMLINKS;
+        self.walk(ROOT_INODE, path, true, &mut budget)
+    }
+
+    /// Like `lookup`, but a symlink in the final component is returned
+    /// itself rather than followed
+    pub fn lookup_nofollow(&self, path: &str) -> Result<u32, FsError> {
+        let mut budget = MAX_SYMLINKS;
+        self.walk(ROOT_INODE, path, false, &mut budget)
+    }
+
+    /// Resolve `path` starting from directory `start` (or the root, if the
+    /// path is absolute). Every symlink followed, at any depth, uses up one
+    /// unit of `budget`, which is what catches loops.
+    fn walk(&self, start: u32, path: &str, follow_last: bool, budget: &mut u32) -> Result<u32, FsError> {
+        let mut inode_idx = if path.starts_with('/') { ROOT_INODE } else { start };
+        let mut components = path.split('/').filter(|c| !c.is_empty()).peekable();
+
+        while let Some(component) = components.next() {
+            let dir = self.get_inode(inode_idx)?;
+            if !dir.is_dir() {
+                return Err(FsError::NotADirectory);
+            }
+            let next = self.find_entry(&dir, component)?;
+            let inode = self.get_inode(next)?;
+
+            let last = components.peek().is_none();
+            if inode.is_symlink() && (follow_last || !last) {
+                if *budget == 0 {
+                    return Err(FsError::TooManyLinks);
+                }
+                *budget -= 1;
+                let target = self.link_target(&inode)?;
+                let target = core::str::from_utf8(&target).map_err(|_| corrupt(Corruption::BadSymlink))?;
+                // Relative targets are relative to the directory holding the link
+                inode_idx = self.walk(inode_idx, target, true, budget)?;
+            } else {
+                inode_idx = next;
+            }
+        }
+        Ok(inode_idx)
+    }
+
+    /// Where a symlink points
+    fn link_target(&self, inode: &Inode) -> Result<Vec<u8>, FsError> {
+        if inode.is_fast_symlink(self.block_size) {
+            let len = inode.size as usize;
+            if len >= FAST_SYMLINK_MAX {
+                return Err(corrupt(Corruption::BadSymlink));
+            }
+            return Ok(inode.block_bytes()[..len].to_vec());
+        }
+        self.read_data(inode)
+    }
+
+    /// Target of the symlink at `path`
+    pub fn readlink(&self, path: &str) -> Result<String, FsError> {
+        let inode = self.get_inode(self.lookup_nofollow(path)?)?;
+        if !inode.is_symlink() {
+            return Err(FsError::NotASymlink);
+        }
+        let target = self.link_target(&inode)?;
+        String::from_utf8(target).map_err(|_| corrupt(Corruption::BadSymlink))
+    }
+
+    // --- Listing and metadata ---
+
+    /// Iterate over the entries of a directory (including `.` and `..`)
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir<'_, D>, FsError> {
+        let dir = self.get_inode(self.lookup(path)?)?;
+        if !dir.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        Ok(ReadDir { fs: self, dir, block: 0, offset: 0, data: None })
+    }
+
+    /// Metadata for `path`, following symlinks
+    pub fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        self.metadata(self.lookup(path)?)
+    }
+
+    /// Metadata for `path`; a final symlink is described itself
+    pub fn lstat(&self, path: &str) -> Result<Metadata, FsError> {
+        self.metadata(self.lookup_nofollow(path)?)
+    }
+
+    fn metadata(&self, index: u32) -> Result<Metadata, FsError> {
+        let inode = self.get_inode(index)?;
+        Ok(Metadata {
+            inode: index,
+            file_type: FileType::from_mode(inode.mode),
+            mode: inode.mode & 0o7777,
+            size: inode.size,
+            uid: inode.uid,
+            gid: inode.gid,
+            links: inode.links_count,
+            atime: inode.atime,
+            mtime: inode.mtime,
+            ctime: inode.ctime,
+        })
+    }
+
+    /// Type of a directory entry: from its file type byte when the image
+    /// has one, otherwise from the inode
+    fn entry_type(&self, inode: u32, file_type: u8) -> FileType {
+        match file_type {
+            FT_REG_FILE => FileType::Regular,
+            FT_DIR => FileType::Directory,
+            FT_CHRDEV => FileType::CharDevice,
+            FT_BLKDEV => FileType::BlockDevice,
+            FT_FIFO => FileType::Fifo,
+            FT_SOCK => FileType::Socket,
+            FT_SYMLINK => FileType::Symlink,
+            _ => self.get_inode(inode).map_or(FileType::Unknown, |i| FileType::from_mode(i.mode)),
+        }
+    }
+
+    // --- File data ---
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FsError> {
+        let inode = self.get_inode(self.lookup(path)?)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.read_data(&inode)
+    }
+
+    /// Read from byte `offset` of a file into `buf`, returning the number of
+    /// bytes read (short at end of file, 0 past it)
+    pub fn read_at(&self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.get_inode(self.lookup(path)?)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        let len = inode.size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        let bs = self.block_size as u64;
+
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % bs) as usize;
+            let n = (self.block_size - within).min(len - done);
+            let block_n = usize::try_from(pos / bs).map_err(|_| FsError::TooLarge)?;
+            let chunk = &mut buf[done..done + n];
+            match self.block_map(&inode, block_n)? {
+                0 => chunk.fill(0),
+                block_idx => self.dev.read_at(self.block_addr(block_idx as u64)? + within as u64, chunk)?,
+            }
+            done += n;
+        }
+        Ok(len)
+    }
+
+    /// All data blocks of an inode, holes zero-filled
+    fn read_data(&self, inode: &Inode) -> Result<Vec<u8>, FsError> {
+        // The size is untrusted: fail cleanly rather than abort on allocation
+        let file_size = usize::try_from(inode.size).map_err(|_| FsError::TooLarge)?;
+        let mut buffer = Vec::new();
+        buffer.try_reserve_exact(file_size).map_err(|_| FsError::TooLarge)?;
+        buffer.resize(file_size, 0);
+
+        for (i, chunk) in buffer.chunks_mut(self.block_size).enumerate() {
+            let block_idx = self.block_map(inode, i)?;
+            // Sparse hole: no block allocated, reads back as zeros
+            if block_idx != 0 {
+                self.dev.read_at(self.block_addr(block_idx as u64)?, chunk)?;
+            }
+        }
+        Ok(buffer)
+    }
+}
+
+/// Iterator over a directory's entries, returned by `Ext2Driver::read_dir`
+pub struct ReadDir<'a, D: BlockDevice = RamDisk> {
+    fs: &'a Ext2Driver<D>,
+    dir: Inode,
+    /// Logical block and byte offset of the next entry
+    block: usize,
+    offset: usize,
+    /// Contents of the current block, once read
+    data: Option<Vec<u8>>,
+}
+
+impl<D: BlockDevice> ReadDir<'_, D> {
+    fn next_entry(&mut self) -> Result<Option<DirEntry>, FsError> {
+        let block_size = self.fs.block_size;
+        let num_blocks = self.dir.size.div_ceil(block_size as u64) as usize;
+
+        while self.block < num_blocks {
+            if self.offset >= block_size {
+                self.block += 1;
+                self.offset = 0;
+                self.data = None;
+                continue;
+            }
+            if self.data.is_none() {
+                let block_idx = self.fs.block_map(&self.dir, self.block)?;
+                if block_idx == 0 {
+                    self.offset = block_size;
+                    continue;
+                }
+                self.data = Some(self.fs.read_block(block_idx as u64)?);
+            }
+
+            let data = self.data.as_deref().unwrap_or_default();
+            let offset = self.offset;
+            let ent = RawDirEntry::parse(data, offset)?;
+            self.offset += ent.rec_len;
+            if ent.inode == 0 { continue; }
+
+            let name = String::from_utf8_lossy(ent.name(data, offset)).into_owned();
+            let file_type = self.fs.entry_type(ent.inode, ent.file_type);
+            return Ok(Some(DirEntry { name, inode: ent.inode, file_type }));
+        }
+        Ok(None)
+    }
+}
+
+impl<D: BlockDevice> Iterator for ReadDir<'_, D> {
+    type Item = Result<DirEntry, FsError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(entry) => entry.map(Ok),
+            Err(e) => {
+                // Stop after reporting corruption rather than spinning on it
+                self.block = usize::MAX;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<D: BlockDevice> FileSystem for Ext2Driver<D> {
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        Ext2Driver::stat(self, path)
+    }
+
+    fn lstat(&self, path: &str) -> Result<Metadata, FsError> {
+        Ext2Driver::lstat(self, path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        Ext2Driver::read_dir(self, path)?.collect()
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ext2Driver::read_at(self, path, offset, buf)
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let offset = usize::try_from(offset).map_err(|_| FsError::TooLarge)?;
+        Ext2Driver::write_at(self, path, offset, data)
+    }
+
+    fn create(&mut self, path: &str, perm: u16) -> Result<(), FsError> {
+        Ext2Driver::create(self, path, perm).map(|_| ())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        Ext2Driver::mkdir(self, path).map(|_| ())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        Ext2Driver::unlink(self, path)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        Ext2Driver::rmdir(self, path)
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let size = usize::try_from(size).map_err(|_| FsError::TooLarge)?;
+        Ext2Driver::truncate(self, path, size)
+    }
+
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::Unsupported("ext2: rename not supported"))
+    }
+
+    fn readlink(&self, path: &str) -> Result<String, FsError> {
+        Ext2Driver::readlink(self, path)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.dev.flush()
+    }
+}
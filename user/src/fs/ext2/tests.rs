@@ -0,0 +1,618 @@
+/// Host tests: a small ext2 image built in memory, plus deliberately
+/// damaged copies of it
+use super::*;
+use alloc::vec::Vec;
+
+const BLOCK: usize = 1024;
+const BLOCKS: usize = 256;
+const INODES: u32 = 32;
+// Layout: superblock 1, descriptors 2, bitmaps 3 and 4, inode table 5..9, root directory 9
+const INODE_TABLE: usize = 5;
+const ROOT_DIR_BLOCK: usize = 9;
+
+fn put16(img: &mut [u8], off: usize, v: u16) {
+    img[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(img: &mut [u8], off: usize, v: u32) {
+    img[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn inode_offset(index: u32) -> usize {
+    INODE_TABLE * BLOCK + (index as usize - 1) * GOOD_OLD_INODE_SIZE
+}
+
+/// Shape of a freshly formatted test filesystem
+struct Geometry {
+    block: usize,
+    blocks: usize,
+    blocks_per_group: usize,
+    inodes_per_group: u32,
+    inode_size: usize,
+}
+
+/// 256KiB in a single group, laid out as the constants above describe
+const SMALL: Geometry = Geometry {
+    block: BLOCK,
+    blocks: BLOCKS,
+    blocks_per_group: 8192,
+    inodes_per_group: INODES,
+    inode_size: GOOD_OLD_INODE_SIZE,
+};
+
+/// A freshly formatted 256KiB filesystem with an empty root directory
+fn image() -> Vec<u8> {
+    format(&SMALL)
+}
+
+/// Lay out an empty filesystem the way mke2fs does: each group holds a
+/// superblock and descriptor backup (sparse_super), its bitmaps and its
+/// inode table, and group 0 also the root directory's block
+fn format(g: &Geometry) -> Vec<u8> {
+    let mut img = vec![0u8; g.block * g.blocks];
+    let first_data_block = usize::from(g.block == 1024);
+    let groups = (g.blocks - first_data_block).div_ceil(g.blocks_per_group);
+    let table_blocks = (g.inodes_per_group as usize * g.inode_size).div_ceil(g.block);
+
+    let sb = 1024;
+    put32(&mut img, sb, groups as u32 * g.inodes_per_group);
+    put32(&mut img, sb + 0x04, g.blocks as u32);
+    put32(&mut img, sb + 0x14, first_data_block as u32);
+    put32(&mut img, sb + 0x18, (g.block / 1024).trailing_zeros()); // log_block_size
+    put32(&mut img, sb + 0x20, g.blocks_per_group as u32);
+    put32(&mut img, sb + 0x24, g.blocks_per_group as u32); // frags_per_group
+    put32(&mut img, sb + 0x28, g.inodes_per_group);
+    put16(&mut img, sb + 0x38, EXT2_MAGIC);
+    put16(&mut img, sb + 0x3A, 1); // Clean
+    put32(&mut img, sb + 0x4C, 1); // Dynamic revision
+    put32(&mut img, sb + 0x54, GOOD_OLD_FIRST_INO);
+    put16(&mut img, sb + 0x58, g.inode_size as u16);
+    put32(&mut img, sb + 0x60, FEATURE_INCOMPAT_FILETYPE);
+    put32(&mut img, sb + 0x64, FEATURE_RO_COMPAT_SPARSE_SUPER);
+    let parsed = Superblock::parse(img[sb..][..SUPERBLOCK_SIZE].try_into().unwrap());
+
+    let (mut free_blocks, mut free_inodes) = (0, 0);
+    let mut root_dir_block = 0;
+    for group in 0..groups {
+        let start = first_data_block + group * g.blocks_per_group;
+        let in_group = g.blocks_per_group.min(g.blocks - start);
+        // Superblock and one descriptor block, where sparse_super keeps copies
+        let mut next = start + if parsed.has_super(group as u32) { 2 } else { 0 };
+        let (block_bitmap, inode_bitmap, table) = (next, next + 1, next + 2);
+        next += 2 + table_blocks;
+        if group == 0 {
+            root_dir_block = next;
+            next += 1;
+        }
+
+        let used = next - start;
+        for bit in 0..used {
+            img[block_bitmap * g.block + bit / 8] |= 1 << (bit % 8);
+        }
+        // Inodes below the first usable one are reserved
+        let reserved = if group == 0 { GOOD_OLD_FIRST_INO - 1 } else { 0 };
+        for bit in 0..reserved as usize {
+            img[inode_bitmap * g.block + bit / 8] |= 1 << (bit % 8);
+        }
+
+        let gd = (first_data_block + 1) * g.block + group * GROUP_DESC_SIZE;
+        put32(&mut img, gd, block_bitmap as u32);
+        put32(&mut img, gd + 0x04, inode_bitmap as u32);
+        put32(&mut img, gd + 0x08, table as u32);
+        put16(&mut img, gd + 0x0C, (in_group - used) as u16);
+        put16(&mut img, gd + 0x0E, (g.inodes_per_group - reserved) as u16);
+        put16(&mut img, gd + 0x10, u16::from(group == 0));
+        free_blocks += in_group - used;
+        free_inodes += g.inodes_per_group - reserved;
+    }
+    put32(&mut img, sb + 0x0C, free_blocks as u32);
+    put32(&mut img, sb + 0x10, free_inodes);
+
+    let table = read_u32(&img, (first_data_block + 1) * g.block + 0x08) as usize;
+    let root = table * g.block + (ROOT_INODE as usize - 1) * g.inode_size;
+    put16(&mut img, root, S_IFDIR | 0o755);
+    put32(&mut img, root + 0x04, g.block as u32);
+    put16(&mut img, root + 0x1A, 2);
+    put32(&mut img, root + 0x1C, (g.block / 512) as u32);
+    put32(&mut img, root + 0x28, root_dir_block as u32);
+
+    let dir = &mut img[root_dir_block * g.block..][..g.block];
+    init_dir_block(dir, ROOT_INODE, ROOT_INODE, FT_DIR);
+    img
+}
+
+fn mount(img: Vec<u8>) -> Ext2Driver<Vec<u8>> {
+    Ext2Driver::mount(img).expect("mount")
+}
+
+fn names(fs: &Ext2Driver<Vec<u8>>, path: &str) -> Vec<String> {
+    fs.read_dir(path).unwrap().map(|e| e.unwrap().name).collect()
+}
+
+fn free_counts(img: &[u8]) -> (u32, u32) {
+    (read_u32(img, 1024 + 0x0C), read_u32(img, 1024 + 0x10))
+}
+
+#[test]
+fn empty_root() {
+    let fs = mount(image());
+    assert_eq!(names(&fs, "/"), [".", ".."]);
+    assert_eq!(fs.lookup("missing"), Err(FsError::NotFound));
+    assert!(fs.stat("/").unwrap().is_dir());
+}
+
+#[test]
+fn write_read_round_trip() {
+    let mut fs = mount(image());
+    fs.write_file("/hello.txt", b"Hello, world!").unwrap();
+    assert_eq!(fs.read_file("hello.txt").unwrap(), b"Hello, world!");
+
+    // Past the twelve direct blocks, so an indirect block is needed
+    let big: Vec<u8> = (0..20 * BLOCK).map(|i| (i % 251) as u8).collect();
+    fs.write_file("/big", &big).unwrap();
+    assert_eq!(fs.read_file("/big").unwrap(), big);
+
+    fs.truncate("/big", 100).unwrap();
+    assert_eq!(fs.read_file("/big").unwrap(), &big[..100]);
+
+    // Growing again leaves zeros, not the old contents
+    fs.write_at("/big", 2000, b"end").unwrap();
+    let data = fs.read_file("/big").unwrap();
+    assert_eq!(data.len(), 2003);
+    assert!(data[100..2000].iter().all(|&b| b == 0));
+
+    // Everything survives a remount
+    let fs = mount(fs.into_inner());
+    assert_eq!(fs.read_file("hello.txt").unwrap(), b"Hello, world!");
+    assert_eq!(fs.stat("/big").unwrap().size, 2003);
+}
+
+#[test]
+fn directories_and_space_accounting() {
+    let img = image();
+    let before = free_counts(&img);
+    let mut fs = mount(img);
+
+    fs.mkdir("/d").unwrap();
+    fs.write_file("/d/f", &[7u8; 3 * BLOCK]).unwrap();
+    assert_eq!(names(&fs, "/d"), [".", "..", "f"]);
+    assert_eq!(fs.stat("/").unwrap().links, 3);
+    assert_eq!(fs.mkdir("/d"), Err(FsError::AlreadyExists));
+    assert_eq!(fs.rmdir("/d"), Err(FsError::DirectoryNotEmpty));
+
+    fs.unlink("/d/f").unwrap();
+    fs.rmdir("/d").unwrap();
+    assert_eq!(names(&fs, "/"), [".", ".."]);
+    let img = fs.into_inner();
+    assert_eq!(free_counts(&img), before);
+    // Freed inodes need a deletion time e2fsck accepts, even with no clock
+    for index in [11, 12] {
+        assert!(read_u32(&img, inode_offset(index) + 0x14) >= INODES);
+    }
+}
+
+#[test]
+fn bad_magic() {
+    let mut img = image();
+    img[1024 + 0x38] = 0;
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Corrupt(Corruption::BadMagic)));
+}
+
+#[test]
+fn truncated_device() {
+    let mut img = image();
+    img.truncate(BLOCK * BLOCKS / 2);
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Corrupt(Corruption::Truncated)));
+
+    // Too short to even hold a superblock
+    assert_eq!(Ext2Driver::mount(vec![0u8; 1500]).err(), Some(FsError::Io));
+}
+
+#[test]
+fn bad_geometry() {
+    let mut img = image();
+    put32(&mut img, 1024 + 0x28, 0); // inodes_per_group
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Corrupt(Corruption::BadSuperblock)));
+
+    let mut img = image();
+    put32(&mut img, 1024 + 0x18, 20); // log_block_size
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Corrupt(Corruption::BadSuperblock)));
+}
+
+#[test]
+fn unknown_features() {
+    let mut img = image();
+    put32(&mut img, 1024 + 0x60, FEATURE_INCOMPAT_FILETYPE | 0x0010_0000);
+    assert!(matches!(Ext2Driver::mount(img).err(), Some(FsError::Unsupported(_))));
+
+    // An unknown read-only feature still mounts, but refuses writes
+    let mut img = image();
+    put32(&mut img, 1024 + 0x64, 0x8000);
+    let mut fs = mount(img);
+    assert!(fs.is_read_only());
+    assert_eq!(fs.create("/f", 0o644), Err(FsError::ReadOnly));
+}
+
+#[test]
+fn zero_rec_len() {
+    let mut img = image();
+    put16(&mut img, ROOT_DIR_BLOCK * BLOCK + 12 + 4, 0);
+    let fs = mount(img);
+
+    let mut entries = fs.read_dir("/").unwrap();
+    assert_eq!(entries.next().unwrap().unwrap().name, ".");
+    assert_eq!(entries.next(), Some(Err(FsError::Corrupt(Corruption::BadDirEntry))));
+    assert_eq!(entries.next(), None);
+    assert_eq!(fs.lookup("x"), Err(FsError::Corrupt(Corruption::BadDirEntry)));
+}
+
+#[test]
+fn block_pointer_out_of_range() {
+    let mut fs = mount(image());
+    let index = fs.create("/f", 0o644).unwrap();
+    fs.write_file("/f", b"data").unwrap();
+
+    let mut img = fs.into_inner();
+    put32(&mut img, inode_offset(index) + 0x28, 0x00FF_FFFF);
+    let mut fs = mount(img);
+    assert_eq!(fs.read_file("/f"), Err(FsError::Corrupt(Corruption::BlockOutOfRange)));
+    assert_eq!(fs.unlink("/f"), Err(FsError::Corrupt(Corruption::BlockOutOfRange)));
+}
+
+#[test]
+fn inode_number_out_of_range() {
+    let mut fs = mount(image());
+    fs.create("/f", 0o644).unwrap();
+
+    // "." and ".." take 12 bytes each, so the new entry follows them
+    let mut img = fs.into_inner();
+    put32(&mut img, ROOT_DIR_BLOCK * BLOCK + 24, 9999);
+    let fs = mount(img);
+    assert_eq!(fs.stat("/f"), Err(FsError::Corrupt(Corruption::InodeOutOfRange)));
+}
+
+#[test]
+fn oversized_file() {
+    let mut fs = mount(image());
+    let index = fs.create("/f", 0o644).unwrap();
+
+    // A size far beyond memory must fail cleanly instead of aborting
+    let mut img = fs.into_inner();
+    put32(&mut img, inode_offset(index) + 0x6C, 0xFFFF_FFFF);
+    let fs = mount(img);
+    assert_eq!(fs.read_file("/f"), Err(FsError::TooLarge));
+}
+
+/// Setting any single metadata byte to 0xFF must never panic
+#[test]
+fn damaged_metadata_never_panics() {
+    let mut fs = mount(image());
+    fs.mkdir("/d").unwrap();
+    fs.write_file("/d/f", b"contents").unwrap();
+    let pristine = fs.into_inner();
+
+    for off in 1024..(ROOT_DIR_BLOCK + 2) * BLOCK {
+        let mut img = pristine.clone();
+        img[off] = 0xFF;
+        let Ok(fs) = Ext2Driver::mount(img) else { continue };
+        if let Ok(entries) = fs.read_dir("/") {
+            entries.for_each(drop);
+        }
+        if let Ok(entries) = fs.read_dir("/d") {
+            entries.for_each(drop);
+        }
+        let _ = fs.stat("/d/f");
+        let _ = fs.readlink("/d/f");
+    }
+}
+
+#[test]
+fn block_tree_levels() {
+    let img = image();
+    let before = free_counts(&img);
+    let mut fs = mount(img);
+    fs.create("/sparse", 0o644).unwrap();
+
+    // With 1KiB blocks the single, double and triple indirect trees start at
+    // blocks 12, 268 and 65804; write either side of each boundary
+    let blocks = [0, 11, 12, 267, 268, 65803, 65804];
+    for (i, &n) in blocks.iter().enumerate() {
+        assert_eq!(fs.write_at("/sparse", n * BLOCK, &[i as u8 + 1; BLOCK]), Ok(BLOCK));
+    }
+    // Seven data blocks, one single indirect, a double indirect with two
+    // children and a triple indirect chain of three
+    let img = fs.into_inner();
+    assert_eq!(before.0 - free_counts(&img).0, 7 + 1 + 3 + 3);
+    let fs = mount(img);
+    assert_eq!(fs.stat("/sparse").unwrap().size, 65805 * BLOCK as u64);
+
+    let mut buf = vec![0xFFu8; BLOCK];
+    for (i, &n) in blocks.iter().enumerate() {
+        assert_eq!(fs.read_at("/sparse", (n * BLOCK) as u64, &mut buf), Ok(BLOCK));
+        assert!(buf.iter().all(|&b| b == i as u8 + 1), "block {n}");
+    }
+    // Holes inside each tree read as zeros
+    for n in [5, 100, 30000, 65000] {
+        assert_eq!(fs.read_at("/sparse", (n * BLOCK) as u64, &mut buf), Ok(BLOCK));
+        assert!(buf.iter().all(|&b| b == 0), "hole at {n}");
+    }
+
+    // Cutting back to the single indirect tree frees everything past it
+    let mut fs = mount(fs.into_inner());
+    fs.truncate("/sparse", 268 * BLOCK).unwrap();
+    let img = fs.into_inner();
+    assert_eq!(before.0 - free_counts(&img).0, 4 + 1);
+    let mut fs = mount(img);
+    fs.unlink("/sparse").unwrap();
+    assert_eq!(free_counts(&fs.into_inner()), before);
+}
+
+#[test]
+fn larger_geometries() {
+    let shapes = [
+        // Several 1KiB-block groups with revision 1 inodes
+        Geometry { block: 1024, blocks: 1 + 4 * 256, blocks_per_group: 256, inodes_per_group: 16, inode_size: 256 },
+        Geometry { block: 2048, blocks: 4 * 256, blocks_per_group: 256, inodes_per_group: 16, inode_size: 256 },
+        Geometry { block: 4096, blocks: 4 * 128, blocks_per_group: 128, inodes_per_group: 32, inode_size: 512 },
+    ];
+    for g in shapes {
+        let img = format(&g);
+        let before = free_counts(&img);
+        let mut fs = mount(img);
+        assert_eq!(fs.block_size(), g.block);
+
+        // Two groups' worth of blocks, so the file crosses into later groups
+        // and past the direct blocks
+        let big: Vec<u8> = (0..2 * g.blocks_per_group * g.block).map(|i| (i % 253) as u8).collect();
+        fs.write_file("/big", &big).unwrap();
+        // More files than group 0 has inodes for
+        let count = g.inodes_per_group;
+        for i in 0..count {
+            fs.write_file(&format!("/f{i}"), format!("file {i}").as_bytes()).unwrap();
+        }
+
+        let mut fs = mount(fs.into_inner());
+        assert_eq!(fs.read_file("/big").unwrap(), big, "{}-byte blocks", g.block);
+        for i in 0..count {
+            assert_eq!(fs.read_file(&format!("/f{i}")).unwrap(), format!("file {i}").as_bytes());
+        }
+        assert!(fs.stat(&format!("/f{}", count - 1)).unwrap().inode > g.inodes_per_group);
+
+        fs.unlink("/big").unwrap();
+        for i in 0..count {
+            fs.unlink(&format!("/f{i}")).unwrap();
+        }
+        assert_eq!(free_counts(&fs.into_inner()), before, "{}-byte blocks", g.block);
+    }
+}
+
+/// Turn the files at the given paths into symlinks. Targets that fit in
+/// `i_block` are stored there (fast links); longer ones in a data block.
+fn with_links(mut fs: Ext2Driver<Vec<u8>>, links: &[(&str, &str)]) -> Ext2Driver<Vec<u8>> {
+    let mut fast = Vec::new();
+    let mut all = Vec::new();
+    for &(path, target) in links {
+        if target.len() < FAST_SYMLINK_MAX {
+            fast.push((fs.create(path, 0o777).unwrap(), target));
+        } else {
+            fs.write_file(path, target.as_bytes()).unwrap();
+        }
+        all.push(fs.lookup_nofollow(path).unwrap());
+    }
+    let mut img = fs.into_inner();
+    for index in all {
+        put16(&mut img, inode_offset(index), S_IFLNK | 0o777);
+    }
+    for (index, target) in fast {
+        put32(&mut img, inode_offset(index) + 0x04, target.len() as u32);
+        img[inode_offset(index) + 0x28..][..target.len()].copy_from_slice(target.as_bytes());
+    }
+    mount(img)
+}
+
+#[test]
+fn symlinks() {
+    let mut fs = mount(image());
+    fs.mkdir("/d").unwrap();
+    fs.write_file("/d/f", b"target").unwrap();
+    let file = fs.lookup("/d/f").unwrap();
+    let long = format!("/d/{}f", "./".repeat(40));
+    let fs = with_links(fs, &[("/fast", "d/f"), ("/slow", &long), ("/d/up", "../d/f"), ("/dir", "d")]);
+
+    // Relative targets resolve from the directory holding the link, and a
+    // link in the middle of a path is followed too
+    for path in ["/fast", "/slow", "/d/up", "/dir/f", "/dir/up"] {
+        assert_eq!(fs.lookup(path), Ok(file), "{path}");
+        assert_eq!(fs.read_file(path).unwrap(), b"target");
+    }
+    assert_eq!(fs.readlink("/fast").unwrap(), "d/f");
+    assert_eq!(fs.readlink("/slow").unwrap(), long);
+    assert_eq!(fs.readlink("/d/f"), Err(FsError::NotASymlink));
+
+    // Only the last component is left unfollowed
+    assert_ne!(fs.lookup_nofollow("/fast"), Ok(file));
+    assert_eq!(fs.lookup_nofollow("/dir/f"), Ok(file));
+    assert_eq!(fs.lstat("/slow").unwrap().file_type, FileType::Symlink);
+    assert_eq!(fs.lstat("/slow").unwrap().size, long.len() as u64);
+    assert_eq!(fs.stat("/slow").unwrap().file_type, FileType::Regular);
+}
+
+#[test]
+fn symlink_loops() {
+    let fs = with_links(mount(image()), &[("/self", "self"), ("/a", "b"), ("/b", "/a"), ("/via", "a/x")]);
+    for path in ["/self", "/a", "/b", "/via", "/self/x"] {
+        assert_eq!(fs.lookup(path), Err(FsError::TooManyLinks), "{path}");
+    }
+    assert_eq!(fs.read_file("/a"), Err(FsError::TooManyLinks));
+
+    // The links themselves are still reachable
+    assert!(fs.lookup_nofollow("/self").is_ok());
+    assert_eq!(fs.readlink("/a").unwrap(), "b");
+    assert_eq!(fs.lstat("/b").unwrap().file_type, FileType::Symlink);
+}
+
+// --- ext4 layouts ---
+
+const EXT4_INODE_SIZE: usize = 256;
+const EXT4_INODES_PER_GROUP: u32 = 16;
+// Two groups of 128 blocks, flex_bg style: both groups' bitmaps and inode
+// tables sit at the start of group 0
+const EXT4_TABLES: [usize; 2] = [7, 11];
+const EXT4_ROOT_DIR_BLOCK: usize = 15;
+/// First inode of group 1, so finding it needs the second 64-byte descriptor
+const EXT4_FILE: u32 = EXT4_INODES_PER_GROUP + 1;
+const EXT4_INDEX_BLOCK: usize = 20;
+const EXT4_LEAF_BLOCK: usize = 21;
+
+fn ext4_inode_offset(index: u32) -> usize {
+    let (group, slot) = ((index - 1) / EXT4_INODES_PER_GROUP, (index - 1) % EXT4_INODES_PER_GROUP);
+    EXT4_TABLES[group as usize] * BLOCK + slot as usize * EXT4_INODE_SIZE
+}
+
+/// Write an extent tree node: `depth` 0 takes (logical, length, start)
+/// extents, higher depths (logical, child block) index entries
+fn put_node(node: &mut [u8], depth: u16, entries: &[(u32, u32, u32)]) {
+    put16(node, 0, EXTENT_MAGIC);
+    put16(node, 2, entries.len() as u16);
+    put16(node, 4, ((node.len() - EXTENT_HEADER_SIZE) / EXTENT_ENTRY_SIZE) as u16);
+    put16(node, 6, depth);
+    for (i, &(logical, a, b)) in entries.iter().enumerate() {
+        let e = EXTENT_HEADER_SIZE + i * EXTENT_ENTRY_SIZE;
+        put32(node, e, logical);
+        if depth == 0 {
+            put16(node, e + 4, a as u16);
+            put32(node, e + 8, b);
+        } else {
+            put32(node, e + 4, a);
+        }
+    }
+}
+
+/// A 256KiB ext4-style filesystem (extents, 64bit, flex_bg, metadata_csum)
+/// whose root holds `file`: five blocks mapped through a two-level extent
+/// tree as data, data, an uninitialized extent, a hole, data
+fn ext4_image() -> Vec<u8> {
+    let mut img = vec![0u8; BLOCK * BLOCKS];
+
+    let sb = 1024;
+    put32(&mut img, sb, 2 * EXT4_INODES_PER_GROUP);
+    put32(&mut img, sb + 0x04, BLOCKS as u32);
+    put32(&mut img, sb + 0x14, 1); // first_data_block
+    put32(&mut img, sb + 0x20, 128); // blocks_per_group
+    put32(&mut img, sb + 0x24, 128);
+    put32(&mut img, sb + 0x28, EXT4_INODES_PER_GROUP);
+    put16(&mut img, sb + 0x38, EXT2_MAGIC);
+    put16(&mut img, sb + 0x3A, 1);
+    put32(&mut img, sb + 0x4C, 1);
+    put32(&mut img, sb + 0x54, GOOD_OLD_FIRST_INO);
+    put16(&mut img, sb + 0x58, EXT4_INODE_SIZE as u16);
+    let incompat = FEATURE_INCOMPAT_FILETYPE | FEATURE_INCOMPAT_EXTENTS | FEATURE_INCOMPAT_64BIT | FEATURE_INCOMPAT_FLEX_BG;
+    put32(&mut img, sb + 0x60, incompat);
+    put32(&mut img, sb + 0x64, FEATURE_RO_COMPAT_SPARSE_SUPER | FEATURE_RO_COMPAT_METADATA_CSUM);
+    put16(&mut img, sb + 0xFE, 64); // desc_size
+
+    for (group, table) in EXT4_TABLES.into_iter().enumerate() {
+        let gd = 2 * BLOCK + group * 64;
+        put32(&mut img, gd, 3 + group as u32);
+        put32(&mut img, gd + 0x04, 5 + group as u32);
+        put32(&mut img, gd + 0x08, table as u32);
+    }
+
+    let root = ext4_inode_offset(ROOT_INODE);
+    put16(&mut img, root, S_IFDIR | 0o755);
+    put32(&mut img, root + 0x04, BLOCK as u32);
+    put16(&mut img, root + 0x1A, 2);
+    put32(&mut img, root + 0x20, EXTENTS_FL);
+    put_node(&mut img[root + 0x28..][..60], 0, &[(0, 1, EXT4_ROOT_DIR_BLOCK as u32)]);
+    let dir = &mut img[EXT4_ROOT_DIR_BLOCK * BLOCK..][..BLOCK];
+    init_dir_block(dir, ROOT_INODE, ROOT_INODE, FT_DIR);
+    assert_eq!(insert_entry(dir, b"file", EXT4_FILE, FT_REG_FILE), Ok(true));
+
+    let file = ext4_inode_offset(EXT4_FILE);
+    put16(&mut img, file, S_IFREG | 0o644);
+    put32(&mut img, file + 0x04, 5 * BLOCK as u32);
+    put16(&mut img, file + 0x1A, 1);
+    put32(&mut img, file + 0x20, EXTENTS_FL);
+    put_node(&mut img[file + 0x28..][..60], 2, &[(0, EXT4_INDEX_BLOCK as u32, 0)]);
+    put_node(&mut img[EXT4_INDEX_BLOCK * BLOCK..][..BLOCK], 1, &[(0, EXT4_LEAF_BLOCK as u32, 0)]);
+    let uninit = EXTENT_INIT_MAX_LEN as u32 + 1;
+    put_node(&mut img[EXT4_LEAF_BLOCK * BLOCK..][..BLOCK], 0, &[(0, 2, 30), (2, uninit, 32), (4, 1, 34)]);
+    for (block, byte) in [(30, 0xA1), (31, 0xA2), (32, 0xEE), (33, 0xEE), (34, 0xA5)] {
+        img[block * BLOCK..][..BLOCK].fill(byte);
+    }
+    img
+}
+
+#[test]
+fn ext4_extent_tree() {
+    let fs = mount(ext4_image());
+    assert!(fs.is_read_only());
+    assert_eq!(names(&fs, "/"), [".", "..", "file"]);
+
+    let data = fs.read_file("/file").unwrap();
+    let expected: Vec<u8> = [0xA1, 0xA2, 0, 0, 0xA5].iter().flat_map(|&b| [b; BLOCK]).collect();
+    assert_eq!(data, expected);
+    // A read straddling the uninitialized extent and the hole
+    let mut buf = [0xFFu8; 4];
+    assert_eq!(fs.read_at("/file", 2 * BLOCK as u64 - 2, &mut buf), Ok(4));
+    assert_eq!(buf, [0xA2, 0xA2, 0, 0]);
+}
+
+#[test]
+fn ext4_refuses_writes() {
+    let mut fs = mount(ext4_image());
+    assert_eq!(fs.create("/new", 0o644), Err(FsError::ReadOnly));
+    assert_eq!(fs.write_at("/file", 0, b"x"), Err(FsError::ReadOnly));
+    assert_eq!(fs.unlink("/file"), Err(FsError::ReadOnly));
+}
+
+#[test]
+fn ext4_bad_extent_trees() {
+    let bad = Err(FsError::Corrupt(Corruption::BadExtentTree));
+
+    let mut img = ext4_image();
+    put16(&mut img, EXT4_LEAF_BLOCK * BLOCK, 0xBEEF);
+    assert_eq!(mount(img).read_file("/file"), bad);
+
+    // The leaf claims to be an index node one level up from where it sits
+    let mut img = ext4_image();
+    put16(&mut img, EXT4_LEAF_BLOCK * BLOCK + 6, 1);
+    assert_eq!(mount(img).read_file("/file"), bad);
+
+    let mut img = ext4_image();
+    put16(&mut img, ext4_inode_offset(EXT4_FILE) + 0x28 + 6, EXTENT_MAX_DEPTH + 1);
+    assert_eq!(mount(img).read_file("/file"), bad);
+
+    // More entries than the root's 60 bytes hold
+    let mut img = ext4_image();
+    put16(&mut img, ext4_inode_offset(EXT4_FILE) + 0x28 + 2, 5);
+    assert_eq!(mount(img).read_file("/file"), bad);
+
+    // An index entry pointing past the end of the filesystem
+    let mut img = ext4_image();
+    put32(&mut img, EXT4_INDEX_BLOCK * BLOCK + EXTENT_HEADER_SIZE + 4, 0x00FF_FFFF);
+    assert_eq!(mount(img).read_file("/file"), Err(FsError::Corrupt(Corruption::BlockOutOfRange)));
+}
+
+#[test]
+fn ext4_features_refused_at_mount() {
+    let sb = 1024;
+    let incompat = read_u32(&ext4_image(), sb + 0x60);
+
+    let mut img = ext4_image();
+    put32(&mut img, sb + 0x60, incompat | FEATURE_INCOMPAT_RECOVER);
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Unsupported("Journal needs recovery")));
+
+    // Inline data (0x8000) isn't something we can read
+    let mut img = ext4_image();
+    put32(&mut img, sb + 0x60, incompat | 0x8000);
+    assert!(matches!(Ext2Driver::mount(img).err(), Some(FsError::Unsupported(_))));
+
+    let mut img = ext4_image();
+    put32(&mut img, sb + 0x150, 1); // blocks_count_hi
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Unsupported("Filesystem too large")));
+
+    let mut img = ext4_image();
+    put16(&mut img, sb + 0xFE, 48); // desc_size that isn't a power of two
+    assert_eq!(Ext2Driver::mount(img).err(), Some(FsError::Corrupt(Corruption::BadSuperblock)));
+}
@@ -0,0 +1,308 @@
+/// Ext2 write support - block/inode allocation, directory entries and file data
+///
+/// Changes go straight to the block device. Allocation, block trees and
+/// directory entries are the `Volume` methods shared with mkext2; every
+/// public call leaves the metadata consistent (bitmaps, free counts, link
+/// counts, `i_blocks`), so the image passes `e2fsck` between operations.
+use super::*;
+use crate::fs::{now, split_parent};
+
+fn touch(inode: &mut Inode) {
+    let t = now();
+    inode.mtime = t;
+    inode.ctime = t;
+}
+
+impl<D: BlockDevice> Volume for Ext2Driver<D> {
+    type Error = FsError;
+
+    fn sb(&self) -> &Superblock {
+        &self.sb
+    }
+
+    fn sb_mut(&mut self) -> &mut Superblock {
+        &mut self.sb
+    }
+
+    /// Store the cached free counts and a fresh write time in the superblock
+    fn write_counts(&mut self) -> Result<(), FsError> {
+        self.sb.wtime = now();
+        let base = SUPERBLOCK_OFFSET;
+        self.dev.write_at(base + Superblock::FREE_BLOCKS_COUNT as u64, &self.sb.free_blocks_count.to_le_bytes())?;
+        self.dev.write_at(base + Superblock::FREE_INODES_COUNT as u64, &self.sb.free_inodes_count.to_le_bytes())?;
+        self.dev.write_at(base + Superblock::WTIME as u64, &self.sb.wtime.to_le_bytes())
+    }
+
+    fn group(&self, group: u32) -> Result<GroupDescriptor, FsError> {
+        self.group_desc(group)
+    }
+
+    fn write_group(&mut self, group: u32, desc: &GroupDescriptor) -> Result<(), FsError> {
+        let addr = self.group_desc_addr(group)? + GroupDescriptor::COUNTS as u64;
+        self.dev.write_at(addr, &desc.encode_counts())
+    }
+
+    /// Contents of a disk block
+    fn read_block(&self, block_idx: u64) -> Result<Vec<u8>, FsError> {
+        let mut buf = vec![0u8; self.block_size];
+        self.dev.read_at(self.block_addr(block_idx)?, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn write_block(&mut self, block_idx: u64, data: &[u8]) -> Result<(), FsError> {
+        let addr = self.block_addr(block_idx)?;
+        self.dev.write_at(addr, data)
+    }
+
+    fn read_inode(&self, index: u32) -> Result<Inode, FsError> {
+        self.get_inode(index)
+    }
+
+    /// Store the first 128 bytes of an inode; any extra space in large inodes is left alone
+    fn write_inode(&mut self, index: u32, inode: &Inode) -> Result<(), FsError> {
+        let addr = self.inode_addr(index)?;
+        self.dev.write_at(addr, &inode.encode())
+    }
+
+    fn clear_inode(&mut self, index: u32) -> Result<(), FsError> {
+        let addr = self.inode_addr(index)?;
+        self.dev.write_at(addr, &vec![0u8; self.inode_size])
+    }
+
+    fn now(&self) -> u32 {
+        now()
+    }
+}
+
+impl<D: BlockDevice> Ext2Driver<D> {
+    fn check_writable(&self) -> Result<(), FsError> {
+        if self.read_only { Err(FsError::ReadOnly) } else { Ok(()) }
+    }
+
+    /// Grow or shrink an inode's data to `size` bytes
+    fn set_size(&mut self, inode: &mut Inode, size: usize) -> Result<(), FsError> {
+        if (size as u64) < inode.size {
+            self.free_blocks_from(inode, size.div_ceil(self.block_size) as u64)?;
+
+            // Zero past the new end so growing the file again reads zeros
+            let tail = size % self.block_size;
+            if tail != 0 {
+                let block_idx = self.block_map(inode, size / self.block_size)?;
+                if block_idx != 0 {
+                    let addr = self.block_addr(block_idx as u64)? + tail as u64;
+                    self.dev.write_at(addr, &vec![0u8; self.block_size - tail])?;
+                }
+            }
+        }
+        inode.size = size as u64;
+        Ok(())
+    }
+
+    /// Parent directory inode and new entry name for `path`, which must not exist yet
+    fn prepare_entry<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let parent_idx = self.lookup(parent)?;
+        let dir = self.get_inode(parent_idx)?;
+        match self.find_entry(&dir, name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok((parent_idx, name)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // --- Public operations ---
+
+    /// Create an empty regular file with permission bits `perm` (e.g. 0o644)
+    pub fn create(&mut self, path: &str, perm: u16) -> Result<u32, FsError> {
+        self.check_writable()?;
+        let (parent_idx, name) = self.prepare_entry(path)?;
+        let index = self.alloc_inode(self.inode_group(parent_idx), false)?;
+
+        let t = now();
+        let mut inode = Inode {
+            mode: S_IFREG | (perm & 0o7777),
+            links_count: 1,
+            atime: t,
+            ctime: t,
+            mtime: t,
+            ..Inode::default()
+        };
+        self.write_inode(index, &inode)?;
+
+        if let Err(e) = self.add_entry(parent_idx, name.as_bytes(), index, FT_REG_FILE) {
+            self.release_inode(index, &mut inode)?;
+            return Err(e);
+        }
+        Ok(index)
+    }
+
+    /// Create a directory containing just `.` and `..`
+    pub fn mkdir(&mut self, path: &str) -> Result<u32, FsError> {
+        self.check_writable()?;
+        let (parent_idx, name) = self.prepare_entry(path)?;
+        let goal = self.inode_group(parent_idx);
+        let index = self.alloc_inode(goal, true)?;
+        let block_idx = match self.alloc_block(goal) {
+            Ok(b) => b,
+            Err(e) => {
+                self.free_inode(index, true)?;
+                return Err(e);
+            }
+        };
+
+        let bs = self.block_size;
+        let dir_type = self.dir_file_type(FT_DIR);
+        let mut data = vec![0u8; bs];
+        init_dir_block(&mut data, index, parent_idx, dir_type);
+        self.write_block(block_idx as u64, &data)?;
+
+        let t = now();
+        let mut inode = Inode {
+            mode: S_IFDIR | 0o755,
+            links_count: 2, // Its entry in the parent, plus its own `.`
+            size: bs as u64,
+            atime: t,
+            ctime: t,
+            mtime: t,
+            ..Inode::default()
+        };
+        inode.block[0] = block_idx;
+        inode.blocks = (bs / 512) as u32;
+        self.write_inode(index, &inode)?;
+
+        if let Err(e) = self.add_entry(parent_idx, name.as_bytes(), index, FT_DIR) {
+            self.release_inode(index, &mut inode)?;
+            return Err(e);
+        }
+
+        // The new `..` links back to the parent
+        let mut parent = self.get_inode(parent_idx)?;
+        parent.links_count += 1;
+        self.write_inode(parent_idx, &parent)?;
+        Ok(index)
+    }
+
+    /// Remove a file's directory entry, freeing it once no links remain
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let (parent, name) = split_parent(path)?;
+        let parent_idx = self.lookup(parent)?;
+        let index = self.find_entry(&self.get_inode(parent_idx)?, name)?;
+        let mut inode = self.get_inode(index)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+
+        self.remove_entry(parent_idx, name.as_bytes())?;
+        inode.links_count = inode.links_count.saturating_sub(1);
+        inode.ctime = now();
+        if inode.links_count == 0 {
+            self.release_inode(index, &mut inode)
+        } else {
+            self.write_inode(index, &inode)
+        }
+    }
+
+    /// Remove an empty directory
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.check_writable()?;
+        let (parent, name) = split_parent(path)?;
+        let parent_idx = self.lookup(parent)?;
+        let index = self.find_entry(&self.get_inode(parent_idx)?, name)?;
+        let mut inode = self.get_inode(index)?;
+
+        let mut empty = true;
+        self.for_each_entry(&inode, |_, name| {
+            empty = name == b"." || name == b"..";
+            empty
+        })?;
+        if !empty {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+
+        self.remove_entry(parent_idx, name.as_bytes())?;
+        self.release_inode(index, &mut inode)?;
+
+        let mut parent = self.get_inode(parent_idx)?;
+        parent.links_count = parent.links_count.saturating_sub(1);
+        self.write_inode(parent_idx, &parent)
+    }
+
+    /// Write `data` at byte `offset` of an existing file, allocating blocks
+    /// as needed and extending the size. Returns the number of bytes written,
+    /// which is short only if the disk fills up part way.
+    pub fn write_at(&mut self, path: &str, offset: usize, data: &[u8]) -> Result<usize, FsError> {
+        self.check_writable()?;
+        let index = self.lookup(path)?;
+        let mut inode = self.get_inode(index)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        match offset.checked_add(data.len()) {
+            Some(end) if end <= u32::MAX as usize => {}
+            _ => return Err(FsError::TooLarge),
+        }
+
+        let goal = self.inode_group(index);
+        let bs = self.block_size;
+        let mut written = 0;
+        let mut error = None;
+        while written < data.len() {
+            let pos = offset + written;
+            let within = pos % bs;
+            let len = (bs - within).min(data.len() - written);
+            let result = self.map_block_alloc(&mut inode, goal, (pos / bs) as u64).and_then(|block_idx| {
+                let addr = self.block_addr(block_idx as u64)? + within as u64;
+                self.dev.write_at(addr, &data[written..written + len])
+            });
+            if let Err(e) = result {
+                error = Some(e);
+                break;
+            }
+            written += len;
+        }
+
+        inode.size = inode.size.max((offset + written) as u64);
+        touch(&mut inode);
+        self.write_inode(index, &inode)?;
+
+        match error {
+            Some(e) if written == 0 => Err(e),
+            _ => Ok(written),
+        }
+    }
+
+    /// Replace a file's contents, creating it if needed
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        self.check_writable()?;
+        match self.lookup(path) {
+            Ok(_) => self.truncate(path, 0)?,
+            Err(FsError::NotFound) => {
+                self.create(path, 0o644)?;
+            }
+            Err(e) => return Err(e),
+        }
+        if self.write_at(path, 0, data)? < data.len() {
+            return Err(FsError::NoSpace);
+        }
+        Ok(())
+    }
+
+    /// Set a file's size, freeing blocks past the new end when shrinking.
+    /// Growing leaves a sparse hole.
+    pub fn truncate(&mut self, path: &str, size: usize) -> Result<(), FsError> {
+        self.check_writable()?;
+        let index = self.lookup(path)?;
+        let mut inode = self.get_inode(index)?;
+        if inode.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        if size > u32::MAX as usize {
+            return Err(FsError::TooLarge);
+        }
+
+        self.set_size(&mut inode, size)?;
+        touch(&mut inode);
+        self.write_inode(index, &inode)
+    }
+}
@@ -0,0 +1,390 @@
+/// FAT directory entries - 8.3 short entries, long file name (LFN) runs
+/// and DOS timestamps
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::fs::{now, FsError};
+
+pub(super) const ENTRY_SIZE: usize = 32;
+
+// Attribute bits
+pub(super) const ATTR_READ_ONLY: u8 = 0x01;
+pub(super) const ATTR_VOLUME_ID: u8 = 0x08;
+pub(super) const ATTR_DIRECTORY: u8 = 0x10;
+pub(super) const ATTR_ARCHIVE: u8 = 0x20;
+/// READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID marks an LFN entry
+pub(super) const ATTR_LONG_NAME: u8 = 0x0F;
+
+/// First name byte of a deleted entry
+pub(super) const DELETED: u8 = 0xE5;
+/// First name byte of the entry that ends the directory
+pub(super) const END: u8 = 0x00;
+
+// NTRes flags (Windows NT and Linux): the 8.3 name is shown in lower case
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+/// Last LFN entry of a run (the first one stored)
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LFN_CHARS: usize = 13;
+/// Where the 13 UTF-16 units sit in an LFN entry
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN: usize = 255;
+
+/// A short (8.3) directory entry
+#[derive(Clone, Copy)]
+pub(super) struct ShortEntry {
+    pub name: [u8; 11],
+    pub attr: u8,
+    pub nt_res: u8,
+    pub ctime: u16,
+    pub cdate: u16,
+    pub adate: u16,
+    pub cluster: u32,
+    pub mtime: u16,
+    pub mdate: u16,
+    pub size: u32,
+}
+
+fn read_u16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+impl ShortEntry {
+    pub fn parse(b: &[u8]) -> Self {
+        let mut name = [0u8; 11];
+        name.copy_from_slice(&b[..11]);
+        ShortEntry {
+            name,
+            attr: b[11],
+            nt_res: b[12],
+            ctime: read_u16(b, 14),
+            cdate: read_u16(b, 16),
+            adate: read_u16(b, 18),
+            cluster: (read_u16(b, 20) as u32) << 16 | read_u16(b, 26) as u32,
+            mtime: read_u16(b, 22),
+            mdate: read_u16(b, 24),
+            size: read_u32(b, 28),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; ENTRY_SIZE] {
+        let mut b = [0u8; ENTRY_SIZE];
+        b[..11].copy_from_slice(&self.name);
+        b[11] = self.attr;
+        b[12] = self.nt_res;
+        b[14..16].copy_from_slice(&self.ctime.to_le_bytes());
+        b[16..18].copy_from_slice(&self.cdate.to_le_bytes());
+        b[18..20].copy_from_slice(&self.adate.to_le_bytes());
+        b[20..22].copy_from_slice(&((self.cluster >> 16) as u16).to_le_bytes());
+        b[22..24].copy_from_slice(&self.mtime.to_le_bytes());
+        b[24..26].copy_from_slice(&self.mdate.to_le_bytes());
+        b[26..28].copy_from_slice(&(self.cluster as u16).to_le_bytes());
+        b[28..32].copy_from_slice(&self.size.to_le_bytes());
+        b
+    }
+
+    /// A new entry stamped with the current time
+    pub fn new(name: [u8; 11], nt_res: u8, attr: u8, cluster: u32) -> Self {
+        let (date, time) = dos_datetime(now() as u64);
+        ShortEntry {
+            name,
+            attr,
+            nt_res,
+            ctime: time,
+            cdate: date,
+            adate: date,
+            cluster,
+            mtime: time,
+            mdate: date,
+            size: 0,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Record a modification now
+    pub fn touch(&mut self) {
+        let (date, time) = dos_datetime(now() as u64);
+        self.mdate = date;
+        self.mtime = time;
+        self.adate = date;
+        self.attr |= ATTR_ARCHIVE;
+    }
+
+    /// The 8.3 name as displayed, e.g. `README.TXT` or `readme.txt`
+    pub fn display_name(&self) -> String {
+        let mut raw = self.name;
+        // 0xE5 is a valid first character in some code pages, stored as 0x05
+        if raw[0] == 0x05 {
+            raw[0] = DELETED;
+        }
+        let part = |bytes: &[u8], lower: bool| -> String {
+            let end = bytes.iter().rposition(|&c| c != b' ').map_or(0, |i| i + 1);
+            bytes[..end]
+                .iter()
+                .map(|&c| if lower { c.to_ascii_lowercase() } else { c } as char)
+                .collect()
+        };
+
+        let mut name = part(&raw[..8], self.nt_res & LOWER_BASE != 0);
+        let ext = part(&raw[8..], self.nt_res & LOWER_EXT != 0);
+        if !ext.is_empty() {
+            name.push('.');
+            name.push_str(&ext);
+        }
+        name
+    }
+}
+
+/// Checksum of an 8.3 name, stored in each of its LFN entries
+pub(super) fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Collects LFN entries while scanning a directory
+#[derive(Default)]
+pub(super) struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// Sequence number expected next (counting down to 1), or 0 if no run is open
+    expect: u8,
+    /// Slot of the first entry of the run
+    pub start: usize,
+}
+
+impl LongName {
+    /// Take one LFN entry at `slot`
+    pub fn push(&mut self, b: &[u8], slot: usize) {
+        let ord = b[0] & !LAST_LONG_ENTRY;
+        if b[0] & LAST_LONG_ENTRY != 0 {
+            if ord == 0 || ord as usize * LFN_CHARS > MAX_NAME_LEN + LFN_CHARS {
+                self.expect = 0;
+                return;
+            }
+            self.units = alloc::vec![0xFFFF; ord as usize * LFN_CHARS];
+            self.checksum = b[13];
+            self.start = slot;
+        } else if self.expect == 0 || ord != self.expect || b[13] != self.checksum {
+            // Out of sequence: an orphan left by a non-LFN-aware tool
+            self.expect = 0;
+            return;
+        }
+
+        let base = (ord as usize - 1) * LFN_CHARS;
+        for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+            self.units[base + i] = read_u16(b, off);
+        }
+        self.expect = ord - 1;
+    }
+
+    /// Forget a partial run
+    pub fn reset(&mut self) {
+        self.expect = 0;
+        self.units.clear();
+    }
+
+    /// The completed name belonging to `short`, if the run matches it
+    pub fn finish(&mut self, short: &ShortEntry) -> Option<String> {
+        let complete = !self.units.is_empty() && self.expect == 0 && self.checksum == checksum(&short.name);
+        let name = if complete {
+            let len = self.units.iter().position(|&u| u == 0 || u == 0xFFFF).unwrap_or(self.units.len());
+            char::decode_utf16(self.units[..len].iter().copied())
+                .collect::<Result<String, _>>()
+                .ok()
+                .filter(|n| !n.is_empty())
+        } else {
+            None
+        };
+        self.reset();
+        name
+    }
+}
+
+/// Characters a long name may not contain
+fn valid_long_char(c: char) -> bool {
+    c >= ' ' && !matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+}
+
+/// Characters allowed in an 8.3 name besides letters and digits
+fn valid_short_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"$%'-_@~`!(){}^#&".contains(&c) || c >= 0x80
+}
+
+/// Check a name for a new entry
+pub(super) fn validate_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    if name.encode_utf16().count() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    // Windows drops trailing dots and spaces, so such names can't round-trip
+    if !name.chars().all(valid_long_char) || name.ends_with('.') || name.ends_with(' ') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// `name` as an exact 8.3 name with NTRes case flags, if it is one:
+/// each part all upper or all lower case, nothing needing an LFN
+pub(super) fn exact_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rsplit_once('.') {
+        Some((b, e)) => (b, e),
+        None => (name, ""),
+    };
+    let ok = |part: &str, max: usize| {
+        !part.is_empty() && part.len() <= max && part.bytes().all(|c| c < 0x80 && valid_short_char(c))
+    };
+    if !ok(base, 8) || !(ext.is_empty() || ok(ext, 3)) {
+        return None;
+    }
+
+    let case = |part: &str, flag: u8| -> Option<u8> {
+        let upper = part.bytes().any(|c| c.is_ascii_uppercase());
+        let lower = part.bytes().any(|c| c.is_ascii_lowercase());
+        match (upper, lower) {
+            (true, true) => None,
+            (false, true) => Some(flag),
+            _ => Some(0),
+        }
+    };
+    let nt_res = case(base, LOWER_BASE)? | case(ext, LOWER_EXT)?;
+
+    let mut short = [b' '; 11];
+    for (i, c) in base.bytes().enumerate() {
+        short[i] = c.to_ascii_uppercase();
+    }
+    for (i, c) in ext.bytes().enumerate() {
+        short[8 + i] = c.to_ascii_uppercase();
+    }
+    if short[0] == DELETED {
+        short[0] = 0x05;
+    }
+    Some((short, nt_res))
+}
+
+/// The `n`th numeric-tail 8.3 alias for a long name, e.g. `LONGFI~1TXT`
+pub(super) fn alias(name: &str, n: u32) -> [u8; 11] {
+    let clean = |s: &str| -> Vec<u8> {
+        s.bytes()
+            .filter(|&c| c != b' ' && c != b'.')
+            .map(|c| {
+                let c = c.to_ascii_uppercase();
+                if c < 0x80 && valid_short_char(c) { c } else { b'_' }
+            })
+            .collect()
+    };
+    let trimmed = name.trim_start_matches('.');
+    let (base, ext) = match trimmed.rsplit_once('.') {
+        Some((b, e)) => (clean(b), clean(e)),
+        None => (clean(trimmed), Vec::new()),
+    };
+
+    let mut tail = [0u8; 8];
+    let mut digits = 0;
+    let mut v = n;
+    while v > 0 && digits < 7 {
+        tail[7 - digits] = b'0' + (v % 10) as u8;
+        v /= 10;
+        digits += 1;
+    }
+    tail[7 - digits] = b'~';
+    let tail = &tail[7 - digits..];
+
+    let mut short = [b' '; 11];
+    let keep = base.len().min(8 - tail.len()).max(if base.is_empty() { 0 } else { 1 });
+    short[..keep].copy_from_slice(&base[..keep]);
+    if keep == 0 {
+        short[0] = b'_';
+    }
+    let at = keep.max(1);
+    short[at..at + tail.len()].copy_from_slice(tail);
+    for (i, &c) in ext.iter().take(3).enumerate() {
+        short[8 + i] = c;
+    }
+    short
+}
+
+/// LFN entries for `name`, in on-disk order, ahead of the short entry `short`
+pub(super) fn long_entries(name: &str, short: &[u8; 11]) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(LFN_CHARS);
+    if !units.len().is_multiple_of(LFN_CHARS) {
+        units.push(0);
+    }
+    units.resize(count * LFN_CHARS, 0xFFFF);
+
+    let sum = checksum(short);
+    (1..=count)
+        .rev()
+        .map(|ord| {
+            let mut b = [0u8; ENTRY_SIZE];
+            b[0] = ord as u8 | if ord == count { LAST_LONG_ENTRY } else { 0 };
+            b[11] = ATTR_LONG_NAME;
+            b[13] = sum;
+            for (i, &off) in LFN_OFFSETS.iter().enumerate() {
+                let u = units[(ord - 1) * LFN_CHARS + i];
+                b[off..off + 2].copy_from_slice(&u.to_le_bytes());
+            }
+            b
+        })
+        .collect()
+}
+
+// --- Timestamps ---
+
+/// Days since 1970-01-01 for a civil date (Howard Hinnant's algorithm)
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if m <= 2 { 1 } else { 0 }, m, d)
+}
+
+/// DOS `(date, time)` for seconds since the UNIX epoch, clamped to 1980..=2107
+pub(super) fn dos_datetime(unix: u64) -> (u16, u16) {
+    let (y, m, d) = civil_from_days((unix / 86400) as i64);
+    if y < 1980 {
+        return (1 << 5 | 1, 0); // 1980-01-01
+    }
+    if y > 2107 {
+        return (127 << 9 | 12 << 5 | 31, 23 << 11 | 59 << 5 | 29);
+    }
+    let secs = unix % 86400;
+    let date = ((y - 1980) as u16) << 9 | (m as u16) << 5 | d as u16;
+    let time = ((secs / 3600) as u16) << 11 | ((secs / 60 % 60) as u16) << 5 | (secs % 60 / 2) as u16;
+    (date, time)
+}
+
+/// Seconds since the UNIX epoch for a DOS date and time (0 if unset)
+pub(super) fn unix_time(date: u16, time: u16) -> u32 {
+    if date == 0 {
+        return 0;
+    }
+    let (y, m, d) = (1980 + (date >> 9) as i64, ((date >> 5) & 0xF) as u32, (date & 0x1F) as u32);
+    let days = days_from_civil(y, m.clamp(1, 12), d.clamp(1, 31));
+    let secs = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    (days * 86400 + secs) as u32
+}
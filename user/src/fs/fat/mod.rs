@@ -0,0 +1,906 @@
+/// FAT12/16/32 filesystem driver with long file names
+///
+/// The FAT variant is decided the way the specification says: by the
+/// number of data clusters, not by the label in the boot sector. Like
+/// the ext2 driver, everything read from the device is range-checked, and
+/// cluster chains are bounded so a looping chain is reported as corruption.
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::vfs::FileSystem;
+use super::{split_parent_with, BlockDevice, Corruption, DirEntry, FileType, FsError, Metadata, RamDisk};
+use dir::{LongName, ShortEntry, ATTR_DIRECTORY, ATTR_LONG_NAME, ATTR_READ_ONLY, ATTR_VOLUME_ID, DELETED, END, ENTRY_SIZE};
+
+mod dir;
+
+#[cfg(test)]
+mod tests;
+
+const BOOT_SIGNATURE: u16 = 0xAA55;
+/// Below this many clusters a volume is FAT12, below `FAT16_MAX_CLUSTERS` FAT16
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+/// The first data cluster; 0 and 1 are reserved FAT entries
+const FIRST_CLUSTER: u32 = 2;
+/// Directories may hold at most this many entries
+const MAX_DIR_ENTRIES: usize = 65536;
+/// Numeric tails tried before giving up on a unique 8.3 alias
+const MAX_ALIAS_TAIL: u32 = 999_999;
+
+// FAT32 FSInfo sector
+const FSINFO_LEAD_SIG: u32 = 0x4161_5252;
+const FSINFO_STRUC_SIG: u32 = 0x6141_7272;
+const FSINFO_FREE_COUNT: u64 = 488;
+const FSINFO_NEXT_FREE: u64 = 492;
+/// FSInfo value meaning "not known"
+const FSINFO_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// Smallest FAT entry value that ends a chain
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0x0FF8,
+            FatType::Fat16 => 0xFFF8,
+            FatType::Fat32 => 0x0FFF_FFF8,
+        }
+    }
+}
+
+/// Whether `dev` starts with a FAT boot sector
+pub fn is_fat<D: BlockDevice>(dev: &D) -> bool {
+    let mut boot = [0u8; 512];
+    if dev.read_at(0, &mut boot).is_err() {
+        return false;
+    }
+    let bytes_per_sector = u16::from_le_bytes([boot[11], boot[12]]);
+    u16::from_le_bytes([boot[510], boot[511]]) == BOOT_SIGNATURE
+        && matches!(boot[0], 0xEB | 0xE9)
+        && matches!(bytes_per_sector, 512 | 1024 | 2048 | 4096)
+}
+
+/// A directory: the fixed FAT12/16 root region, or a cluster chain
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Dir {
+    FixedRoot,
+    Chain(u32),
+}
+
+/// A directory entry found on disk, with where it is stored
+struct Located {
+    parent: Dir,
+    /// Slot of the first LFN entry (or of the short entry, without one)
+    first_slot: usize,
+    /// Slot of the short entry
+    slot: usize,
+    entry: ShortEntry,
+    name: String,
+}
+
+/// What a path names
+enum Target {
+    Root,
+    Entry(Located),
+}
+
+/// A directory read into memory, with the device ranges it came from
+struct DirData {
+    bytes: Vec<u8>,
+    /// `(device offset, length)` of each stretch, in order
+    regions: Vec<(u64, usize)>,
+}
+
+impl DirData {
+    fn slots(&self) -> usize {
+        self.bytes.len() / ENTRY_SIZE
+    }
+
+    fn slot(&self, i: usize) -> &[u8] {
+        &self.bytes[i * ENTRY_SIZE..(i + 1) * ENTRY_SIZE]
+    }
+
+    /// Device offset of slot `i`
+    fn slot_addr(&self, i: usize) -> Option<u64> {
+        let mut pos = i * ENTRY_SIZE;
+        for &(addr, len) in &self.regions {
+            if pos < len {
+                return Some(addr + pos as u64);
+            }
+            pos -= len;
+        }
+        None
+    }
+}
+
+pub struct FatFs<D: BlockDevice = RamDisk> {
+    dev: D,
+    fat_type: FatType,
+    cluster_size: usize,
+    /// Byte offset and length of the first FAT
+    fat_start: u64,
+    fat_size: u64,
+    num_fats: u32,
+    /// FAT12/16 root directory region (offset, entry count)
+    root_start: u64,
+    root_entries: usize,
+    /// FAT32 root directory cluster
+    root_cluster: u32,
+    /// Byte offset of cluster 2
+    data_start: u64,
+    cluster_count: u32,
+    /// FAT32 FSInfo sector offset, when it has valid signatures
+    fsinfo: Option<u64>,
+    /// Where the next cluster search starts
+    next_free: u32,
+}
+
+fn read_u16(b: &[u8], off: usize) -> u16 {
+    u16::from_le_bytes([b[off], b[off + 1]])
+}
+
+fn read_u32(b: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([b[off], b[off + 1], b[off + 2], b[off + 3]])
+}
+
+const fn corrupt(what: Corruption) -> FsError {
+    FsError::Corrupt(what)
+}
+
+/// Split a path into its parent directory and a valid long name
+fn split_parent(path: &str) -> Result<(&str, &str), FsError> {
+    split_parent_with(path, dir::validate_name)
+}
+
+impl<D: BlockDevice> FatFs<D> {
+    /// Mount the FAT volume on `dev`, validating the BIOS parameter block
+    pub fn mount(dev: D) -> Result<Self, FsError> {
+        if !is_fat(&dev) {
+            return Err(corrupt(Corruption::BadMagic));
+        }
+        let mut boot = [0u8; 512];
+        dev.read_at(0, &mut boot)?;
+
+        let bytes_per_sector = read_u16(&boot, 11) as u64;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = read_u16(&boot, 14) as u64;
+        let num_fats = boot[16] as u32;
+        let root_entries = read_u16(&boot, 17) as usize;
+        let total_sectors = match read_u16(&boot, 19) {
+            0 => read_u32(&boot, 32) as u64,
+            n => n as u64,
+        };
+        let fat_sectors = match read_u16(&boot, 22) {
+            0 => read_u32(&boot, 36) as u64,
+            n => n as u64,
+        };
+
+        let cluster_size = bytes_per_sector * sectors_per_cluster;
+        if !sectors_per_cluster.is_power_of_two()
+            || cluster_size > 65536
+            || reserved == 0
+            || num_fats == 0
+            || fat_sectors == 0
+            || total_sectors == 0
+        {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+        if total_sectors * bytes_per_sector > dev.size() {
+            return Err(corrupt(Corruption::Truncated));
+        }
+
+        let root_sectors = (root_entries as u64 * ENTRY_SIZE as u64).div_ceil(bytes_per_sector);
+        let first_data_sector = reserved + num_fats as u64 * fat_sectors + root_sectors;
+        let data_sectors = total_sectors.checked_sub(first_data_sector).ok_or(corrupt(Corruption::BadSuperblock))?;
+        let cluster_count = u32::try_from(data_sectors / sectors_per_cluster).map_err(|_| corrupt(Corruption::BadSuperblock))?;
+
+        let fat_type = if cluster_count < FAT12_MAX_CLUSTERS {
+            FatType::Fat12
+        } else if cluster_count < FAT16_MAX_CLUSTERS {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+
+        // The FAT must have an entry for every cluster
+        let fat_size = fat_sectors * bytes_per_sector;
+        let entries = cluster_count as u64 + FIRST_CLUSTER as u64;
+        let needed = match fat_type {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        };
+        if cluster_count == 0 || fat_size < needed {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+
+        let mut fs = FatFs {
+            dev,
+            fat_type,
+            cluster_size: cluster_size as usize,
+            fat_start: reserved * bytes_per_sector,
+            fat_size,
+            num_fats,
+            root_start: (reserved + num_fats as u64 * fat_sectors) * bytes_per_sector,
+            root_entries,
+            root_cluster: 0,
+            data_start: first_data_sector * bytes_per_sector,
+            cluster_count,
+            fsinfo: None,
+            next_free: FIRST_CLUSTER,
+        };
+
+        if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err(corrupt(Corruption::BadSuperblock));
+            }
+            fs.root_cluster = read_u32(&boot, 44);
+            fs.check_cluster(fs.root_cluster)?;
+
+            let fsinfo = read_u16(&boot, 48) as u64;
+            if fsinfo != 0 && fsinfo < reserved {
+                let addr = fsinfo * bytes_per_sector;
+                let mut sector = [0u8; 512];
+                fs.dev.read_at(addr, &mut sector)?;
+                if read_u32(&sector, 0) == FSINFO_LEAD_SIG && read_u32(&sector, 484) == FSINFO_STRUC_SIG {
+                    fs.fsinfo = Some(addr);
+                    let hint = read_u32(&sector, FSINFO_NEXT_FREE as usize);
+                    if fs.check_cluster(hint).is_ok() {
+                        fs.next_free = hint;
+                    }
+                }
+            }
+        } else if root_entries == 0 {
+            return Err(corrupt(Corruption::BadSuperblock));
+        }
+        Ok(fs)
+    }
+
+    /// Give back the underlying device
+    pub fn into_inner(self) -> D {
+        self.dev
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.cluster_size
+    }
+
+    // --- The allocation table ---
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FsError> {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.cluster_count {
+            return Err(corrupt(Corruption::BlockOutOfRange));
+        }
+        Ok(())
+    }
+
+    fn cluster_addr(&self, cluster: u32) -> Result<u64, FsError> {
+        self.check_cluster(cluster)?;
+        Ok(self.data_start + (cluster - FIRST_CLUSTER) as u64 * self.cluster_size as u64)
+    }
+
+    /// Byte offset of a cluster's entry within a FAT
+    fn fat_offset(&self, cluster: u32) -> u64 {
+        match self.fat_type {
+            FatType::Fat12 => cluster as u64 + cluster as u64 / 2,
+            FatType::Fat16 => cluster as u64 * 2,
+            FatType::Fat32 => cluster as u64 * 4,
+        }
+    }
+
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let addr = self.fat_start + self.fat_offset(cluster);
+        match self.fat_type {
+            FatType::Fat12 => {
+                let mut b = [0u8; 2];
+                self.dev.read_at(addr, &mut b)?;
+                let v = u16::from_le_bytes(b) as u32;
+                Ok(if cluster & 1 == 1 { v >> 4 } else { v & 0xFFF })
+            }
+            FatType::Fat16 => {
+                let mut b = [0u8; 2];
+                self.dev.read_at(addr, &mut b)?;
+                Ok(u16::from_le_bytes(b) as u32)
+            }
+            FatType::Fat32 => {
+                let mut b = [0u8; 4];
+                self.dev.read_at(addr, &mut b)?;
+                Ok(u32::from_le_bytes(b) & 0x0FFF_FFFF)
+            }
+        }
+    }
+
+    /// Set a cluster's entry in every copy of the FAT
+    fn set_fat(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for copy in 0..self.num_fats as u64 {
+            let addr = self.fat_start + copy * self.fat_size + self.fat_offset(cluster);
+            match self.fat_type {
+                FatType::Fat12 => {
+                    let mut b = [0u8; 2];
+                    self.dev.read_at(addr, &mut b)?;
+                    let old = u16::from_le_bytes(b);
+                    let v = (value & 0xFFF) as u16;
+                    let new = if cluster & 1 == 1 { (old & 0x000F) | v << 4 } else { (old & 0xF000) | v };
+                    self.dev.write_at(addr, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.dev.write_at(addr, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // The top four bits are reserved and must be preserved
+                    let mut b = [0u8; 4];
+                    self.dev.read_at(addr, &mut b)?;
+                    let new = (u32::from_le_bytes(b) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.dev.write_at(addr, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Every cluster of the chain starting at `first` (empty for 0)
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            self.check_cluster(cluster)?;
+            // Longer than the volume: the chain loops
+            if clusters.len() >= self.cluster_count as usize {
+                return Err(corrupt(Corruption::BadClusterChain));
+            }
+            clusters.push(cluster);
+            let next = self.fat_entry(cluster)?;
+            if next >= self.fat_type.end_of_chain() {
+                break;
+            }
+            if next == 0 {
+                return Err(corrupt(Corruption::BadClusterChain));
+            }
+            cluster = next;
+        }
+        Ok(clusters)
+    }
+
+    /// Adjust the FAT32 free cluster count, if the volume keeps one
+    fn adjust_free_count(&mut self, delta: i32, hint: u32) -> Result<(), FsError> {
+        let Some(addr) = self.fsinfo else { return Ok(()) };
+        let mut b = [0u8; 4];
+        self.dev.read_at(addr + FSINFO_FREE_COUNT, &mut b)?;
+        let count = u32::from_le_bytes(b);
+        if count != FSINFO_UNKNOWN && count <= self.cluster_count {
+            let count = count.saturating_add_signed(delta).min(self.cluster_count);
+            self.dev.write_at(addr + FSINFO_FREE_COUNT, &count.to_le_bytes())?;
+        }
+        self.dev.write_at(addr + FSINFO_NEXT_FREE, &hint.to_le_bytes())
+    }
+
+    /// Allocate a zeroed cluster and mark it as the end of a chain
+    fn alloc_cluster(&mut self) -> Result<u32, FsError> {
+        let start = self.next_free.clamp(FIRST_CLUSTER, FIRST_CLUSTER + self.cluster_count - 1) - FIRST_CLUSTER;
+        for k in 0..self.cluster_count {
+            let cluster = FIRST_CLUSTER + (start + k) % self.cluster_count;
+            if self.fat_entry(cluster)? != 0 {
+                continue;
+            }
+            self.set_fat(cluster, 0x0FFF_FFFF)?;
+            let addr = self.cluster_addr(cluster)?;
+            self.dev.write_at(addr, &vec![0u8; self.cluster_size])?;
+            self.next_free = cluster + 1;
+            self.adjust_free_count(-1, cluster)?;
+            return Ok(cluster);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Free a whole chain
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        let clusters = self.chain(first)?;
+        for &cluster in &clusters {
+            self.set_fat(cluster, 0)?;
+        }
+        if let Some(&last) = clusters.last() {
+            self.adjust_free_count(clusters.len() as i32, last)?;
+        }
+        Ok(())
+    }
+
+    /// Make a chain `count` clusters long, allocating or freeing at its end.
+    /// Returns the (possibly new) first cluster and the whole chain.
+    fn resize_chain(&mut self, first: u32, count: usize) -> Result<(u32, Vec<u32>), FsError> {
+        let mut clusters = self.chain(first)?;
+        if count < clusters.len() {
+            match count {
+                0 => self.free_chain(first)?,
+                _ => {
+                    let next = clusters[count];
+                    self.set_fat(clusters[count - 1], 0x0FFF_FFFF)?;
+                    self.free_chain(next)?;
+                }
+            }
+            clusters.truncate(count);
+        }
+        let had = clusters.len();
+        while clusters.len() < count {
+            let cluster = match self.alloc_cluster() {
+                Ok(cluster) => cluster,
+                Err(e) => {
+                    // Give back what this call allocated so nothing leaks
+                    if had < clusters.len() {
+                        if had > 0 {
+                            self.set_fat(clusters[had - 1], 0x0FFF_FFFF)?;
+                        }
+                        self.free_chain(clusters[had])?;
+                    }
+                    return Err(e);
+                }
+            };
+            if let Some(&last) = clusters.last() {
+                self.set_fat(last, cluster)?;
+            }
+            clusters.push(cluster);
+        }
+        Ok((clusters.first().copied().unwrap_or(0), clusters))
+    }
+
+    // --- Directories ---
+
+    fn read_dir_data(&self, dir: Dir) -> Result<DirData, FsError> {
+        let regions = match dir {
+            Dir::FixedRoot => vec![(self.root_start, self.root_entries * ENTRY_SIZE)],
+            Dir::Chain(first) => {
+                let mut regions = Vec::new();
+                for cluster in self.chain(first)? {
+                    regions.push((self.cluster_addr(cluster)?, self.cluster_size));
+                }
+                regions
+            }
+        };
+        let total: usize = regions.iter().map(|&(_, len)| len).sum();
+        let mut bytes = vec![0u8; total];
+        let mut pos = 0;
+        for &(addr, len) in &regions {
+            self.dev.read_at(addr, &mut bytes[pos..pos + len])?;
+            pos += len;
+        }
+        Ok(DirData { bytes, regions })
+    }
+
+    /// Every live entry of a directory, with its long name when it has one
+    fn entries(&self, dir: Dir) -> Result<(DirData, Vec<Located>), FsError> {
+        let data = self.read_dir_data(dir)?;
+        let mut found = Vec::new();
+        let mut long = LongName::default();
+
+        for slot in 0..data.slots() {
+            let b = data.slot(slot);
+            match b[0] {
+                END => break,
+                DELETED => {
+                    long.reset();
+                    continue;
+                }
+                _ => {}
+            }
+            if b[11] & 0x3F == ATTR_LONG_NAME {
+                long.push(b, slot);
+                continue;
+            }
+            let entry = ShortEntry::parse(b);
+            let start = long.start;
+            let long_name = long.finish(&entry);
+            if entry.attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let first_slot = if long_name.is_some() { start } else { slot };
+            let name = long_name.unwrap_or_else(|| entry.display_name());
+            found.push(Located { parent: dir, first_slot, slot, entry, name });
+        }
+        Ok((data, found))
+    }
+
+    /// The directory an entry describes
+    fn as_dir(&self, entry: &ShortEntry) -> Dir {
+        // `..` entries pointing at the root store cluster 0
+        match (entry.cluster, self.fat_type) {
+            (0, FatType::Fat32) => Dir::Chain(self.root_cluster),
+            (0, _) => Dir::FixedRoot,
+            (c, _) => Dir::Chain(c),
+        }
+    }
+
+    fn root(&self) -> Dir {
+        match self.fat_type {
+            FatType::Fat32 => Dir::Chain(self.root_cluster),
+            _ => Dir::FixedRoot,
+        }
+    }
+
+    /// Find `name` in a directory; names compare case-insensitively, as on
+    /// every FAT implementation
+    fn find(&self, dir: Dir, name: &str) -> Result<Located, FsError> {
+        let (_, entries) = self.entries(dir)?;
+        entries
+            .into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name) || e.entry.display_name().eq_ignore_ascii_case(name))
+            .ok_or(FsError::NotFound)
+    }
+
+    fn resolve(&self, path: &str) -> Result<Target, FsError> {
+        let mut target = Target::Root;
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let dir = match &target {
+                Target::Root => self.root(),
+                Target::Entry(e) if e.entry.is_dir() => self.as_dir(&e.entry),
+                Target::Entry(_) => return Err(FsError::NotADirectory),
+            };
+            if component == ".." && dir == self.root() {
+                continue;
+            }
+            let found = self.find(dir, component)?;
+            target = if found.entry.is_dir() && self.as_dir(&found.entry) == self.root() {
+                Target::Root
+            } else {
+                Target::Entry(found)
+            };
+        }
+        Ok(target)
+    }
+
+    /// The directory at `path`
+    fn resolve_dir(&self, path: &str) -> Result<Dir, FsError> {
+        match self.resolve(path)? {
+            Target::Root => Ok(self.root()),
+            Target::Entry(e) if e.entry.is_dir() => Ok(self.as_dir(&e.entry)),
+            Target::Entry(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// The file (not directory) at `path`
+    fn resolve_file(&self, path: &str) -> Result<Located, FsError> {
+        match self.resolve(path)? {
+            Target::Entry(e) if !e.entry.is_dir() => Ok(e),
+            _ => Err(FsError::IsADirectory),
+        }
+    }
+
+    /// Store an updated short entry in its slot
+    fn write_entry(&mut self, at: &Located) -> Result<(), FsError> {
+        let data = self.read_dir_data(at.parent)?;
+        let addr = data.slot_addr(at.slot).ok_or(corrupt(Corruption::BadDirEntry))?;
+        self.dev.write_at(addr, &at.entry.encode())
+    }
+
+    /// Mark an entry and its LFN run deleted
+    fn delete_entry(&mut self, at: &Located) -> Result<(), FsError> {
+        let data = self.read_dir_data(at.parent)?;
+        for slot in at.first_slot..=at.slot {
+            let addr = data.slot_addr(slot).ok_or(corrupt(Corruption::BadDirEntry))?;
+            self.dev.write_at(addr, &[DELETED])?;
+        }
+        Ok(())
+    }
+
+    /// First of `n` consecutive free slots, growing the directory if needed
+    fn free_slots(&mut self, dir: Dir, n: usize) -> Result<(DirData, usize), FsError> {
+        loop {
+            let data = self.read_dir_data(dir)?;
+            let mut run = 0;
+            for slot in 0..data.slots() {
+                let b = data.slot(slot)[0];
+                if b == DELETED || b == END {
+                    run += 1;
+                    if run == n {
+                        return Ok((data, slot + 1 - n));
+                    }
+                } else {
+                    run = 0;
+                }
+            }
+
+            let Dir::Chain(first) = dir else { return Err(FsError::NoSpace) };
+            if data.slots() + self.cluster_size / ENTRY_SIZE > MAX_DIR_ENTRIES {
+                return Err(FsError::NoSpace);
+            }
+            let count = self.chain(first)?.len();
+            self.resize_chain(first, count + 1)?;
+        }
+    }
+
+    /// Add an entry named `name` to `dir`, with LFN entries when the name
+    /// isn't a plain 8.3 name. `entry.name` is filled in here.
+    fn add_entry(&mut self, dir: Dir, name: &str, mut entry: ShortEntry) -> Result<Located, FsError> {
+        let (_, existing) = self.entries(dir)?;
+        let mut long = Vec::new();
+        match dir::exact_short_name(name) {
+            Some((short, nt_res)) if !existing.iter().any(|e| e.entry.name == short) => {
+                entry.name = short;
+                entry.nt_res = nt_res;
+            }
+            _ => {
+                let short = (1..=MAX_ALIAS_TAIL)
+                    .map(|n| dir::alias(name, n))
+                    .find(|s| !existing.iter().any(|e| e.entry.name == *s))
+                    .ok_or(FsError::AlreadyExists)?;
+                entry.name = short;
+                entry.nt_res = 0;
+                long = dir::long_entries(name, &short);
+            }
+        }
+
+        let (data, first_slot) = self.free_slots(dir, long.len() + 1)?;
+        for (i, raw) in long.iter().chain(core::iter::once(&entry.encode())).enumerate() {
+            let addr = data.slot_addr(first_slot + i).ok_or(corrupt(Corruption::BadDirEntry))?;
+            self.dev.write_at(addr, raw)?;
+        }
+        Ok(Located { parent: dir, first_slot, slot: first_slot + long.len(), entry, name: name.into() })
+    }
+
+    /// Parent directory and name for a new entry at `path`, which must not exist yet
+    fn prepare_entry<'p>(&self, path: &'p str) -> Result<(Dir, &'p str), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.resolve_dir(parent)?;
+        match self.find(dir, name) {
+            Ok(_) => Err(FsError::AlreadyExists),
+            Err(FsError::NotFound) => Ok((dir, name)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Cluster number a `..` entry stores for `dir`
+    fn dotdot_cluster(&self, dir: Dir) -> u32 {
+        match dir {
+            Dir::Chain(c) if c != self.root_cluster || self.fat_type != FatType::Fat32 => c,
+            _ => 0,
+        }
+    }
+
+    // --- File data ---
+
+    /// Grow or shrink a file to `size` bytes; new bytes read as zeros
+    fn set_size(&mut self, at: &mut Located, size: u64) -> Result<Vec<u32>, FsError> {
+        let size = u32::try_from(size).map_err(|_| FsError::TooLarge)?;
+        let old = at.entry.size;
+        let count = (size as usize).div_ceil(self.cluster_size);
+        let (first, clusters) = self.resize_chain(at.entry.cluster, count)?;
+
+        // Clear stale bytes past the old end in its last cluster
+        if size > old && !(old as usize).is_multiple_of(self.cluster_size) {
+            let cluster = clusters[old as usize / self.cluster_size];
+            let within = old as usize % self.cluster_size;
+            let len = (self.cluster_size - within).min((size - old) as usize);
+            self.dev.write_at(self.cluster_addr(cluster)? + within as u64, &vec![0u8; len])?;
+        }
+
+        at.entry.cluster = first;
+        at.entry.size = size;
+        at.entry.touch();
+        self.write_entry(at)?;
+        Ok(clusters)
+    }
+}
+
+impl<D: BlockDevice> FileSystem for FatFs<D> {
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        let entry = match self.resolve(path)? {
+            Target::Root => {
+                return Ok(Metadata {
+                    inode: self.dotdot_cluster(self.root()).max(1),
+                    file_type: FileType::Directory,
+                    mode: 0o755,
+                    size: 0,
+                    uid: 0,
+                    gid: 0,
+                    links: 2,
+                    atime: 0,
+                    mtime: 0,
+                    ctime: 0,
+                })
+            }
+            Target::Entry(e) => e.entry,
+        };
+        let (file_type, mode) = if entry.is_dir() { (FileType::Directory, 0o755) } else { (FileType::Regular, 0o644) };
+        let mode = if entry.attr & ATTR_READ_ONLY != 0 { mode & !0o222 } else { mode };
+        Ok(Metadata {
+            inode: entry.cluster,
+            file_type,
+            mode,
+            size: if entry.is_dir() { 0 } else { entry.size as u64 },
+            uid: 0,
+            gid: 0,
+            links: 1,
+            atime: dir::unix_time(entry.adate, 0),
+            mtime: dir::unix_time(entry.mdate, entry.mtime),
+            ctime: dir::unix_time(entry.cdate, entry.ctime),
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let (_, entries) = self.entries(self.resolve_dir(path)?)?;
+        Ok(entries
+            .into_iter()
+            .map(|e| DirEntry {
+                name: e.name,
+                inode: e.entry.cluster,
+                file_type: if e.entry.is_dir() { FileType::Directory } else { FileType::Regular },
+            })
+            .collect())
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let at = self.resolve_file(path)?;
+        let size = at.entry.size as u64;
+        let len = size.saturating_sub(offset).min(buf.len() as u64) as usize;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let cs = self.cluster_size as u64;
+        let clusters = self.chain(at.entry.cluster)?;
+        if (clusters.len() as u64) < size.div_ceil(cs) {
+            return Err(corrupt(Corruption::BadClusterChain));
+        }
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % cs) as usize;
+            let n = (self.cluster_size - within).min(len - done);
+            let addr = self.cluster_addr(clusters[(pos / cs) as usize])?;
+            self.dev.read_at(addr + within as u64, &mut buf[done..done + n])?;
+            done += n;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut at = self.resolve_file(path)?;
+        let end = offset.checked_add(data.len() as u64).ok_or(FsError::TooLarge)?;
+        let size = (at.entry.size as u64).max(end);
+        let clusters = self.set_size(&mut at, size)?;
+
+        let cs = self.cluster_size as u64;
+        let mut done = 0;
+        while done < data.len() {
+            let pos = offset + done as u64;
+            let within = (pos % cs) as usize;
+            let n = (self.cluster_size - within).min(data.len() - done);
+            let addr = self.cluster_addr(clusters[(pos / cs) as usize])?;
+            self.dev.write_at(addr + within as u64, &data[done..done + n])?;
+            done += n;
+        }
+        Ok(data.len())
+    }
+
+    fn create(&mut self, path: &str, perm: u16) -> Result<(), FsError> {
+        let (dir, name) = self.prepare_entry(path)?;
+        let attr = if perm & 0o222 == 0 { ATTR_READ_ONLY | dir::ATTR_ARCHIVE } else { dir::ATTR_ARCHIVE };
+        self.add_entry(dir, name, ShortEntry::new([b' '; 11], 0, attr, 0)).map(|_| ())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = self.prepare_entry(path)?;
+        let cluster = self.alloc_cluster()?;
+
+        let mut dot = ShortEntry::new(*b".          ", 0, ATTR_DIRECTORY, cluster);
+        let mut dotdot = ShortEntry::new(*b"..         ", 0, ATTR_DIRECTORY, self.dotdot_cluster(parent));
+        dot.size = 0;
+        dotdot.size = 0;
+        let addr = self.cluster_addr(cluster)?;
+        self.dev.write_at(addr, &dot.encode())?;
+        self.dev.write_at(addr + ENTRY_SIZE as u64, &dotdot.encode())?;
+
+        if let Err(e) = self.add_entry(parent, name, ShortEntry::new([b' '; 11], 0, ATTR_DIRECTORY, cluster)) {
+            self.free_chain(cluster)?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let found = self.find(self.resolve_dir(parent)?, name)?;
+        if found.entry.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.delete_entry(&found)?;
+        self.free_chain(found.entry.cluster)
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let found = self.find(self.resolve_dir(parent)?, name)?;
+        if !found.entry.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        let (_, children) = self.entries(self.as_dir(&found.entry))?;
+        if children.iter().any(|c| c.name != "." && c.name != "..") {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        self.delete_entry(&found)?;
+        self.free_chain(found.entry.cluster)
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let mut at = self.resolve_file(path)?;
+        self.set_size(&mut at, size).map(|_| ())
+    }
+
+    /// Move an entry, replacing an existing file, or an empty directory
+    /// when moving a directory
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = split_parent(from)?;
+        let (to_parent, to_name) = split_parent(to)?;
+        let source = self.find(self.resolve_dir(from_parent)?, from_name)?;
+        let dest_dir = self.resolve_dir(to_parent)?;
+        let moving_dir = source.entry.is_dir();
+
+        // A directory can't move into its own subtree: walk up from the destination.
+        // Every step lands on a different cluster, so a longer walk means `..` loops.
+        if moving_dir {
+            let mut d = dest_dir;
+            let mut steps = 0;
+            while d != self.root() {
+                if d == self.as_dir(&source.entry) {
+                    return Err(FsError::InvalidPath);
+                }
+                if steps == self.cluster_count {
+                    return Err(corrupt(Corruption::BadClusterChain));
+                }
+                steps += 1;
+                d = self.as_dir(&self.find(d, "..")?.entry);
+            }
+        }
+
+        match self.find(dest_dir, to_name) {
+            Ok(existing) if existing.parent == source.parent && existing.slot == source.slot => {}
+            Ok(existing) => {
+                match (moving_dir, existing.entry.is_dir()) {
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (true, true) => {
+                        let (_, children) = self.entries(self.as_dir(&existing.entry))?;
+                        if children.iter().any(|c| c.name != "." && c.name != "..") {
+                            return Err(FsError::DirectoryNotEmpty);
+                        }
+                    }
+                    _ => {}
+                }
+                self.delete_entry(&existing)?;
+                self.free_chain(existing.entry.cluster)?;
+            }
+            Err(FsError::NotFound) => {}
+            Err(e) => return Err(e),
+        }
+
+        // Free the old name first so a case-only rename keeps its 8.3 name,
+        // and put it back if the new one doesn't fit
+        let data = self.read_dir_data(source.parent)?;
+        let old = data.bytes[source.first_slot * ENTRY_SIZE..(source.slot + 1) * ENTRY_SIZE].to_vec();
+        self.delete_entry(&source)?;
+        if let Err(e) = self.add_entry(dest_dir, to_name, source.entry) {
+            for (i, raw) in old.chunks(ENTRY_SIZE).enumerate() {
+                let addr = data.slot_addr(source.first_slot + i).ok_or(corrupt(Corruption::BadDirEntry))?;
+                self.dev.write_at(addr, raw)?;
+            }
+            return Err(e);
+        }
+
+        if moving_dir && dest_dir != source.parent {
+            let mut dotdot = self.find(self.as_dir(&source.entry), "..")?;
+            dotdot.entry.cluster = self.dotdot_cluster(dest_dir);
+            self.write_entry(&dotdot)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        self.dev.flush()
+    }
+}
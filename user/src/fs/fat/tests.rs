@@ -0,0 +1,415 @@
+/// Host tests: FAT12, FAT16 and FAT32 volumes formatted in memory, a
+/// consistency check run after every change, and damaged images
+use super::*;
+use alloc::collections::BTreeSet;
+use alloc::string::ToString;
+
+const SECTOR: usize = 512;
+
+fn put16(img: &mut [u8], off: usize, v: u16) {
+    img[off..off + 2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(img: &mut [u8], off: usize, v: u32) {
+    img[off..off + 4].copy_from_slice(&v.to_le_bytes());
+}
+
+/// Format a volume the way mkfs.fat would, with two FATs
+fn mkfs(total: u32, per_cluster: u8, reserved: u16, root_entries: u16, fat_sectors: u32) -> Vec<u8> {
+    let mut img = vec![0u8; total as usize * SECTOR];
+    img[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+    img[3..11].copy_from_slice(b"AETHEROS");
+    put16(&mut img, 11, SECTOR as u16);
+    img[13] = per_cluster;
+    put16(&mut img, 14, reserved);
+    img[16] = 2;
+    put16(&mut img, 17, root_entries);
+    match u16::try_from(total) {
+        Ok(t) => put16(&mut img, 19, t),
+        Err(_) => put32(&mut img, 32, total),
+    }
+    img[21] = 0xF8;
+    put16(&mut img, 510, BOOT_SIGNATURE);
+
+    let fat32 = root_entries == 0;
+    let root_sectors = (root_entries as u32 * 32).div_ceil(SECTOR as u32);
+    let clusters = (total - reserved as u32 - 2 * fat_sectors - root_sectors) / per_cluster as u32;
+    if fat32 {
+        put32(&mut img, 36, fat_sectors);
+        put32(&mut img, 44, 2); // root cluster
+        put16(&mut img, 48, 1); // FSInfo sector
+    } else {
+        put16(&mut img, 22, fat_sectors as u16);
+    }
+
+    let fat_start = reserved as usize * SECTOR;
+    for copy in 0..2 {
+        let fat = fat_start + copy * fat_sectors as usize * SECTOR;
+        if fat32 {
+            put32(&mut img, fat, 0x0FFF_FFF8);
+            put32(&mut img, fat + 4, 0x0FFF_FFFF);
+            put32(&mut img, fat + 8, 0x0FFF_FFFF); // The root directory
+        } else if clusters < FAT12_MAX_CLUSTERS {
+            img[fat..fat + 3].copy_from_slice(&[0xF8, 0xFF, 0xFF]);
+        } else {
+            img[fat..fat + 4].copy_from_slice(&[0xF8, 0xFF, 0xFF, 0xFF]);
+        }
+    }
+
+    if fat32 {
+        let info = SECTOR;
+        put32(&mut img, info, FSINFO_LEAD_SIG);
+        put32(&mut img, info + 484, FSINFO_STRUC_SIG);
+        put32(&mut img, info + FSINFO_FREE_COUNT as usize, clusters - 1);
+        put32(&mut img, info + FSINFO_NEXT_FREE as usize, 3);
+        put32(&mut img, info + 508, 0xAA55_0000);
+    }
+    img
+}
+
+/// A 1.44MB floppy
+fn fat12() -> Vec<u8> {
+    mkfs(2880, 1, 1, 224, 9)
+}
+
+/// 16MiB with 2KiB clusters
+fn fat16() -> Vec<u8> {
+    mkfs(32768, 4, 1, 512, 32)
+}
+
+/// Just over the FAT32 threshold, with 512-byte clusters
+fn fat32() -> Vec<u8> {
+    mkfs(70000, 1, 32, 0, 548)
+}
+
+fn mount(img: Vec<u8>) -> FatFs<Vec<u8>> {
+    FatFs::mount(img).expect("mount")
+}
+
+fn names(fs: &FatFs<Vec<u8>>, path: &str) -> Vec<String> {
+    fs.read_dir(path).unwrap().into_iter().map(|e| e.name).collect()
+}
+
+fn write_file(fs: &mut FatFs<Vec<u8>>, path: &str, data: &[u8]) {
+    if fs.stat(path).is_err() {
+        fs.create(path, 0o644).unwrap();
+    }
+    fs.truncate(path, 0).unwrap();
+    fs.write_at(path, 0, data).unwrap();
+}
+
+fn read_file(fs: &mut FatFs<Vec<u8>>, path: &str) -> Vec<u8> {
+    let mut buf = vec![0u8; fs.stat(path).unwrap().size as usize];
+    assert_eq!(fs.read_at(path, 0, &mut buf).unwrap(), buf.len());
+    buf
+}
+
+/// What fsck.fat would look at: every cluster belongs to exactly one
+/// chain, every chain matches its file's size, nothing allocated is
+/// unreachable, the FAT copies agree and FSInfo's free count is right
+fn check(fs: &FatFs<Vec<u8>>) {
+    let mut used = BTreeSet::new();
+    let mut dirs = vec![fs.root()];
+    if let Dir::Chain(c) = fs.root() {
+        used.extend(fs.chain(c).unwrap());
+    }
+    while let Some(dir) = dirs.pop() {
+        let (_, entries) = fs.entries(dir).unwrap();
+        for e in entries.iter().filter(|e| e.name != "." && e.name != "..") {
+            let chain = fs.chain(e.entry.cluster).unwrap();
+            if e.entry.is_dir() {
+                assert!(!chain.is_empty(), "{} has no clusters", e.name);
+                let dotdot = fs.find(fs.as_dir(&e.entry), "..").unwrap();
+                assert!(fs.as_dir(&dotdot.entry) == dir, "{} has a stale ..", e.name);
+                dirs.push(fs.as_dir(&e.entry));
+            } else {
+                assert_eq!(chain.len(), (e.entry.size as usize).div_ceil(fs.cluster_size), "{}", e.name);
+            }
+            for c in chain {
+                assert!(used.insert(c), "cluster {} is cross-linked", c);
+            }
+        }
+    }
+
+    let mut free = 0;
+    for c in FIRST_CLUSTER..FIRST_CLUSTER + fs.cluster_count {
+        match fs.fat_entry(c).unwrap() {
+            0 => free += 1,
+            _ => assert!(used.contains(&c), "cluster {} is lost", c),
+        }
+    }
+
+    let fat = fs.fat_start as usize;
+    let size = fs.fat_size as usize;
+    assert!(fs.dev[fat..fat + size] == fs.dev[fat + size..fat + 2 * size], "FAT copies differ");
+    if let Some(info) = fs.fsinfo {
+        assert_eq!(read_u32(&fs.dev, (info + FSINFO_FREE_COUNT) as usize), free);
+    }
+}
+
+#[test]
+fn variant_follows_cluster_count() {
+    assert_eq!(mount(fat12()).fat_type(), FatType::Fat12);
+    assert_eq!(mount(fat16()).fat_type(), FatType::Fat16);
+    assert_eq!(mount(fat32()).fat_type(), FatType::Fat32);
+    for img in [fat12(), fat16(), fat32()] {
+        let fs = mount(img);
+        assert!(names(&fs, "/").is_empty());
+        assert!(fs.stat("/").unwrap().is_dir());
+        check(&fs);
+    }
+}
+
+#[test]
+fn write_read_round_trip() {
+    for img in [fat12(), fat16(), fat32()] {
+        let mut fs = mount(img);
+        write_file(&mut fs, "/hello.txt", b"Hello, world!");
+        assert_eq!(read_file(&mut fs, "HELLO.TXT"), b"Hello, world!");
+
+        let big: Vec<u8> = (0..40 * fs.cluster_size()).map(|i| (i % 251) as u8).collect();
+        write_file(&mut fs, "/big", &big);
+        assert_eq!(read_file(&mut fs, "/big"), big);
+        check(&fs);
+
+        fs.truncate("/big", 100).unwrap();
+        assert_eq!(read_file(&mut fs, "/big"), &big[..100]);
+
+        // Growing again leaves zeros, not the old contents
+        fs.write_at("/big", 5000, b"end").unwrap();
+        let data = read_file(&mut fs, "/big");
+        assert_eq!(data.len(), 5003);
+        assert!(data[100..5000].iter().all(|&b| b == 0));
+        check(&fs);
+
+        let fs = mount(fs.into_inner());
+        assert_eq!(fs.stat("/big").unwrap().size, 5003);
+        assert_eq!(fs.stat("/hello.txt").unwrap().mode, 0o644);
+    }
+}
+
+#[test]
+fn long_names() {
+    let mut fs = mount(fat32());
+    write_file(&mut fs, "/A file with a long name.text", b"1");
+    write_file(&mut fs, "/A file with a long name.text.bak", b"2");
+    write_file(&mut fs, "/readme.md", b"3");
+    write_file(&mut fs, "/Ünïcödé", b"4");
+    assert_eq!(
+        names(&fs, "/"),
+        ["A file with a long name.text", "A file with a long name.text.bak", "readme.md", "Ünïcödé"]
+    );
+
+    // Each long name got its own 8.3 alias, and both names find the file
+    let (_, entries) = fs.entries(fs.root()).unwrap();
+    assert_eq!(&entries[0].entry.name, b"AFILEW~1TEX");
+    assert_eq!(&entries[1].entry.name, b"AFILEW~1BAK");
+    assert_eq!(&entries[2].entry.name, b"README  MD ");
+    assert_eq!(read_file(&mut fs, "/afilew~1.bak"), b"2");
+    assert_eq!(read_file(&mut fs, "/a FILE with a long NAME.text"), b"1");
+
+    assert_eq!(fs.create("/README.MD", 0o644), Err(FsError::AlreadyExists));
+    assert_eq!(fs.create("/a:b", 0o644), Err(FsError::InvalidPath));
+    assert_eq!(fs.create(&"x".repeat(256), 0o644), Err(FsError::NameTooLong));
+
+    // Deleting frees the LFN slots along with the short entry
+    fs.unlink("/A file with a long name.text").unwrap();
+    write_file(&mut fs, "/Another long name", b"5");
+    assert_eq!(names(&fs, "/")[0], "Another long name");
+    check(&fs);
+}
+
+#[test]
+fn rename_files_and_directories() {
+    for img in [fat12(), fat32()] {
+        let mut fs = mount(img);
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        fs.mkdir("/c").unwrap();
+        write_file(&mut fs, "/a/b/f", b"data");
+        write_file(&mut fs, "/c/g", b"other");
+
+        assert_eq!(fs.rename("/a", "/a/b/a"), Err(FsError::InvalidPath));
+        assert_eq!(fs.rename("/a", "/c"), Err(FsError::DirectoryNotEmpty));
+        assert_eq!(fs.rename("/c/g", "/a"), Err(FsError::IsADirectory));
+
+        // Moving a directory updates its `..`
+        fs.rename("/a/b", "/c/Moved directory").unwrap();
+        assert_eq!(read_file(&mut fs, "/c/moved directory/f"), b"data");
+        assert_eq!(names(&fs, "/c/Moved directory/.."), [".", "..", "g", "Moved directory"]);
+        check(&fs);
+
+        // Replacing a file frees the old contents
+        fs.rename("/c/g", "/c/Moved directory/f").unwrap();
+        assert_eq!(read_file(&mut fs, "/c/Moved directory/f"), b"other");
+        fs.rename("/c/Moved directory/f", "/c/Moved directory/F").unwrap();
+        assert_eq!(names(&fs, "/c/Moved directory"), [".", "..", "F"]);
+
+        // An empty directory can replace an empty directory
+        fs.rename("/a", "/c/Moved directory/..").unwrap_err();
+        fs.mkdir("/empty").unwrap();
+        fs.rename("/a", "/empty").unwrap();
+        let mut root = names(&fs, "/");
+        root.sort();
+        assert_eq!(root, ["c", "empty"]);
+        check(&fs);
+    }
+}
+
+#[test]
+fn space_accounting() {
+    for img in [fat12(), fat16(), fat32()] {
+        let img_before = img.clone();
+        let mut fs = mount(img);
+        fs.mkdir("/d").unwrap();
+        let data = vec![7u8; 10 * fs.cluster_size() + 1];
+        write_file(&mut fs, "/d/f", &data);
+        assert_eq!(fs.rmdir("/d"), Err(FsError::DirectoryNotEmpty));
+        assert_eq!(fs.mkdir("/d"), Err(FsError::AlreadyExists));
+        check(&fs);
+
+        fs.unlink("/d/f").unwrap();
+        fs.rmdir("/d").unwrap();
+        check(&fs);
+
+        // The FATs are back to how they were formatted
+        let fat = fs.fat_start as usize;
+        let end = fat + 2 * fs.fat_size as usize;
+        let img = fs.into_inner();
+        assert!(img[fat..end] == img_before[fat..end]);
+    }
+}
+
+#[test]
+fn directories_grow_and_fixed_root_fills() {
+    let mut fs = mount(fat12());
+    fs.mkdir("/many").unwrap();
+    for i in 0..100 {
+        write_file(&mut fs, &format!("/many/file number {}", i), i.to_string().as_bytes());
+    }
+    assert_eq!(names(&fs, "/many").len(), 102);
+    assert_eq!(read_file(&mut fs, "/many/file number 99"), b"99");
+    check(&fs);
+
+    // The FAT12/16 root directory can't grow
+    let mut created = 0;
+    loop {
+        match fs.create(&format!("/F{}", created), 0o644) {
+            Ok(()) => created += 1,
+            Err(e) => {
+                assert_eq!(e, FsError::NoSpace);
+                break;
+            }
+        }
+    }
+    assert_eq!(created, 224 - 1);
+    check(&fs);
+}
+
+#[test]
+fn full_volume() {
+    let mut fs = mount(fat12());
+    fs.create("/fill", 0o644).unwrap();
+    let huge = vec![1u8; 2 * 1024 * 1024];
+    assert_eq!(fs.write_at("/fill", 0, &huge), Err(FsError::NoSpace));
+    fs.unlink("/fill").unwrap();
+    check(&fs);
+}
+
+#[test]
+fn read_only_attribute() {
+    let mut fs = mount(fat16());
+    fs.create("/ro", 0o444).unwrap();
+    assert_eq!(fs.stat("/ro").unwrap().mode, 0o444);
+}
+
+#[test]
+fn mount_any_detects_fat() {
+    let fs = crate::fs::mount_any(fat16()).unwrap();
+    assert!(fs.read_dir("/").unwrap().is_empty());
+    assert_eq!(crate::fs::mount_any(vec![0u8; 65536]).err(), Some(FsError::Corrupt(Corruption::BadMagic)));
+}
+
+#[test]
+fn bad_boot_sector() {
+    let mut img = fat12();
+    img[510] = 0;
+    assert_eq!(FatFs::mount(img).err(), Some(FsError::Corrupt(Corruption::BadMagic)));
+
+    let mut img = fat12();
+    img[13] = 3;
+    assert_eq!(FatFs::mount(img).err(), Some(FsError::Corrupt(Corruption::BadSuperblock)));
+
+    // A FAT too small for the clusters it has to describe
+    let mut img = fat16();
+    put16(&mut img, 22, 8);
+    assert_eq!(FatFs::mount(img).err(), Some(FsError::Corrupt(Corruption::BadSuperblock)));
+
+    let mut img = fat16();
+    img.truncate(img.len() / 2);
+    assert_eq!(FatFs::mount(img).err(), Some(FsError::Corrupt(Corruption::Truncated)));
+}
+
+#[test]
+fn looping_chain() {
+    let mut fs = mount(fat16());
+    let data = vec![1u8; 3 * fs.cluster_size()];
+    write_file(&mut fs, "/f", &data);
+    let first = fs.find(fs.root(), "f").unwrap().entry.cluster;
+    let last = *fs.chain(first).unwrap().last().unwrap();
+    fs.set_fat(last, first).unwrap();
+
+    let mut buf = [0u8; 16];
+    assert_eq!(fs.read_at("/f", 0, &mut buf), Err(FsError::Corrupt(Corruption::BadClusterChain)));
+    assert_eq!(fs.unlink("/f"), Err(FsError::Corrupt(Corruption::BadClusterChain)));
+}
+
+#[test]
+fn looping_dotdot() {
+    let mut fs = mount(fat16());
+    fs.mkdir("/a").unwrap();
+    fs.mkdir("/a/b").unwrap();
+    fs.mkdir("/c").unwrap();
+    // Point b's ".." back at b itself
+    let b = fs.find(fs.root(), "a").and_then(|a| fs.find(fs.as_dir(&a.entry), "b")).unwrap();
+    let mut dotdot = fs.find(fs.as_dir(&b.entry), "..").unwrap();
+    dotdot.entry.cluster = b.entry.cluster;
+    fs.write_entry(&dotdot).unwrap();
+
+    assert_eq!(fs.rename("/c", "/a/b/c"), Err(FsError::Corrupt(Corruption::BadClusterChain)));
+    assert_eq!(names(&fs, "/"), ["a", "c"]);
+}
+
+#[test]
+fn cluster_out_of_range() {
+    let mut fs = mount(fat12());
+    write_file(&mut fs, "/f", b"data");
+    let mut at = fs.find(fs.root(), "f").unwrap();
+    at.entry.cluster = 5000;
+    fs.write_entry(&at).unwrap();
+
+    let mut buf = [0u8; 4];
+    assert_eq!(fs.read_at("/f", 0, &mut buf), Err(FsError::Corrupt(Corruption::BlockOutOfRange)));
+}
+
+/// Setting any single metadata byte to 0xFF must never panic
+#[test]
+fn damaged_metadata_never_panics() {
+    let mut fs = mount(fat12());
+    fs.mkdir("/Some directory").unwrap();
+    write_file(&mut fs, "/Some directory/f", b"contents");
+    let data_start = fs.data_start as usize;
+    let pristine = fs.into_inner();
+
+    for off in 0..data_start + 2 * SECTOR {
+        let mut img = pristine.clone();
+        img[off] = 0xFF;
+        let Ok(mut fs) = FatFs::mount(img) else { continue };
+        let _ = fs.read_dir("/");
+        let _ = fs.read_dir("/Some directory");
+        let mut buf = [0u8; 16];
+        let _ = fs.read_at("/Some directory/f", 0, &mut buf);
+        let _ = fs.write_at("/Some directory/f", 0, b"more");
+        let _ = fs.mkdir("/new");
+    }
+}
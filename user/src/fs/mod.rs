@@ -1,245 +1,235 @@
-use crate::DISK_ADDR;
-
-const BLOCK_SIZE: usize = 1024;
-
-// --- Headers ---
-#[repr(C)]
-struct Superblock {
-    inodes_count: u32,
-    blocks_count: u32,
-    r_blocks_count: u32,
-    free_blocks_count: u32,
-    free_inodes_count: u32,
-    first_data_block: u32,
-    log_block_size: u32,
-    log_frag_size: u32,
-    blocks_per_group: u32,
-    frags_per_group: u32,
-    inodes_per_group: u32,
-    mtime: u32,
-    wtime: u32,
-    mnt_count: u16,
-    max_mnt_count: u16,
-    magic: u16,
-    state: u16,
-    // ...
+/// Guest filesystems
+use alloc::boxed::Box;
+use alloc::string::String;
+
+pub mod block;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+pub mod share;
+pub mod tmpfs;
+pub mod vfs;
+
+pub use block::{BlockDevice, HostDisk, RamDisk};
+pub use devfs::DevFs;
+pub use ext2::{Ext2Driver, ReadDir};
+pub use fat::FatFs;
+pub use share::ShareFs;
+pub use tmpfs::TmpFs;
+pub use vfs::{Fd, FileSystem, OpenFlags, SeekFrom, Vfs};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Unknown,
+}
+
+impl FileType {
+    /// From the `S_IFMT` bits of a Unix mode
+    pub fn from_mode(mode: u16) -> FileType {
+        match mode & 0xF000 {
+            0x8000 => FileType::Regular,
+            0x4000 => FileType::Directory,
+            0xA000 => FileType::Symlink,
+            0x2000 => FileType::CharDevice,
+            0x6000 => FileType::BlockDevice,
+            0x1000 => FileType::Fifo,
+            0xC000 => FileType::Socket,
+            _ => FileType::Unknown,
+        }
+    }
 }
 
-#[repr(C)]
-struct GroupDescriptor {
-    block_bitmap: u32,
-    inode_bitmap: u32,
-    inode_table: u32,
-    free_blocks_count: u16,
-    free_inodes_count: u16,
-    used_dirs_count: u16,
-    pad: u16,
-    reserved: [u32; 3],
+/// One entry of a directory listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u32,
+    pub file_type: FileType,
 }
 
-#[repr(C)]
-struct Inode {
-    mode: u16,
-    uid: u16,
-    size: u32,
-    atime: u32,
-    ctime: u32,
-    mtime: u32,
-    dtime: u32,
-    gid: u16,
-    links_count: u16,
-    blocks: u32,
-    flags: u32,
-    osd1: u32,
-    block: [u32; 15],
-    generation: u32,
-    file_acl: u32,
-    dir_acl: u32,
-    faddr: u32,
-    osd2: [u8; 12],
+/// What `stat` reports about a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: u32,
+    pub file_type: FileType,
+    /// Permission bits (e.g. 0o755), without the file type
+    pub mode: u16,
+    pub size: u64,
+    pub uid: u32,
+    pub gid: u32,
+    pub links: u16,
+    /// Seconds since the UNIX epoch
+    pub atime: u32,
+    pub mtime: u32,
+    pub ctime: u32,
 }
 
-#[repr(C, packed)]
-struct DirEntry {
-    inode: u32,
-    rec_len: u16,
-    name_len: u8,
-    file_type: u8,
-    // name follows
+impl Metadata {
+    pub fn is_dir(&self) -> bool { self.file_type == FileType::Directory }
+    pub fn is_file(&self) -> bool { self.file_type == FileType::Regular }
+    pub fn is_symlink(&self) -> bool { self.file_type == FileType::Symlink }
 }
 
-pub struct Ext2Driver {
-    base_addr: usize,
-    inode_table_block: u32,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    /// A path component does not exist
+    NotFound,
+    /// A non-final path component is not a directory
+    NotADirectory,
+    /// Tried to read a directory as a file
+    IsADirectory,
+    /// Logical block beyond what triple indirection can address
+    TooLarge,
+    /// No free blocks or inodes left
+    NoSpace,
+    /// The target of a create or mkdir already exists
+    AlreadyExists,
+    /// rmdir on a directory that still has entries
+    DirectoryNotEmpty,
+    /// A path component is longer than 255 bytes
+    NameTooLong,
+    /// Empty name, or `.`/`..` where a new entry is needed
+    InvalidPath,
+    /// Path resolution followed too many symlinks (probably a loop)
+    TooManyLinks,
+    /// readlink on something that isn't a symlink
+    NotASymlink,
+    /// Write to a filesystem mounted read-only
+    ReadOnly,
+    /// The host refused access to a shared file
+    PermissionDenied,
+    /// A file descriptor that isn't open, or not open for this access
+    BadFileDescriptor,
+    /// Unmount of a filesystem that still has open files
+    Busy,
+    /// Rename between two different filesystems
+    CrossDevice,
+    /// A seek before the start of a file, or a buffer too small for a record
+    InvalidArgument,
+    /// The image uses a feature this driver can't handle
+    Unsupported(&'static str),
+    /// The underlying device failed or was accessed out of range
+    Io,
+    /// On-disk structure failed validation
+    Corrupt(Corruption),
 }
 
-impl Ext2Driver {
-    pub fn new() -> Option<Self> {
-        unsafe {
-            let sb = &*((DISK_ADDR + 1024) as *const Superblock);
-            if sb.magic != 0xEF53 {
-                crate::console_println("Ext2: Invalid Magic (Not 0xEF53)");
-                return None;
-            }
-            
-            // Assume Block Group 0
-            // Group Descriptor at 2048 (Block 2)
-            let gd = &*((DISK_ADDR + 2048) as *const GroupDescriptor);
-            
-            crate::console_println("Ext2: Mounted Successfully.");
-            
-            Some(Ext2Driver {
-                base_addr: DISK_ADDR,
-                inode_table_block: gd.inode_table,
-            })
+/// Which on-disk structure failed validation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    /// No filesystem signature where one was expected
+    BadMagic,
+    /// Superblock geometry is inconsistent
+    BadSuperblock,
+    /// The filesystem claims more blocks than the device holds
+    Truncated,
+    /// A block pointer beyond the end of the filesystem
+    BlockOutOfRange,
+    /// An inode number of 0 or beyond the inode count
+    InodeOutOfRange,
+    /// A directory entry with a bad record length or name length
+    BadDirEntry,
+    /// Bad extent header, depth or entry count
+    BadExtentTree,
+    /// Symlink target too long for its inode, or not UTF-8
+    BadSymlink,
+    /// A FAT cluster chain that loops, or ends early
+    BadClusterChain,
+}
+
+impl Corruption {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Corruption::BadMagic => "Corrupt filesystem: bad magic",
+            Corruption::BadSuperblock => "Corrupt filesystem: bad superblock",
+            Corruption::Truncated => "Corrupt filesystem: image truncated",
+            Corruption::BlockOutOfRange => "Corrupt filesystem: block out of range",
+            Corruption::InodeOutOfRange => "Corrupt filesystem: inode out of range",
+            Corruption::BadDirEntry => "Corrupt filesystem: bad directory entry",
+            Corruption::BadExtentTree => "Corrupt filesystem: bad extent tree",
+            Corruption::BadSymlink => "Corrupt filesystem: bad symlink",
+            Corruption::BadClusterChain => "Corrupt filesystem: bad cluster chain",
         }
     }
-    
-    fn get_inode(&self, index: u32) -> Option<&Inode> {
-        // Simple implementation: only support BG0 inodes (< inodes_per_group)
-        // Inode index starts at 1
-        if index < 1 { return None; }
-        
-        let table_addr = self.base_addr + (self.inode_table_block as usize * BLOCK_SIZE);
-        let inode_size = core::mem::size_of::<Inode>(); // 128
-        let offset = (index - 1) as usize * inode_size;
-        
-        unsafe {
-            Some(&*((table_addr + offset) as *const Inode))
+}
+
+impl FsError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsError::NotFound => "No such file or directory",
+            FsError::NotADirectory => "Not a directory",
+            FsError::IsADirectory => "Is a directory",
+            FsError::TooLarge => "File too large",
+            FsError::NoSpace => "No space left on device",
+            FsError::AlreadyExists => "File exists",
+            FsError::DirectoryNotEmpty => "Directory not empty",
+            FsError::NameTooLong => "File name too long",
+            FsError::InvalidPath => "Invalid path",
+            FsError::TooManyLinks => "Too many levels of symbolic links",
+            FsError::NotASymlink => "Not a symbolic link",
+            FsError::ReadOnly => "Read-only file system",
+            FsError::PermissionDenied => "Permission denied",
+            FsError::BadFileDescriptor => "Bad file descriptor",
+            FsError::Busy => "Device or resource busy",
+            FsError::CrossDevice => "Invalid cross-device link",
+            FsError::InvalidArgument => "Invalid argument",
+            FsError::Unsupported(what) => what,
+            FsError::Io => "I/O error",
+            FsError::Corrupt(c) => c.as_str(),
         }
     }
-    
-    pub fn list_root(&self) {
-        crate::console_println("--- Listing / ---");
-        let root = self.get_inode(2).expect("No Root Inode");
-        
-        // Read Block 0 of Root Inode
-        let block_idx = root.block[0];
-        let block_addr = self.base_addr + (block_idx as usize * BLOCK_SIZE);
-        
-        let mut offset = 0;
-        let mut count = 0;
-        
-        unsafe {
-            while offset < BLOCK_SIZE {
-                let ent = &*((block_addr + offset) as *const DirEntry);
-                if ent.inode == 0 { break; } // End
-                
-                let name_ptr = (block_addr + offset + 8) as *const u8;
-                let name_len = ent.name_len as usize;
-                
-                // Print name char by char
-                for i in 0..name_len {
-                    crate::console_putc(*name_ptr.add(i) as char);
-                }
-                
-                if ent.file_type == 2 {
-                    crate::console_putc('/');
-                }
-                crate::console_putc('\n');
-                
-                count += 1;
-                offset += ent.rec_len as usize;
-                if ent.rec_len == 0 { break; } // Safety
-            }
-        }
-        if count == 0 {
-             crate::console_println("(Empty or Error)");
-        }
-        crate::console_println("-----------------");
+}
+
+/// Mount whatever filesystem `dev` holds, recognised by its magic: ext2 by
+/// the superblock signature, FAT by the boot sector
+pub fn mount_any<D: BlockDevice + 'static>(dev: D) -> Result<Box<dyn FileSystem>, FsError> {
+    if ext2::is_ext2(&dev) {
+        return Ok(Box::new(Ext2Driver::mount(dev)?));
     }
-    
-    pub fn read_file(&self, filename: &str) -> Option<alloc::vec::Vec<u8>> {
-        // 1. Scan Root Dir for filename
-        let root = self.get_inode(2).expect("No Root Inode");
-        let block_idx = root.block[0];
-        let block_addr = self.base_addr + (block_idx as usize * BLOCK_SIZE);
-        
-        let mut target_inode_idx = 0;
-        let mut offset = 0;
-        
-        unsafe {
-            while offset < BLOCK_SIZE {
-                let ent = &*((block_addr + offset) as *const DirEntry);
-                if ent.inode == 0 { break; }
-                
-                let name_ptr = (block_addr + offset + 8) as *const u8;
-                let name_slice = core::slice::from_raw_parts(name_ptr, ent.name_len as usize);
-                
-                // Compare name
-                let mut match_name = true;
-                if name_slice.len() != filename.len() {
-                    match_name = false;
-                } else {
-                    for i in 0..filename.len() {
-                        if name_slice[i] != filename.as_bytes()[i] {
-                            match_name = false;
-                            break;
-                        }
-                    }
-                }
-                
-                if match_name {
-                    target_inode_idx = ent.inode;
-                    break;
-                }
-                
-                offset += ent.rec_len as usize;
-                if ent.rec_len == 0 { break; }
-            }
-        }
-        
-        if target_inode_idx == 0 {
-            return None;
-        }
-        
-        // 2. Read File Data
-        let inode = self.get_inode(target_inode_idx).unwrap();
-        let file_size = inode.size as usize;
-        let mut buffer = alloc::vec::Vec::with_capacity(file_size);
-        
-        // Read blocks
-        let num_blocks = (file_size + BLOCK_SIZE - 1) / BLOCK_SIZE;
-        
-        for i in 0..num_blocks {
-            // Support only direct blocks (0-11)
-            if i >= 12 {
-                crate::console_println("Error: File too large (>12 blocks)");
-                break;
-            }
-            
-            let block_idx = inode.block[i];
-            if block_idx == 0 { break; } // Standard says sparse files are 0, but here implies end
-            
-            let block_ptr = (self.base_addr + (block_idx as usize * BLOCK_SIZE)) as *const u8;
-            
-            let bytes_to_read = if i == num_blocks - 1 {
-                file_size % BLOCK_SIZE
-            } else {
-                BLOCK_SIZE
-            };
-            let bytes_to_read = if bytes_to_read == 0 { BLOCK_SIZE } else { bytes_to_read };
-            
-            unsafe {
-                for j in 0..bytes_to_read {
-                    buffer.push(*block_ptr.add(j));
-                }
-            }
-        }
-        
-        Some(buffer)
+    if fat::is_fat(&dev) {
+        return Ok(Box::new(FatFs::mount(dev)?));
     }
+    Err(FsError::Corrupt(Corruption::BadMagic))
+}
 
-    pub fn cat(&self, filename: &str) {
-        if let Some(data) = self.read_file(filename) {
-             crate::console_println("--- File Content ---");
-             for b in data {
-                 crate::console_putc(b as char);
-             }
-             crate::console_putc('\n');
-             crate::console_println("--------------------");
-        } else {
-            crate::console_println("File not found.");
-        }
+// --- Helpers shared by the filesystems ---
+
+/// Longest name a directory entry may have, in bytes
+const MAX_NAME_LEN: usize = 255;
+
+/// Current time for timestamps, in seconds since the UNIX epoch (0 if the
+/// host has no clock)
+fn now() -> u32 {
+    (crate::get_time_ns() / 1_000_000_000) as u32
+}
+
+/// Check a name for a new directory entry
+fn valid_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
     }
+    if name.len() > MAX_NAME_LEN {
+        return Err(FsError::NameTooLong);
+    }
+    Ok(())
+}
+
+/// Split a path into its parent directory and final component
+fn split_parent(path: &str) -> Result<(&str, &str), FsError> {
+    split_parent_with(path, valid_name)
+}
+
+/// `split_parent` for filesystems with their own rules for names
+fn split_parent_with(path: &str, valid: fn(&str) -> Result<(), FsError>) -> Result<(&str, &str), FsError> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    valid(name)?;
+    Ok((parent, name))
 }
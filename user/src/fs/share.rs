@@ -0,0 +1,428 @@
+/// Host directory share client, usually mounted at `/share`
+///
+/// Speaks the 9P2000.L subset from `aether_abi::share` to the host. Every
+/// operation walks a fresh fid from the root to its path and clunks it
+/// afterwards, so nothing is cached and files changed on the host show up
+/// immediately. Symlinks are reported as such but never followed: the
+/// host refuses to walk through them.
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::{Cell, RefCell};
+
+use aether_abi::mmio::SHARE_ADDR;
+use aether_abi::share::*;
+
+use super::vfs::FileSystem;
+use super::{split_parent, DirEntry, FileType, FsError, Metadata};
+
+const ROOT_FID: u32 = 0;
+
+// Treaddir entry types (`d_type`)
+const DT_DIR: u8 = 4;
+const DT_LNK: u8 = 10;
+
+/// Carries one request to the server and its reply back
+pub trait Transport {
+    /// Send the T-message `request` and receive the R-message into `reply`,
+    /// returning its length
+    fn call(&mut self, request: &[u8], reply: &mut [u8]) -> Result<usize, FsError>;
+}
+
+/// The request queue in guest memory at `SHARE_ADDR`
+pub struct SharedQueue {
+    base: *mut u8,
+}
+
+impl SharedQueue {
+    pub fn guest() -> Self {
+        let base = unsafe { crate::BASE_ADDRESS } + SHARE_ADDR;
+        unsafe { Self::new(base as *mut u8) }
+    }
+
+    /// # Safety
+    /// `base` must point at `QUEUE_SIZE` bytes shared with the host and
+    /// used by nothing else.
+    pub unsafe fn new(base: *mut u8) -> Self {
+        SharedQueue { base }
+    }
+
+    fn header(&self, offset: usize) -> *mut u32 {
+        unsafe { self.base.add(offset) as *mut u32 }
+    }
+}
+
+impl Transport for SharedQueue {
+    fn call(&mut self, request: &[u8], reply: &mut [u8]) -> Result<usize, FsError> {
+        if request.len() > MSIZE {
+            return Err(FsError::InvalidArgument);
+        }
+        unsafe {
+            let head = self.header(HEAD).read_volatile();
+            let slot = self.base.add(slot_offset(head));
+            core::ptr::copy_nonoverlapping(request.as_ptr(), slot, request.len());
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+            self.header(HEAD).write_volatile(head.wrapping_add(1));
+
+            crate::share_notify();
+
+            core::sync::atomic::fence(core::sync::atomic::Ordering::Acquire);
+            if self.header(DONE).read_volatile() != head.wrapping_add(1) {
+                // Nobody answered: take the request back so the queue stays in step
+                self.header(HEAD).write_volatile(head);
+                return Err(FsError::Io);
+            }
+            let size = (slot as *const u32).read_unaligned() as usize;
+            let len = size.min(reply.len()).min(MSIZE);
+            core::ptr::copy_nonoverlapping(slot, reply.as_mut_ptr(), len);
+            Ok(len)
+        }
+    }
+}
+
+/// The error the guest sees for a server's errno
+fn from_errno(code: u32) -> FsError {
+    match code {
+        ENOENT => FsError::NotFound,
+        ENOTDIR => FsError::NotADirectory,
+        EISDIR => FsError::IsADirectory,
+        EEXIST => FsError::AlreadyExists,
+        ENOTEMPTY => FsError::DirectoryNotEmpty,
+        ENAMETOOLONG => FsError::NameTooLong,
+        ELOOP => FsError::TooManyLinks,
+        EROFS => FsError::ReadOnly,
+        ENOSPC => FsError::NoSpace,
+        EFBIG => FsError::TooLarge,
+        EBADF => FsError::BadFileDescriptor,
+        EXDEV => FsError::CrossDevice,
+        EINVAL => FsError::InvalidArgument,
+        EPERM | EACCES => FsError::PermissionDenied,
+        EOPNOTSUPP => FsError::Unsupported("share: operation not supported by the host"),
+        _ => FsError::Io,
+    }
+}
+
+/// Path components with `.` dropped and `..` applied
+fn components(path: &str) -> Vec<&str> {
+    let mut names = Vec::new();
+    for c in path.split('/') {
+        match c {
+            "" | "." => {}
+            ".." => {
+                names.pop();
+            }
+            c => names.push(c),
+        }
+    }
+    names
+}
+
+fn file_type(mode: u32) -> FileType {
+    FileType::from_mode((mode & S_IFMT) as u16)
+}
+
+pub struct ShareFs<T: Transport = SharedQueue> {
+    transport: RefCell<T>,
+    next_fid: Cell<u32>,
+    next_tag: Cell<u16>,
+}
+
+impl ShareFs<SharedQueue> {
+    /// Attach to the host's share, if it exports one
+    pub fn guest() -> Result<Self, FsError> {
+        Self::attach(SharedQueue::guest())
+    }
+}
+
+/// A walked fid, clunked when dropped
+struct Fid<'a, T: Transport> {
+    fs: &'a ShareFs<T>,
+    fid: u32,
+}
+
+impl<T: Transport> Drop for Fid<'_, T> {
+    fn drop(&mut self) {
+        let _ = self.fs.rpc(TCLUNK, |w| {
+            w.u32(self.fid);
+        }, |_| Some(()));
+    }
+}
+
+impl<T: Transport> ShareFs<T> {
+    /// Negotiate the protocol version and attach to the share's root
+    pub fn attach(transport: T) -> Result<Self, FsError> {
+        let fs = ShareFs { transport: RefCell::new(transport), next_fid: Cell::new(ROOT_FID + 1), next_tag: Cell::new(0) };
+        let version = fs.rpc(TVERSION, |w| {
+            w.u32(MSIZE as u32).str(VERSION);
+        }, |r| {
+            r.u32()?;
+            Some(r.str()? == VERSION)
+        })?;
+        if !version {
+            return Err(FsError::Unsupported("share: host doesn't speak 9P2000.L"));
+        }
+        fs.rpc(TATTACH, |w| {
+            w.u32(ROOT_FID).u32(NOFID).str("guest").str("").u32(0);
+        }, |r| r.qid())?;
+        Ok(fs)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.transport.into_inner()
+    }
+
+    /// One request/reply exchange: `build` writes the T-message body and
+    /// `parse` reads the R-message body
+    fn rpc<R>(
+        &self,
+        kind: u8,
+        build: impl FnOnce(&mut Writer),
+        parse: impl FnOnce(&mut Reader) -> Option<R>,
+    ) -> Result<R, FsError> {
+        let tag = self.next_tag.get();
+        self.next_tag.set(tag.wrapping_add(1) % NOTAG);
+
+        let mut request = vec![0u8; MSIZE];
+        let mut w = Writer::new(&mut request, kind, tag);
+        build(&mut w);
+        let len = w.finish().ok_or(FsError::InvalidArgument)?;
+
+        let mut reply = vec![0u8; MSIZE];
+        let n = self.transport.borrow_mut().call(&request[..len], &mut reply)?;
+        let (mut r, rkind, rtag) = Reader::new(&reply[..n]).ok_or(FsError::Io)?;
+        if rtag != tag {
+            return Err(FsError::Io);
+        }
+        if rkind == RLERROR {
+            return Err(from_errno(r.u32().ok_or(FsError::Io)?));
+        }
+        if rkind != kind + 1 {
+            return Err(FsError::Io);
+        }
+        parse(&mut r).ok_or(FsError::Io)
+    }
+
+    fn alloc_fid(&self) -> u32 {
+        let fid = self.next_fid.get();
+        let next = fid.wrapping_add(1);
+        self.next_fid.set(if next == NOFID { ROOT_FID + 1 } else { next });
+        fid
+    }
+
+    /// A new fid for `path`, walked from the root
+    fn walk(&self, path: &str) -> Result<Fid<'_, T>, FsError> {
+        let names = components(path);
+        let fid = self.alloc_fid();
+
+        // Zero names clones the root; longer paths go MAX_WALK names at a time
+        let mut from = ROOT_FID;
+        let mut chunks = names.chunks(MAX_WALK);
+        let mut guard = None;
+        loop {
+            let chunk = chunks.next().unwrap_or(&[]);
+            let qids = self.rpc(TWALK, |w| {
+                w.u32(from).u32(fid).u16(chunk.len() as u16);
+                for name in chunk {
+                    w.str(name);
+                }
+            }, |r| {
+                let n = r.u16()? as usize;
+                let mut last = None;
+                for _ in 0..n {
+                    last = Some(r.qid()?);
+                }
+                Some((n, last))
+            })?;
+
+            // A short walk leaves `fid` unchanged (or, on the first chunk, unused)
+            let (walked, last) = qids;
+            if walked < chunk.len() {
+                return Err(match last {
+                    Some(q) if q.kind & QT_DIR == 0 => FsError::NotADirectory,
+                    _ => FsError::NotFound,
+                });
+            }
+            guard.get_or_insert(Fid { fs: self, fid });
+            from = fid;
+            if chunks.len() == 0 {
+                break;
+            }
+        }
+        guard.ok_or(FsError::Io)
+    }
+
+    fn lopen(&self, fid: &Fid<'_, T>, flags: u32) -> Result<(), FsError> {
+        self.rpc(TLOPEN, |w| {
+            w.u32(fid.fid).u32(flags);
+        }, |r| r.qid().map(drop))
+    }
+}
+
+impl<T: Transport> FileSystem for ShareFs<T> {
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        let fid = self.walk(path)?;
+        self.rpc(TGETATTR, |w| {
+            w.u32(fid.fid).u64(GETATTR_BASIC);
+        }, |r| {
+            let _valid = r.u64()?;
+            let qid = r.qid()?;
+            let mode = r.u32()?;
+            let uid = r.u32()?;
+            let gid = r.u32()?;
+            let links = r.u64()?;
+            let _rdev = r.u64()?;
+            let size = r.u64()?;
+            let _blksize = r.u64()?;
+            let _blocks = r.u64()?;
+            let atime = r.u64()?;
+            r.u64()?;
+            let mtime = r.u64()?;
+            r.u64()?;
+            let ctime = r.u64()?;
+            Some(Metadata {
+                inode: qid.path as u32,
+                file_type: file_type(mode),
+                mode: (mode & 0o7777) as u16,
+                size,
+                uid,
+                gid,
+                links: links.min(u16::MAX as u64) as u16,
+                atime: atime as u32,
+                mtime: mtime as u32,
+                ctime: ctime as u32,
+            })
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let fid = self.walk(path)?;
+        self.lopen(&fid, O_RDONLY)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let batch = self.rpc(TREADDIR, |w| {
+                w.u32(fid.fid).u64(offset).u32(IO_MAX as u32);
+            }, |r| {
+                let count = r.u32()? as usize;
+                let end = r.remaining().checked_sub(count)?;
+                let mut batch = Vec::new();
+                while r.remaining() > end {
+                    let qid = r.qid()?;
+                    let next = r.u64()?;
+                    let kind = r.u8()?;
+                    let name = r.str()?.to_string();
+                    batch.push((qid, next, kind, name));
+                }
+                Some(batch)
+            })?;
+            let Some(&(_, next, ..)) = batch.last() else { break };
+            offset = next;
+            entries.extend(batch.into_iter().map(|(qid, _, kind, name)| DirEntry {
+                name,
+                inode: qid.path as u32,
+                file_type: match kind {
+                    DT_DIR => FileType::Directory,
+                    DT_LNK => FileType::Symlink,
+                    _ => FileType::Regular,
+                },
+            }));
+        }
+        Ok(entries)
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let fid = self.walk(path)?;
+        self.lopen(&fid, O_RDONLY)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let want = (buf.len() - done).min(IO_MAX);
+            let n = self.rpc(TREAD, |w| {
+                w.u32(fid.fid).u64(offset + done as u64).u32(want as u32);
+            }, |r| {
+                let count = r.u32()? as usize;
+                let data = r.bytes(count.min(want))?;
+                buf[done..done + data.len()].copy_from_slice(data);
+                Some(data.len())
+            })?;
+            done += n;
+            if n < want {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let fid = self.walk(path)?;
+        self.lopen(&fid, O_WRONLY)?;
+        let mut done = 0;
+        for chunk in data.chunks(IO_MAX) {
+            let n = self.rpc(TWRITE, |w| {
+                w.u32(fid.fid).u64(offset + done as u64).u32(chunk.len() as u32).bytes(chunk);
+            }, |r| r.u32())? as usize;
+            done += n;
+            if n < chunk.len() {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    fn create(&mut self, path: &str, perm: u16) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let fid = self.walk(parent)?;
+        // The fid now names the new file, and is clunked as usual
+        self.rpc(TLCREATE, |w| {
+            w.u32(fid.fid).str(name).u32(O_RDWR | O_CREAT | O_EXCL).u32(perm as u32).u32(0);
+        }, |r| r.qid().map(drop))
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let fid = self.walk(parent)?;
+        self.rpc(TMKDIR, |w| {
+            w.u32(fid.fid).str(name).u32(0o755).u32(0);
+        }, |r| r.qid().map(drop))
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let fid = self.walk(parent)?;
+        self.rpc(TUNLINKAT, |w| {
+            w.u32(fid.fid).str(name).u32(0);
+        }, |_| Some(()))
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let fid = self.walk(parent)?;
+        self.rpc(TUNLINKAT, |w| {
+            w.u32(fid.fid).str(name).u32(AT_REMOVEDIR);
+        }, |_| Some(()))
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let fid = self.walk(path)?;
+        self.rpc(TSETATTR, |w| {
+            w.u32(fid.fid).u32(SETATTR_SIZE).u32(0).u32(0).u32(0).u64(size);
+            w.u64(0).u64(0).u64(0).u64(0);
+        }, |_| Some(()))
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = split_parent(from)?;
+        let (to_parent, to_name) = split_parent(to)?;
+        let old_dir = self.walk(from_parent)?;
+        let new_dir = self.walk(to_parent)?;
+        self.rpc(TRENAMEAT, |w| {
+            w.u32(old_dir.fid).str(from_name).u32(new_dir.fid).str(to_name);
+        }, |_| Some(()))
+    }
+
+    fn readlink(&self, path: &str) -> Result<String, FsError> {
+        let fid = self.walk(path)?;
+        self.rpc(TREADLINK, |w| {
+            w.u32(fid.fid);
+        }, |r| r.str().map(String::from))
+    }
+}
@@ -0,0 +1,419 @@
+/// In-memory filesystem, usually mounted at `/tmp`
+///
+/// Everything lives on the guest heap and is lost when the filesystem is
+/// dropped. File data grows with `try_reserve`, so running out of heap
+/// shows up as `FsError::NoSpace` rather than an allocation abort.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::vfs::FileSystem;
+use super::{now, split_parent, DirEntry, FileType, FsError, Metadata};
+
+const ROOT_INODE: u32 = 1;
+
+enum Content {
+    File(Vec<u8>),
+    /// Entries by name; `.` and `..` are implied
+    Dir(BTreeMap<String, u32>),
+}
+
+struct Node {
+    content: Content,
+    mode: u16,
+    /// Containing directory (the root is its own parent)
+    parent: u32,
+    atime: u32,
+    mtime: u32,
+    ctime: u32,
+}
+
+impl Node {
+    fn is_dir(&self) -> bool {
+        matches!(self.content, Content::Dir(_))
+    }
+
+    fn touch(&mut self) {
+        let t = now();
+        self.mtime = t;
+        self.ctime = t;
+    }
+}
+
+pub struct TmpFs {
+    /// Node `i` has inode number `i + 1`; freed slots are reused
+    nodes: Vec<Option<Node>>,
+    /// Bytes of file data stored, and the most allowed
+    used: usize,
+    limit: usize,
+}
+
+impl Default for TmpFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TmpFs {
+    /// An empty filesystem limited only by the heap
+    pub fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+
+    /// An empty filesystem holding at most `limit` bytes of file data
+    pub fn with_limit(limit: usize) -> Self {
+        let t = now();
+        let root = Node {
+            content: Content::Dir(BTreeMap::new()),
+            mode: 0o1777,
+            parent: ROOT_INODE,
+            atime: t,
+            mtime: t,
+            ctime: t,
+        };
+        TmpFs { nodes: alloc::vec![Some(root)], used: 0, limit }
+    }
+
+    /// Bytes of file data currently stored
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    fn node(&self, ino: u32) -> Result<&Node, FsError> {
+        self.nodes.get(ino as usize - 1).and_then(|n| n.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: u32) -> Result<&mut Node, FsError> {
+        self.nodes.get_mut(ino as usize - 1).and_then(|n| n.as_mut()).ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self, ino: u32) -> Result<&BTreeMap<String, u32>, FsError> {
+        match &self.node(ino)?.content {
+            Content::Dir(entries) => Ok(entries),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn entries_mut(&mut self, ino: u32) -> Result<&mut BTreeMap<String, u32>, FsError> {
+        match &mut self.node_mut(ino)?.content {
+            Content::Dir(entries) => Ok(entries),
+            Content::File(_) => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// Inode number of `path`
+    fn lookup(&self, path: &str) -> Result<u32, FsError> {
+        let mut ino = ROOT_INODE;
+        for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            ino = match component {
+                ".." => self.node(ino)?.parent,
+                name => *self.entries(ino)?.get(name).ok_or(FsError::NotFound)?,
+            };
+        }
+        Ok(ino)
+    }
+
+    /// Parent directory and name for a new entry at `path`, which must not exist yet
+    fn prepare_entry<'p>(&self, path: &'p str) -> Result<(u32, &'p str), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.lookup(parent)?;
+        if self.entries(dir)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok((dir, name))
+    }
+
+    /// Store a node in a free slot, returning its inode number
+    fn insert(&mut self, node: Node) -> Result<u32, FsError> {
+        let index = match self.nodes.iter().position(|n| n.is_none()) {
+            Some(i) => {
+                self.nodes[i] = Some(node);
+                i
+            }
+            None => {
+                self.nodes.try_reserve(1).map_err(|_| FsError::NoSpace)?;
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        };
+        u32::try_from(index + 1).map_err(|_| FsError::NoSpace)
+    }
+
+    fn add_node(&mut self, path: &str, content: Content, mode: u16) -> Result<u32, FsError> {
+        let (dir, name) = self.prepare_entry(path)?;
+        let t = now();
+        let ino = self.insert(Node { content, mode, parent: dir, atime: t, mtime: t, ctime: t })?;
+        self.entries_mut(dir)?.insert(name.to_string(), ino);
+        self.node_mut(dir)?.touch();
+        Ok(ino)
+    }
+
+    /// Drop an unlinked node and give back its space
+    fn release(&mut self, ino: u32) {
+        if let Some(Node { content: Content::File(data), .. }) = self.nodes[ino as usize - 1].take() {
+            self.used -= data.len();
+        }
+    }
+
+    /// Set a file's length, zero-filling when it grows
+    fn resize(&mut self, ino: u32, size: usize) -> Result<(), FsError> {
+        let (used, limit) = (self.used, self.limit);
+        let node = self.node_mut(ino)?;
+        let Content::File(data) = &mut node.content else { return Err(FsError::IsADirectory) };
+
+        let old = data.len();
+        if size > old {
+            let grow = size - old;
+            if used.checked_add(grow).is_none_or(|total| total > limit) {
+                return Err(FsError::NoSpace);
+            }
+            data.try_reserve(grow).map_err(|_| FsError::NoSpace)?;
+        }
+        data.resize(size, 0);
+        node.touch();
+        self.used = used + size - old;
+        Ok(())
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        let ino = self.lookup(path)?;
+        let node = self.node(ino)?;
+        let (file_type, size, links) = match &node.content {
+            Content::File(data) => (FileType::Regular, data.len() as u64, 1),
+            Content::Dir(entries) => {
+                let subdirs = entries.values().filter(|&&i| self.node(i).is_ok_and(Node::is_dir)).count();
+                (FileType::Directory, 0, 2 + subdirs as u16)
+            }
+        };
+        Ok(Metadata {
+            inode: ino,
+            file_type,
+            mode: node.mode,
+            size,
+            uid: 0,
+            gid: 0,
+            links,
+            atime: node.atime,
+            mtime: node.mtime,
+            ctime: node.ctime,
+        })
+    }
+
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let ino = self.lookup(path)?;
+        let parent = self.node(ino)?.parent;
+        let entries = self.entries(ino)?;
+
+        let mut list = Vec::with_capacity(entries.len() + 2);
+        for (name, inode) in [(".", ino), ("..", parent)] {
+            list.push(DirEntry { name: name.to_string(), inode, file_type: FileType::Directory });
+        }
+        for (name, &inode) in entries {
+            let file_type = if self.node(inode)?.is_dir() { FileType::Directory } else { FileType::Regular };
+            list.push(DirEntry { name: name.clone(), inode, file_type });
+        }
+        Ok(list)
+    }
+
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let ino = self.lookup(path)?;
+        let node = self.node_mut(ino)?;
+        let Content::File(data) = &node.content else { return Err(FsError::IsADirectory) };
+
+        let start = usize::try_from(offset).unwrap_or(usize::MAX).min(data.len());
+        let n = (data.len() - start).min(buf.len());
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        node.atime = now();
+        Ok(n)
+    }
+
+    fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let ino = self.lookup(path)?;
+        let start = usize::try_from(offset).map_err(|_| FsError::TooLarge)?;
+        let end = start.checked_add(data.len()).ok_or(FsError::TooLarge)?;
+
+        let len = match &self.node(ino)?.content {
+            Content::File(d) => d.len(),
+            Content::Dir(_) => return Err(FsError::IsADirectory),
+        };
+        if end > len {
+            self.resize(ino, end)?;
+        }
+        let node = self.node_mut(ino)?;
+        if let Content::File(d) = &mut node.content {
+            d[start..end].copy_from_slice(data);
+        }
+        node.touch();
+        Ok(data.len())
+    }
+
+    fn create(&mut self, path: &str, perm: u16) -> Result<(), FsError> {
+        self.add_node(path, Content::File(Vec::new()), perm & 0o7777).map(|_| ())
+    }
+
+    fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        self.add_node(path, Content::Dir(BTreeMap::new()), 0o755).map(|_| ())
+    }
+
+    fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.lookup(parent)?;
+        let ino = *self.entries(dir)?.get(name).ok_or(FsError::NotFound)?;
+        if self.node(ino)?.is_dir() {
+            return Err(FsError::IsADirectory);
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.node_mut(dir)?.touch();
+        self.release(ino);
+        Ok(())
+    }
+
+    fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (parent, name) = split_parent(path)?;
+        let dir = self.lookup(parent)?;
+        let ino = *self.entries(dir)?.get(name).ok_or(FsError::NotFound)?;
+        if !self.entries(ino)?.is_empty() {
+            return Err(FsError::DirectoryNotEmpty);
+        }
+        self.entries_mut(dir)?.remove(name);
+        self.node_mut(dir)?.touch();
+        self.release(ino);
+        Ok(())
+    }
+
+    fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let ino = self.lookup(path)?;
+        let size = usize::try_from(size).map_err(|_| FsError::TooLarge)?;
+        self.resize(ino, size)
+    }
+
+    /// Move an entry, replacing an existing file, or an empty directory
+    /// when moving a directory
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_parent, from_name) = split_parent(from)?;
+        let (to_parent, to_name) = split_parent(to)?;
+        let from_dir = self.lookup(from_parent)?;
+        let to_dir = self.lookup(to_parent)?;
+        let ino = *self.entries(from_dir)?.get(from_name).ok_or(FsError::NotFound)?;
+        let moving_dir = self.node(ino)?.is_dir();
+
+        // A directory can't move into its own subtree
+        if moving_dir {
+            let mut d = to_dir;
+            loop {
+                if d == ino {
+                    return Err(FsError::InvalidPath);
+                }
+                if d == ROOT_INODE { break; }
+                d = self.node(d)?.parent;
+            }
+        }
+
+        if let Some(&existing) = self.entries(to_dir)?.get(to_name) {
+            if existing == ino {
+                return Ok(());
+            }
+            match (moving_dir, self.node(existing)?.is_dir()) {
+                (false, true) => return Err(FsError::IsADirectory),
+                (true, false) => return Err(FsError::NotADirectory),
+                (true, true) if !self.entries(existing)?.is_empty() => return Err(FsError::DirectoryNotEmpty),
+                _ => {}
+            }
+            self.release(existing);
+        }
+
+        self.entries_mut(from_dir)?.remove(from_name);
+        self.entries_mut(to_dir)?.insert(to_name.to_string(), ino);
+        self.node_mut(from_dir)?.touch();
+        self.node_mut(to_dir)?.touch();
+        let node = self.node_mut(ino)?;
+        node.parent = to_dir;
+        node.ctime = now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(fs: &TmpFs, path: &str) -> Vec<String> {
+        fs.read_dir(path).unwrap().into_iter().map(|e| e.name).collect()
+    }
+
+    #[test]
+    fn files_grow_and_shrink() {
+        let mut fs = TmpFs::new();
+        fs.create("/a", 0o644).unwrap();
+        assert_eq!(fs.write_at("/a", 4, b"data"), Ok(4));
+
+        let mut buf = [0xFFu8; 16];
+        assert_eq!(fs.read_at("/a", 0, &mut buf), Ok(8));
+        assert_eq!(&buf[..8], b"\0\0\0\0data");
+        assert_eq!(fs.read_at("/a", 100, &mut buf), Ok(0));
+        assert_eq!(fs.used(), 8);
+
+        fs.truncate("/a", 2).unwrap();
+        assert_eq!(fs.stat("/a").unwrap().size, 2);
+        fs.unlink("/a").unwrap();
+        assert_eq!(fs.used(), 0);
+        assert_eq!(fs.stat("/a"), Err(FsError::NotFound));
+    }
+
+    #[test]
+    fn directories() {
+        let mut fs = TmpFs::new();
+        fs.mkdir("/d").unwrap();
+        fs.mkdir("/d/e").unwrap();
+        fs.create("/d/f", 0o600).unwrap();
+        assert_eq!(fs.mkdir("/d"), Err(FsError::AlreadyExists));
+        assert_eq!(fs.create("/d/f/g", 0o644), Err(FsError::NotADirectory));
+        assert_eq!(names(&fs, "/d"), [".", "..", "e", "f"]);
+        assert_eq!(fs.stat("/d").unwrap().links, 3);
+        assert_eq!(fs.stat("/d/f").unwrap().mode, 0o600);
+
+        assert_eq!(fs.rmdir("/d"), Err(FsError::DirectoryNotEmpty));
+        assert_eq!(fs.unlink("/d/e"), Err(FsError::IsADirectory));
+        fs.rmdir("/d/e").unwrap();
+        fs.unlink("/d/f").unwrap();
+        fs.rmdir("/d").unwrap();
+        assert_eq!(names(&fs, "/"), [".", ".."]);
+    }
+
+    #[test]
+    fn rename() {
+        let mut fs = TmpFs::new();
+        fs.mkdir("/a").unwrap();
+        fs.mkdir("/a/b").unwrap();
+        fs.create("/x", 0o644).unwrap();
+        fs.write_at("/x", 0, b"x").unwrap();
+        fs.create("/y", 0o644).unwrap();
+        fs.write_at("/y", 0, b"yy").unwrap();
+
+        // Replacing a file frees the old one
+        fs.rename("/x", "/y").unwrap();
+        assert_eq!(fs.stat("/y").unwrap().size, 1);
+        assert_eq!(fs.used(), 1);
+
+        fs.rename("/y", "/a/b/z").unwrap();
+        assert_eq!(names(&fs, "/a/b"), [".", "..", "z"]);
+        assert_eq!(fs.rename("/a", "/a/b/c"), Err(FsError::InvalidPath));
+        assert_eq!(fs.rename("/a/b/z", "/a"), Err(FsError::IsADirectory));
+
+        fs.rename("/a/b", "/b").unwrap();
+        assert_eq!(fs.read_dir("/b/..").unwrap()[0].inode, ROOT_INODE);
+        assert_eq!(names(&fs, "/"), [".", "..", "a", "b"]);
+    }
+
+    #[test]
+    fn limit() {
+        let mut fs = TmpFs::with_limit(10);
+        fs.create("/a", 0o644).unwrap();
+        assert_eq!(fs.write_at("/a", 0, &[1; 8]), Ok(8));
+        assert_eq!(fs.write_at("/a", 8, &[1; 8]), Err(FsError::NoSpace));
+        assert_eq!(fs.truncate("/a", 10), Ok(()));
+        assert_eq!(fs.used(), 10);
+    }
+}
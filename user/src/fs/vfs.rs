@@ -0,0 +1,625 @@
+/// Virtual filesystem - one namespace over several mounted filesystems
+///
+/// Filesystems are mounted at absolute paths. A path belongs to the mount
+/// with the longest matching prefix and is passed to that filesystem
+/// relative to its mount point. `.` and `..` are resolved lexically before
+/// routing, so symlinks are only followed within a single filesystem.
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use super::{DirEntry, FileType, FsError, Metadata};
+
+/// A filesystem that can be mounted into a `Vfs`. Paths are absolute
+/// within the filesystem (`/` is its root). Operations a filesystem doesn't
+/// support default to `FsError::ReadOnly`.
+pub trait FileSystem {
+    /// Metadata for `path`, following symlinks
+    fn stat(&self, path: &str) -> Result<Metadata, FsError>;
+
+    /// Metadata for `path`; a final symlink is described itself
+    fn lstat(&self, path: &str) -> Result<Metadata, FsError> {
+        self.stat(path)
+    }
+
+    /// Entries of a directory, including `.` and `..` where the filesystem has them
+    fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError>;
+
+    /// Read from byte `offset` of a file, returning the number of bytes read
+    /// (0 at end of file)
+    fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+
+    /// Write at byte `offset` of an existing file, extending it as needed
+    fn write_at(&mut self, _path: &str, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Create an empty regular file with permission bits `perm`
+    fn create(&mut self, _path: &str, _perm: u16) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn mkdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn rmdir(&mut self, _path: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&mut self, _path: &str, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Move `from` to `to`, both within this filesystem
+    fn rename(&mut self, _from: &str, _to: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// Target of the symlink at `path`
+    fn readlink(&self, path: &str) -> Result<String, FsError> {
+        self.lstat(path)?;
+        Err(FsError::NotASymlink)
+    }
+
+    /// Make earlier writes durable
+    fn sync(&mut self) -> Result<(), FsError> {
+        Ok(())
+    }
+}
+
+/// Index of an open file in a `Vfs`
+pub type Fd = usize;
+
+/// How a file is opened; combine with `|`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// Create the file if it doesn't exist
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// Empty the file on open (needs `WRITE`)
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// Every write goes to the current end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 4);
+
+    pub const fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+/// Where `Vfs::seek` measures from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+struct Mount {
+    /// Normalized absolute mount point, e.g. `/` or `/dev`
+    path: String,
+    fs: Box<dyn FileSystem>,
+}
+
+/// An open file. Handles refer to files by path, so renaming or unlinking
+/// an open file leaves its handle pointing at whatever takes its place.
+struct OpenFile {
+    mount: usize,
+    /// Path within the mounted filesystem
+    path: String,
+    offset: u64,
+    flags: OpenFlags,
+}
+
+pub struct Vfs {
+    /// Slots stay put when a filesystem is unmounted, so open files can
+    /// keep referring to their mount by index
+    mounts: Vec<Option<Mount>>,
+    files: Vec<Option<OpenFile>>,
+    cwd: String,
+}
+
+impl Default for Vfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vfs {
+    /// An empty namespace; mount a filesystem at `/` before use
+    pub fn new() -> Self {
+        Vfs { mounts: Vec::new(), files: Vec::new(), cwd: "/".to_string() }
+    }
+
+    // --- Paths and mounts ---
+
+    /// Absolute form of `path`, resolved against the working directory,
+    /// with `.`, `..` and repeated slashes removed
+    pub fn absolute(&self, path: &str) -> String {
+        let mut parts: Vec<&str> = Vec::new();
+        let base = if path.starts_with('/') { "" } else { self.cwd.as_str() };
+        for component in base.split('/').chain(path.split('/')) {
+            match component {
+                "" | "." => {}
+                ".." => {
+                    parts.pop();
+                }
+                c => parts.push(c),
+            }
+        }
+
+        let mut abs = String::new();
+        for part in parts {
+            abs.push('/');
+            abs.push_str(part);
+        }
+        if abs.is_empty() {
+            abs.push('/');
+        }
+        abs
+    }
+
+    /// Mount holding an absolute path, and the path within that filesystem
+    fn route(&self, abs: &str) -> Result<(usize, String), FsError> {
+        let mut best: Option<(usize, usize)> = None;
+        for (i, mount) in self.mounts.iter().enumerate() {
+            let Some(mount) = mount else { continue };
+            let mp = mount.path.as_str();
+            let inside = mp == "/" || abs == mp || abs.strip_prefix(mp).is_some_and(|rest| rest.starts_with('/'));
+            if inside && best.is_none_or(|(_, len)| mp.len() > len) {
+                best = Some((i, mp.len()));
+            }
+        }
+
+        let (index, len) = best.ok_or(FsError::NotFound)?;
+        let rest = if len == 1 { abs } else { &abs[len..] };
+        let rel = if rest.is_empty() { "/" } else { rest };
+        Ok((index, rel.to_string()))
+    }
+
+    /// Route a user path: its mount index and path within that filesystem
+    fn resolve(&self, path: &str) -> Result<(usize, String), FsError> {
+        self.route(&self.absolute(path))
+    }
+
+    fn fs(&self, mount: usize) -> Result<&dyn FileSystem, FsError> {
+        match self.mounts.get(mount) {
+            Some(Some(m)) => Ok(m.fs.as_ref()),
+            _ => Err(FsError::BadFileDescriptor),
+        }
+    }
+
+    fn fs_mut(&mut self, mount: usize) -> Result<&mut dyn FileSystem, FsError> {
+        match self.mounts.get_mut(mount) {
+            Some(Some(m)) => Ok(m.fs.as_mut()),
+            _ => Err(FsError::BadFileDescriptor),
+        }
+    }
+
+    fn is_mount_point(&self, abs: &str) -> bool {
+        self.mounts.iter().flatten().any(|m| m.path == abs)
+    }
+
+    /// Attach `fs` at `path`, which may hide part of another filesystem
+    pub fn mount(&mut self, path: &str, fs: Box<dyn FileSystem>) -> Result<(), FsError> {
+        let path = self.absolute(path);
+        if self.is_mount_point(&path) {
+            return Err(FsError::AlreadyExists);
+        }
+        let mount = Some(Mount { path, fs });
+        match self.mounts.iter_mut().find(|m| m.is_none()) {
+            Some(slot) => *slot = mount,
+            None => self.mounts.push(mount),
+        }
+        Ok(())
+    }
+
+    /// Detach the filesystem mounted at `path` and hand it back.
+    /// Fails with `Busy` while any of its files are open.
+    pub fn unmount(&mut self, path: &str) -> Result<Box<dyn FileSystem>, FsError> {
+        let path = self.absolute(path);
+        let index = self
+            .mounts
+            .iter()
+            .position(|m| m.as_ref().is_some_and(|m| m.path == path))
+            .ok_or(FsError::NotFound)?;
+        if self.files.iter().flatten().any(|f| f.mount == index) {
+            return Err(FsError::Busy);
+        }
+        let mut mount = self.mounts[index].take().ok_or(FsError::NotFound)?;
+        mount.fs.sync()?;
+        Ok(mount.fs)
+    }
+
+    /// Mount points, in mount order
+    pub fn mounts(&self) -> impl Iterator<Item = &str> {
+        self.mounts.iter().flatten().map(|m| m.path.as_str())
+    }
+
+    // --- Working directory ---
+
+    pub fn cwd(&self) -> &str {
+        &self.cwd
+    }
+
+    /// Change the working directory, which must exist
+    pub fn chdir(&mut self, path: &str) -> Result<(), FsError> {
+        let abs = self.absolute(path);
+        if !self.stat(&abs)?.is_dir() {
+            return Err(FsError::NotADirectory);
+        }
+        self.cwd = abs;
+        Ok(())
+    }
+
+    // --- Open files ---
+
+    /// Open a file, returning a descriptor positioned at its start
+    pub fn open(&mut self, path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        let writing = flags.contains(OpenFlags::WRITE);
+        let fs = self.fs_mut(mount)?;
+
+        match fs.stat(&rel) {
+            Ok(meta) if meta.is_dir() => return Err(FsError::IsADirectory),
+            Ok(_) => {
+                if writing && flags.contains(OpenFlags::TRUNCATE) {
+                    fs.truncate(&rel, 0)?;
+                }
+            }
+            Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => fs.create(&rel, 0o644)?,
+            Err(e) => return Err(e),
+        }
+
+        let file = Some(OpenFile { mount, path: rel, offset: 0, flags });
+        match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.files[fd] = file;
+                Ok(fd)
+            }
+            None => {
+                self.files.push(file);
+                Ok(self.files.len() - 1)
+            }
+        }
+    }
+
+    pub fn close(&mut self, fd: Fd) -> Result<(), FsError> {
+        match self.files.get_mut(fd) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                Ok(())
+            }
+            _ => Err(FsError::BadFileDescriptor),
+        }
+    }
+
+    fn file(&self, fd: Fd) -> Result<&OpenFile, FsError> {
+        self.files.get(fd).and_then(|f| f.as_ref()).ok_or(FsError::BadFileDescriptor)
+    }
+
+    /// Read at the descriptor's offset and advance it
+    pub fn read(&mut self, fd: Fd, buf: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.file(fd)?;
+        if !file.flags.contains(OpenFlags::READ) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let (mount, offset, path) = (file.mount, file.offset, file.path.clone());
+
+        let n = self.fs_mut(mount)?.read_at(&path, offset, buf)?;
+        if let Some(Some(file)) = self.files.get_mut(fd) {
+            file.offset += n as u64;
+        }
+        Ok(n)
+    }
+
+    /// Write at the descriptor's offset (or the end, with `APPEND`) and advance it
+    pub fn write(&mut self, fd: Fd, data: &[u8]) -> Result<usize, FsError> {
+        let file = self.file(fd)?;
+        if !file.flags.contains(OpenFlags::WRITE) {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let (mount, mut offset, path) = (file.mount, file.offset, file.path.clone());
+        let append = file.flags.contains(OpenFlags::APPEND);
+
+        let fs = self.fs_mut(mount)?;
+        if append {
+            offset = fs.stat(&path)?.size;
+        }
+        let n = fs.write_at(&path, offset, data)?;
+        if let Some(Some(file)) = self.files.get_mut(fd) {
+            file.offset = offset + n as u64;
+        }
+        Ok(n)
+    }
+
+    /// Move the descriptor's offset, returning the new position. Seeking
+    /// past the end is allowed; a later write leaves a hole.
+    pub fn seek(&mut self, fd: Fd, pos: SeekFrom) -> Result<u64, FsError> {
+        let file = self.file(fd)?;
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(delta) => file.offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.fs(file.mount)?.stat(&file.path)?.size.checked_add_signed(delta),
+        }
+        .ok_or(FsError::InvalidArgument)?;
+
+        if let Some(Some(file)) = self.files.get_mut(fd) {
+            file.offset = new;
+        }
+        Ok(new)
+    }
+
+    /// Metadata of an open file
+    pub fn fstat(&self, fd: Fd) -> Result<Metadata, FsError> {
+        let file = self.file(fd)?;
+        self.fs(file.mount)?.stat(&file.path)
+    }
+
+    // --- Path operations ---
+
+    pub fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs(mount)?.stat(&rel)
+    }
+
+    pub fn lstat(&self, path: &str) -> Result<Metadata, FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs(mount)?.lstat(&rel)
+    }
+
+    pub fn readlink(&self, path: &str) -> Result<String, FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs(mount)?.readlink(&rel)
+    }
+
+    /// Entries of a directory. Filesystems mounted directly below it are
+    /// listed too, even where the directory has no entry of that name.
+    pub fn read_dir(&self, path: &str) -> Result<Vec<DirEntry>, FsError> {
+        let abs = self.absolute(path);
+        let (mount, rel) = self.route(&abs)?;
+        let mut entries = self.fs(mount)?.read_dir(&rel)?;
+
+        for m in self.mounts.iter().flatten() {
+            let Some((parent, name)) = m.path.rsplit_once('/') else { continue };
+            let parent = if parent.is_empty() { "/" } else { parent };
+            if name.is_empty() || parent != abs || entries.iter().any(|e| e.name == name) {
+                continue;
+            }
+            let inode = m.fs.stat("/").map_or(0, |meta| meta.inode);
+            entries.push(DirEntry { name: name.to_string(), inode, file_type: FileType::Directory });
+        }
+        Ok(entries)
+    }
+
+    pub fn mkdir(&mut self, path: &str) -> Result<(), FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs_mut(mount)?.mkdir(&rel)
+    }
+
+    pub fn unlink(&mut self, path: &str) -> Result<(), FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs_mut(mount)?.unlink(&rel)
+    }
+
+    /// Remove an empty directory; mount points can't be removed
+    pub fn rmdir(&mut self, path: &str) -> Result<(), FsError> {
+        let abs = self.absolute(path);
+        if self.is_mount_point(&abs) {
+            return Err(FsError::Busy);
+        }
+        let (mount, rel) = self.route(&abs)?;
+        self.fs_mut(mount)?.rmdir(&rel)
+    }
+
+    /// Rename within one filesystem
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), FsError> {
+        let (from_abs, to_abs) = (self.absolute(from), self.absolute(to));
+        if self.is_mount_point(&from_abs) || self.is_mount_point(&to_abs) {
+            return Err(FsError::Busy);
+        }
+        let (mount, from_rel) = self.route(&from_abs)?;
+        let (to_mount, to_rel) = self.route(&to_abs)?;
+        if mount != to_mount {
+            return Err(FsError::CrossDevice);
+        }
+        self.fs_mut(mount)?.rename(&from_rel, &to_rel)
+    }
+
+    pub fn truncate(&mut self, path: &str, size: u64) -> Result<(), FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        self.fs_mut(mount)?.truncate(&rel, size)
+    }
+
+    /// Whole contents of a file
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let (mount, rel) = self.resolve(path)?;
+        let fs = self.fs_mut(mount)?;
+        let size = usize::try_from(fs.stat(&rel)?.size).map_err(|_| FsError::TooLarge)?;
+
+        let mut data = Vec::new();
+        data.try_reserve_exact(size).map_err(|_| FsError::TooLarge)?;
+        data.resize(size, 0);
+        let mut done = 0;
+        while done < size {
+            match fs.read_at(&rel, done as u64, &mut data[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        data.truncate(done);
+        Ok(data)
+    }
+
+    /// Replace a file's contents, creating it if needed
+    pub fn write_file(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let fd = self.open(path, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE)?;
+        let mut done = 0;
+        let result = loop {
+            if done == data.len() {
+                break Ok(());
+            }
+            match self.write(fd, &data[done..]) {
+                Ok(0) => break Err(FsError::NoSpace),
+                Ok(n) => done += n,
+                Err(e) => break Err(e),
+            }
+        };
+        self.close(fd)?;
+        result
+    }
+
+    /// Flush every mounted filesystem
+    pub fn sync(&mut self) -> Result<(), FsError> {
+        for mount in self.mounts.iter_mut().flatten() {
+            mount.fs.sync()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// One directory holding one file, `/file`
+    struct OneFile {
+        data: Vec<u8>,
+    }
+
+    impl OneFile {
+        fn boxed(data: &[u8]) -> Box<dyn FileSystem> {
+            Box::new(OneFile { data: data.to_vec() })
+        }
+    }
+
+    impl FileSystem for OneFile {
+        fn stat(&self, path: &str) -> Result<Metadata, FsError> {
+            let (file_type, size) = match path {
+                "/" => (FileType::Directory, 0),
+                "/file" => (FileType::Regular, self.data.len() as u64),
+                _ => return Err(FsError::NotFound),
+            };
+            Ok(Metadata { inode: 1, file_type, mode: 0o644, size, uid: 0, gid: 0, links: 1, atime: 0, mtime: 0, ctime: 0 })
+        }
+
+        fn read_dir(&self, _path: &str) -> Result<Vec<DirEntry>, FsError> {
+            Ok(vec![DirEntry { name: "file".to_string(), inode: 1, file_type: FileType::Regular }])
+        }
+
+        fn read_at(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+            self.stat(path)?;
+            let data = self.data.get(offset as usize..).unwrap_or_default();
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            Ok(n)
+        }
+
+        fn write_at(&mut self, path: &str, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+            self.stat(path)?;
+            let end = offset as usize + data.len();
+            if self.data.len() < end {
+                self.data.resize(end, 0);
+            }
+            self.data[offset as usize..end].copy_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn truncate(&mut self, _path: &str, size: u64) -> Result<(), FsError> {
+            self.data.resize(size as usize, 0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn normalizes_paths() {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", OneFile::boxed(b"")).unwrap();
+        vfs.mount("/mnt/disk", OneFile::boxed(b"")).unwrap();
+        assert_eq!(vfs.absolute("a//b/./c/../d/"), "/a/b/d");
+        assert_eq!(vfs.absolute("/../.."), "/");
+
+        vfs.chdir("/mnt/disk").unwrap();
+        assert_eq!(vfs.absolute("x"), "/mnt/disk/x");
+        assert_eq!(vfs.absolute(".."), "/mnt");
+        assert_eq!(vfs.chdir("file"), Err(FsError::NotADirectory));
+        assert_eq!(vfs.cwd(), "/mnt/disk");
+    }
+
+    #[test]
+    fn routes_to_longest_mount() {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", OneFile::boxed(b"root")).unwrap();
+        vfs.mount("/mnt", OneFile::boxed(b"mounted")).unwrap();
+        assert_eq!(vfs.mount("/mnt/", OneFile::boxed(b"")).err(), Some(FsError::AlreadyExists));
+
+        assert_eq!(vfs.read_file("/file").unwrap(), b"root");
+        assert_eq!(vfs.read_file("/mnt/file").unwrap(), b"mounted");
+        assert_eq!(vfs.read_file("/mntfile"), Err(FsError::NotFound));
+
+        let names: Vec<String> = vfs.read_dir("/").unwrap().into_iter().map(|e| e.name).collect();
+        assert_eq!(names, ["file", "mnt"]);
+    }
+
+    #[test]
+    fn descriptors() {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", OneFile::boxed(b"0123456789")).unwrap();
+        let fd = vfs.open("file", OpenFlags::READ | OpenFlags::WRITE).unwrap();
+
+        let mut buf = [0u8; 4];
+        assert_eq!(vfs.read(fd, &mut buf), Ok(4));
+        assert_eq!(&buf, b"0123");
+        assert_eq!(vfs.seek(fd, SeekFrom::Current(2)), Ok(6));
+        assert_eq!(vfs.read(fd, &mut buf), Ok(4));
+        assert_eq!(&buf, b"6789");
+        assert_eq!(vfs.read(fd, &mut buf), Ok(0));
+
+        assert_eq!(vfs.seek(fd, SeekFrom::End(-2)), Ok(8));
+        assert_eq!(vfs.write(fd, b"XYZ"), Ok(3));
+        assert_eq!(vfs.fstat(fd).unwrap().size, 11);
+        assert_eq!(vfs.seek(fd, SeekFrom::Current(-20)), Err(FsError::InvalidArgument));
+
+        // Open files keep their filesystem mounted
+        assert_eq!(vfs.unmount("/").err(), Some(FsError::Busy));
+        vfs.close(fd).unwrap();
+        assert_eq!(vfs.read(fd, &mut buf), Err(FsError::BadFileDescriptor));
+        assert!(vfs.unmount("/").is_ok());
+    }
+
+    #[test]
+    fn open_flags() {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", OneFile::boxed(b"old contents")).unwrap();
+        assert_eq!(vfs.open("/", OpenFlags::READ), Err(FsError::IsADirectory));
+        assert_eq!(vfs.open("missing", OpenFlags::READ), Err(FsError::NotFound));
+        // Creating needs a writable filesystem
+        assert_eq!(vfs.open("missing", OpenFlags::WRITE | OpenFlags::CREATE), Err(FsError::ReadOnly));
+
+        let fd = vfs.open("file", OpenFlags::READ).unwrap();
+        assert_eq!(vfs.write(fd, b"x"), Err(FsError::BadFileDescriptor));
+
+        let fd = vfs.open("file", OpenFlags::WRITE | OpenFlags::APPEND).unwrap();
+        vfs.write(fd, b"!").unwrap();
+        assert_eq!(vfs.read_file("file").unwrap(), b"old contents!");
+
+        vfs.write_file("file", b"new").unwrap();
+        assert_eq!(vfs.read_file("file").unwrap(), b"new");
+    }
+}
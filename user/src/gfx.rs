@@ -0,0 +1,669 @@
+/// 2D Graphics - Primitives, clipping, blits and off-screen surfaces
+///
+/// All drawing goes through a `Painter`, which wraps anything implementing
+/// `Canvas` (the framebuffer or a heap-allocated `Surface`) together with a
+/// clipping rectangle. Pixels are 0xAARRGGBB; the framebuffer ignores alpha.
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::font::{get_char_bitmap, FONT_WIDTH};
+use crate::{SCREEN_WIDTH, SCREEN_HEIGHT};
+
+/// Packed 0xAARRGGBB color
+pub type Color = u32;
+
+pub const BLACK: Color = 0xFF000000;
+pub const WHITE: Color = 0xFFFFFFFF;
+pub const TRANSPARENT: Color = 0x00000000;
+
+/// Build an opaque color from RGB components
+#[inline]
+pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+    rgba(r, g, b, 0xFF)
+}
+
+/// Build a color from RGBA components
+#[inline]
+pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+    ((a as u32) << 24) | ((r as u32) << 16) | ((g as u32) << 8) | (b as u32)
+}
+
+/// Alpha-blend `src` over `dst` ("source over" operator)
+#[inline]
+pub fn blend(dst: Color, src: Color) -> Color {
+    let a = src >> 24;
+    if a == 0xFF { return src; }
+    if a == 0 { return dst; }
+    let inv = 255 - a;
+
+    let mix = |shift: u32| -> u32 {
+        let s = (src >> shift) & 0xFF;
+        let d = (dst >> shift) & 0xFF;
+        ((s * a + d * inv + 127) / 255) << shift
+    };
+    let da = dst >> 24;
+    let out_a = a + (da * inv + 127) / 255;
+
+    (out_a << 24) | mix(16) | mix(8) | mix(0)
+}
+
+// --- Geometry ---
+
+/// A wide coordinate pulled back into `i32`. Edges past the ends of the
+/// range can't be on any canvas anyway.
+fn saturate(v: i64) -> i32 {
+    v.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+/// Axis-aligned rectangle. May extend past the canvas; drawing clips it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub w: u32,
+    pub h: u32,
+}
+
+impl Rect {
+    pub const fn new(x: i32, y: i32, w: u32, h: u32) -> Self {
+        Rect { x, y, w, h }
+    }
+
+    /// Edges one past the last column and row, saturating at `i32::MAX`
+    pub fn right(&self) -> i32 { saturate(self.x as i64 + self.w as i64) }
+    pub fn bottom(&self) -> i32 { saturate(self.y as i64 + self.h as i64) }
+
+    pub fn is_empty(&self) -> bool {
+        self.w == 0 || self.h == 0
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x && y >= self.y && x < self.right() && y < self.bottom()
+    }
+
+    /// Overlapping area of two rectangles (empty if they don't touch)
+    pub fn intersect(&self, other: &Rect) -> Rect {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = self.right().min(other.right());
+        let y1 = self.bottom().min(other.bottom());
+        if x1 <= x0 || y1 <= y0 {
+            return Rect::default();
+        }
+        Rect::new(x0, y0, (x1 as i64 - x0 as i64) as u32, (y1 as i64 - y0 as i64) as u32)
+    }
+
+    /// Smallest rectangle covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() { return *other; }
+        if other.is_empty() { return *self; }
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = self.right().max(other.right());
+        let y1 = self.bottom().max(other.bottom());
+        Rect::new(x0, y0, (x1 as i64 - x0 as i64) as u32, (y1 as i64 - y0 as i64) as u32)
+    }
+
+    pub fn offset(&self, dx: i32, dy: i32) -> Rect {
+        Rect::new(self.x.saturating_add(dx), self.y.saturating_add(dy), self.w, self.h)
+    }
+}
+
+// --- Canvas ---
+
+/// A 2D pixel buffer that can be drawn into, row by row
+pub trait Canvas {
+    fn width(&self) -> usize;
+    fn height(&self) -> usize;
+
+    /// Pixels of row `y` (`y < height()`)
+    fn row(&self, y: usize) -> &[Color];
+
+    /// Mutable pixels of row `y` (`y < height()`)
+    fn row_mut(&mut self, y: usize) -> &mut [Color];
+
+    fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width() as u32, self.height() as u32)
+    }
+}
+
+/// The guest framebuffer at `FB_ADDR` (640x480, 32bpp)
+pub struct Framebuffer {
+    base: *mut Color,
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer { base: crate::get_fb_addr() as *mut Color }
+    }
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Canvas for Framebuffer {
+    fn width(&self) -> usize { SCREEN_WIDTH }
+    fn height(&self) -> usize { SCREEN_HEIGHT }
+
+    fn row(&self, y: usize) -> &[Color] {
+        assert!(y < SCREEN_HEIGHT);
+        unsafe { core::slice::from_raw_parts(self.base.add(y * SCREEN_WIDTH), SCREEN_WIDTH) }
+    }
+
+    fn row_mut(&mut self, y: usize) -> &mut [Color] {
+        assert!(y < SCREEN_HEIGHT);
+        unsafe { core::slice::from_raw_parts_mut(self.base.add(y * SCREEN_WIDTH), SCREEN_WIDTH) }
+    }
+}
+
+/// Heap-allocated off-screen pixel buffer
+#[derive(Clone)]
+pub struct Surface {
+    width: usize,
+    height: usize,
+    pixels: Vec<Color>,
+}
+
+impl Surface {
+    /// Create a surface filled with `TRANSPARENT`
+    pub fn new(width: usize, height: usize) -> Self {
+        Self::filled(width, height, TRANSPARENT)
+    }
+
+    pub fn filled(width: usize, height: usize, color: Color) -> Self {
+        Surface { width, height, pixels: vec![color; width * height] }
+    }
+
+    /// Wrap existing pixel data (`pixels.len()` must be `width * height`)
+    pub fn from_pixels(width: usize, height: usize, pixels: Vec<Color>) -> Option<Self> {
+        if pixels.len() != width * height {
+            return None;
+        }
+        Some(Surface { width, height, pixels })
+    }
+
+    pub fn pixels(&self) -> &[Color] { &self.pixels }
+    pub fn pixels_mut(&mut self) -> &mut [Color] { &mut self.pixels }
+
+    pub fn get(&self, x: usize, y: usize) -> Option<Color> {
+        if x >= self.width || y >= self.height { return None; }
+        Some(self.pixels[y * self.width + x])
+    }
+}
+
+impl Canvas for Surface {
+    fn width(&self) -> usize { self.width }
+    fn height(&self) -> usize { self.height }
+
+    fn row(&self, y: usize) -> &[Color] {
+        &self.pixels[y * self.width..(y + 1) * self.width]
+    }
+
+    fn row_mut(&mut self, y: usize) -> &mut [Color] {
+        &mut self.pixels[y * self.width..(y + 1) * self.width]
+    }
+}
+
+// --- Painter ---
+
+/// How source pixels are combined with the destination during a blit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlitMode {
+    /// Overwrite destination pixels
+    Copy,
+    /// Blend using the source alpha channel
+    Alpha,
+    /// Copy, skipping pixels equal to the key color (alpha ignored)
+    ColorKey(Color),
+}
+
+/// Draws primitives onto a canvas, restricted to a clipping rectangle
+pub struct Painter<'a, C: Canvas + ?Sized> {
+    canvas: &'a mut C,
+    clip: Rect,
+}
+
+impl<'a, C: Canvas + ?Sized> Painter<'a, C> {
+    pub fn new(canvas: &'a mut C) -> Self {
+        let clip = canvas.bounds();
+        Painter { canvas, clip }
+    }
+
+    /// Restrict drawing to `rect` (intersected with the canvas bounds)
+    pub fn set_clip(&mut self, rect: Rect) {
+        self.clip = rect.intersect(&self.canvas.bounds());
+    }
+
+    /// Allow drawing anywhere on the canvas
+    pub fn reset_clip(&mut self) {
+        self.clip = self.canvas.bounds();
+    }
+
+    pub fn clip(&self) -> Rect {
+        self.clip
+    }
+
+    pub fn canvas(&mut self) -> &mut C {
+        self.canvas
+    }
+
+    /// Fill the whole clip area
+    pub fn clear(&mut self, color: Color) {
+        let clip = self.clip;
+        self.fill_rect(clip, color);
+    }
+
+    pub fn pixel(&mut self, x: i32, y: i32, color: Color) {
+        if self.clip.contains(x, y) {
+            self.canvas.row_mut(y as usize)[x as usize] = color;
+        }
+    }
+
+    /// Blend a single pixel using the color's alpha
+    pub fn blend_pixel(&mut self, x: i32, y: i32, color: Color) {
+        if self.clip.contains(x, y) {
+            let px = &mut self.canvas.row_mut(y as usize)[x as usize];
+            *px = blend(*px, color);
+        }
+    }
+
+    /// Fill the horizontal span [x0, x1) on row y
+    pub fn hline(&mut self, x0: i32, x1: i32, y: i32, color: Color) {
+        if y < self.clip.y || y >= self.clip.bottom() { return; }
+        let (a, b) = if x0 <= x1 { (x0, x1) } else { (x1, x0) };
+        let a = a.max(self.clip.x);
+        let b = b.min(self.clip.right());
+        if a >= b { return; }
+        self.canvas.row_mut(y as usize)[a as usize..b as usize].fill(color);
+    }
+
+    /// Fill the vertical span [y0, y1) in column x
+    pub fn vline(&mut self, x: i32, y0: i32, y1: i32, color: Color) {
+        if x < self.clip.x || x >= self.clip.right() { return; }
+        let (a, b) = if y0 <= y1 { (y0, y1) } else { (y1, y0) };
+        let a = a.max(self.clip.y);
+        let b = b.min(self.clip.bottom());
+        for y in a..b {
+            self.canvas.row_mut(y as usize)[x as usize] = color;
+        }
+    }
+
+    /// Line between two points, both endpoints inclusive (Bresenham)
+    pub fn line(&mut self, x0: i32, y0: i32, x1: i32, y1: i32, color: Color) {
+        let Some((x0, y0, x1, y1)) = self.clip_line(x0, y0, x1, y1) else { return };
+        if y0 == y1 {
+            self.hline(x0.min(x1), x0.max(x1) + 1, y0, color);
+            return;
+        }
+        if x0 == x1 {
+            self.vline(x0, y0.min(y1), y0.max(y1) + 1, color);
+            return;
+        }
+
+        let dx = (x1 - x0).abs();
+        let dy = -(y1 - y0).abs();
+        let sx = if x0 < x1 { 1 } else { -1 };
+        let sy = if y0 < y1 { 1 } else { -1 };
+        let mut err = dx + dy;
+        let (mut x, mut y) = (x0, y0);
+
+        loop {
+            self.pixel(x, y, color);
+            if x == x1 && y == y1 { break; }
+            let e2 = 2 * err;
+            if e2 >= dy { err += dy; x += sx; }
+            if e2 <= dx { err += dx; y += sy; }
+        }
+    }
+
+    /// The part of a segment inside the clip area (Cohen-Sutherland), so
+    /// drawing only walks visible pixels. Worked in wide integers, since
+    /// the endpoints may be anywhere.
+    fn clip_line(&self, x0: i32, y0: i32, x1: i32, y1: i32) -> Option<(i32, i32, i32, i32)> {
+        if self.clip.is_empty() { return None; }
+        let (left, top) = (self.clip.x as i64, self.clip.y as i64);
+        let (right, bottom) = (self.clip.right() as i64 - 1, self.clip.bottom() as i64 - 1);
+        let outcode = |x: i64, y: i64| {
+            (x < left) as u8 | ((x > right) as u8) << 1 | ((y < top) as u8) << 2 | ((y > bottom) as u8) << 3
+        };
+        // Nearest integer to a + b * c / d
+        let lerp = |a: i64, b: i64, c: i64, d: i64| {
+            let (n, d) = (b as i128 * c as i128, d as i128);
+            let (n, d) = if d < 0 { (-n, -d) } else { (n, d) };
+            a + (2 * n + d).div_euclid(2 * d) as i64
+        };
+
+        let mut p = [(x0 as i64, y0 as i64), (x1 as i64, y1 as i64)];
+        loop {
+            let codes = [outcode(p[0].0, p[0].1), outcode(p[1].0, p[1].1)];
+            if codes[0] | codes[1] == 0 { break; }
+            if codes[0] & codes[1] != 0 { return None; }
+
+            // Move an outside endpoint onto an edge it lies beyond
+            let i = if codes[0] != 0 { 0 } else { 1 };
+            let (code, (ax, ay), (bx, by)) = (codes[i], p[i], p[1 - i]);
+            p[i] = if code & 0b1100 != 0 {
+                let y = if code & 0b0100 != 0 { top } else { bottom };
+                (lerp(ax, bx - ax, y - ay, by - ay), y)
+            } else {
+                let x = if code & 0b0001 != 0 { left } else { right };
+                (x, lerp(ay, by - ay, x - ax, bx - ax))
+            };
+        }
+        Some((p[0].0 as i32, p[0].1 as i32, p[1].0 as i32, p[1].1 as i32))
+    }
+
+    /// Rectangle outline (1px)
+    pub fn rect(&mut self, r: Rect, color: Color) {
+        if r.is_empty() { return; }
+        self.hline(r.x, r.right(), r.y, color);
+        self.hline(r.x, r.right(), r.bottom() - 1, color);
+        self.vline(r.x, r.y, r.bottom(), color);
+        self.vline(r.right() - 1, r.y, r.bottom(), color);
+    }
+
+    /// Solid rectangle, filled row by row
+    pub fn fill_rect(&mut self, r: Rect, color: Color) {
+        let r = r.intersect(&self.clip);
+        if r.is_empty() { return; }
+        let (x0, x1) = (r.x as usize, r.right() as usize);
+        for y in r.y..r.bottom() {
+            self.canvas.row_mut(y as usize)[x0..x1].fill(color);
+        }
+    }
+
+    /// Rectangle blended over the existing contents
+    pub fn blend_rect(&mut self, r: Rect, color: Color) {
+        let r = r.intersect(&self.clip);
+        if r.is_empty() { return; }
+        let (x0, x1) = (r.x as usize, r.right() as usize);
+        for y in r.y..r.bottom() {
+            for px in &mut self.canvas.row_mut(y as usize)[x0..x1] {
+                *px = blend(*px, color);
+            }
+        }
+    }
+
+    /// Circle outline (midpoint algorithm)
+    pub fn circle(&mut self, cx: i32, cy: i32, radius: i32, color: Color) {
+        if radius < 0 { return; }
+        let (cx, cy) = (cx as i64, cy as i64);
+        let (mut x, mut y) = (radius as i64, 0);
+        let mut err = 1 - x;
+        while x >= y {
+            for (px, py) in [(x, y), (y, x), (-y, x), (-x, y), (-x, -y), (-y, -x), (y, -x), (x, -y)] {
+                self.pixel(saturate(cx + px), saturate(cy + py), color);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Solid circle, filled with horizontal spans
+    pub fn fill_circle(&mut self, cx: i32, cy: i32, radius: i32, color: Color) {
+        if radius < 0 { return; }
+        let (cx, cy) = (cx as i64, cy as i64);
+        let (mut x, mut y) = (radius as i64, 0);
+        let mut err = 1 - x;
+        while x >= y {
+            for (half, dy) in [(x, y), (x, -y), (y, x), (y, -x)] {
+                self.hline(saturate(cx - half), saturate(cx + half + 1), saturate(cy + dy), color);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// Closed polygon outline
+    pub fn polygon(&mut self, points: &[(i32, i32)], color: Color) {
+        if points.is_empty() { return; }
+        for i in 0..points.len() {
+            let (x0, y0) = points[i];
+            let (x1, y1) = points[(i + 1) % points.len()];
+            self.line(x0, y0, x1, y1, color);
+        }
+    }
+
+    /// Solid polygon (even-odd rule, sampled at pixel centers)
+    pub fn fill_polygon(&mut self, points: &[(i32, i32)], color: Color) {
+        if points.len() < 3 { return; }
+        let min_y = points.iter().map(|p| p.1).min().unwrap().max(self.clip.y);
+        let max_y = points.iter().map(|p| p.1).max().unwrap().min(self.clip.bottom());
+
+        let mut xs: Vec<i32> = Vec::with_capacity(points.len());
+        for y in min_y..max_y {
+            // Scanline through pixel centers, in half-pixel units
+            let sy = 2 * y as i64 + 1;
+            xs.clear();
+            for i in 0..points.len() {
+                let (x0, y0) = points[i];
+                let (x1, y1) = points[(i + 1) % points.len()];
+                let (x0, x1) = (x0 as i64, x1 as i64);
+                let (y0, y1) = (2 * y0 as i64, 2 * y1 as i64);
+                if (y0 <= sy && sy < y1) || (y1 <= sy && sy < y0) {
+                    // Edge crossing in half-pixel units, snapped to the first
+                    // pixel whose center lies at or right of it
+                    let num = 2 * (sy - y0) as i128 * (x1 - x0) as i128;
+                    let x = x0 * 2 + num.div_euclid((y1 - y0) as i128) as i64;
+                    xs.push(saturate(x.div_euclid(2)));
+                }
+            }
+            xs.sort_unstable();
+            for pair in xs.chunks_exact(2) {
+                self.hline(pair[0], pair[1], y, color);
+            }
+        }
+    }
+
+    /// Draw text in the console font, leaving background pixels untouched
+    pub fn text(&mut self, x: i32, y: i32, s: &str, color: Color) {
+        for (i, c) in s.bytes().enumerate() {
+            let gx = x as i64 + (i * FONT_WIDTH) as i64;
+            for (row, bits) in get_char_bitmap(c).iter().enumerate() {
+                for col in 0..FONT_WIDTH {
+                    if (bits >> (7 - col)) & 1 != 0 {
+                        self.pixel(saturate(gx + col as i64), saturate(y as i64 + row as i64), color);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Draw a whole surface with its top-left corner at (x, y)
+    pub fn blit(&mut self, src: &Surface, x: i32, y: i32, mode: BlitMode) {
+        self.blit_region(src, src.bounds(), x, y, mode);
+    }
+
+    /// Draw the `src_rect` part of a canvas with its top-left corner at (x, y)
+    pub fn blit_region<S: Canvas + ?Sized>(&mut self, src: &S, src_rect: Rect, x: i32, y: i32, mode: BlitMode) {
+        let src_rect = src_rect.intersect(&src.bounds());
+        let dst = Rect::new(x, y, src_rect.w, src_rect.h).intersect(&self.clip);
+        if dst.is_empty() { return; }
+
+        // Shift the source origin by however much the clip trimmed off
+        let sx = (src_rect.x as i64 + (dst.x as i64 - x as i64)) as usize;
+        let sy = (src_rect.y as i64 + (dst.y as i64 - y as i64)) as i32;
+        let w = dst.w as usize;
+
+        for row in 0..dst.h as i32 {
+            let s = &src.row((sy + row) as usize)[sx..sx + w];
+            let d = &mut self.canvas.row_mut((dst.y + row) as usize)[dst.x as usize..dst.x as usize + w];
+            match mode {
+                BlitMode::Copy => d.copy_from_slice(s),
+                BlitMode::Alpha => {
+                    for (dp, &sp) in d.iter_mut().zip(s) {
+                        *dp = blend(*dp, sp);
+                    }
+                }
+                BlitMode::ColorKey(key) => {
+                    for (dp, &sp) in d.iter_mut().zip(s) {
+                        if sp & 0x00FFFFFF != key & 0x00FFFFFF {
+                            *dp = sp;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: Color = rgb(255, 0, 0);
+
+    /// Coordinates of the pixels set to `color`, row by row
+    fn drawn(s: &Surface, color: Color) -> Vec<(i32, i32)> {
+        let mut out = Vec::new();
+        for y in 0..s.height() {
+            for x in 0..s.width() {
+                if s.get(x, y) == Some(color) {
+                    out.push((x as i32, y as i32));
+                }
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn rects() {
+        let a = Rect::new(0, 0, 10, 10);
+        assert_eq!(a.intersect(&Rect::new(5, -5, 10, 10)), Rect::new(5, 0, 5, 5));
+        assert!(a.intersect(&Rect::new(10, 0, 5, 5)).is_empty());
+        assert_eq!(a.union(&Rect::new(-2, 4, 4, 10)), Rect::new(-2, 0, 12, 14));
+        assert_eq!(a.union(&Rect::default()), a);
+        assert!(a.contains(9, 0) && !a.contains(10, 0) && !a.contains(0, -1));
+    }
+
+    #[test]
+    fn blending() {
+        assert_eq!(blend(WHITE, RED), RED);
+        assert_eq!(blend(RED, TRANSPARENT), RED);
+        assert_eq!(blend(BLACK, rgba(255, 255, 255, 0x80)), rgb(0x80, 0x80, 0x80));
+        // Translucent over transparent stays translucent
+        assert_eq!(blend(TRANSPARENT, rgba(0, 0, 255, 0x40)) >> 24, 0x40);
+        assert_eq!(blend(rgba(0, 0, 0, 0x80), rgba(0, 0, 0, 0x80)) >> 24, 0xC0);
+    }
+
+    #[test]
+    fn drawing_is_clipped() {
+        let mut s = Surface::new(8, 8);
+        let mut p = Painter::new(&mut s);
+        p.fill_rect(Rect::new(-4, 6, 6, 10), RED);
+        p.pixel(8, 0, RED);
+        p.pixel(-1, 3, RED);
+        p.set_clip(Rect::new(4, 4, 100, 100));
+        assert_eq!(p.clip(), Rect::new(4, 4, 4, 4));
+        p.hline(-100, 100, 5, WHITE);
+        p.vline(4, -100, 100, WHITE);
+        p.reset_clip();
+        p.blend_rect(Rect::new(0, 7, 1, 5), rgba(0, 0, 0, 0));
+        assert_eq!(drawn(&s, RED), [(0, 6), (1, 6), (0, 7), (1, 7)]);
+        assert_eq!(drawn(&s, WHITE), [(4, 4), (4, 5), (5, 5), (6, 5), (7, 5), (4, 6), (4, 7)]);
+    }
+
+    #[test]
+    fn lines() {
+        let mut s = Surface::new(8, 8);
+        Painter::new(&mut s).line(0, 0, 7, 3, RED);
+        assert_eq!(drawn(&s, RED), [(0, 0), (1, 0), (2, 1), (3, 1), (4, 2), (5, 2), (6, 3), (7, 3)]);
+
+        // Far-off endpoints are clipped, not walked or overflowed
+        let mut s = Surface::new(8, 8);
+        let mut p = Painter::new(&mut s);
+        p.line(i32::MIN, i32::MIN, i32::MAX, i32::MAX, RED);
+        p.line(i32::MIN, 2, i32::MAX, 2, WHITE);
+        p.line(i32::MAX, i32::MIN, i32::MAX, i32::MAX, WHITE);
+        p.line(-1_000_000_000, 1_000_000_007, 1_000_000_007, -1_000_000_000, WHITE);
+        assert_eq!(drawn(&s, RED).len(), 7);
+        assert!(drawn(&s, RED).iter().all(|&(x, y)| x == y));
+        assert_eq!(s.get(0, 7), Some(WHITE));
+        assert_eq!(s.get(7, 0), Some(WHITE));
+        assert!((0..8).all(|x| x == 2 || s.get(x, 2) == Some(WHITE)));
+    }
+
+    #[test]
+    fn polygons() {
+        let mut s = Surface::new(8, 8);
+        let square = [(1, 1), (5, 1), (5, 5), (1, 5)];
+        Painter::new(&mut s).fill_polygon(&square, RED);
+        let expected: Vec<(i32, i32)> = (1..5).flat_map(|y| (1..5).map(move |x| (x, y))).collect();
+        assert_eq!(drawn(&s, RED), expected);
+
+        // Pixel centers on the diagonal, a right edge, are left out
+        let mut s = Surface::new(8, 8);
+        Painter::new(&mut s).fill_polygon(&[(0, 0), (4, 4), (0, 4)], RED);
+        assert_eq!(drawn(&s, RED), [(0, 1), (0, 2), (1, 2), (0, 3), (1, 3), (2, 3)]);
+
+        // Even-odd: the overlap of a square drawn twice is a hole
+        let mut s = Surface::new(8, 8);
+        let twice = [(0, 0), (4, 0), (4, 4), (0, 4), (0, 0), (2, 0), (2, 2), (0, 2)];
+        Painter::new(&mut s).fill_polygon(&twice, RED);
+        assert_eq!(s.get(1, 1), Some(TRANSPARENT));
+        assert_eq!(s.get(3, 3), Some(RED));
+
+        // Mostly off the canvas
+        let mut s = Surface::new(8, 8);
+        Painter::new(&mut s).fill_polygon(&[(-100, -100), (4, -100), (4, 2), (-100, 2)], RED);
+        assert_eq!(drawn(&s, RED).len(), 8);
+    }
+
+    #[test]
+    fn blits() {
+        let src = Surface::from_pixels(2, 2, vec![RED, WHITE, TRANSPARENT, rgba(255, 255, 255, 0x80)]).unwrap();
+        let mut s = Surface::filled(4, 4, BLACK);
+        let mut p = Painter::new(&mut s);
+        p.blit(&src, 3, -1, BlitMode::Copy);
+        p.blit(&src, 0, 2, BlitMode::Alpha);
+        p.blit_region(&src, Rect::new(0, 0, 2, 1), 1, 0, BlitMode::ColorKey(WHITE));
+        assert_eq!(s.row(0), [BLACK, RED, BLACK, TRANSPARENT]);
+        assert_eq!(s.row(2), [RED, WHITE, BLACK, BLACK]);
+        assert_eq!(s.row(3), [BLACK, rgb(0x80, 0x80, 0x80), BLACK, BLACK]);
+    }
+
+    #[test]
+    fn edge_coordinates() {
+        let far = Rect::new(i32::MAX - 2, i32::MAX - 2, 10, 10);
+        assert_eq!((far.right(), far.bottom()), (i32::MAX, i32::MAX));
+        assert!(far.contains(i32::MAX - 1, i32::MAX - 2));
+        assert_eq!(Rect::new(i32::MIN, 0, u32::MAX, 1).right(), i32::MAX);
+        assert_eq!(far.union(&Rect::new(i32::MIN, i32::MIN, 1, 1)).w, u32::MAX);
+        assert_eq!(far.offset(10, 10), Rect::new(i32::MAX, i32::MAX, 10, 10));
+        assert!(Rect::new(i32::MIN, 0, 1, 1).intersect(&far).is_empty());
+
+        let src = Surface::filled(4, 4, RED);
+        let mut s = Surface::new(8, 8);
+        let mut p = Painter::new(&mut s);
+        for (x, y) in [(i32::MAX - 2, 0), (0, i32::MAX), (i32::MIN, 0), (i32::MIN, i32::MIN)] {
+            p.fill_rect(Rect::new(x, y, 10, 10), RED);
+            p.blend_rect(Rect::new(x, y, 10, 10), RED);
+            p.rect(Rect::new(x, y, 10, 10), RED);
+            p.blit(&src, x, y, BlitMode::Copy);
+            p.circle(x, y, 3, RED);
+            p.fill_circle(x, y, 3, RED);
+            p.fill_polygon(&[(x, y), (x.saturating_add(9), y), (x, y.saturating_add(9))], RED);
+            p.text(x, y, "edge", RED);
+        }
+        assert!(drawn(&s, RED).is_empty());
+
+        // A huge rectangle from far off still covers the canvas
+        let mut p = Painter::new(&mut s);
+        p.fill_rect(Rect::new(i32::MIN, i32::MIN, u32::MAX, u32::MAX), WHITE);
+        p.fill_polygon(&[(i32::MIN, i32::MIN), (i32::MAX, i32::MIN), (i32::MAX, i32::MAX), (i32::MIN, i32::MAX)], RED);
+        assert_eq!(drawn(&s, RED).len(), 64);
+    }
+}
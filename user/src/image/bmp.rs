@@ -0,0 +1,141 @@
+/// Windows BMP - uncompressed 1/4/8/16/24/32 bpp and BITFIELDS
+use alloc::vec::Vec;
+
+use super::{check_dimensions, filled, ImageError};
+use crate::gfx::{rgb, rgba, Color, Surface};
+
+const FILE_HEADER_SIZE: usize = 14;
+
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+
+fn u16_at(data: &[u8], off: usize) -> Result<u16, ImageError> {
+    let b = data.get(off..off + 2).ok_or(ImageError::Truncated)?;
+    Ok(u16::from_le_bytes([b[0], b[1]]))
+}
+
+fn u32_at(data: &[u8], off: usize) -> Result<u32, ImageError> {
+    let b = data.get(off..off + 4).ok_or(ImageError::Truncated)?;
+    Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Extracts one channel from a packed pixel using a BITFIELDS mask
+#[derive(Clone, Copy)]
+struct Channel {
+    mask: u32,
+    shift: u32,
+    max: u32,
+}
+
+impl Channel {
+    fn new(mask: u32) -> Self {
+        if mask == 0 {
+            return Channel { mask: 0, shift: 0, max: 0 };
+        }
+        let shift = mask.trailing_zeros();
+        Channel { mask, shift, max: mask >> shift }
+    }
+
+    /// Channel value scaled to 0..=255 (`default` if the mask is empty)
+    fn get(&self, px: u32, default: u8) -> u8 {
+        if self.max == 0 {
+            return default;
+        }
+        let v = (px & self.mask) >> self.shift;
+        ((v as u64 * 255 + self.max as u64 / 2) / self.max as u64) as u8
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Surface, ImageError> {
+    if !data.starts_with(b"BM") {
+        return Err(ImageError::UnknownFormat);
+    }
+    let pixel_offset = u32_at(data, 10)? as usize;
+    let dib = FILE_HEADER_SIZE;
+    let dib_size = u32_at(data, dib)? as usize;
+    if dib_size < 40 {
+        return Err(ImageError::Unsupported("bmp: OS/2 core header"));
+    }
+
+    let raw_width = u32_at(data, dib + 4)? as i32;
+    let raw_height = u32_at(data, dib + 8)? as i32;
+    let bpp = u16_at(data, dib + 14)?;
+    let compression = u32_at(data, dib + 16)?;
+    let colors_used = u32_at(data, dib + 32)? as usize;
+
+    if raw_width <= 0 || raw_height == 0 || raw_height == i32::MIN {
+        return Err(ImageError::BadDimensions);
+    }
+    let width = raw_width as usize;
+    let top_down = raw_height < 0;
+    let height = raw_height.unsigned_abs() as usize;
+    check_dimensions(width, height)?;
+
+    // Channel masks: explicit for BITFIELDS, fixed defaults otherwise
+    let (r, g, b, a) = match (compression, bpp) {
+        (BI_RGB, 16) => (0x7C00, 0x03E0, 0x001F, 0),
+        (BI_RGB, 32) => (0x00FF0000, 0x0000FF00, 0x000000FF, 0),
+        (BI_RGB, _) => (0, 0, 0, 0),
+        (BI_BITFIELDS | BI_ALPHABITFIELDS, 16 | 32) => {
+            // Masks live in the V4+ header, or right after a 40-byte header
+            let a = if dib_size >= 56 || compression == BI_ALPHABITFIELDS {
+                u32_at(data, dib + 52)?
+            } else {
+                0
+            };
+            (u32_at(data, dib + 40)?, u32_at(data, dib + 44)?, u32_at(data, dib + 48)?, a)
+        }
+        (BI_BITFIELDS | BI_ALPHABITFIELDS, _) => {
+            return Err(ImageError::Corrupt("bmp: bitfields with bad depth"))
+        }
+        _ => return Err(ImageError::Unsupported("bmp: compressed bitmaps")),
+    };
+    let (r, g, b, a) = (Channel::new(r), Channel::new(g), Channel::new(b), Channel::new(a));
+
+    // Palette follows the DIB header (and the masks, for a 40-byte BITFIELDS header)
+    let mut palette: Vec<Color> = Vec::new();
+    if bpp <= 8 {
+        let count = if colors_used == 0 { 1usize << bpp } else { colors_used.min(256) };
+        let start = dib + dib_size;
+        for i in 0..count {
+            let e = data.get(start + i * 4..start + i * 4 + 4).ok_or(ImageError::Truncated)?;
+            palette.push(rgb(e[2], e[1], e[0]));
+        }
+    }
+
+    let stride = (width * bpp as usize).div_ceil(32) * 4;
+    let mut pixels = filled(width * height, 0 as Color)?;
+
+    for row in 0..height {
+        let src_off = pixel_offset + row * stride;
+        let src = data.get(src_off..src_off + stride).ok_or(ImageError::Truncated)?;
+        let y = if top_down { row } else { height - 1 - row };
+        let dst = &mut pixels[y * width..(y + 1) * width];
+
+        for (x, out) in dst.iter_mut().enumerate() {
+            *out = match bpp {
+                1 | 4 | 8 => {
+                    let bits = bpp as usize;
+                    let bit = x * bits;
+                    let shift = 8 - bits - (bit % 8);
+                    let idx = (src[bit / 8] >> shift) as usize & ((1 << bits) - 1);
+                    *palette.get(idx).ok_or(ImageError::Corrupt("bmp: palette index out of range"))?
+                }
+                16 => {
+                    let px = u16::from_le_bytes([src[x * 2], src[x * 2 + 1]]) as u32;
+                    rgba(r.get(px, 0), g.get(px, 0), b.get(px, 0), a.get(px, 255))
+                }
+                24 => rgb(src[x * 3 + 2], src[x * 3 + 1], src[x * 3]),
+                32 => {
+                    let o = x * 4;
+                    let px = u32::from_le_bytes([src[o], src[o + 1], src[o + 2], src[o + 3]]);
+                    rgba(r.get(px, 0), g.get(px, 0), b.get(px, 0), a.get(px, 255))
+                }
+                _ => return Err(ImageError::Unsupported("bmp: unsupported bit depth")),
+            };
+        }
+    }
+
+    Surface::from_pixels(width, height, pixels).ok_or(ImageError::BadDimensions)
+}
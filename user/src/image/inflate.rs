@@ -0,0 +1,286 @@
+/// DEFLATE / zlib decompressor (RFC 1950, RFC 1951)
+///
+/// Small canonical-Huffman decoder in the spirit of zlib's `puff.c`.
+/// Only used by the PNG decoder, so it inflates whole buffers at once, into
+/// an output buffer sized up front to the raw image data.
+use alloc::vec::Vec;
+
+use super::{buffer, ImageError};
+
+const TOO_LONG: ImageError = ImageError::Corrupt("inflate: more data than expected");
+
+const MAX_BITS: usize = 15;
+const MAX_LIT_CODES: usize = 288;
+const MAX_DIST_CODES: usize = 30;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+/// Order in which code length code lengths are stored
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit_buf: u32,
+    bit_cnt: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, bit_buf: 0, bit_cnt: 0 }
+    }
+
+    fn bits(&mut self, need: u32) -> Result<u32, ImageError> {
+        while self.bit_cnt < need {
+            let byte = *self.data.get(self.pos).ok_or(ImageError::Truncated)?;
+            self.pos += 1;
+            self.bit_buf |= (byte as u32) << self.bit_cnt;
+            self.bit_cnt += 8;
+        }
+        let val = self.bit_buf & ((1u32 << need) - 1);
+        self.bit_buf = if need == 32 { 0 } else { self.bit_buf >> need };
+        self.bit_cnt -= need;
+        Ok(val)
+    }
+
+    /// Drop bits up to the next byte boundary
+    fn align(&mut self) {
+        self.bit_buf = 0;
+        self.bit_cnt = 0;
+    }
+}
+
+/// Canonical Huffman table: symbol counts per length plus symbols in code order
+struct Huffman {
+    count: [u16; MAX_BITS + 1],
+    symbol: [u16; MAX_LIT_CODES],
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self, ImageError> {
+        let mut h = Huffman { count: [0; MAX_BITS + 1], symbol: [0; MAX_LIT_CODES] };
+        for &len in lengths {
+            h.count[len as usize] += 1;
+        }
+        if h.count[0] as usize == lengths.len() {
+            // No codes at all: legal (e.g. no distance codes), decode will fail if used
+            return Ok(h);
+        }
+
+        // Reject over-subscribed code sets
+        let mut left: i32 = 1;
+        for len in 1..=MAX_BITS {
+            left <<= 1;
+            left -= h.count[len] as i32;
+            if left < 0 {
+                return Err(ImageError::Corrupt("inflate: over-subscribed code"));
+            }
+        }
+
+        let mut offs = [0u16; MAX_BITS + 1];
+        for len in 1..MAX_BITS {
+            offs[len + 1] = offs[len] + h.count[len];
+        }
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                h.symbol[offs[len as usize] as usize] = sym as u16;
+                offs[len as usize] += 1;
+            }
+        }
+        Ok(h)
+    }
+
+    fn decode(&self, br: &mut BitReader) -> Result<u16, ImageError> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for len in 1..=MAX_BITS {
+            code |= br.bits(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - count < first {
+                return Ok(self.symbol[(index + (code - first)) as usize]);
+            }
+            index += count;
+            first += count;
+            first <<= 1;
+            code <<= 1;
+        }
+        Err(ImageError::Corrupt("inflate: bad huffman code"))
+    }
+}
+
+/// Decompress a zlib stream (2-byte header, deflate data, Adler-32 trailer)
+/// that inflates to at most `limit` bytes
+pub fn zlib_decompress(data: &[u8], limit: usize) -> Result<Vec<u8>, ImageError> {
+    if data.len() < 2 {
+        return Err(ImageError::Truncated);
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0F != 8 || !((cmf as u16) << 8 | flg as u16).is_multiple_of(31) {
+        return Err(ImageError::Corrupt("zlib: bad header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(ImageError::Unsupported("zlib: preset dictionary"));
+    }
+    inflate(&data[2..], limit)
+}
+
+/// Decompress raw deflate data of at most `limit` bytes. The output never
+/// grows past the buffer reserved for it, so a stream that would is
+/// rejected rather than exhausting the heap.
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>, ImageError> {
+    let mut out = buffer(limit)?;
+    let mut br = BitReader::new(data);
+
+    loop {
+        let last = br.bits(1)?;
+        match br.bits(2)? {
+            0 => stored(&mut br, &mut out, limit)?,
+            1 => {
+                let (lit, dist) = fixed_tables()?;
+                codes(&mut br, &mut out, limit, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut br)?;
+                codes(&mut br, &mut out, limit, &lit, &dist)?;
+            }
+            _ => return Err(ImageError::Corrupt("inflate: bad block type")),
+        }
+        if last == 1 {
+            break;
+        }
+    }
+    Ok(out)
+}
+
+fn stored(br: &mut BitReader, out: &mut Vec<u8>, limit: usize) -> Result<(), ImageError> {
+    br.align();
+    let hdr = br.data.get(br.pos..br.pos + 4).ok_or(ImageError::Truncated)?;
+    let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+    let nlen = u16::from_le_bytes([hdr[2], hdr[3]]);
+    if len != !nlen {
+        return Err(ImageError::Corrupt("inflate: stored length mismatch"));
+    }
+    br.pos += 4;
+    let bytes = br.data.get(br.pos..br.pos + len as usize).ok_or(ImageError::Truncated)?;
+    if out.len() + bytes.len() > limit {
+        return Err(TOO_LONG);
+    }
+    out.extend_from_slice(bytes);
+    br.pos += len as usize;
+    Ok(())
+}
+
+fn fixed_tables() -> Result<(Huffman, Huffman), ImageError> {
+    let mut lengths = [0u8; MAX_LIT_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let lit = Huffman::new(&lengths)?;
+    let dist = Huffman::new(&[5u8; MAX_DIST_CODES])?;
+    Ok((lit, dist))
+}
+
+fn dynamic_tables(br: &mut BitReader) -> Result<(Huffman, Huffman), ImageError> {
+    let nlen = br.bits(5)? as usize + 257;
+    let ndist = br.bits(5)? as usize + 1;
+    let ncode = br.bits(4)? as usize + 4;
+    if nlen > 286 || ndist > MAX_DIST_CODES {
+        return Err(ImageError::Corrupt("inflate: bad code counts"));
+    }
+
+    let mut lengths = [0u8; 19];
+    for &idx in CLEN_ORDER.iter().take(ncode) {
+        lengths[idx] = br.bits(3)? as u8;
+    }
+    let clen = Huffman::new(&lengths)?;
+
+    let mut lengths = [0u8; MAX_LIT_CODES + MAX_DIST_CODES];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let sym = clen.decode(br)?;
+        if sym < 16 {
+            lengths[i] = sym as u8;
+            i += 1;
+            continue;
+        }
+        let (value, repeat) = match sym {
+            16 => {
+                if i == 0 {
+                    return Err(ImageError::Corrupt("inflate: repeat with no previous length"));
+                }
+                (lengths[i - 1], 3 + br.bits(2)? as usize)
+            }
+            17 => (0, 3 + br.bits(3)? as usize),
+            _ => (0, 11 + br.bits(7)? as usize),
+        };
+        if i + repeat > nlen + ndist {
+            return Err(ImageError::Corrupt("inflate: too many lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(ImageError::Corrupt("inflate: missing end-of-block code"));
+    }
+
+    let lit = Huffman::new(&lengths[..nlen])?;
+    let dist = Huffman::new(&lengths[nlen..nlen + ndist])?;
+    Ok((lit, dist))
+}
+
+fn codes(br: &mut BitReader, out: &mut Vec<u8>, limit: usize, lit: &Huffman, dist: &Huffman) -> Result<(), ImageError> {
+    loop {
+        let sym = lit.decode(br)? as usize;
+        if sym < 256 {
+            if out.len() == limit {
+                return Err(TOO_LONG);
+            }
+            out.push(sym as u8);
+            continue;
+        }
+        if sym == 256 {
+            return Ok(());
+        }
+
+        let sym = sym - 257;
+        if sym >= LEN_BASE.len() {
+            return Err(ImageError::Corrupt("inflate: bad length symbol"));
+        }
+        let len = LEN_BASE[sym] as usize + br.bits(LEN_EXTRA[sym] as u32)? as usize;
+
+        let dsym = dist.decode(br)? as usize;
+        if dsym >= DIST_BASE.len() {
+            return Err(ImageError::Corrupt("inflate: bad distance symbol"));
+        }
+        let d = DIST_BASE[dsym] as usize + br.bits(DIST_EXTRA[dsym] as u32)? as usize;
+        if d > out.len() {
+            return Err(ImageError::Corrupt("inflate: distance too far back"));
+        }
+        if out.len() + len > limit {
+            return Err(TOO_LONG);
+        }
+
+        // Byte-by-byte: the source may overlap the bytes being produced
+        let start = out.len() - d;
+        for k in 0..len {
+            let b = out[start + k];
+            out.push(b);
+        }
+    }
+}
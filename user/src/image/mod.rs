@@ -0,0 +1,109 @@
+/// Image decoding - BMP, PPM/PGM/PBM, QOI and PNG into `gfx::Surface`
+///
+/// Decoders take the whole encoded file as a byte slice (as returned by
+/// `Ext2Driver::read_file`) and produce a 0xAARRGGBB surface ready to blit.
+use alloc::vec::Vec;
+
+use crate::gfx::Surface;
+
+pub mod bmp;
+pub mod ppm;
+pub mod qoi;
+pub mod png;
+mod inflate;
+
+#[cfg(test)]
+mod tests;
+
+/// Largest image we will try to decode: its pixels take half the heap, and
+/// decoding a PNG needs about as much again for the inflated data
+pub const MAX_PIXELS: usize = crate::HEAP_SIZE / 2 / core::mem::size_of::<crate::gfx::Color>();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// Signature not recognised by any decoder
+    UnknownFormat,
+    /// Data ended before the image was complete
+    Truncated,
+    /// Valid file using a feature we don't decode
+    Unsupported(&'static str),
+    /// Malformed data
+    Corrupt(&'static str),
+    /// Dimensions are zero or exceed `MAX_PIXELS`
+    BadDimensions,
+    /// The heap can't hold the decoded image
+    OutOfMemory,
+}
+
+impl ImageError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageError::UnknownFormat => "unknown image format",
+            ImageError::Truncated => "truncated image data",
+            ImageError::Unsupported(what) => what,
+            ImageError::Corrupt(what) => what,
+            ImageError::BadDimensions => "bad image dimensions",
+            ImageError::OutOfMemory => "not enough memory for the image",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Bmp,
+    Ppm,
+    Qoi,
+    Png,
+}
+
+/// Identify the image format from its leading magic bytes
+pub fn detect(data: &[u8]) -> Option<Format> {
+    if data.starts_with(b"BM") {
+        Some(Format::Bmp)
+    } else if data.starts_with(b"qoif") {
+        Some(Format::Qoi)
+    } else if data.starts_with(&png::SIGNATURE) {
+        Some(Format::Png)
+    } else if data.len() >= 2 && data[0] == b'P' && (b'1'..=b'6').contains(&data[1]) {
+        Some(Format::Ppm)
+    } else {
+        None
+    }
+}
+
+/// Decode any supported format, picking the decoder from the file's magic
+pub fn decode(data: &[u8]) -> Result<Surface, ImageError> {
+    match detect(data) {
+        Some(Format::Bmp) => bmp::decode(data),
+        Some(Format::Ppm) => ppm::decode(data),
+        Some(Format::Qoi) => qoi::decode(data),
+        Some(Format::Png) => png::decode(data),
+        None => Err(ImageError::UnknownFormat),
+    }
+}
+
+/// Validate dimensions before allocating a surface for them
+fn check_dimensions(width: usize, height: usize) -> Result<(), ImageError> {
+    if width == 0 || height == 0 {
+        return Err(ImageError::BadDimensions);
+    }
+    match width.checked_mul(height) {
+        Some(n) if n <= MAX_PIXELS => Ok(()),
+        _ => Err(ImageError::BadDimensions),
+    }
+}
+
+/// An empty buffer with room for `len` items, failing instead of aborting
+/// the guest when the heap is short
+fn buffer<T>(len: usize) -> Result<Vec<T>, ImageError> {
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| ImageError::OutOfMemory)?;
+    Ok(buf)
+}
+
+/// `len` copies of `value`, failing like `buffer`
+fn filled<T: Clone>(len: usize, value: T) -> Result<Vec<T>, ImageError> {
+    let mut buf = buffer(len)?;
+    buf.resize(len, value);
+    Ok(buf)
+}
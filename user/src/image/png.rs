@@ -0,0 +1,310 @@
+/// PNG - all color types and bit depths, Adam7 interlacing, tRNS transparency
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::inflate::zlib_decompress;
+use super::{check_dimensions, filled, ImageError};
+use crate::gfx::{rgba, Color, Surface};
+
+pub const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+const COLOR_GRAY: u8 = 0;
+const COLOR_RGB: u8 = 2;
+const COLOR_PALETTE: u8 = 3;
+const COLOR_GRAY_ALPHA: u8 = 4;
+const COLOR_RGBA: u8 = 6;
+
+/// Adam7 passes: (x start, y start, x step, y step)
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+struct Header {
+    width: usize,
+    height: usize,
+    depth: u8,
+    color: u8,
+    interlaced: bool,
+}
+
+impl Header {
+    fn channels(&self) -> usize {
+        match self.color {
+            COLOR_GRAY | COLOR_PALETTE => 1,
+            COLOR_GRAY_ALPHA => 2,
+            COLOR_RGB => 3,
+            _ => 4,
+        }
+    }
+
+    fn bits_per_pixel(&self) -> usize {
+        self.channels() * self.depth as usize
+    }
+
+    /// Bytes per scanline of `width` pixels, without the filter byte
+    fn stride(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    /// Size of the inflated image data: every scanline of every pass, each
+    /// with its filter byte
+    fn raw_size(&self) -> usize {
+        if !self.interlaced {
+            return (self.stride(self.width) + 1) * self.height;
+        }
+        ADAM7
+            .iter()
+            .filter(|&&(x0, y0, _, _)| x0 < self.width && y0 < self.height)
+            .map(|&(x0, y0, dx, dy)| {
+                let (pw, ph) = ((self.width - x0).div_ceil(dx), (self.height - y0).div_ceil(dy));
+                (self.stride(pw) + 1) * ph
+            })
+            .sum()
+    }
+}
+
+/// Per-image state needed to turn samples into colors
+struct Palette {
+    colors: Vec<Color>,
+    /// tRNS for gray/RGB images: the sample values that are fully transparent
+    key: Option<[u16; 3]>,
+}
+
+pub fn decode(data: &[u8]) -> Result<Surface, ImageError> {
+    if !data.starts_with(&SIGNATURE) {
+        return Err(ImageError::UnknownFormat);
+    }
+
+    let mut pos = SIGNATURE.len();
+    let mut header: Option<Header> = None;
+    let mut palette = Palette { colors: Vec::new(), key: None };
+    let mut idat: Vec<u8> = Vec::new();
+
+    loop {
+        let chunk = data.get(pos..pos + 8).ok_or(ImageError::Truncated)?;
+        let len = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]) as usize;
+        let kind = [chunk[4], chunk[5], chunk[6], chunk[7]];
+        let body = data.get(pos + 8..pos + 8 + len).ok_or(ImageError::Truncated)?;
+        pos += 12 + len; // length, type, data, CRC
+
+        match &kind {
+            b"IHDR" => {
+                if body.len() < 13 {
+                    return Err(ImageError::Corrupt("png: short IHDR"));
+                }
+                let hdr = Header {
+                    width: u32::from_be_bytes([body[0], body[1], body[2], body[3]]) as usize,
+                    height: u32::from_be_bytes([body[4], body[5], body[6], body[7]]) as usize,
+                    depth: body[8],
+                    color: body[9],
+                    interlaced: body[12] == 1,
+                };
+                let valid_depth = match hdr.color {
+                    COLOR_GRAY => matches!(hdr.depth, 1 | 2 | 4 | 8 | 16),
+                    COLOR_PALETTE => matches!(hdr.depth, 1 | 2 | 4 | 8),
+                    COLOR_RGB | COLOR_GRAY_ALPHA | COLOR_RGBA => matches!(hdr.depth, 8 | 16),
+                    _ => false,
+                };
+                if !valid_depth || body[10] != 0 || body[11] != 0 || body[12] > 1 {
+                    return Err(ImageError::Corrupt("png: bad IHDR"));
+                }
+                check_dimensions(hdr.width, hdr.height)?;
+                header = Some(hdr);
+            }
+            b"PLTE" => {
+                palette.colors = body.chunks_exact(3).map(|c| rgba(c[0], c[1], c[2], 255)).collect();
+            }
+            b"tRNS" => {
+                let hdr = header.as_ref().ok_or(ImageError::Corrupt("png: tRNS before IHDR"))?;
+                match hdr.color {
+                    COLOR_PALETTE => {
+                        for (color, &a) in palette.colors.iter_mut().zip(body) {
+                            *color = (*color & 0x00FFFFFF) | ((a as u32) << 24);
+                        }
+                    }
+                    COLOR_GRAY if body.len() >= 2 => {
+                        let v = u16::from_be_bytes([body[0], body[1]]);
+                        palette.key = Some([v, v, v]);
+                    }
+                    COLOR_RGB if body.len() >= 6 => {
+                        palette.key = Some([
+                            u16::from_be_bytes([body[0], body[1]]),
+                            u16::from_be_bytes([body[2], body[3]]),
+                            u16::from_be_bytes([body[4], body[5]]),
+                        ]);
+                    }
+                    _ => {}
+                }
+            }
+            b"IDAT" => idat.extend_from_slice(body),
+            b"IEND" => break,
+            _ => {
+                // Unknown critical chunks (uppercase first letter) can't be skipped
+                if kind[0] & 0x20 == 0 {
+                    return Err(ImageError::Unsupported("png: unknown critical chunk"));
+                }
+            }
+        }
+    }
+
+    let hdr = header.ok_or(ImageError::Corrupt("png: missing IHDR"))?;
+    if hdr.color == COLOR_PALETTE && palette.colors.is_empty() {
+        return Err(ImageError::Corrupt("png: missing PLTE"));
+    }
+
+    let raw = zlib_decompress(&idat, hdr.raw_size())?;
+    drop(idat);
+
+    let mut pixels = filled(hdr.width * hdr.height, 0 as Color)?;
+    if hdr.interlaced {
+        let mut offset = 0;
+        for &(x0, y0, dx, dy) in ADAM7.iter() {
+            if x0 >= hdr.width || y0 >= hdr.height {
+                continue;
+            }
+            let pw = (hdr.width - x0).div_ceil(dx);
+            let ph = (hdr.height - y0).div_ceil(dy);
+            let used = decode_pass(&hdr, &palette, &raw[offset..], pw, ph, |x, y, c| {
+                pixels[(y0 + y * dy) * hdr.width + x0 + x * dx] = c;
+            })?;
+            offset += used;
+        }
+    } else {
+        let width = hdr.width;
+        decode_pass(&hdr, &palette, &raw, hdr.width, hdr.height, |x, y, c| {
+            pixels[y * width + x] = c;
+        })?;
+    }
+
+    Surface::from_pixels(hdr.width, hdr.height, pixels).ok_or(ImageError::BadDimensions)
+}
+
+/// Unfilter one (sub)image and hand each pixel to `put`. Returns bytes consumed.
+fn decode_pass<F: FnMut(usize, usize, Color)>(
+    hdr: &Header,
+    palette: &Palette,
+    raw: &[u8],
+    width: usize,
+    height: usize,
+    mut put: F,
+) -> Result<usize, ImageError> {
+    let stride = hdr.stride(width);
+    let bpp = hdr.bits_per_pixel().div_ceil(8);
+    let mut prev = vec![0u8; stride];
+    let mut cur = vec![0u8; stride];
+
+    for y in 0..height {
+        let line = raw.get(y * (stride + 1)..(y + 1) * (stride + 1)).ok_or(ImageError::Truncated)?;
+        cur.copy_from_slice(&line[1..]);
+        unfilter(line[0], &mut cur, &prev, bpp)?;
+
+        for x in 0..width {
+            put(x, y, pixel(hdr, palette, &cur, x)?);
+        }
+        core::mem::swap(&mut prev, &mut cur);
+    }
+    Ok(height * (stride + 1))
+}
+
+fn unfilter(filter: u8, cur: &mut [u8], prev: &[u8], bpp: usize) -> Result<(), ImageError> {
+    match filter {
+        0 => {}
+        1 => {
+            for i in bpp..cur.len() {
+                cur[i] = cur[i].wrapping_add(cur[i - bpp]);
+            }
+        }
+        2 => {
+            for i in 0..cur.len() {
+                cur[i] = cur[i].wrapping_add(prev[i]);
+            }
+        }
+        3 => {
+            for i in 0..cur.len() {
+                let left = if i >= bpp { cur[i - bpp] as u16 } else { 0 };
+                cur[i] = cur[i].wrapping_add(((left + prev[i] as u16) / 2) as u8);
+            }
+        }
+        4 => {
+            for i in 0..cur.len() {
+                let a = if i >= bpp { cur[i - bpp] } else { 0 };
+                let c = if i >= bpp { prev[i - bpp] } else { 0 };
+                cur[i] = cur[i].wrapping_add(paeth(a, prev[i], c));
+            }
+        }
+        _ => return Err(ImageError::Corrupt("png: bad filter type")),
+    }
+    Ok(())
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Read sample `i` of a scanline at the image's bit depth
+fn sample(line: &[u8], depth: u8, i: usize) -> u16 {
+    match depth {
+        16 => u16::from_be_bytes([line[i * 2], line[i * 2 + 1]]),
+        8 => line[i] as u16,
+        _ => {
+            let bit = i * depth as usize;
+            let shift = 8 - depth as usize - (bit % 8);
+            ((line[bit / 8] >> shift) & ((1u8 << depth) - 1)) as u16
+        }
+    }
+}
+
+/// Scale a sample to 8 bits
+fn to_u8(v: u16, depth: u8) -> u8 {
+    match depth {
+        16 => (v >> 8) as u8,
+        8 => v as u8,
+        _ => (v as u32 * 255 / ((1u32 << depth) - 1)) as u8,
+    }
+}
+
+fn pixel(hdr: &Header, palette: &Palette, line: &[u8], x: usize) -> Result<Color, ImageError> {
+    let d = hdr.depth;
+    let ch = hdr.channels();
+    let s = |c: usize| sample(line, d, x * ch + c);
+
+    let color = match hdr.color {
+        COLOR_PALETTE => *palette
+            .colors
+            .get(s(0) as usize)
+            .ok_or(ImageError::Corrupt("png: palette index out of range"))?,
+        COLOR_GRAY => {
+            let v = s(0);
+            let g = to_u8(v, d);
+            let a = if palette.key.is_some_and(|k| k[0] == v) { 0 } else { 255 };
+            rgba(g, g, g, a)
+        }
+        COLOR_GRAY_ALPHA => {
+            let g = to_u8(s(0), d);
+            rgba(g, g, g, to_u8(s(1), d))
+        }
+        COLOR_RGB => {
+            let (r, g, b) = (s(0), s(1), s(2));
+            let a = if palette.key == Some([r, g, b]) { 0 } else { 255 };
+            rgba(to_u8(r, d), to_u8(g, d), to_u8(b, d), a)
+        }
+        _ => rgba(to_u8(s(0), d), to_u8(s(1), d), to_u8(s(2), d), to_u8(s(3), d)),
+    };
+    Ok(color)
+}
@@ -0,0 +1,145 @@
+/// Netpbm - PBM (P1/P4), PGM (P2/P5) and PPM (P3/P6)
+use super::{buffer, check_dimensions, ImageError};
+use crate::gfx::{rgb, Surface};
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Skip whitespace and `#` comments
+    fn skip_space(&mut self) {
+        while let Some(&c) = self.data.get(self.pos) {
+            if c == b'#' {
+                while let Some(&c) = self.data.get(self.pos) {
+                    if c == b'\n' || c == b'\r' { break; }
+                    self.pos += 1;
+                }
+            } else if c.is_ascii_whitespace() {
+                self.pos += 1;
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Read an ASCII decimal number
+    fn number(&mut self) -> Result<u32, ImageError> {
+        self.skip_space();
+        let start = self.pos;
+        let mut val: u32 = 0;
+        while let Some(&c) = self.data.get(self.pos) {
+            if !c.is_ascii_digit() { break; }
+            val = val
+                .checked_mul(10)
+                .and_then(|v| v.checked_add((c - b'0') as u32))
+                .ok_or(ImageError::Corrupt("ppm: number overflow"))?;
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return Err(if self.pos >= self.data.len() {
+                ImageError::Truncated
+            } else {
+                ImageError::Corrupt("ppm: expected number")
+            });
+        }
+        Ok(val)
+    }
+
+    /// Read a single ASCII bit (P1 allows digits without separators)
+    fn bit(&mut self) -> Result<bool, ImageError> {
+        self.skip_space();
+        match self.data.get(self.pos) {
+            Some(b'0') => { self.pos += 1; Ok(false) }
+            Some(b'1') => { self.pos += 1; Ok(true) }
+            Some(_) => Err(ImageError::Corrupt("pbm: expected bit")),
+            None => Err(ImageError::Truncated),
+        }
+    }
+
+    fn byte(&mut self) -> Result<u8, ImageError> {
+        let b = *self.data.get(self.pos).ok_or(ImageError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    /// Binary sample: one byte, or two big-endian bytes when maxval > 255
+    fn sample(&mut self, wide: bool) -> Result<u32, ImageError> {
+        if wide {
+            let hi = self.byte()? as u32;
+            Ok((hi << 8) | self.byte()? as u32)
+        } else {
+            Ok(self.byte()? as u32)
+        }
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Surface, ImageError> {
+    if data.len() < 2 || data[0] != b'P' {
+        return Err(ImageError::UnknownFormat);
+    }
+    let kind = data[1];
+    let mut p = Parser { data, pos: 2 };
+
+    let width = p.number()? as usize;
+    let height = p.number()? as usize;
+    check_dimensions(width, height)?;
+    let maxval = if kind == b'1' || kind == b'4' { 1 } else { p.number()? };
+    if maxval == 0 || maxval > 65535 {
+        return Err(ImageError::Corrupt("ppm: bad maxval"));
+    }
+    // Exactly one whitespace byte separates the header from binary data
+    if matches!(kind, b'4' | b'5' | b'6') {
+        p.pos += 1;
+    }
+
+    let scale = |v: u32| -> Result<u8, ImageError> {
+        if v > maxval {
+            return Err(ImageError::Corrupt("ppm: sample exceeds maxval"));
+        }
+        Ok(((v * 255 + maxval / 2) / maxval) as u8)
+    };
+    let wide = maxval > 255;
+    let mut pixels = buffer(width * height)?;
+
+    match kind {
+        b'1' => {
+            for _ in 0..width * height {
+                // PBM: 1 is black
+                pixels.push(if p.bit()? { rgb(0, 0, 0) } else { rgb(255, 255, 255) });
+            }
+        }
+        b'4' => {
+            let stride = width.div_ceil(8);
+            for _ in 0..height {
+                let row = data.get(p.pos..p.pos + stride).ok_or(ImageError::Truncated)?;
+                for x in 0..width {
+                    let set = row[x / 8] & (0x80 >> (x % 8)) != 0;
+                    pixels.push(if set { rgb(0, 0, 0) } else { rgb(255, 255, 255) });
+                }
+                p.pos += stride;
+            }
+        }
+        b'2' | b'5' => {
+            for _ in 0..width * height {
+                let v = if kind == b'2' { p.number()? } else { p.sample(wide)? };
+                let g = scale(v)?;
+                pixels.push(rgb(g, g, g));
+            }
+        }
+        b'3' | b'6' => {
+            for _ in 0..width * height {
+                let mut c = [0u8; 3];
+                for ch in c.iter_mut() {
+                    let v = if kind == b'3' { p.number()? } else { p.sample(wide)? };
+                    *ch = scale(v)?;
+                }
+                pixels.push(rgb(c[0], c[1], c[2]));
+            }
+        }
+        _ => return Err(ImageError::UnknownFormat),
+    }
+
+    Surface::from_pixels(width, height, pixels).ok_or(ImageError::BadDimensions)
+}
@@ -0,0 +1,92 @@
+/// QOI - "Quite OK Image" format (qoiformat.org)
+use super::{buffer, check_dimensions, ImageError};
+use crate::gfx::{rgba, Surface};
+
+const OP_INDEX: u8 = 0x00;
+const OP_DIFF: u8 = 0x40;
+const OP_LUMA: u8 = 0x80;
+const OP_RGB: u8 = 0xFE;
+const OP_RGBA: u8 = 0xFF;
+const MASK_2: u8 = 0xC0;
+
+const HEADER_SIZE: usize = 14;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Px {
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+}
+
+impl Px {
+    fn hash(&self) -> usize {
+        (self.r as usize * 3 + self.g as usize * 5 + self.b as usize * 7 + self.a as usize * 11) % 64
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Surface, ImageError> {
+    if data.len() < HEADER_SIZE {
+        return Err(ImageError::Truncated);
+    }
+    if &data[0..4] != b"qoif" {
+        return Err(ImageError::UnknownFormat);
+    }
+    let width = u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let height = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) as usize;
+    check_dimensions(width, height)?;
+
+    let total = width * height;
+    let mut pixels = buffer(total)?;
+    let mut index = [Px { r: 0, g: 0, b: 0, a: 0 }; 64];
+    let mut px = Px { r: 0, g: 0, b: 0, a: 255 };
+    let mut pos = HEADER_SIZE;
+
+    let next = |pos: &mut usize| -> Result<u8, ImageError> {
+        let b = *data.get(*pos).ok_or(ImageError::Truncated)?;
+        *pos += 1;
+        Ok(b)
+    };
+
+    while pixels.len() < total {
+        let b1 = next(&mut pos)?;
+        let mut run = 1;
+
+        if b1 == OP_RGB {
+            px.r = next(&mut pos)?;
+            px.g = next(&mut pos)?;
+            px.b = next(&mut pos)?;
+        } else if b1 == OP_RGBA {
+            px.r = next(&mut pos)?;
+            px.g = next(&mut pos)?;
+            px.b = next(&mut pos)?;
+            px.a = next(&mut pos)?;
+        } else {
+            match b1 & MASK_2 {
+                OP_INDEX => px = index[b1 as usize],
+                OP_DIFF => {
+                    px.r = px.r.wrapping_add(((b1 >> 4) & 0x03).wrapping_sub(2));
+                    px.g = px.g.wrapping_add(((b1 >> 2) & 0x03).wrapping_sub(2));
+                    px.b = px.b.wrapping_add((b1 & 0x03).wrapping_sub(2));
+                }
+                OP_LUMA => {
+                    let b2 = next(&mut pos)?;
+                    let vg = (b1 & 0x3F).wrapping_sub(32);
+                    px.r = px.r.wrapping_add(vg.wrapping_sub(8).wrapping_add((b2 >> 4) & 0x0F));
+                    px.g = px.g.wrapping_add(vg);
+                    px.b = px.b.wrapping_add(vg.wrapping_sub(8).wrapping_add(b2 & 0x0F));
+                }
+                _ => run = (b1 & 0x3F) as usize + 1, // OP_RUN
+            }
+        }
+
+        index[px.hash()] = px;
+        let color = rgba(px.r, px.g, px.b, px.a);
+        let run = run.min(total - pixels.len());
+        for _ in 0..run {
+            pixels.push(color);
+        }
+    }
+
+    Surface::from_pixels(width, height, pixels).ok_or(ImageError::BadDimensions)
+}
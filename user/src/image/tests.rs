@@ -0,0 +1,396 @@
+/// Decoding small images encoded here, plus truncated, damaged and
+/// oversized copies of them
+use super::*;
+use crate::gfx::{rgba, Canvas, Color};
+
+/// 3x2 test pattern; one pixel is translucent
+const W: usize = 3;
+const H: usize = 2;
+const PIXELS: [Color; W * H] = [
+    0xFFFF0000, 0xFF00FF00, 0xFF0000FF,
+    0x80FFFFFF, 0xFF000000, 0xFF123456,
+];
+
+fn channels(c: Color) -> [u8; 4] {
+    let [b, g, r, a] = c.to_le_bytes();
+    [r, g, b, a]
+}
+
+fn be32(v: u32) -> [u8; 4] {
+    v.to_be_bytes()
+}
+
+// --- Encoders ---
+
+/// zlib stream of stored deflate blocks
+fn zlib(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend([1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend(len.to_le_bytes());
+        out.extend((!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend(be32(b << 16 | a));
+    out
+}
+
+fn chunk(kind: &[u8; 4], body: &[u8], out: &mut Vec<u8>) {
+    out.extend(be32(body.len() as u32));
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let mut crc = !0u32;
+    for &byte in kind.iter().chain(body) {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    out.extend(be32(!crc));
+}
+
+/// PNG with the given header fields; `extra` chunks go before IDAT
+fn png_file(width: usize, height: usize, depth: u8, color: u8, interlaced: bool, extra: &[(&[u8; 4], &[u8])], idat: &[u8]) -> Vec<u8> {
+    let mut out = png::SIGNATURE.to_vec();
+    let ihdr = [&be32(width as u32)[..], &be32(height as u32), &[depth, color, 0, 0, interlaced as u8]].concat();
+    chunk(b"IHDR", &ihdr, &mut out);
+    for (kind, body) in extra {
+        chunk(kind, body, &mut out);
+    }
+    chunk(b"IDAT", idat, &mut out);
+    chunk(b"IEND", &[], &mut out);
+    out
+}
+
+fn png_with(width: usize, height: usize, interlaced: bool, raw: &[u8]) -> Vec<u8> {
+    png_file(width, height, 8, 6, interlaced, &[], &zlib(raw))
+}
+
+/// Adam7 passes as (x start, y start, x step, y step), or the whole image
+fn passes(interlaced: bool) -> &'static [(usize, usize, usize, usize)] {
+    if interlaced { &[(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)] }
+    else { &[(0, 0, 1, 1)] }
+}
+
+/// 8-bit RGBA PNG, unfiltered, optionally Adam7-interlaced
+fn png(width: usize, height: usize, pixels: &[Color], interlaced: bool) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for &(x0, y0, dx, dy) in passes(interlaced) {
+        if x0 >= width {
+            continue;
+        }
+        for y in (y0..height).step_by(dy) {
+            raw.push(0);
+            for x in (x0..width).step_by(dx) {
+                raw.extend(channels(pixels[y * width + x]));
+            }
+        }
+    }
+    png_with(width, height, interlaced, &raw)
+}
+
+/// Palette PNG of `depth`-bit indices, packed high bits first
+fn indexed_png(width: usize, height: usize, depth: u8, indices: &[u8], interlaced: bool, extra: &[(&[u8; 4], &[u8])]) -> Vec<u8> {
+    let mut raw = Vec::new();
+    for &(x0, y0, dx, dy) in passes(interlaced) {
+        if x0 >= width || y0 >= height {
+            continue;
+        }
+        for y in (y0..height).step_by(dy) {
+            raw.push(0);
+            let start = raw.len();
+            for (i, x) in (x0..width).step_by(dx).enumerate() {
+                let bit = i * depth as usize;
+                if bit.is_multiple_of(8) {
+                    raw.push(0);
+                }
+                raw[start + bit / 8] |= indices[y * width + x] << (8 - depth as usize - bit % 8);
+            }
+        }
+    }
+    png_file(width, height, depth, 3, interlaced, extra, &zlib(&raw))
+}
+
+/// Filter a scanline with PNG filter `kind`, given the unfiltered line above
+fn filter(kind: u8, line: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+    let mut out = vec![kind];
+    for i in 0..line.len() {
+        let a = if i >= bpp { line[i - bpp] } else { 0 };
+        let b = prev[i];
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        let predicted = match kind {
+            0 => 0,
+            1 => a,
+            2 => b,
+            3 => ((a as u16 + b as u16) / 2) as u8,
+            _ => {
+                let p = a as i16 + b as i16 - c as i16;
+                let (pa, pb, pc) = ((p - a as i16).abs(), (p - b as i16).abs(), (p - c as i16).abs());
+                if pa <= pb && pa <= pc { a } else if pb <= pc { b } else { c }
+            }
+        };
+        out.push(line[i].wrapping_sub(predicted));
+    }
+    out
+}
+
+/// QOI using only `QOI_OP_RGBA`
+fn qoi(width: usize, height: usize, pixels: &[Color]) -> Vec<u8> {
+    let mut out = b"qoif".to_vec();
+    out.extend(be32(width as u32));
+    out.extend(be32(height as u32));
+    out.extend([4, 0]);
+    for &p in pixels {
+        out.push(0xFF);
+        out.extend(channels(p));
+    }
+    out.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+    out
+}
+
+/// Bottom-up 24-bit BMP
+fn bmp(width: usize, height: usize, pixels: &[Color]) -> Vec<u8> {
+    let stride = (width * 3).div_ceil(4) * 4;
+    let mut out = b"BM".to_vec();
+    out.extend(((54 + stride * height) as u32).to_le_bytes());
+    out.extend([0; 4]);
+    out.extend(54u32.to_le_bytes());
+    out.extend(40u32.to_le_bytes());
+    out.extend((width as i32).to_le_bytes());
+    out.extend((height as i32).to_le_bytes());
+    out.extend(1u16.to_le_bytes());
+    out.extend(24u16.to_le_bytes());
+    out.extend([0; 24]);
+    for y in (0..height).rev() {
+        for &p in &pixels[y * width..(y + 1) * width] {
+            let [r, g, b, _] = channels(p);
+            out.extend([b, g, r]);
+        }
+        out.resize(out.len() + stride - width * 3, 0);
+    }
+    out
+}
+
+/// Binary (P6) or plain (P3) PPM
+fn ppm(width: usize, height: usize, pixels: &[Color], binary: bool) -> Vec<u8> {
+    let mut out = format!("P{}\n# test\n{} {}\n255\n", if binary { 6 } else { 3 }, width, height).into_bytes();
+    for &p in pixels {
+        let [r, g, b, _] = channels(p);
+        if binary {
+            out.extend([r, g, b]);
+        } else {
+            out.extend(format!("{} {} {}\n", r, g, b).bytes());
+        }
+    }
+    out
+}
+
+/// What formats without alpha should decode `PIXELS` to
+fn opaque() -> Vec<Color> {
+    PIXELS.iter().map(|&p| p | 0xFF000000).collect()
+}
+
+fn samples() -> [(Vec<u8>, Vec<Color>); 6] {
+    [
+        (png(W, H, &PIXELS, false), PIXELS.to_vec()),
+        (png(W, H, &PIXELS, true), PIXELS.to_vec()),
+        (qoi(W, H, &PIXELS), PIXELS.to_vec()),
+        (bmp(W, H, &PIXELS), opaque()),
+        (ppm(W, H, &PIXELS, true), opaque()),
+        (ppm(W, H, &PIXELS, false), opaque()),
+    ]
+}
+
+// --- Tests ---
+
+#[test]
+fn round_trips() {
+    for (data, expected) in samples() {
+        let img = decode(&data).unwrap_or_else(|e| panic!("{:?}: {}", detect(&data), e.as_str()));
+        assert_eq!((img.width(), img.height()), (W, H));
+        assert_eq!(img.pixels(), &expected[..], "{:?}", detect(&data));
+    }
+}
+
+#[test]
+fn interlaced_png_with_every_pass() {
+    let (w, h) = (9, 9);
+    let pixels: Vec<Color> = (0..w * h).map(|i| rgba(i as u8, (i * 7) as u8, 255 - i as u8, 255)).collect();
+    let img = decode(&png(w, h, &pixels, true)).unwrap();
+    assert_eq!(img.pixels(), &pixels[..]);
+}
+
+#[test]
+fn truncated_files_are_errors() {
+    for (data, _) in samples() {
+        // Plain PPM text can end early and still parse as a shorter number
+        let plain = data.starts_with(b"P3");
+        // Decoders stop once they have every pixel, so end markers may go
+        let trailer = match detect(&data) {
+            Some(Format::Png) => 12,
+            Some(Format::Qoi) => 8,
+            _ => 0,
+        };
+        for len in 0..data.len() - trailer {
+            let result = decode(&data[..len]);
+            assert!(plain || result.is_err(), "{:?} cut to {} bytes decoded", detect(&data), len);
+        }
+    }
+}
+
+#[test]
+fn damaged_files_never_panic() {
+    for (data, _) in samples() {
+        for i in 0..data.len() {
+            for flip in [0x01, 0x80, 0xFF] {
+                let mut bad = data.clone();
+                bad[i] ^= flip;
+                let _ = decode(&bad);
+            }
+        }
+    }
+}
+
+#[test]
+fn oversized_headers_are_rejected_before_allocating() {
+    let side = 2048;
+    assert!(side * side > MAX_PIXELS);
+    let mut big_png = png(1, 1, &[0], false);
+    big_png[16..24].copy_from_slice(&[&be32(side as u32)[..], &be32(side as u32)].concat());
+    let mut big_bmp = bmp(1, 1, &[0]);
+    big_bmp[18..26].copy_from_slice(&[(side as i32).to_le_bytes(), (side as i32).to_le_bytes()].concat());
+    let big_ppm = format!("P6 {} {} 255\n", side, side).into_bytes();
+    for data in [big_png, qoi(side, side, &[]), big_bmp, big_ppm] {
+        assert_eq!(decode(&data).err(), Some(ImageError::BadDimensions), "{:?}", detect(&data));
+    }
+
+    let empty = qoi(0, 5, &[]);
+    assert_eq!(decode(&empty).err(), Some(ImageError::BadDimensions));
+}
+
+#[test]
+fn inflate_stops_at_the_image_size() {
+    // Two scanlines of data for a one-scanline image
+    let raw = [0, 1, 2, 3, 4, 0, 5, 6, 7, 8];
+    let data = png_with(1, 1, false, &raw);
+    assert_eq!(decode(&data).err(), Some(ImageError::Corrupt("inflate: more data than expected")));
+    // Short is truncated, not out of bounds
+    let data = png_with(1, 1, false, &raw[..3]);
+    assert_eq!(decode(&data).err(), Some(ImageError::Truncated));
+}
+
+/// 8x8 grayscale image the deflate vectors below hold
+fn gray_pattern() -> Vec<Color> {
+    let levels = [0x00, 0x55, 0xAA, 0xFF];
+    (0..64).map(|i| (i % 8, i / 8)).map(|(x, y)| levels[(x * x + 3 * y + x * y) % 4]).map(|g| rgba(g, g, g, 255)).collect()
+}
+
+#[test]
+fn fixed_huffman() {
+    // zlib's output for "hello hello hello, jello!" with fixed codes, which
+    // repeats "hello " through a length/distance pair
+    let data = [
+        0x78, 0x01, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0x57, 0xC8, 0x40, 0x90, 0x3A, 0x0A, 0x59, 0x20, 0x4A, 0x11, 0x00,
+        0x78, 0xBE, 0x09, 0x00,
+    ];
+    assert_eq!(data[2] >> 1 & 3, 1);
+    assert_eq!(inflate::zlib_decompress(&data, 64).unwrap(), b"hello hello hello, jello!");
+
+    // The gray pattern's scanlines, fixed codes with back-references
+    let idat = [
+        0x78, 0xDA, 0x63, 0x60, 0x08, 0x85, 0xC2, 0xFF, 0xA1, 0xA1, 0xFF, 0x41, 0x98, 0x61, 0x55, 0x28, 0x04, 0x32,
+        0x40, 0x05, 0xFE, 0x33, 0x10, 0xA1, 0x06, 0x00, 0x17, 0x14, 0x1F, 0xE1,
+    ];
+    assert_eq!(idat[2] >> 1 & 3, 1);
+    let img = decode(&png_file(8, 8, 8, 0, false, &[], &idat)).unwrap();
+    assert_eq!(img.pixels(), &gray_pattern()[..]);
+}
+
+#[test]
+fn dynamic_huffman() {
+    // The same scanlines, Huffman-coded with tables sent in the stream
+    let idat = [
+        0x78, 0x01, 0x05, 0xC1, 0x01, 0x01, 0x00, 0x00, 0x08, 0xC3, 0xA0, 0x95, 0xA4, 0xA4, 0x25, 0x2F, 0x94, 0x24,
+        0x8D, 0x8D, 0x75, 0xCE, 0x39, 0xB1, 0xB1, 0x95, 0x24, 0x8D, 0x8D, 0x75, 0xCE, 0x39, 0xB1, 0xB1, 0x3D, 0x17,
+        0x14, 0x1F, 0xE1,
+    ];
+    assert_eq!(idat[2] >> 1 & 3, 2);
+    let img = decode(&png_file(8, 8, 8, 0, false, &[], &idat)).unwrap();
+    assert_eq!(img.pixels(), &gray_pattern()[..]);
+}
+
+#[test]
+fn filters_by_hand() {
+    // 3x4 grayscale; each row worked out from the one above
+    let raw = [
+        1, 200, 100, 200, // Sub: 200, 44 (wrapped), 244
+        3, 0, 10, 0, // Average: 100, 82, 163 (82 + 244 needs 9 bits)
+        4, 5, 214, 0, // Paeth: 105 (above), 40 (above), 82 (upper left)
+        2, 1, 2, 3, // Up: 106, 42, 85
+    ];
+    let img = decode(&png_file(3, 4, 8, 0, false, &[], &zlib(&raw))).unwrap();
+    let expected: Vec<Color> =
+        [200, 44, 244, 100, 82, 163, 105, 40, 82, 106, 42, 85].iter().map(|&g| rgba(g, g, g, 255)).collect();
+    assert_eq!(img.pixels(), &expected[..]);
+}
+
+#[test]
+fn every_filter() {
+    // One filter for both rows, then two mixed
+    for kinds in [[0, 0], [1, 1], [2, 2], [3, 3], [4, 4], [4, 3]] {
+        let mut raw = Vec::new();
+        let mut prev = [0u8; W * 4];
+        for (row, kind) in PIXELS.chunks(W).zip(kinds) {
+            let line: Vec<u8> = row.iter().flat_map(|&p| channels(p)).collect();
+            raw.extend(filter(kind, &line, &prev, 4));
+            prev.copy_from_slice(&line);
+        }
+        let img = decode(&png_with(W, H, false, &raw)).unwrap();
+        assert_eq!(img.pixels(), &PIXELS[..], "filters {kinds:?}");
+    }
+}
+
+#[test]
+fn palette_images() {
+    let plte: &[u8] = &[255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+    // Alpha for the first two entries only; the rest stay opaque
+    let trns: &[u8] = &[0x00, 0x80];
+    let colors = [0x00FF0000, 0x8000FF00, 0xFF0000FF, 0xFFFFFFFF];
+
+    // An odd width leaves part of each packed scanline's last byte unused
+    let (w, h) = (5, 3);
+    let indices: Vec<u8> = (0..w * h).map(|i| (i * 3 % 4) as u8).collect();
+    let expected: Vec<Color> = indices.iter().map(|&i| colors[i as usize]).collect();
+    for depth in [2, 4, 8] {
+        let data = indexed_png(w, h, depth, &indices, false, &[(b"PLTE", plte), (b"tRNS", trns)]);
+        assert_eq!(decode(&data).unwrap().pixels(), &expected[..], "{depth}-bit indices");
+    }
+
+    let data = indexed_png(w, h, 2, &indices, false, &[(b"PLTE", &plte[..6])]);
+    assert_eq!(decode(&data).err(), Some(ImageError::Corrupt("png: palette index out of range")));
+    let data = indexed_png(w, h, 2, &indices, false, &[]);
+    assert_eq!(decode(&data).err(), Some(ImageError::Corrupt("png: missing PLTE")));
+}
+
+#[test]
+fn interlaced_palette() {
+    // Every Adam7 pass; at 1 bit the narrow passes' scanlines are under a byte
+    let (w, h) = (9, 10);
+    let plte: Vec<u8> = (0..16u8).flat_map(|i| [i * 16, 255 - i * 16, i]).collect();
+    for depth in [1u8, 4] {
+        let indices: Vec<u8> = (0..w * h).map(|i| (i * 7 % (1 << depth)) as u8).collect();
+        let expected: Vec<Color> = indices.iter().map(|&i| rgba(i * 16, 255 - i * 16, i, 255)).collect();
+        let data = indexed_png(w, h, depth, &indices, true, &[(b"PLTE", &plte)]);
+        assert_eq!(decode(&data).unwrap().pixels(), &expected[..], "{depth}-bit indices");
+    }
+}
@@ -0,0 +1,93 @@
+/// Input events - keyboard and pointer, polled from the host's MMIO registers
+use aether_abi::mmio::{POINTER_X, POINTER_Y, POINTER_BUTTONS};
+use aether_wasm_sdk::abi::{self, EVENT_SIZE};
+
+pub const BUTTON_LEFT: u32 = 1 << 0;
+pub const BUTTON_RIGHT: u32 = 1 << 1;
+pub const BUTTON_MIDDLE: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A key press, as delivered by `console_getc`
+    Key(char),
+    /// The pointer moved to (x, y)
+    PointerMove { x: i32, y: i32 },
+    /// A button (one of `BUTTON_*`) was pressed at (x, y)
+    PointerDown { x: i32, y: i32, button: u32 },
+    /// A button was released at (x, y)
+    PointerUp { x: i32, y: i32, button: u32 },
+}
+
+impl Event {
+    /// Same event with pointer coordinates shifted by (dx, dy)
+    pub fn translate(self, dx: i32, dy: i32) -> Event {
+        match self {
+            Event::Key(c) => Event::Key(c),
+            Event::PointerMove { x, y } => Event::PointerMove { x: x + dx, y: y + dy },
+            Event::PointerDown { x, y, button } => Event::PointerDown { x: x + dx, y: y + dy, button },
+            Event::PointerUp { x, y, button } => Event::PointerUp { x: x + dx, y: y + dy, button },
+        }
+    }
+
+    /// The record `/dev/input` and WASM apps read (`abi::encode_event`)
+    pub fn encode(self) -> [u8; EVENT_SIZE] {
+        let (kind, x, y, data) = match self {
+            Event::Key(c) => (abi::EVENT_KEY, 0, 0, c as u32),
+            Event::PointerMove { x, y } => (abi::EVENT_POINTER_MOVE, x, y, 0),
+            Event::PointerDown { x, y, button } => (abi::EVENT_POINTER_DOWN, x, y, button),
+            Event::PointerUp { x, y, button } => (abi::EVENT_POINTER_UP, x, y, button),
+        };
+        abi::encode_event(kind, x, y, data)
+    }
+}
+
+/// Last pointer state seen by `poll_event`, used to turn host state into events
+struct PointerState {
+    x: u32,
+    y: u32,
+    buttons: u32,
+}
+
+static mut POINTER: PointerState = PointerState { x: 0, y: 0, buttons: 0 };
+
+fn read_reg(offset: usize) -> u32 {
+    unsafe { ((crate::BASE_ADDRESS + offset) as *const u32).read_volatile() }
+}
+
+/// Current pointer position and button mask
+pub fn pointer_state() -> (i32, i32, u32) {
+    (read_reg(POINTER_X) as i32, read_reg(POINTER_Y) as i32, read_reg(POINTER_BUTTONS))
+}
+
+/// Return the next pending input event, if any (non-blocking)
+///
+/// Keys take priority. Button changes are reported one button at a time,
+/// before any movement that happened in the same frame.
+pub fn poll_event() -> Option<Event> {
+    if let Some(c) = crate::console_getc() {
+        return Some(Event::Key(c));
+    }
+
+    let (x, y, buttons) = pointer_state();
+    let last = unsafe { &mut *core::ptr::addr_of_mut!(POINTER) };
+
+    let changed = buttons ^ last.buttons;
+    if changed != 0 {
+        let button = 1 << changed.trailing_zeros();
+        last.buttons ^= button;
+        last.x = x as u32;
+        last.y = y as u32;
+        return Some(if buttons & button != 0 {
+            Event::PointerDown { x, y, button }
+        } else {
+            Event::PointerUp { x, y, button }
+        });
+    }
+
+    if x as u32 != last.x || y as u32 != last.y {
+        last.x = x as u32;
+        last.y = y as u32;
+        return Some(Event::PointerMove { x, y });
+    }
+    None
+}
@@ -1,4 +1,5 @@
-#![no_std]
+// Host `cargo test` builds link std; guests are freestanding
+#![cfg_attr(not(test), no_std)]
 
 use core::arch::asm;
 use aether_abi::HyperCall;
@@ -15,6 +16,11 @@ pub use console::{init as console_init, println as console_println, set_colors,
 pub use aether_abi::mmio::{FB_ADDR, KEYBOARD_STATUS, KEYBOARD_DATA, DISK_ADDR};
 
 pub mod fs;
+pub mod gfx;
+pub mod image;
+pub mod input;
+pub mod compositor;
+pub mod wasm;
 
 pub const SCREEN_WIDTH: usize = 640;
 pub const SCREEN_HEIGHT: usize = 480;
@@ -81,6 +87,112 @@ pub fn exit(code: u64) -> ! {
     }
 }
 
+/// Wall-clock time from the host, in nanoseconds since the UNIX epoch
+/// (0 if the host doesn't implement the hypercall, or under `cargo test`)
+pub fn get_time_ns() -> u64 {
+    #[allow(unused_mut)]
+    let mut ns: u64 = 0;
+
+    #[cfg(all(target_arch = "aarch64", not(test)))]
+    unsafe {
+        asm!(
+            "hvc #0",
+            inlateout("x0") 0u64 => ns,
+            in("x8") HyperCall::GetTime as u64,
+            options(nostack, nomem)
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") 0x500u16,
+            inlateout("rax") HyperCall::GetTime as u64 => ns,
+            options(nostack, nomem)
+        );
+    }
+
+    ns
+}
+
+/// 64 random bits from the host's entropy source (0 if the host doesn't
+/// implement the hypercall, or under `cargo test`)
+pub fn get_random() -> u64 {
+    #[allow(unused_mut)]
+    let mut bits: u64 = 0;
+
+    #[cfg(all(target_arch = "aarch64", not(test)))]
+    unsafe {
+        asm!(
+            "hvc #0",
+            inlateout("x0") 0u64 => bits,
+            in("x8") HyperCall::GetRandom as u64,
+            options(nostack, nomem)
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") 0x500u16,
+            inlateout("rax") HyperCall::GetRandom as u64 => bits,
+            options(nostack, nomem)
+        );
+    }
+
+    bits
+}
+
+/// Ask the host to answer the requests queued at `SHARE_ADDR`; returns
+/// once they are done (or at once, without a share device)
+pub fn share_notify() {
+    // No `nomem`: the host rewrites the queue during the call
+    #[cfg(all(target_arch = "aarch64", not(test)))]
+    unsafe {
+        asm!(
+            "hvc #0",
+            in("x8") HyperCall::ShareNotify as u64,
+            options(nostack)
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") 0x500u16,
+            in("al") HyperCall::ShareNotify as u8,
+            options(nostack)
+        );
+    }
+}
+
+/// Ask the host to carry out the requests queued at `BLOCK_ADDR`; returns
+/// once they are done (or at once, without a block device)
+pub fn block_notify() {
+    // No `nomem`: the host fills in the queue during the call
+    #[cfg(all(target_arch = "aarch64", not(test)))]
+    unsafe {
+        asm!(
+            "hvc #0",
+            in("x8") HyperCall::BlockNotify as u64,
+            options(nostack)
+        );
+    }
+
+    #[cfg(all(target_arch = "x86_64", not(test)))]
+    unsafe {
+        asm!(
+            "out dx, al",
+            in("dx") 0x500u16,
+            in("al") HyperCall::BlockNotify as u8,
+            options(nostack)
+        );
+    }
+}
+
 /// Helper to get dynamic FB address
 pub fn get_fb_addr() -> usize {
     unsafe { BASE_ADDRESS + (FB_ADDR as usize) }
@@ -119,21 +231,26 @@ pub fn fill_screen(r: u8, g: u8, b: u8) {
 // --- Heap Allocation ---
 use linked_list_allocator::LockedHeap;
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 pub static ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 extern "C" {
     static _heap_start: usize;
 }
 
+/// Most the guest heap can hold; it starts at `_heap_start` (end of .bss)
+/// and ends before the device queues at `QUEUE_WINDOW`
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
 pub fn init_heap() {
     unsafe {
         let heap_start = &_heap_start as *const usize as usize;
         // Adjusted for UEFI Relocatable Execution:
-        // Use fixed 4MB heap instead of hardcoded 0x7FF000 limit.
-        // This ensures we stay within the allocated RAM block (assuming it's > 4MB + code).
-        let heap_size = 4 * 1024 * 1024; 
-        ALLOCATOR.lock().init(heap_start as *mut u8, heap_size);
+        // Use fixed 4MB heap instead of hardcoded 0x7FF000 limit,
+        // cut short if the image is big enough to reach the queues.
+        let queues = BASE_ADDRESS + aether_abi::mmio::QUEUE_WINDOW;
+        let size = HEAP_SIZE.min(queues.saturating_sub(heap_start));
+        ALLOCATOR.lock().init(heap_start as *mut u8, size);
     }
 }
 
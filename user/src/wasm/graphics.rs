@@ -0,0 +1,245 @@
+/// `aether_graphics` and `aether_input` - drawing to the screen and reading
+/// the keyboard and pointer
+///
+/// Drawing goes straight to the display canvas, clipped to it; there's no
+/// back buffer to spare on a 4 MiB heap. `present` ends a frame by waiting
+/// until a display refresh has passed since the last one. Values crossing
+/// the boundary are defined in `aether_wasm_sdk::abi`, next to the
+/// guest-side bindings.
+use alloc::format;
+
+use aether_wasm_sdk::abi::*;
+use wasmi::core::Trap;
+use wasmi::errors::LinkerError;
+use wasmi::{Caller, Linker};
+
+use super::{memory, slice, Host};
+use crate::gfx::{BlitMode, Canvas, Painter, Rect, Surface};
+
+/// One refresh at 60 Hz
+const FRAME_NS: u64 = 1_000_000_000 / 60;
+
+impl Host {
+    fn painter(&mut self) -> Option<Painter<'_, dyn Canvas>> {
+        self.drew = true;
+        self.display.as_deref_mut().map(Painter::new)
+    }
+
+    fn screen_size(&self) -> (i32, i32) {
+        self.display.as_ref().map_or((0, 0), |d| (d.width() as i32, d.height() as i32))
+    }
+
+    /// The on-screen part of a `w` x `h` rectangle at (x, y). Modules can
+    /// pass anything, so it's cut down before any `Rect` is built.
+    fn on_screen(&self, x: i32, y: i32, w: i32, h: i32) -> Option<Rect> {
+        let (width, height) = self.screen_size();
+        let (x0, y0) = ((x as i64).max(0), (y as i64).max(0));
+        let x1 = (x as i64 + w.max(0) as i64).min(width as i64);
+        let y1 = (y as i64 + h.max(0) as i64).min(height as i64);
+        (x1 > x0 && y1 > y0).then(|| Rect::new(x0 as i32, y0 as i32, (x1 - x0) as u32, (y1 - y0) as u32))
+    }
+
+    fn clear(&mut self, color: u32) {
+        if let Some(mut p) = self.painter() {
+            p.clear(color);
+        }
+    }
+
+    fn pixel(&mut self, x: i32, y: i32, color: u32) {
+        if let Some(mut p) = self.painter() {
+            p.pixel(x, y, color);
+        }
+    }
+
+    fn fill_rect(&mut self, x: i32, y: i32, w: i32, h: i32, color: u32) {
+        let area = self.on_screen(x, y, w, h);
+        if let (Some(mut p), Some(area)) = (self.painter(), area) {
+            p.fill_rect(area, color);
+        }
+    }
+
+    /// Draw the `w` x `h` image at `ptr` with its top-left corner at (x, y)
+    #[allow(clippy::too_many_arguments)]
+    fn blit(&mut self, mem: &[u8], ptr: i32, w: i32, h: i32, x: i32, y: i32, mode: i32, key: u32) -> Result<(), Trap> {
+        let mode = match mode {
+            BLIT_COPY => BlitMode::Copy,
+            BLIT_ALPHA => BlitMode::Alpha,
+            BLIT_COLOR_KEY => BlitMode::ColorKey(key),
+            _ => return Err(Trap::new(format!("unknown blit mode {}", mode))),
+        };
+        let (w, h) = (w.max(0) as usize, h.max(0) as usize);
+        let len = w
+            .checked_mul(h)
+            .and_then(|n| n.checked_mul(4))
+            .and_then(|n| i32::try_from(n).ok())
+            .ok_or_else(|| Trap::new(format!("{}x{} image is too large", w, h)))?;
+        let pixels = slice(mem, ptr, len)?;
+        let area = self.on_screen(x, y, w as i32, h as i32);
+        let (Some(mut p), Some(area)) = (self.painter(), area) else { return Ok(()) };
+
+        // Linear memory needn't be aligned for `Color`, so copy a row at a
+        // time, skipping rows that are off screen
+        let mut row = Surface::new(w, 1);
+        for (r, src) in pixels.chunks_exact(4 * w.max(1)).enumerate().take(h) {
+            let dy = y as i64 + r as i64;
+            if dy < area.y as i64 || dy >= area.bottom() as i64 {
+                continue;
+            }
+            for (d, s) in row.pixels_mut().iter_mut().zip(src.chunks_exact(4)) {
+                *d = u32::from_le_bytes([s[0], s[1], s[2], s[3]]);
+            }
+            p.blit(&row, x, dy as i32, mode);
+        }
+        Ok(())
+    }
+
+    /// Wait out the rest of the frame; no Sleep hypercall yet, so spin
+    fn present(&mut self) {
+        let mut now = crate::get_time_ns();
+        // No clock (an older host, or tests), so no pacing
+        if now == 0 {
+            return;
+        }
+        while now < self.last_frame.saturating_add(FRAME_NS) {
+            now = crate::get_time_ns();
+        }
+        self.last_frame = now;
+    }
+
+    /// Write the next event at `ptr` and return its kind, or `EVENT_NONE`
+    fn poll_event(&mut self, mem: &mut [u8], ptr: i32) -> Result<i32, Trap> {
+        let start = ptr as u32 as usize;
+        let record = start
+            .checked_add(EVENT_SIZE)
+            .and_then(|end| mem.get_mut(start..end))
+            .ok_or_else(|| Trap::new(format!("event record at {:#x} is out of bounds", start)))?;
+        let Some(event) = (self.events)() else { return Ok(EVENT_NONE) };
+        let encoded = event.encode();
+        record.copy_from_slice(&encoded);
+        Ok(decode_event(&encoded).0)
+    }
+}
+
+pub(super) fn link(linker: &mut Linker<Host>) -> Result<(), LinkerError> {
+    type C<'a> = Caller<'a, Host>;
+
+    linker.func_wrap(GRAPHICS_MODULE, "width", |c: C| c.data().screen_size().0)?;
+    linker.func_wrap(GRAPHICS_MODULE, "height", |c: C| c.data().screen_size().1)?;
+    linker.func_wrap(GRAPHICS_MODULE, "clear", |mut c: C, color: i32| c.data_mut().clear(color as u32))?;
+    linker.func_wrap(GRAPHICS_MODULE, "pixel", |mut c: C, x: i32, y: i32, color: i32| {
+        c.data_mut().pixel(x, y, color as u32)
+    })?;
+    linker.func_wrap(GRAPHICS_MODULE, "fill_rect", |mut c: C, x: i32, y: i32, w: i32, h: i32, color: i32| {
+        c.data_mut().fill_rect(x, y, w, h, color as u32)
+    })?;
+    linker.func_wrap(
+        GRAPHICS_MODULE,
+        "blit",
+        |mut c: C, ptr: i32, w: i32, h: i32, x: i32, y: i32, mode: i32, key: i32| -> Result<(), Trap> {
+            let (mem, host) = memory(&c)?.data_and_store_mut(&mut c);
+            host.blit(mem, ptr, w, h, x, y, mode, key as u32)
+        },
+    )?;
+    linker.func_wrap(GRAPHICS_MODULE, "present", |mut c: C| c.data_mut().present())?;
+
+    linker.func_wrap(INPUT_MODULE, "poll_event", |mut c: C, ptr: i32| -> Result<i32, Trap> {
+        let (mem, host) = memory(&c)?.data_and_store_mut(&mut c);
+        host.poll_event(mem, ptr)
+    })?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+    use crate::gfx::{BLACK, WHITE};
+    use crate::input::Event;
+
+    fn host() -> Host {
+        Host::with_console(|_| {}).display(Surface::filled(8, 4, BLACK))
+    }
+
+    fn row(host: &Host, y: usize) -> Vec<u32> {
+        host.display.as_ref().unwrap().row(y).to_vec()
+    }
+
+    #[test]
+    fn draws_clipped_to_the_screen() {
+        let mut host = host();
+        assert_eq!(host.screen_size(), (8, 4));
+        assert!(!host.used_display());
+        host.fill_rect(6, 2, 10, 10, WHITE);
+        host.pixel(0, 0, 0xFF112233);
+        host.pixel(-1, 9, WHITE);
+        host.fill_rect(0, 0, -3, 2, WHITE);
+        assert!(host.used_display());
+        assert_eq!(row(&host, 0), [0xFF112233, BLACK, BLACK, BLACK, BLACK, BLACK, BLACK, BLACK]);
+        assert_eq!(row(&host, 3)[5..], [BLACK, WHITE, WHITE]);
+
+        // Without a display everything is a no-op
+        let mut headless = Host::with_console(|_| {});
+        assert_eq!(headless.screen_size(), (0, 0));
+        headless.clear(WHITE);
+        headless.present();
+    }
+
+    #[test]
+    fn blits_from_linear_memory() {
+        let mut host = host();
+        // A 2x2 image at an odd address; the transparent pixel is keyed out
+        let pixels = [0xFF0000FFu32, 0x00000000, 0xFF00FF00, 0xFFFF0000];
+        let mut mem = vec![0u8; 1];
+        mem.extend(pixels.iter().flat_map(|p| p.to_le_bytes()));
+        host.blit(&mem, 1, 2, 2, 7, 2, BLIT_COLOR_KEY, 0).unwrap();
+        assert_eq!(row(&host, 2)[6..], [BLACK, 0xFF0000FF]);
+        assert_eq!(row(&host, 3)[6..], [BLACK, 0xFF00FF00]);
+
+        host.blit(&mem, 1, 2, 2, -1, -1, BLIT_COPY, 0).unwrap();
+        assert_eq!(row(&host, 0)[..2], [0xFFFF0000, BLACK]);
+
+        assert!(host.blit(&mem, 1, 2, 3, 0, 0, BLIT_COPY, 0).is_err());
+        assert!(host.blit(&mem, 1, 2, 2, 0, 0, 9, 0).is_err());
+        assert!(host.blit(&mem, 0, 0x10000, 0x10000, 0, 0, BLIT_COPY, 0).is_err());
+    }
+
+    #[test]
+    fn far_off_origins_draw_nothing() {
+        let mut host = host();
+        let mem: Vec<u8> = [WHITE; 4].iter().flat_map(|p| p.to_le_bytes()).collect();
+        for (x, y) in [(i32::MAX, 0), (0, i32::MAX), (i32::MAX - 2, i32::MAX - 2), (i32::MIN, 0), (0, i32::MIN)] {
+            host.fill_rect(x, y, 10, 10, WHITE);
+            host.fill_rect(x, y, i32::MAX, i32::MAX, WHITE);
+            host.pixel(x, y, WHITE);
+            host.blit(&mem, 0, 2, 2, x, y, BLIT_COPY, 0).unwrap();
+            host.blit(&mem, 0, 2, 2, x, y, BLIT_ALPHA, 0).unwrap();
+        }
+        assert!((0..4).all(|y| row(&host, y).iter().all(|&p| p == BLACK)));
+
+        // The widest rectangle from i32::MIN ends just short of the screen;
+        // one from less far off reaches across it
+        host.fill_rect(i32::MIN, 1, i32::MAX, 1, WHITE);
+        assert!(row(&host, 1).iter().all(|&p| p == BLACK));
+        host.fill_rect(-(i32::MAX / 2), 3, i32::MAX, 1, WHITE);
+        assert!(row(&host, 3).iter().all(|&p| p == WHITE));
+    }
+
+    #[test]
+    fn events() {
+        let mut queue = vec![
+            Event::PointerDown { x: 3, y: -1, button: BUTTON_RIGHT },
+            Event::Key('é'),
+        ];
+        let mut host = host().events(move || queue.pop());
+        let mut mem = [0u8; 20];
+        assert!(host.poll_event(&mut mem, 8).is_err());
+
+        assert_eq!(host.poll_event(&mut mem, 4).unwrap(), EVENT_KEY);
+        assert_eq!(mem[4..8], 1u32.to_le_bytes());
+        assert_eq!(mem[16..20], ('é' as u32).to_le_bytes());
+        assert_eq!(host.poll_event(&mut mem, 0).unwrap(), EVENT_POINTER_DOWN);
+        let fields: Vec<u32> = mem[..16].chunks(4).map(|b| u32::from_le_bytes(b.try_into().unwrap())).collect();
+        assert_eq!(fields, [EVENT_POINTER_DOWN as u32, 3, -1i32 as u32, BUTTON_RIGHT]);
+        assert_eq!(host.poll_event(&mut mem, 0).unwrap(), EVENT_NONE);
+    }
+}
@@ -0,0 +1,274 @@
+/// WebAssembly apps - load a module, link the AetherOS host imports and
+/// run its entry point
+///
+/// Modules run in `wasmi`, an interpreter, so anything compiled to
+/// `wasm32` can be copied onto the disk and run from the shell without
+/// rebuilding the guest. These host imports are linked:
+///
+/// - `env.print(ptr: i32, len: i32)` writes the bytes at `ptr` and a
+///   newline to the console, and `env.exit(code: i32)` ends the program
+/// - `wasi_snapshot_preview1`, so `wasm32-wasip1` binaries built by Rust,
+///   C or Go run unchanged (see `wasi`)
+/// - `aether_graphics` and `aether_input`, for drawing to the screen and
+///   polling the keyboard and pointer (see `graphics`; Rust apps use the
+///   `aether-wasm-sdk` crate)
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use wasmi::core::Trap;
+use wasmi::errors::LinkerError;
+use wasmi::{Caller, Engine, Extern, Func, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, Value};
+
+use crate::fs::Vfs;
+use crate::gfx::{Canvas, Framebuffer};
+use crate::input::Event;
+
+mod graphics;
+mod wasi;
+
+#[cfg(test)]
+mod tests;
+
+/// Largest linear memory a module may have; the whole guest heap is 4 MiB
+pub const MAX_MEMORY: usize = 2 * 1024 * 1024;
+
+/// Exports tried as the entry point, in order
+const ENTRY_POINTS: [&str; 2] = ["_start", "run"];
+
+/// Why a module didn't run to completion
+#[derive(Debug)]
+pub enum WasmError {
+    /// Not a valid module
+    Load(wasmi::Error),
+    /// An import we don't provide, too much memory, or a trap in the start
+    /// function
+    Instantiate(wasmi::Error),
+    /// No `_start` or `run` export taking no arguments
+    NoEntryPoint,
+    /// The program trapped: an `unreachable`, an out of bounds access, a
+    /// division by zero, a stack overflow or a failed host call
+    Trap(wasmi::Error),
+}
+
+impl fmt::Display for WasmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WasmError::Load(e) => write!(f, "invalid module: {}", e),
+            WasmError::Instantiate(e) => write!(f, "can't instantiate: {}", e),
+            WasmError::NoEntryPoint => f.write_str("no _start or run export"),
+            WasmError::Trap(e) => write!(f, "trap: {}", e),
+        }
+    }
+}
+
+/// Where a program's console output goes
+type Output = Box<dyn FnMut(&[u8])>;
+
+/// Waits for the next byte typed at the console; `None` at end of input
+type Input = Box<dyn FnMut() -> Option<u8>>;
+
+/// Returns the next pending input event without waiting
+type Events = Box<dyn FnMut() -> Option<Event>>;
+
+/// State the host imports work on, kept in the module's `Store`
+pub struct Host {
+    console: Output,
+    keyboard: Input,
+    limits: StoreLimits,
+    /// Program arguments, the program's own name first
+    args: Vec<String>,
+    /// Environment as `KEY=value` strings
+    env: Vec<String>,
+    /// The filesystem WASI calls see, if the program is given one
+    vfs: Option<Vfs>,
+    /// WASI descriptors, filled in when the program starts
+    fds: Vec<Option<wasi::Handle>>,
+    /// Rest of the last line typed at the console, not yet read
+    line: VecDeque<u8>,
+    /// Where `aether_graphics` draws, if anywhere
+    display: Option<Box<dyn Canvas>>,
+    events: Events,
+    /// Whether the program has drawn on the display
+    drew: bool,
+    /// When `present` last returned, in nanoseconds
+    last_frame: u64,
+}
+
+impl Host {
+    /// Console output and input on the AetherOS console, graphics on the
+    /// framebuffer and events from the keyboard and pointer
+    pub fn new() -> Self {
+        Self::with_console(crate::console::write)
+            .keyboard(|| loop {
+                if let Some(c) = crate::console_getc() {
+                    return Some(c as u8);
+                }
+            })
+            .display(Framebuffer::new())
+            .events(crate::input::poll_event)
+    }
+
+    /// Console output sent to `console` instead, no console input, no
+    /// display and no events
+    pub fn with_console(console: impl FnMut(&[u8]) + 'static) -> Self {
+        Host {
+            console: Box::new(console),
+            keyboard: Box::new(|| None),
+            limits: StoreLimitsBuilder::new().memory_size(MAX_MEMORY).build(),
+            args: Vec::new(),
+            env: Vec::new(),
+            vfs: None,
+            fds: Vec::new(),
+            line: VecDeque::new(),
+            display: None,
+            events: Box::new(|| None),
+            drew: false,
+            last_frame: 0,
+        }
+    }
+
+    /// Read console input from `keyboard`, which blocks for each byte
+    pub fn keyboard(mut self, keyboard: impl FnMut() -> Option<u8> + 'static) -> Self {
+        self.keyboard = Box::new(keyboard);
+        self
+    }
+
+    pub fn args<S: Into<String>>(mut self, args: impl IntoIterator<Item = S>) -> Self {
+        self.args = args.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Environment variables, as `KEY=value`
+    pub fn env<S: Into<String>>(mut self, env: impl IntoIterator<Item = S>) -> Self {
+        self.env = env.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Give WASI programs `vfs`, with `/` and its working directory (as
+    /// `.`) preopened. Get it back with `take_vfs` after the run.
+    pub fn vfs(mut self, vfs: Vfs) -> Self {
+        self.vfs = Some(vfs);
+        self
+    }
+
+    pub fn take_vfs(&mut self) -> Option<Vfs> {
+        self.vfs.take()
+    }
+
+    /// Draw `aether_graphics` calls on `display`
+    pub fn display(mut self, display: impl Canvas + 'static) -> Self {
+        self.display = Some(Box::new(display));
+        self
+    }
+
+    /// Take `aether_input` events from `events`, which mustn't block
+    pub fn events(mut self, events: impl FnMut() -> Option<Event> + 'static) -> Self {
+        self.events = Box::new(events);
+        self
+    }
+
+    /// Whether the program drew anything, so the screen needs repainting
+    pub fn used_display(&self) -> bool {
+        self.drew
+    }
+}
+
+impl Default for Host {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Run a module with the AetherOS console and no filesystem, returning its
+/// exit code
+pub fn run(bytes: &[u8]) -> Result<i32, WasmError> {
+    run_with(bytes, &mut Host::new())
+}
+
+/// Run a module against `host`, returning its exit code: the value `run`
+/// returned, the code passed to `exit` or `proc_exit`, or 0. Files the
+/// program leaves open are closed.
+pub fn run_with(bytes: &[u8], host: &mut Host) -> Result<i32, WasmError> {
+    let engine = Engine::default();
+    let mut store = Store::new(&engine, core::mem::take(host));
+    store.limiter(|host| &mut host.limits);
+    store.data_mut().open_std();
+
+    let result = instantiate_and_run(&engine, &mut store, bytes);
+    *host = store.into_data();
+    host.close_all();
+    result
+}
+
+fn instantiate_and_run(engine: &Engine, store: &mut Store<Host>, bytes: &[u8]) -> Result<i32, WasmError> {
+    let module = Module::new(engine, bytes).map_err(WasmError::Load)?;
+    let mut linker = <Linker<Host>>::new(engine);
+    // Only fails for duplicate definitions
+    link_env(&mut linker)
+        .and_then(|()| wasi::link(&mut linker))
+        .and_then(|()| graphics::link(&mut linker))
+        .expect("host imports");
+    let instance = match linker.instantiate(&mut *store, &module).and_then(|pre| pre.start(&mut *store)) {
+        Ok(instance) => instance,
+        Err(e) => return exit_code(e).map_err(WasmError::Instantiate),
+    };
+
+    let entry = ENTRY_POINTS
+        .iter()
+        .filter_map(|name| instance.get_func(&*store, name))
+        .find(|func| is_entry_point(store, func))
+        .ok_or(WasmError::NoEntryPoint)?;
+    let mut results = [Value::I32(0)];
+    let results = &mut results[..entry.ty(&*store).results().len()];
+    match entry.call(&mut *store, &[], results) {
+        Ok(()) => Ok(results.first().and_then(Value::i32).unwrap_or(0)),
+        Err(e) => exit_code(e).map_err(WasmError::Trap),
+    }
+}
+
+/// No parameters, and nothing or an exit code back
+fn is_entry_point(store: &Store<Host>, func: &Func) -> bool {
+    let ty = func.ty(store);
+    ty.params().is_empty() && matches!(ty.results(), [] | [wasmi::core::ValueType::I32])
+}
+
+/// The code of an `exit`, which unwinds as a trap; other errors stay errors
+fn exit_code(error: wasmi::Error) -> Result<i32, wasmi::Error> {
+    match &error {
+        wasmi::Error::Trap(trap) => trap.i32_exit_status().ok_or(error),
+        _ => Err(error),
+    }
+}
+
+fn link_env(linker: &mut Linker<Host>) -> Result<(), LinkerError> {
+    linker.func_wrap("env", "print", |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), Trap> {
+        let (mem, host) = memory(&caller)?.data_and_store_mut(&mut caller);
+        let text = slice(mem, ptr, len)?;
+        (host.console)(text);
+        (host.console)(b"\n");
+        Ok(())
+    })?;
+    linker.func_wrap("env", "exit", |code: i32| -> Result<(), Trap> { Err(Trap::i32_exit(code)) })?;
+    Ok(())
+}
+
+/// The caller's exported linear memory
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, Trap> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Trap::new("module exports no memory"))
+}
+
+/// `len` bytes at `ptr` in linear memory, both taken as unsigned
+fn slice(mem: &[u8], ptr: i32, len: i32) -> Result<&[u8], Trap> {
+    let start = ptr as u32 as usize;
+    start
+        .checked_add(len as u32 as usize)
+        .and_then(|end| mem.get(start..end))
+        .ok_or_else(|| Trap::new(format!("{} bytes at {:#x} are out of bounds", len as u32, start)))
+}
@@ -0,0 +1,177 @@
+//! Running hand-assembled modules against a captured console
+use super::*;
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+
+const UNREACHABLE: u8 = 0x00;
+const CALL: u8 = 0x10;
+const I32_CONST: u8 = 0x41;
+const I32: u8 = 0x7F;
+
+fn leb(mut n: u32, out: &mut Vec<u8>) {
+    loop {
+        let byte = (n & 0x7F) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn section(id: u8, count: u32, items: &[u8], out: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    leb(count, &mut body);
+    body.extend_from_slice(items);
+    out.push(id);
+    leb(body.len() as u32, out);
+    out.extend(body);
+}
+
+fn name(s: &str) -> Vec<u8> {
+    let mut out = vec![s.len() as u8];
+    out.extend_from_slice(s.as_bytes());
+    out
+}
+
+/// `i32.const n` for small non-negative `n`
+fn i32_const(n: u8) -> [u8; 2] {
+    assert!(n < 64);
+    [I32_CONST, n]
+}
+
+/// A module importing `env.print` (function 0) and `env.exit` (function 1),
+/// with `pages` of memory holding `data` at 0, and exporting `export` as a
+/// function of no parameters returning `results` that runs `code`
+fn module(export: &str, results: &[u8], code: &[u8], pages: u8, data: &[u8]) -> Vec<u8> {
+    let imports = [("env", "print", &[I32, I32][..], &[][..]), ("env", "exit", &[I32], &[])];
+    module_importing(&imports, export, results, code, pages, data)
+}
+
+/// As `module`, with `imports` (module, name, parameters, results) as
+/// functions 0, 1, ...
+fn module_importing(
+    imports: &[(&str, &str, &[u8], &[u8])],
+    export: &str,
+    results: &[u8],
+    code: &[u8],
+    pages: u8,
+    data: &[u8],
+) -> Vec<u8> {
+    let mut m = b"\0asm\x01\0\0\0".to_vec();
+    // One type per import, then the export's
+    let mut types = Vec::new();
+    for (params, res) in imports.iter().map(|i| (i.2, i.3)).chain([(&[][..], results)]) {
+        types.extend([0x60, params.len() as u8]);
+        types.extend_from_slice(params);
+        types.push(res.len() as u8);
+        types.extend_from_slice(res);
+    }
+    section(1, imports.len() as u32 + 1, &types, &mut m);
+    let mut entries = Vec::new();
+    for (i, (module, field, _, _)) in imports.iter().enumerate() {
+        entries.extend([name(module), name(field), vec![0, i as u8]].concat());
+    }
+    section(2, imports.len() as u32, &entries, &mut m);
+    section(3, 1, &[imports.len() as u8], &mut m);
+    section(5, 1, &[0, pages], &mut m);
+    let exports = [name("memory"), vec![2, 0], name(export), vec![0, imports.len() as u8]].concat();
+    section(7, 2, &exports, &mut m);
+    let body = [&[0u8][..], code, &[0x0B]].concat();
+    let mut func = Vec::new();
+    leb(body.len() as u32, &mut func);
+    func.extend(body);
+    section(10, 1, &func, &mut m);
+    let mut segment = vec![0, I32_CONST, 0, 0x0B];
+    leb(data.len() as u32, &mut segment);
+    segment.extend_from_slice(data);
+    section(11, 1, &segment, &mut m);
+    m
+}
+
+/// Run `bytes`, returning the result and everything printed
+fn run_captured(bytes: &[u8]) -> (Result<i32, WasmError>, Vec<u8>) {
+    let out = Rc::new(RefCell::new(Vec::new()));
+    let sink = out.clone();
+    let result = run_with(bytes, &mut Host::with_console(move |b| sink.borrow_mut().extend_from_slice(b)));
+    let printed = out.borrow().clone();
+    (result, printed)
+}
+
+#[test]
+fn prints_and_returns_exit_code() {
+    let code = [&i32_const(0)[..], &i32_const(5), &[CALL, 0], &i32_const(7)].concat();
+    let (result, out) = run_captured(&module("run", &[I32], &code, 1, b"Hello"));
+    assert_eq!(result.unwrap(), 7);
+    assert_eq!(out, b"Hello\n");
+
+    // `_start` returning nothing exits with 0
+    let (result, _) = run_captured(&module("_start", &[], &[], 1, b""));
+    assert_eq!(result.unwrap(), 0);
+}
+
+#[test]
+fn exit_unwinds() {
+    let code = [&i32_const(3)[..], &[CALL, 1, UNREACHABLE]].concat();
+    let (result, _) = run_captured(&module("_start", &[], &code, 1, b""));
+    assert_eq!(result.unwrap(), 3);
+}
+
+#[test]
+fn traps_are_reported() {
+    let (result, _) = run_captured(&module("_start", &[], &[UNREACHABLE], 1, b""));
+    assert!(matches!(result, Err(WasmError::Trap(_))));
+
+    // A print running off the end of memory traps instead of reading past it
+    let code = [&[I32_CONST, 0xF0, 0xFF, 0x03][..], &i32_const(32), &[CALL, 0]].concat();
+    let (result, out) = run_captured(&module("_start", &[], &code, 1, b""));
+    let message = format!("{}", result.unwrap_err());
+    assert!(message.contains("out of bounds"), "{}", message);
+    assert!(out.is_empty());
+}
+
+#[test]
+fn bad_modules() {
+    let (result, _) = run_captured(b"\0asm\x01\0\0\0\x01");
+    assert!(matches!(result, Err(WasmError::Load(_))));
+    let (result, _) = run_captured(&module("main", &[], &[], 1, b""));
+    assert!(matches!(result, Err(WasmError::NoEntryPoint)));
+    // 64 pages is 4 MiB, over MAX_MEMORY
+    let (result, _) = run_captured(&module("_start", &[], &[], 64, b""));
+    assert!(matches!(result, Err(WasmError::Instantiate(_))));
+}
+
+#[test]
+fn wasi_command() {
+    // fd_write(1, [iovec at 16], 1, &written at 24); proc_exit(written)
+    let imports = [
+        ("wasi_snapshot_preview1", "fd_write", &[I32, I32, I32, I32][..], &[I32][..]),
+        ("wasi_snapshot_preview1", "proc_exit", &[I32], &[]),
+    ];
+    let code = [
+        &i32_const(1)[..], &i32_const(16), &i32_const(1), &i32_const(24), &[CALL, 0, 0x1A],
+        &i32_const(24), &[0x28, 2, 0], &[CALL, 1],
+    ]
+    .concat();
+    let data = [&b"hi wasi\n"[..], &[0; 8], &[0, 0, 0, 0, 8, 0, 0, 0]].concat();
+    let (result, out) = run_captured(&module_importing(&imports, "_start", &[], &code, 1, &data));
+    assert_eq!(result.unwrap(), 8);
+    assert_eq!(out, b"hi wasi\n");
+}
+
+#[test]
+fn graphics_imports() {
+    // pixel(1, 0, width()); return height()
+    let imports = [
+        ("aether_graphics", "width", &[][..], &[I32][..]),
+        ("aether_graphics", "height", &[], &[I32]),
+        ("aether_graphics", "pixel", &[I32, I32, I32], &[]),
+    ];
+    let code = [&i32_const(1)[..], &i32_const(0), &[CALL, 0, CALL, 2, CALL, 1]].concat();
+    let bytes = module_importing(&imports, "run", &[I32], &code, 1, b"");
+    let mut host = Host::with_console(|_| {}).display(crate::gfx::Surface::new(3, 2));
+    assert_eq!(run_with(&bytes, &mut host).unwrap(), 2);
+    assert!(host.used_display());
+}
@@ -0,0 +1,845 @@
+/// WASI preview1 (`wasi_snapshot_preview1`) on top of the AetherOS SDK
+///
+/// Descriptors 0-2 are the console. With a filesystem, 3 is `/` and 4 is
+/// the working directory the program was started from, preopened as `.`;
+/// paths resolve through the `Vfs`, so every mount is visible. Console
+/// input is line buffered and echoed, with backspace and Ctrl-D for end of
+/// input.
+///
+/// Calls work on guest memory as a byte slice and answer with an errno.
+/// Sockets, `poll_oneoff`, `fd_pread`/`fd_pwrite` and setting times or
+/// flags aren't provided: they link, so programs that merely mention them
+/// load, but return `ENOSYS`.
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use wasmi::core::{Trap, ValueType};
+use wasmi::errors::LinkerError;
+use wasmi::{Caller, FuncType, Linker, Value};
+
+use super::{memory, Host};
+use crate::fs::{Fd, FileType, FsError, Metadata, OpenFlags, SeekFrom};
+
+const MODULE: &str = "wasi_snapshot_preview1";
+
+/// A WASI error number
+pub(super) type Errno = u16;
+
+const ESUCCESS: Errno = 0;
+const EACCES: Errno = 2;
+const EBADF: Errno = 8;
+const EBUSY: Errno = 10;
+const EEXIST: Errno = 20;
+const EFAULT: Errno = 21;
+const EFBIG: Errno = 22;
+const EINVAL: Errno = 28;
+const EIO: Errno = 29;
+const EISDIR: Errno = 31;
+const ELOOP: Errno = 32;
+const ENAMETOOLONG: Errno = 37;
+const ENOENT: Errno = 44;
+const ENOSPC: Errno = 51;
+const ENOSYS: Errno = 52;
+const ENOTDIR: Errno = 54;
+const ENOTEMPTY: Errno = 55;
+const ENOTSUP: Errno = 58;
+const EROFS: Errno = 69;
+const ESPIPE: Errno = 70;
+const EXDEV: Errno = 75;
+
+// File types, as in `filestat` and `dirent`
+const FILETYPE_UNKNOWN: u8 = 0;
+const FILETYPE_BLOCK_DEVICE: u8 = 1;
+const FILETYPE_CHARACTER_DEVICE: u8 = 2;
+const FILETYPE_DIRECTORY: u8 = 3;
+const FILETYPE_REGULAR_FILE: u8 = 4;
+const FILETYPE_SOCKET_STREAM: u8 = 6;
+const FILETYPE_SYMBOLIC_LINK: u8 = 7;
+
+const RIGHT_FD_READ: u64 = 1 << 1;
+const RIGHT_FD_WRITE: u64 = 1 << 6;
+/// Every right preview1 defines; we don't restrict descriptors
+const RIGHTS_ALL: u64 = (1 << 29) - 1;
+
+const OFLAGS_CREAT: u16 = 1 << 0;
+const OFLAGS_DIRECTORY: u16 = 1 << 1;
+const OFLAGS_EXCL: u16 = 1 << 2;
+const OFLAGS_TRUNC: u16 = 1 << 3;
+const FDFLAGS_APPEND: u16 = 1 << 0;
+const LOOKUP_SYMLINK_FOLLOW: u32 = 1 << 0;
+
+const WHENCE_SET: u8 = 0;
+const WHENCE_CUR: u8 = 1;
+const WHENCE_END: u8 = 2;
+
+const CLOCK_THREAD_CPUTIME_ID: u32 = 3;
+
+/// Size of a `dirent` header, before the name
+const DIRENT_SIZE: usize = 24;
+
+/// Ctrl-D, end of console input
+const EOT: u8 = 0x04;
+
+/// What a WASI descriptor refers to
+pub(super) enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    /// A directory, by absolute path; preopened ones have the name the
+    /// program knows them by
+    Dir { path: String, preopen: Option<&'static str> },
+    File(Fd),
+}
+
+fn errno(e: FsError) -> Errno {
+    match e {
+        FsError::NotFound => ENOENT,
+        FsError::NotADirectory => ENOTDIR,
+        FsError::IsADirectory => EISDIR,
+        FsError::TooLarge => EFBIG,
+        FsError::NoSpace => ENOSPC,
+        FsError::AlreadyExists => EEXIST,
+        FsError::DirectoryNotEmpty => ENOTEMPTY,
+        FsError::NameTooLong => ENAMETOOLONG,
+        FsError::InvalidPath | FsError::NotASymlink | FsError::InvalidArgument => EINVAL,
+        FsError::TooManyLinks => ELOOP,
+        FsError::ReadOnly => EROFS,
+        FsError::PermissionDenied => EACCES,
+        FsError::BadFileDescriptor => EBADF,
+        FsError::Busy => EBUSY,
+        FsError::CrossDevice => EXDEV,
+        FsError::Unsupported(_) => ENOTSUP,
+        FsError::Io | FsError::Corrupt(_) => EIO,
+    }
+}
+
+fn filetype(t: FileType) -> u8 {
+    match t {
+        FileType::Regular => FILETYPE_REGULAR_FILE,
+        FileType::Directory => FILETYPE_DIRECTORY,
+        FileType::Symlink => FILETYPE_SYMBOLIC_LINK,
+        FileType::CharDevice => FILETYPE_CHARACTER_DEVICE,
+        FileType::BlockDevice => FILETYPE_BLOCK_DEVICE,
+        FileType::Socket => FILETYPE_SOCKET_STREAM,
+        FileType::Fifo | FileType::Unknown => FILETYPE_UNKNOWN,
+    }
+}
+
+// --- Guest memory ---
+
+/// `len` bytes at `ptr`, or `EFAULT` if they're out of bounds
+fn bytes(mem: &[u8], ptr: u32, len: u32) -> Result<&[u8], Errno> {
+    let start = ptr as usize;
+    mem.get(start..start + len as usize).ok_or(EFAULT)
+}
+
+fn bytes_mut(mem: &mut [u8], ptr: u32, len: u32) -> Result<&mut [u8], Errno> {
+    let start = ptr as usize;
+    mem.get_mut(start..start + len as usize).ok_or(EFAULT)
+}
+
+fn get_u32(mem: &[u8], ptr: u32) -> Result<u32, Errno> {
+    Ok(u32::from_le_bytes(bytes(mem, ptr, 4)?.try_into().unwrap()))
+}
+
+fn put(mem: &mut [u8], ptr: u32, data: &[u8]) -> Result<(), Errno> {
+    bytes_mut(mem, ptr, data.len() as u32)?.copy_from_slice(data);
+    Ok(())
+}
+
+fn put_u32(mem: &mut [u8], ptr: u32, v: u32) -> Result<(), Errno> {
+    put(mem, ptr, &v.to_le_bytes())
+}
+
+fn put_u64(mem: &mut [u8], ptr: u32, v: u64) -> Result<(), Errno> {
+    put(mem, ptr, &v.to_le_bytes())
+}
+
+/// The `(buf, len)` pairs of an iovec array
+fn iovecs(mem: &[u8], ptr: u32, count: u32) -> Result<Vec<(u32, u32)>, Errno> {
+    let raw = bytes(mem, ptr, count.checked_mul(8).ok_or(EFAULT)?)?;
+    raw.chunks_exact(8)
+        .map(|iov| {
+            let (buf, len) = (get_u32(iov, 0)?, get_u32(iov, 4)?);
+            bytes(mem, buf, len)?;
+            Ok((buf, len))
+        })
+        .collect()
+}
+
+/// A `filestat`: dev, ino, filetype, nlink, size, atim, mtim, ctim
+fn put_filestat(mem: &mut [u8], ptr: u32, meta: &Metadata) -> Result<(), Errno> {
+    let mut stat = [0u8; 64];
+    stat[8..16].copy_from_slice(&(meta.inode as u64).to_le_bytes());
+    stat[16] = filetype(meta.file_type);
+    stat[24..32].copy_from_slice(&(meta.links as u64).to_le_bytes());
+    stat[32..40].copy_from_slice(&meta.size.to_le_bytes());
+    for (at, secs) in [(40, meta.atime), (48, meta.mtime), (56, meta.ctime)] {
+        stat[at..at + 8].copy_from_slice(&(secs as u64 * 1_000_000_000).to_le_bytes());
+    }
+    put(mem, ptr, &stat)
+}
+
+/// What `args_sizes_get` and `environ_sizes_get` report: the count, and
+/// the bytes the strings take with their NULs
+fn put_sizes(mem: &mut [u8], strings: &[String], count_ptr: u32, size_ptr: u32) -> Result<(), Errno> {
+    put_u32(mem, count_ptr, strings.len() as u32)?;
+    put_u32(mem, size_ptr, strings.iter().map(|s| s.len() as u32 + 1).sum())
+}
+
+/// Pointers to the strings at `ptrs`, the NUL-terminated strings at `buf`
+fn put_strings(mem: &mut [u8], strings: &[String], mut ptrs: u32, mut buf: u32) -> Result<(), Errno> {
+    for s in strings {
+        put_u32(mem, ptrs, buf)?;
+        put(mem, buf, s.as_bytes())?;
+        put(mem, buf + s.len() as u32, &[0])?;
+        ptrs += 4;
+        buf += s.len() as u32 + 1;
+    }
+    Ok(())
+}
+
+impl Host {
+    /// Descriptors a program starts with
+    pub(super) fn open_std(&mut self) {
+        self.fds = alloc::vec![Some(Handle::Stdin), Some(Handle::Stdout), Some(Handle::Stderr)];
+        if let Some(vfs) = &self.vfs {
+            let cwd = vfs.cwd().to_string();
+            self.fds.push(Some(Handle::Dir { path: "/".to_string(), preopen: Some("/") }));
+            self.fds.push(Some(Handle::Dir { path: cwd, preopen: Some(".") }));
+        }
+    }
+
+    /// Close what the program left open
+    pub(super) fn close_all(&mut self) {
+        for handle in self.fds.drain(..).flatten() {
+            if let (Handle::File(fd), Some(vfs)) = (handle, self.vfs.as_mut()) {
+                let _ = vfs.close(fd);
+            }
+        }
+    }
+
+    fn handle(&self, fd: u32) -> Result<&Handle, Errno> {
+        self.fds.get(fd as usize).and_then(Option::as_ref).ok_or(EBADF)
+    }
+
+    /// The `Vfs` descriptor of an open file
+    fn file(&self, fd: u32) -> Result<Fd, Errno> {
+        match self.handle(fd)? {
+            Handle::File(fd) => Ok(*fd),
+            Handle::Dir { .. } => Err(EISDIR),
+            _ => Err(ESPIPE),
+        }
+    }
+
+    fn insert(&mut self, handle: Handle) -> u32 {
+        match self.fds.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.fds[fd] = Some(handle);
+                fd as u32
+            }
+            None => {
+                self.fds.push(Some(handle));
+                self.fds.len() as u32 - 1
+            }
+        }
+    }
+
+    /// Absolute path of `len` bytes at `ptr`, taken relative to directory `dirfd`
+    fn path(&self, mem: &[u8], dirfd: u32, ptr: u32, len: u32) -> Result<String, Errno> {
+        let Handle::Dir { path: dir, .. } = self.handle(dirfd)? else { return Err(ENOTDIR) };
+        let rel = core::str::from_utf8(bytes(mem, ptr, len)?).map_err(|_| EINVAL)?;
+        let vfs = self.vfs.as_ref().ok_or(EBADF)?;
+        Ok(vfs.absolute(&format!("{}/{}", dir, rel)))
+    }
+
+    /// A directory's `Vfs` path, or `ENOTDIR`
+    fn dir_path(&self, fd: u32) -> Result<&str, Errno> {
+        match self.handle(fd)? {
+            Handle::Dir { path, .. } => Ok(path),
+            _ => Err(ENOTDIR),
+        }
+    }
+
+    // --- Arguments, environment, clocks, randomness ---
+
+    fn args_sizes_get(&mut self, mem: &mut [u8], argc: u32, size: u32) -> Result<(), Errno> {
+        put_sizes(mem, &self.args, argc, size)
+    }
+
+    fn args_get(&mut self, mem: &mut [u8], argv: u32, buf: u32) -> Result<(), Errno> {
+        put_strings(mem, &self.args, argv, buf)
+    }
+
+    fn environ_sizes_get(&mut self, mem: &mut [u8], count: u32, size: u32) -> Result<(), Errno> {
+        put_sizes(mem, &self.env, count, size)
+    }
+
+    fn environ_get(&mut self, mem: &mut [u8], environ: u32, buf: u32) -> Result<(), Errno> {
+        put_strings(mem, &self.env, environ, buf)
+    }
+
+    fn clock_res_get(&mut self, mem: &mut [u8], id: u32, res: u32) -> Result<(), Errno> {
+        if id > CLOCK_THREAD_CPUTIME_ID {
+            return Err(EINVAL);
+        }
+        put_u64(mem, res, 1)
+    }
+
+    /// Every clock reads the host's wall clock, which is all we have
+    fn clock_time_get(&mut self, mem: &mut [u8], id: u32, time: u32) -> Result<(), Errno> {
+        if id > CLOCK_THREAD_CPUTIME_ID {
+            return Err(EINVAL);
+        }
+        put_u64(mem, time, crate::get_time_ns())
+    }
+
+    fn random_get(&mut self, mem: &mut [u8], buf: u32, len: u32) -> Result<(), Errno> {
+        for chunk in bytes_mut(mem, buf, len)?.chunks_mut(8) {
+            chunk.copy_from_slice(&crate::get_random().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(())
+    }
+
+    // --- Descriptors ---
+
+    fn fd_write(&mut self, mem: &mut [u8], fd: u32, iovs: u32, count: u32, written: u32) -> Result<(), Errno> {
+        // `None` for the console
+        let file = match self.handle(fd)? {
+            Handle::Stdout | Handle::Stderr => None,
+            Handle::File(file) => Some(*file),
+            Handle::Dir { .. } => return Err(EISDIR),
+            Handle::Stdin => return Err(EBADF),
+        };
+        let mut total = 0;
+        for (buf, len) in iovecs(mem, iovs, count)? {
+            let data = bytes(mem, buf, len)?;
+            let n = match file {
+                Some(file) => self.vfs.as_mut().ok_or(EBADF)?.write(file, data).map_err(errno)?,
+                None => {
+                    (self.console)(data);
+                    data.len()
+                }
+            };
+            total += n as u32;
+            if n < data.len() {
+                break;
+            }
+        }
+        put_u32(mem, written, total)
+    }
+
+    fn fd_read(&mut self, mem: &mut [u8], fd: u32, iovs: u32, count: u32, read: u32) -> Result<(), Errno> {
+        // `None` for the console
+        let file = match self.handle(fd)? {
+            Handle::Stdin => None,
+            Handle::File(file) => Some(*file),
+            Handle::Dir { .. } => return Err(EISDIR),
+            Handle::Stdout | Handle::Stderr => return Err(EBADF),
+        };
+        let mut total = 0;
+        for (buf, len) in iovecs(mem, iovs, count)? {
+            let dest = bytes_mut(mem, buf, len)?;
+            let n = match file {
+                Some(file) => self.vfs.as_mut().ok_or(EBADF)?.read(file, dest).map_err(errno)?,
+                None => self.read_console(dest, total == 0),
+            };
+            total += n as u32;
+            if n < dest.len() {
+                break;
+            }
+        }
+        put_u32(mem, read, total)
+    }
+
+    /// Copy typed input into `dest`. With `wait`, and nothing buffered,
+    /// read and echo a line first; returns 0 at end of input.
+    fn read_console(&mut self, dest: &mut [u8], wait: bool) -> usize {
+        if self.line.is_empty() && wait && !dest.is_empty() {
+            while let Some(c) = (self.keyboard)().filter(|&c| c != EOT) {
+                match c {
+                    0x08 => {
+                        if self.line.pop_back().is_some() {
+                            (self.console)(&[0x08]);
+                        }
+                    }
+                    _ => {
+                        (self.console)(&[c]);
+                        self.line.push_back(c);
+                        if c == b'\n' {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let n = dest.len().min(self.line.len());
+        for (d, c) in dest.iter_mut().zip(self.line.drain(..n)) {
+            *d = c;
+        }
+        n
+    }
+
+    fn fd_close(&mut self, fd: u32) -> Result<(), Errno> {
+        self.handle(fd)?;
+        if let Some(Handle::File(file)) = self.fds[fd as usize].take() {
+            self.vfs.as_mut().ok_or(EBADF)?.close(file).map_err(errno)?;
+        }
+        Ok(())
+    }
+
+    fn fd_seek(&mut self, mem: &mut [u8], fd: u32, offset: i64, whence: u8, new: u32) -> Result<(), Errno> {
+        let file = self.file(fd)?;
+        let pos = match whence {
+            WHENCE_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| EINVAL)?),
+            WHENCE_CUR => SeekFrom::Current(offset),
+            WHENCE_END => SeekFrom::End(offset),
+            _ => return Err(EINVAL),
+        };
+        let at = self.vfs.as_mut().ok_or(EBADF)?.seek(file, pos).map_err(errno)?;
+        put_u64(mem, new, at)
+    }
+
+    fn fd_tell(&mut self, mem: &mut [u8], fd: u32, at: u32) -> Result<(), Errno> {
+        self.fd_seek(mem, fd, 0, WHENCE_CUR, at)
+    }
+
+    fn fd_sync(&mut self, fd: u32) -> Result<(), Errno> {
+        self.file(fd)?;
+        self.vfs.as_mut().ok_or(EBADF)?.sync().map_err(errno)
+    }
+
+    /// An `fdstat`: filetype, flags, base and inheriting rights
+    fn fd_fdstat_get(&mut self, mem: &mut [u8], fd: u32, stat: u32) -> Result<(), Errno> {
+        // The console has no seek or tell rights, which is what makes
+        // `isatty` true
+        let (kind, rights) = match self.handle(fd)? {
+            Handle::Stdin | Handle::Stdout | Handle::Stderr => {
+                (FILETYPE_CHARACTER_DEVICE, RIGHT_FD_READ | RIGHT_FD_WRITE)
+            }
+            Handle::Dir { .. } => (FILETYPE_DIRECTORY, RIGHTS_ALL),
+            Handle::File(_) => (FILETYPE_REGULAR_FILE, RIGHTS_ALL),
+        };
+        let mut raw = [0u8; 24];
+        raw[0] = kind;
+        raw[8..16].copy_from_slice(&rights.to_le_bytes());
+        raw[16..24].copy_from_slice(&RIGHTS_ALL.to_le_bytes());
+        put(mem, stat, &raw)
+    }
+
+    fn fd_filestat_get(&mut self, mem: &mut [u8], fd: u32, stat: u32) -> Result<(), Errno> {
+        let meta = match self.handle(fd)? {
+            Handle::File(file) => self.vfs.as_ref().ok_or(EBADF)?.fstat(*file),
+            Handle::Dir { path, .. } => self.vfs.as_ref().ok_or(EBADF)?.stat(path),
+            _ => {
+                let mut stat_raw = [0u8; 64];
+                stat_raw[16] = FILETYPE_CHARACTER_DEVICE;
+                return put(mem, stat, &stat_raw);
+            }
+        };
+        put_filestat(mem, stat, &meta.map_err(errno)?)
+    }
+
+    /// Preopened directories answer with the length of their name; the
+    /// rest with `EBADF`, which is how programs find the end of the list
+    fn fd_prestat_get(&mut self, mem: &mut [u8], fd: u32, prestat: u32) -> Result<(), Errno> {
+        let Handle::Dir { preopen: Some(name), .. } = self.handle(fd)? else { return Err(EBADF) };
+        let mut raw = [0u8; 8];
+        raw[4..].copy_from_slice(&(name.len() as u32).to_le_bytes());
+        put(mem, prestat, &raw)
+    }
+
+    fn fd_prestat_dir_name(&mut self, mem: &mut [u8], fd: u32, buf: u32, len: u32) -> Result<(), Errno> {
+        let Handle::Dir { preopen: Some(name), .. } = self.handle(fd)? else { return Err(EBADF) };
+        if (len as usize) < name.len() {
+            return Err(ENAMETOOLONG);
+        }
+        put(mem, buf, name.as_bytes())
+    }
+
+    /// Fill `buf` with `dirent`s from entry `cookie` on. A full buffer
+    /// means there may be more: the last entry may be cut short, and the
+    /// program reads again from its `d_next`.
+    fn fd_readdir(&mut self, mem: &mut [u8], fd: u32, buf: u32, len: u32, cookie: u64, used: u32) -> Result<(), Errno> {
+        let entries = self.vfs.as_ref().ok_or(EBADF)?.read_dir(self.dir_path(fd)?).map_err(errno)?;
+        let out = bytes_mut(mem, buf, len)?;
+        let mut n = 0;
+        let start = usize::try_from(cookie).unwrap_or(usize::MAX);
+        for (i, entry) in entries.iter().enumerate().skip(start) {
+            let mut dirent = [0u8; DIRENT_SIZE];
+            dirent[0..8].copy_from_slice(&(i as u64 + 1).to_le_bytes());
+            dirent[8..16].copy_from_slice(&(entry.inode as u64).to_le_bytes());
+            dirent[16..20].copy_from_slice(&(entry.name.len() as u32).to_le_bytes());
+            dirent[20] = filetype(entry.file_type);
+            for part in [&dirent[..], entry.name.as_bytes()] {
+                let take = part.len().min(out.len() - n);
+                out[n..n + take].copy_from_slice(&part[..take]);
+                n += take;
+            }
+            if n == out.len() {
+                break;
+            }
+        }
+        put_u32(mem, used, n as u32)
+    }
+
+    // --- Paths ---
+
+    #[allow(clippy::too_many_arguments)]
+    fn path_open(
+        &mut self,
+        mem: &mut [u8],
+        dirfd: u32,
+        path: (u32, u32),
+        oflags: u16,
+        rights: u64,
+        fdflags: u16,
+        opened: u32,
+    ) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        let vfs = self.vfs.as_mut().ok_or(EBADF)?;
+        let handle = match vfs.stat(&path) {
+            Ok(_) if oflags & OFLAGS_CREAT != 0 && oflags & OFLAGS_EXCL != 0 => return Err(EEXIST),
+            Ok(meta) if meta.is_dir() => {
+                if oflags & OFLAGS_TRUNC != 0 {
+                    return Err(EISDIR);
+                }
+                Handle::Dir { path, preopen: None }
+            }
+            Ok(_) if oflags & OFLAGS_DIRECTORY != 0 => return Err(ENOTDIR),
+            Err(FsError::NotFound) if oflags & OFLAGS_DIRECTORY != 0 => return Err(ENOENT),
+            _ => {
+                let mut flags = OpenFlags::READ;
+                for (set, flag) in [
+                    (rights & RIGHT_FD_WRITE != 0, OpenFlags::WRITE),
+                    (oflags & OFLAGS_CREAT != 0, OpenFlags::CREATE),
+                    (oflags & OFLAGS_TRUNC != 0, OpenFlags::TRUNCATE),
+                    (fdflags & FDFLAGS_APPEND != 0, OpenFlags::APPEND),
+                ] {
+                    if set {
+                        flags = flags | flag;
+                    }
+                }
+                Handle::File(vfs.open(&path, flags).map_err(errno)?)
+            }
+        };
+        let fd = self.insert(handle);
+        put_u32(mem, opened, fd)
+    }
+
+    fn path_filestat_get(&mut self, mem: &mut [u8], dirfd: u32, flags: u32, path: (u32, u32), stat: u32) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        let vfs = self.vfs.as_ref().ok_or(EBADF)?;
+        let meta = if flags & LOOKUP_SYMLINK_FOLLOW != 0 { vfs.stat(&path) } else { vfs.lstat(&path) };
+        put_filestat(mem, stat, &meta.map_err(errno)?)
+    }
+
+    fn path_readlink(&mut self, mem: &mut [u8], dirfd: u32, path: (u32, u32), buf: u32, len: u32, used: u32) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        let target = self.vfs.as_ref().ok_or(EBADF)?.readlink(&path).map_err(errno)?;
+        // Truncated to fit, as readlink(2) does
+        let n = target.len().min(len as usize);
+        put(mem, buf, &target.as_bytes()[..n])?;
+        put_u32(mem, used, n as u32)
+    }
+
+    fn path_create_directory(&mut self, mem: &mut [u8], dirfd: u32, path: (u32, u32)) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        self.vfs.as_mut().ok_or(EBADF)?.mkdir(&path).map_err(errno)
+    }
+
+    fn path_remove_directory(&mut self, mem: &mut [u8], dirfd: u32, path: (u32, u32)) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        self.vfs.as_mut().ok_or(EBADF)?.rmdir(&path).map_err(errno)
+    }
+
+    fn path_unlink_file(&mut self, mem: &mut [u8], dirfd: u32, path: (u32, u32)) -> Result<(), Errno> {
+        let path = self.path(mem, dirfd, path.0, path.1)?;
+        self.vfs.as_mut().ok_or(EBADF)?.unlink(&path).map_err(errno)
+    }
+
+    fn path_rename(&mut self, mem: &mut [u8], from_dir: u32, from: (u32, u32), to_dir: u32, to: (u32, u32)) -> Result<(), Errno> {
+        let from = self.path(mem, from_dir, from.0, from.1)?;
+        let to = self.path(mem, to_dir, to.0, to.1)?;
+        self.vfs.as_mut().ok_or(EBADF)?.rename(&from, &to).map_err(errno)
+    }
+}
+
+/// Run a call on the caller's memory and host state, answering its errno
+fn call(caller: &mut Caller<'_, Host>, f: impl FnOnce(&mut [u8], &mut Host) -> Result<(), Errno>) -> Result<i32, Trap> {
+    let (mem, host) = memory(caller)?.data_and_store_mut(caller);
+    Ok(f(mem, host).err().unwrap_or(ESUCCESS) as i32)
+}
+
+/// Calls linked only so that programs importing them load, with their
+/// parameter types
+const UNSUPPORTED: [(&str, &[ValueType]); 18] = {
+    use ValueType::{I32, I64};
+    [
+        ("fd_advise", &[I32, I64, I64, I32]),
+        ("fd_allocate", &[I32, I64, I64]),
+        ("fd_fdstat_set_flags", &[I32, I32]),
+        ("fd_fdstat_set_rights", &[I32, I64, I64]),
+        ("fd_filestat_set_size", &[I32, I64]),
+        ("fd_filestat_set_times", &[I32, I64, I64, I32]),
+        ("fd_pread", &[I32, I32, I32, I64, I32]),
+        ("fd_pwrite", &[I32, I32, I32, I64, I32]),
+        ("fd_renumber", &[I32, I32]),
+        ("path_filestat_set_times", &[I32, I32, I32, I32, I64, I64, I32]),
+        ("path_link", &[I32, I32, I32, I32, I32, I32, I32]),
+        ("path_symlink", &[I32, I32, I32, I32, I32]),
+        ("poll_oneoff", &[I32, I32, I32, I32]),
+        ("proc_raise", &[I32]),
+        ("sock_accept", &[I32, I32, I32]),
+        ("sock_recv", &[I32, I32, I32, I32, I32, I32]),
+        ("sock_send", &[I32, I32, I32, I32, I32]),
+        ("sock_shutdown", &[I32, I32]),
+    ]
+};
+
+/// Add every preview1 call to `linker`
+pub(super) fn link(linker: &mut Linker<Host>) -> Result<(), LinkerError> {
+    type C<'a> = Caller<'a, Host>;
+
+    linker.func_wrap(MODULE, "args_sizes_get", |mut c: C, argc: i32, size: i32| {
+        call(&mut c, |mem, host| host.args_sizes_get(mem, argc as u32, size as u32))
+    })?;
+    linker.func_wrap(MODULE, "args_get", |mut c: C, argv: i32, buf: i32| {
+        call(&mut c, |mem, host| host.args_get(mem, argv as u32, buf as u32))
+    })?;
+    linker.func_wrap(MODULE, "environ_sizes_get", |mut c: C, count: i32, size: i32| {
+        call(&mut c, |mem, host| host.environ_sizes_get(mem, count as u32, size as u32))
+    })?;
+    linker.func_wrap(MODULE, "environ_get", |mut c: C, environ: i32, buf: i32| {
+        call(&mut c, |mem, host| host.environ_get(mem, environ as u32, buf as u32))
+    })?;
+    linker.func_wrap(MODULE, "clock_res_get", |mut c: C, id: i32, res: i32| {
+        call(&mut c, |mem, host| host.clock_res_get(mem, id as u32, res as u32))
+    })?;
+    linker.func_wrap(MODULE, "clock_time_get", |mut c: C, id: i32, _precision: i64, time: i32| {
+        call(&mut c, |mem, host| host.clock_time_get(mem, id as u32, time as u32))
+    })?;
+    linker.func_wrap(MODULE, "random_get", |mut c: C, buf: i32, len: i32| {
+        call(&mut c, |mem, host| host.random_get(mem, buf as u32, len as u32))
+    })?;
+    linker.func_wrap(MODULE, "proc_exit", |code: i32| -> Result<(), Trap> { Err(Trap::i32_exit(code)) })?;
+    linker.func_wrap(MODULE, "sched_yield", || ESUCCESS as i32)?;
+
+    linker.func_wrap(MODULE, "fd_write", |mut c: C, fd: i32, iovs: i32, count: i32, written: i32| {
+        call(&mut c, |mem, host| host.fd_write(mem, fd as u32, iovs as u32, count as u32, written as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_read", |mut c: C, fd: i32, iovs: i32, count: i32, read: i32| {
+        call(&mut c, |mem, host| host.fd_read(mem, fd as u32, iovs as u32, count as u32, read as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_close", |mut c: C, fd: i32| call(&mut c, |_, host| host.fd_close(fd as u32)))?;
+    linker.func_wrap(MODULE, "fd_seek", |mut c: C, fd: i32, offset: i64, whence: i32, new: i32| {
+        call(&mut c, |mem, host| host.fd_seek(mem, fd as u32, offset, whence as u8, new as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_tell", |mut c: C, fd: i32, at: i32| {
+        call(&mut c, |mem, host| host.fd_tell(mem, fd as u32, at as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_sync", |mut c: C, fd: i32| call(&mut c, |_, host| host.fd_sync(fd as u32)))?;
+    linker.func_wrap(MODULE, "fd_datasync", |mut c: C, fd: i32| call(&mut c, |_, host| host.fd_sync(fd as u32)))?;
+    linker.func_wrap(MODULE, "fd_fdstat_get", |mut c: C, fd: i32, stat: i32| {
+        call(&mut c, |mem, host| host.fd_fdstat_get(mem, fd as u32, stat as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_filestat_get", |mut c: C, fd: i32, stat: i32| {
+        call(&mut c, |mem, host| host.fd_filestat_get(mem, fd as u32, stat as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_prestat_get", |mut c: C, fd: i32, prestat: i32| {
+        call(&mut c, |mem, host| host.fd_prestat_get(mem, fd as u32, prestat as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_prestat_dir_name", |mut c: C, fd: i32, buf: i32, len: i32| {
+        call(&mut c, |mem, host| host.fd_prestat_dir_name(mem, fd as u32, buf as u32, len as u32))
+    })?;
+    linker.func_wrap(MODULE, "fd_readdir", |mut c: C, fd: i32, buf: i32, len: i32, cookie: i64, used: i32| {
+        call(&mut c, |mem, host| host.fd_readdir(mem, fd as u32, buf as u32, len as u32, cookie as u64, used as u32))
+    })?;
+
+    linker.func_wrap(
+        MODULE,
+        "path_open",
+        |mut c: C, dirfd: i32, _lookup: i32, path: i32, len: i32, oflags: i32, rights: i64, _inheriting: i64, fdflags: i32, opened: i32| {
+            call(&mut c, |mem, host| {
+                let path = (path as u32, len as u32);
+                host.path_open(mem, dirfd as u32, path, oflags as u16, rights as u64, fdflags as u16, opened as u32)
+            })
+        },
+    )?;
+    linker.func_wrap(MODULE, "path_filestat_get", |mut c: C, dirfd: i32, flags: i32, path: i32, len: i32, stat: i32| {
+        call(&mut c, |mem, host| host.path_filestat_get(mem, dirfd as u32, flags as u32, (path as u32, len as u32), stat as u32))
+    })?;
+    linker.func_wrap(MODULE, "path_readlink", |mut c: C, dirfd: i32, path: i32, len: i32, buf: i32, buf_len: i32, used: i32| {
+        call(&mut c, |mem, host| {
+            host.path_readlink(mem, dirfd as u32, (path as u32, len as u32), buf as u32, buf_len as u32, used as u32)
+        })
+    })?;
+    linker.func_wrap(MODULE, "path_create_directory", |mut c: C, dirfd: i32, path: i32, len: i32| {
+        call(&mut c, |mem, host| host.path_create_directory(mem, dirfd as u32, (path as u32, len as u32)))
+    })?;
+    linker.func_wrap(MODULE, "path_remove_directory", |mut c: C, dirfd: i32, path: i32, len: i32| {
+        call(&mut c, |mem, host| host.path_remove_directory(mem, dirfd as u32, (path as u32, len as u32)))
+    })?;
+    linker.func_wrap(MODULE, "path_unlink_file", |mut c: C, dirfd: i32, path: i32, len: i32| {
+        call(&mut c, |mem, host| host.path_unlink_file(mem, dirfd as u32, (path as u32, len as u32)))
+    })?;
+    linker.func_wrap(
+        MODULE,
+        "path_rename",
+        |mut c: C, from_dir: i32, from: i32, from_len: i32, to_dir: i32, to: i32, to_len: i32| {
+            call(&mut c, |mem, host| {
+                host.path_rename(mem, from_dir as u32, (from as u32, from_len as u32), to_dir as u32, (to as u32, to_len as u32))
+            })
+        },
+    )?;
+
+    for (name, params) in UNSUPPORTED {
+        let ty = FuncType::new(params.iter().copied(), [ValueType::I32]);
+        linker.func_new(MODULE, name, ty, |_, _, results: &mut [Value]| {
+            results[0] = Value::I32(ENOSYS as i32);
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fs::{TmpFs, Vfs};
+    use alloc::boxed::Box;
+    use alloc::rc::Rc;
+    use alloc::vec;
+    use core::cell::RefCell;
+
+    /// A host with `/` on a tmpfs holding `dir/` and the working directory
+    /// `/dir`, and 4 KiB of memory
+    fn host() -> (Host, Vec<u8>) {
+        let mut vfs = Vfs::new();
+        vfs.mount("/", Box::new(TmpFs::new())).unwrap();
+        vfs.mkdir("/dir").unwrap();
+        vfs.chdir("/dir").unwrap();
+        let mut host = Host::with_console(|_| {}).vfs(vfs);
+        host.open_std();
+        (host, vec![0; 4096])
+    }
+
+    /// Put `s` at `ptr`, returning its (ptr, len)
+    fn string(mem: &mut [u8], ptr: u32, s: &str) -> (u32, u32) {
+        put(mem, ptr, s.as_bytes()).unwrap();
+        (ptr, s.len() as u32)
+    }
+
+    /// One iovec at 0x100 for `len` bytes at 0x200
+    fn iovec(mem: &mut [u8], len: u32) {
+        put_u32(mem, 0x100, 0x200).unwrap();
+        put_u32(mem, 0x104, len).unwrap();
+    }
+
+    #[test]
+    fn args_and_environment() {
+        let mut host = Host::with_console(|_| {}).args(["app", "-v"]).env(["HOME=/"]);
+        let mut mem = vec![0; 256];
+        host.args_sizes_get(&mut mem, 0, 4).unwrap();
+        assert_eq!((get_u32(&mem, 0), get_u32(&mem, 4)), (Ok(2), Ok(7)));
+        host.args_get(&mut mem, 16, 32).unwrap();
+        assert_eq!((get_u32(&mem, 16), get_u32(&mem, 20)), (Ok(32), Ok(36)));
+        assert_eq!(&mem[32..39], b"app\0-v\0");
+        host.environ_get(&mut mem, 64, 80).unwrap();
+        assert_eq!(&mem[80..87], b"HOME=/\0");
+        // Pointers outside memory fault instead of panicking
+        assert_eq!(host.args_get(&mut mem, 16, 254), Err(EFAULT));
+    }
+
+    #[test]
+    fn console_input_is_line_edited() {
+        let echo = Rc::new(RefCell::new(Vec::new()));
+        let sink = echo.clone();
+        let mut keys = b"ab\x08c\nrest\n\x04".iter().copied();
+        let mut host = Host::with_console(move |b| sink.borrow_mut().extend_from_slice(b)).keyboard(move || keys.next());
+        host.open_std();
+        let mut mem = vec![0; 1024];
+
+        // A short read leaves the rest of the line for next time
+        iovec(&mut mem, 2);
+        host.fd_read(&mut mem, 0, 0x100, 1, 0).unwrap();
+        assert_eq!((get_u32(&mem, 0), &mem[0x200..0x202]), (Ok(2), &b"ac"[..]));
+        iovec(&mut mem, 16);
+        host.fd_read(&mut mem, 0, 0x100, 1, 0).unwrap();
+        assert_eq!((get_u32(&mem, 0), mem[0x200]), (Ok(1), b'\n'));
+        host.fd_read(&mut mem, 0, 0x100, 1, 0).unwrap();
+        assert_eq!(&mem[0x200..0x205], b"rest\n");
+        host.fd_read(&mut mem, 0, 0x100, 1, 0).unwrap();
+        assert_eq!(get_u32(&mem, 0), Ok(0));
+        assert_eq!(&echo.borrow()[..], b"ab\x08c\nrest\n");
+    }
+
+    #[test]
+    fn files() {
+        let (mut host, mut mem) = host();
+        // Preopens: `/` and the working directory as `.`
+        host.fd_prestat_dir_name(&mut mem, 4, 0, 8).unwrap();
+        assert_eq!(mem[0], b'.');
+        assert_eq!(host.fd_prestat_get(&mut mem, 5, 0), Err(EBADF));
+
+        // Create, write and reopen a file relative to the working directory
+        let path = string(&mut mem, 0x300, "notes.txt");
+        host.path_open(&mut mem, 4, path, OFLAGS_CREAT, RIGHT_FD_WRITE, 0, 0).unwrap();
+        let fd = get_u32(&mem, 0).unwrap();
+        assert_eq!(fd, 5);
+        string(&mut mem, 0x200, "hello");
+        iovec(&mut mem, 5);
+        host.fd_write(&mut mem, fd, 0x100, 1, 0).unwrap();
+        host.fd_close(fd).unwrap();
+        assert_eq!(host.path_open(&mut mem, 4, path, OFLAGS_CREAT | OFLAGS_EXCL, 0, 0, 0), Err(EEXIST));
+
+        let path = string(&mut mem, 0x300, "/dir/notes.txt");
+        host.path_open(&mut mem, 3, path, 0, RIGHT_FD_READ, 0, 0).unwrap();
+        iovec(&mut mem, 16);
+        host.fd_seek(&mut mem, 5, 1, WHENCE_SET, 0).unwrap();
+        host.fd_read(&mut mem, 5, 0x100, 1, 0).unwrap();
+        assert_eq!((get_u32(&mem, 0), &mem[0x200..0x204]), (Ok(4), &b"ello"[..]));
+        host.fd_filestat_get(&mut mem, 5, 0x400).unwrap();
+        assert_eq!((mem[0x410], mem[0x420]), (FILETYPE_REGULAR_FILE, 5));
+
+        let missing = string(&mut mem, 0x300, "nope");
+        assert_eq!(host.path_open(&mut mem, 4, missing, 0, RIGHT_FD_READ, 0, 0), Err(ENOENT));
+        assert_eq!(host.fd_write(&mut mem, 4, 0x100, 1, 0), Err(EISDIR));
+        host.close_all();
+        assert!(host.take_vfs().unwrap().close(0).is_err(), "left open");
+    }
+
+    #[test]
+    fn read_dir() {
+        let (mut host, mut mem) = host();
+        for name in ["a", "bb"] {
+            let path = string(&mut mem, 0x300, name);
+            host.path_create_directory(&mut mem, 4, path).unwrap();
+        }
+        // Read as libc does: whole entries count, and a full buffer means
+        // reading again from the last whole entry's cookie
+        let mut names = Vec::new();
+        let mut cookie = 0;
+        loop {
+            host.fd_readdir(&mut mem, 4, 0x200, 40, cookie, 0).unwrap();
+            let used = get_u32(&mem, 0).unwrap() as usize;
+            let mut at = 0x200;
+            while at + DIRENT_SIZE <= 0x200 + used {
+                let name_len = get_u32(&mem, at as u32 + 16).unwrap() as usize;
+                if at + DIRENT_SIZE + name_len > 0x200 + used {
+                    break;
+                }
+                cookie = u64::from_le_bytes(mem[at..at + 8].try_into().unwrap());
+                names.push(String::from_utf8(mem[at + DIRENT_SIZE..at + DIRENT_SIZE + name_len].to_vec()).unwrap());
+                at += DIRENT_SIZE + name_len;
+            }
+            if used < 40 {
+                break;
+            }
+        }
+        names.retain(|n| n != "." && n != "..");
+        names.sort();
+        assert_eq!(names, ["a", "bb"]);
+    }
+}
@@ -0,0 +1,43 @@
+//! Values passed across the import boundary, shared with the host
+//!
+//! Colors are 0xAARRGGBB. `blit` reads `w * h` little-endian colors at its
+//! pointer. `poll_event` writes an event record at its pointer (see
+//! `encode_event`); the guest's `/dev/input` device reads the same records.
+
+pub const GRAPHICS_MODULE: &str = "aether_graphics";
+pub const INPUT_MODULE: &str = "aether_input";
+
+pub const BLIT_COPY: i32 = 0;
+pub const BLIT_ALPHA: i32 = 1;
+pub const BLIT_COLOR_KEY: i32 = 2;
+
+/// `poll_event` return values; 0 means nothing is pending
+pub const EVENT_NONE: i32 = 0;
+pub const EVENT_KEY: i32 = 1;
+pub const EVENT_POINTER_MOVE: i32 = 2;
+pub const EVENT_POINTER_DOWN: i32 = 3;
+pub const EVENT_POINTER_UP: i32 = 4;
+
+/// Size of an event record
+pub const EVENT_SIZE: usize = 16;
+
+pub const BUTTON_LEFT: u32 = 1 << 0;
+pub const BUTTON_RIGHT: u32 = 1 << 1;
+pub const BUTTON_MIDDLE: u32 = 1 << 2;
+
+/// An event record: `[kind, x, y, data]` as little-endian 32-bit fields,
+/// where `data` is the key's code point for `EVENT_KEY` or the button for
+/// pointer presses and releases
+pub fn encode_event(kind: i32, x: i32, y: i32, data: u32) -> [u8; EVENT_SIZE] {
+    let mut record = [0u8; EVENT_SIZE];
+    for (field, v) in record.chunks_exact_mut(4).zip([kind as u32, x as u32, y as u32, data]) {
+        field.copy_from_slice(&v.to_le_bytes());
+    }
+    record
+}
+
+/// The `(kind, x, y, data)` fields of a record from `encode_event`
+pub fn decode_event(record: &[u8; EVENT_SIZE]) -> (i32, i32, i32, u32) {
+    let field = |i: usize| u32::from_le_bytes([record[i], record[i + 1], record[i + 2], record[i + 3]]);
+    (field(0) as i32, field(4) as i32, field(8) as i32, field(12))
+}
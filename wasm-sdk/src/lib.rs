@@ -0,0 +1,120 @@
+//! Bindings for WASM apps running on AetherOS: the `aether_graphics` and
+//! `aether_input` host imports, wrapped in safe functions
+//!
+//! Build with `--target wasm32-unknown-unknown` or `wasm32-wasip1` and run
+//! the module from the guest shell (`wasm /bin/app.wasm`). The screen is
+//! drawn to directly; call `present` once per frame, which also paces the
+//! app to the display. The constants in `abi` are shared with the host side
+//! in `aether-user`.
+
+#![no_std]
+
+pub mod abi;
+
+/// A pixel, 0xAARRGGBB
+pub type Color = u32;
+
+pub const BLACK: Color = 0xFF000000;
+pub const WHITE: Color = 0xFFFFFFFF;
+
+pub const fn rgb(r: u8, g: u8, b: u8) -> Color {
+    0xFF000000 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+pub const fn rgba(r: u8, g: u8, b: u8, a: u8) -> Color {
+    (a as u32) << 24 | (r as u32) << 16 | (g as u32) << 8 | b as u32
+}
+
+pub use abi::{BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT};
+
+/// How `blit` combines source pixels with the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlitMode {
+    /// Overwrite the screen
+    Copy,
+    /// Blend using the source alpha channel
+    Alpha,
+    /// Copy, skipping pixels equal to the key color (alpha ignored)
+    ColorKey(Color),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// A key press
+    Key(char),
+    /// The pointer moved to (x, y)
+    PointerMove { x: i32, y: i32 },
+    /// A button (one of `BUTTON_*`) was pressed at (x, y)
+    PointerDown { x: i32, y: i32, button: u32 },
+    /// A button was released at (x, y)
+    PointerUp { x: i32, y: i32, button: u32 },
+}
+
+mod sys {
+    #[link(wasm_import_module = "aether_graphics")]
+    extern "C" {
+        pub fn width() -> i32;
+        pub fn height() -> i32;
+        pub fn clear(color: u32);
+        pub fn pixel(x: i32, y: i32, color: u32);
+        pub fn fill_rect(x: i32, y: i32, w: i32, h: i32, color: u32);
+        pub fn blit(pixels: *const u32, w: i32, h: i32, x: i32, y: i32, mode: i32, key: u32);
+        pub fn present();
+    }
+
+    #[link(wasm_import_module = "aether_input")]
+    extern "C" {
+        pub fn poll_event(event: *mut [u8; crate::abi::EVENT_SIZE]) -> i32;
+    }
+}
+
+/// Screen size in pixels; (0, 0) when the host has no display
+pub fn screen_size() -> (u32, u32) {
+    unsafe { (sys::width() as u32, sys::height() as u32) }
+}
+
+/// Fill the whole screen
+pub fn clear(color: Color) {
+    unsafe { sys::clear(color) }
+}
+
+/// Set one pixel; off-screen pixels are ignored
+pub fn pixel(x: i32, y: i32, color: Color) {
+    unsafe { sys::pixel(x, y, color) }
+}
+
+/// Fill a rectangle, clipped to the screen
+pub fn fill_rect(x: i32, y: i32, w: u32, h: u32, color: Color) {
+    unsafe { sys::fill_rect(x, y, w as i32, h as i32, color) }
+}
+
+/// Draw a `w`-pixel-wide image, row by row, with its top-left corner at
+/// (x, y), clipped to the screen
+pub fn blit(pixels: &[Color], w: u32, x: i32, y: i32, mode: BlitMode) {
+    let h = if w == 0 { 0 } else { pixels.len() / w as usize };
+    let (mode, key) = match mode {
+        BlitMode::Copy => (abi::BLIT_COPY, 0),
+        BlitMode::Alpha => (abi::BLIT_ALPHA, 0),
+        BlitMode::ColorKey(key) => (abi::BLIT_COLOR_KEY, key),
+    };
+    unsafe { sys::blit(pixels.as_ptr(), w as i32, h as i32, x, y, mode, key) }
+}
+
+/// End the frame, waiting until the display is ready for the next one
+pub fn present() {
+    unsafe { sys::present() }
+}
+
+/// The next pending input event, if any (doesn't block)
+pub fn poll_event() -> Option<Event> {
+    let mut record = [0u8; abi::EVENT_SIZE];
+    unsafe { sys::poll_event(&mut record) };
+    let (kind, x, y, data) = abi::decode_event(&record);
+    match kind {
+        abi::EVENT_KEY => char::from_u32(data).map(Event::Key),
+        abi::EVENT_POINTER_MOVE => Some(Event::PointerMove { x, y }),
+        abi::EVENT_POINTER_DOWN => Some(Event::PointerDown { x, y, button: data }),
+        abi::EVENT_POINTER_UP => Some(Event::PointerUp { x, y, button: data }),
+        _ => None,
+    }
+}